@@ -20,11 +20,15 @@ use bao_tree::io::fsm::BaoContentItem;
 use bao_tree::io::DecodeError;
 use bao_tree::ChunkNum;
 use bytes::BytesMut;
+use iroh_io::AsyncSliceWriter;
 use quinn::RecvStream;
 use range_collections::RangeSet2;
 use tracing::{debug, error};
 
-use crate::protocol::{write_lp, AnyGetRequest, RangeSpecSeq};
+use crate::protocol::{
+    AnyGetRequest, Closed, FanOutRequest, FanOutResponse, FanOutStreamHeader, RangeSpecSeq,
+    FRAMING, MAX_FAN_OUT_STREAMS,
+};
 use crate::util::io::{TrackingReader, TrackingWriter};
 use crate::IROH_BLOCK_SIZE;
 
@@ -51,17 +55,19 @@ impl Stats {
 ///
 #[doc = include_str!("../docs/img/get_machine.drawio.svg")]
 pub mod fsm {
-    use std::result;
+    use std::ops::Range;
+    use std::{io, result};
 
-    use crate::protocol::{read_lp, GetRequest, NonEmptyRequestRangeSpecIter};
+    use crate::protocol::{GetRequest, NonEmptyRequestRangeSpecIter};
 
     use super::*;
 
     use bao_tree::io::fsm::{
         ResponseDecoderReading, ResponseDecoderReadingNext, ResponseDecoderStart,
     };
+    use bytes::Bytes;
     use derive_more::From;
-    use iroh_io::AsyncSliceWriter;
+    use tokio::sync::mpsc;
 
     self_cell::self_cell! {
         struct RangesIterInner {
@@ -177,8 +183,7 @@ pub mod fsm {
             {
                 debug!("sending request");
                 // wrap the get request in a request so we can serialize it
-                let request_bytes = postcard::to_stdvec(&request)?;
-                write_lp(&mut writer, &request_bytes).await?;
+                FRAMING.write_message(&mut writer, &request).await?;
             }
 
             // 2. Finish writing before expecting a response
@@ -194,12 +199,23 @@ pub mod fsm {
                 AnyGetRequest::CustomGet(_) => {
                     // we sent a custom request, so we need the actual GetRequest from the response
                     let mut buffer = BytesMut::new();
-                    let response = read_lp(&mut reader, &mut buffer)
-                        .await?
-                        .context("unexpected EOF when reading response to custom get request")?;
-                    postcard::from_bytes::<GetRequest>(&response).context(
-                        "unable to deserialize response to custom get request as get request",
-                    )?
+                    FRAMING
+                        .read_message::<GetRequest>(&mut reader, &mut buffer)
+                        .await
+                        .context(
+                            "unable to deserialize response to custom get request as get request",
+                        )?
+                        .context("unexpected EOF when reading response to custom get request")?
+                }
+                AnyGetRequest::FanOut(_) => {
+                    return Err(GetResponseError::Generic(anyhow::anyhow!(
+                        "fan-out requests must be sent using get::fan_out"
+                    )));
+                }
+                AnyGetRequest::Push(_) => {
+                    return Err(GetResponseError::Generic(anyhow::anyhow!(
+                        "push requests must be sent using push::push"
+                    )));
                 }
             };
             let hash = request.hash;
@@ -292,6 +308,11 @@ pub mod fsm {
         pub fn finish(self) -> AtClosing {
             AtClosing::new(self.misc, self.reader)
         }
+
+        /// Cancel the request, see [`AtBlobContent::cancel`]
+        pub fn cancel(self) -> Stats {
+            self.misc.cancel(self.reader)
+        }
     }
 
     impl AtStartRoot {
@@ -320,6 +341,11 @@ pub mod fsm {
         pub fn finish(self) -> AtClosing {
             AtClosing::new(self.misc, self.reader)
         }
+
+        /// Cancel the request, see [`AtBlobContent::cancel`]
+        pub fn cancel(self) -> Stats {
+            self.misc.cancel(self.reader)
+        }
     }
 
     /// State before reading a size header
@@ -330,6 +356,11 @@ pub mod fsm {
     }
 
     impl AtBlobHeader {
+        /// Cancel the request, see [`AtBlobContent::cancel`]
+        pub fn cancel(self) -> Stats {
+            self.misc.cancel(self.stream.finish())
+        }
+
         /// Read the size header, returning it and going into the `Content` state.
         pub async fn next(self) -> Result<(AtBlobContent, u64), std::io::Error> {
             let (stream, size) = self.stream.next().await?;
@@ -386,6 +417,54 @@ pub mod fsm {
             Ok((done, res))
         }
 
+        /// Read the bytes in `range` from the response
+        ///
+        /// This is meant for responses to a [`GetRequest::byte_range`] request. Data of
+        /// the received chunks outside of `range` is dropped. If the range extends past
+        /// the end of the blob, the result is shorter than the range.
+        pub async fn read_byte_range(
+            self,
+            range: Range<u64>,
+        ) -> result::Result<(AtEndBlob, Vec<u8>), DecodeError> {
+            let (mut curr, size) = self.next().await?;
+            let end = range.end.min(size);
+            let start = range.start.min(end);
+            let mut res = vec![0u8; (end - start) as usize];
+            let done = loop {
+                match curr.next().await {
+                    BlobContentNext::More((next, data)) => {
+                        if let BaoContentItem::Leaf(leaf) = data? {
+                            let leaf_start = leaf.offset.0;
+                            let leaf_end = leaf_start + leaf.data.len() as u64;
+                            let from = leaf_start.max(start);
+                            let to = leaf_end.min(end);
+                            if from < to {
+                                res[(from - start) as usize..(to - start) as usize]
+                                    .copy_from_slice(
+                                        &leaf.data[(from - leaf_start) as usize
+                                            ..(to - leaf_start) as usize],
+                                    );
+                            }
+                        }
+                        curr = next;
+                    }
+                    BlobContentNext::Done(done) => break done,
+                }
+            };
+            Ok((done, res))
+        }
+
+        /// Send the data of the blob to `tx` as soon as it is verified
+        ///
+        /// See [`AtBlobContent::send_verified`].
+        pub async fn send_verified(
+            self,
+            tx: &mpsc::Sender<(u64, Bytes)>,
+        ) -> result::Result<AtEndBlob, DecodeError> {
+            let (content, _size) = self.next().await?;
+            content.send_verified(tx).await
+        }
+
         /// Write the entire blob to a slice writer
         pub async fn write_all<D: AsyncSliceWriter>(
             self,
@@ -430,6 +509,15 @@ pub mod fsm {
     }
 
     impl AtBlobContent {
+        /// Cancel the request, returning statistics
+        ///
+        /// Unlike dropping the state, this tells the provider that the requester is no
+        /// longer interested with [`Closed::Cancelled`], so it stops sending the rest of
+        /// the response right away.
+        pub fn cancel(self) -> Stats {
+            self.misc.cancel(self.stream.finish())
+        }
+
         /// Read the next item, either content, an error, or the end of the blob
         pub async fn next(self) -> BlobContentNext {
             match self.stream.next().await {
@@ -445,6 +533,32 @@ pub mod fsm {
             }
         }
 
+        /// Send the data of the blob to `tx` as soon as it is verified
+        ///
+        /// Each item is the offset of the data in the blob and the data itself, the
+        /// data of a leaf of the bao tree. This allows consuming a blob while it is
+        /// still being received, e.g. to start playing a media file. Fails if `tx`
+        /// is closed before the blob is complete.
+        pub async fn send_verified(
+            self,
+            tx: &mpsc::Sender<(u64, Bytes)>,
+        ) -> result::Result<AtEndBlob, DecodeError> {
+            let mut content = self;
+            loop {
+                match content.next().await {
+                    BlobContentNext::More((content1, item)) => {
+                        content = content1;
+                        if let BaoContentItem::Leaf(leaf) = item? {
+                            tx.send((leaf.offset.0, leaf.data)).await.map_err(|_| {
+                                io::Error::new(io::ErrorKind::BrokenPipe, "receiver dropped")
+                            })?;
+                        }
+                    }
+                    BlobContentNext::Done(end) => return Ok(end),
+                }
+            }
+        }
+
         /// Write the entire blob to a slice writer
         pub async fn write_all<D: AsyncSliceWriter>(
             self,
@@ -508,6 +622,11 @@ pub mod fsm {
     }
 
     impl AtEndBlob {
+        /// Cancel the request, see [`AtBlobContent::cancel`]
+        pub fn cancel(self) -> Stats {
+            self.misc.cancel(self.stream)
+        }
+
         /// Read the next child, or finish
         pub fn next(mut self) -> EndBlobNext {
             if let Some((offset, ranges)) = self.misc.ranges_iter.next() {
@@ -544,11 +663,7 @@ pub mod fsm {
                 reader.stop(0u8.into()).ok();
                 error!("Received unexpected data from the provider: {chunk:?}");
             }
-            Ok(Stats {
-                elapsed: self.misc.start.elapsed(),
-                bytes_written: self.misc.bytes_written,
-                bytes_read,
-            })
+            Ok(self.misc.stats(bytes_read))
         }
     }
 
@@ -562,6 +677,170 @@ pub mod fsm {
         /// iterator over the ranges of the collection and the children
         ranges_iter: RangesIter,
     }
+
+    impl Misc {
+        fn stats(&self, bytes_read: u64) -> Stats {
+            Stats {
+                elapsed: self.start.elapsed(),
+                bytes_written: self.bytes_written,
+                bytes_read,
+            }
+        }
+
+        /// Ask the provider to stop sending the response
+        fn cancel(&self, reader: TrackingReader<RecvStream>) -> Stats {
+            let (mut reader, bytes_read) = reader.into_parts();
+            reader.stop(Closed::Cancelled.into()).ok();
+            self.stats(bytes_read)
+        }
+    }
+}
+
+/// Get a single blob using a [`FanOutRequest`], writing the verified data to `data`.
+///
+/// The provider answers on multiple unidirectional streams, each containing a bao
+/// encoded range of the blob. Every part is verified against the requested hash and
+/// written at its offset, so parts may arrive in any order.
+///
+/// Unidirectional streams that belong to other requests, e.g. leftovers from an aborted
+/// fan-out on the same connection, are dropped. Concurrent fan-out requests on the same
+/// connection will still steal each other's streams and fail.
+pub async fn fan_out<D: AsyncSliceWriter>(
+    connection: &quinn::Connection,
+    request: FanOutRequest,
+    mut data: D,
+) -> Result<Stats, GetResponseError> {
+    let start = Instant::now();
+    let hash = request.hash;
+    let (writer, reader) = connection.open_bi().await?;
+    let request_id = writer.id().index();
+    let mut reader = TrackingReader::new(reader);
+    let mut writer = TrackingWriter::new(writer);
+    debug!("sending fan-out request");
+    FRAMING
+        .write_message(&mut writer, &AnyGetRequest::FanOut(request))
+        .await?;
+    let (mut writer, bytes_written) = writer.into_parts();
+    writer.finish().await?;
+
+    let mut buffer = BytesMut::new();
+    let response: FanOutResponse = FRAMING
+        .read_message(&mut reader, &mut buffer)
+        .await?
+        .context("blob not found")?;
+    if response.streams == 0 || response.streams > MAX_FAN_OUT_STREAMS {
+        return Err(
+            anyhow::anyhow!("invalid number of fan-out streams: {}", response.streams).into(),
+        );
+    }
+    let (mut reader, bytes_read_request) = reader.into_parts();
+
+    // decode all parts concurrently, and write the verified leaves from this task
+    let (tx, mut rx) = tokio::sync::mpsc::channel(usize::from(response.streams) * 2);
+    let parts = futures::future::try_join_all((0..response.streams).map(|_| {
+        let tx = tx.clone();
+        async move {
+            loop {
+                let stream = connection.accept_uni().await?;
+                if let Some(part) = read_fan_out_part(stream, hash, request_id, &tx).await? {
+                    break Result::<_, GetResponseError>::Ok(part);
+                }
+            }
+        }
+    }));
+    drop(tx);
+    let write = async {
+        while let Some((offset, bytes)) = rx.recv().await {
+            data.write_bytes_at(offset, bytes).await?;
+        }
+        std::io::Result::Ok(())
+    };
+    let transfer = async {
+        let (parts, write) = tokio::join!(parts, write);
+        let parts = parts?;
+        write.context("failed to write fan-out data")?;
+        Result::<_, GetResponseError>::Ok(parts)
+    };
+    // The provider finishes the request stream once all parts are sent, and resets it
+    // if it fails to send any of them, in which case some parts will never arrive.
+    let request_stream = async {
+        match reader.read_chunk(8, false).await? {
+            None => futures::future::pending().await,
+            Some(chunk) => {
+                error!("Received unexpected data from the provider: {chunk:?}");
+                Err(anyhow::anyhow!("unexpected data on fan-out request stream").into())
+            }
+        }
+    };
+    let parts = tokio::select! {
+        parts = transfer => parts?,
+        res = request_stream => return res,
+    };
+
+    // make sure the parts covered the entire blob
+    let mut covered = RangeSet2::empty();
+    for (ranges, _) in &parts {
+        covered |= ranges.clone();
+    }
+    if covered != RangeSet2::all() {
+        return Err(anyhow::anyhow!("fan-out parts do not cover the blob").into());
+    }
+    let bytes_read = parts.iter().map(|(_, read)| read).sum::<u64>();
+
+    Ok(Stats {
+        elapsed: start.elapsed(),
+        bytes_written,
+        bytes_read: bytes_read + bytes_read_request,
+    })
+}
+
+/// Read and verify a single part of a fan-out response.
+///
+/// Verified leaves are sent to `tx`. Returns the ranges of the part and the number
+/// of bytes read from the stream, or `None` if the stream belongs to another request.
+async fn read_fan_out_part(
+    stream: RecvStream,
+    hash: Hash,
+    request_id: u64,
+    tx: &tokio::sync::mpsc::Sender<(u64, bytes::Bytes)>,
+) -> Result<Option<(RangeSet2<ChunkNum>, u64)>, GetResponseError> {
+    use bao_tree::io::fsm::{ResponseDecoderReadingNext, ResponseDecoderStart};
+
+    let mut reader = TrackingReader::new(stream);
+    let mut buffer = BytesMut::new();
+    let header: FanOutStreamHeader = FRAMING
+        .read_message(&mut reader, &mut buffer)
+        .await?
+        .context("unexpected EOF when reading fan-out header")?;
+    if header.request_id != request_id {
+        // dropping the stream stops it, so the provider does not keep sending
+        debug!(
+            "dropping fan-out stream for unexpected request {}",
+            header.request_id
+        );
+        return Ok(None);
+    }
+    let ranges = header.ranges.to_chunk_ranges();
+    let (mut decoder, _size) =
+        ResponseDecoderStart::new(hash.into(), ranges.clone(), IROH_BLOCK_SIZE, reader)
+            .next()
+            .await
+            .map_err(DecodeError::Io)?;
+    let reader = loop {
+        match decoder.next().await {
+            ResponseDecoderReadingNext::More((next, item)) => {
+                if let BaoContentItem::Leaf(leaf) = item? {
+                    tx.send((leaf.offset.0, leaf.data))
+                        .await
+                        .context("fan-out writer dropped")?;
+                }
+                decoder = next;
+            }
+            ResponseDecoderReadingNext::Done(reader) => break reader,
+        }
+    };
+    let (_, bytes_read) = reader.into_parts();
+    Ok(Some((ranges, bytes_read)))
 }
 
 /// Error when processing a response
@@ -5,8 +5,10 @@
 
 pub mod collection;
 pub mod get;
+pub mod metrics;
 pub mod protocol;
 pub mod provider;
+pub mod push;
 pub mod util;
 
 #[cfg(test)]
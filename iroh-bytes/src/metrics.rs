@@ -0,0 +1,39 @@
+//! Metrics for iroh-bytes
+
+use iroh_metrics::{
+    core::{Counter, Metric},
+    struct_iterable::Iterable,
+};
+
+/// Enum of metrics for the module
+#[allow(missing_docs)]
+#[derive(Debug, Clone, Iterable)]
+pub struct Metrics {
+    // Requests served by the provider, the active transfers are the started ones which
+    // are neither completed nor aborted.
+    pub transfers_started: Counter,
+    pub transfers_completed: Counter,
+    pub transfers_aborted: Counter,
+
+    pub blobs_sent: Counter,
+    pub bytes_sent: Counter,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            transfers_started: Counter::new("transfers_started"),
+            transfers_completed: Counter::new("transfers_completed"),
+            transfers_aborted: Counter::new("transfers_aborted"),
+
+            blobs_sent: Counter::new("Number of blobs sent, including collections"),
+            bytes_sent: Counter::new("Number of bytes sent by the provider"),
+        }
+    }
+}
+
+impl Metric for Metrics {
+    fn name() -> &'static str {
+        "Bytes"
+    }
+}
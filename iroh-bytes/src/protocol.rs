@@ -1,15 +1,17 @@
 //! Protocol for communication between provider and client.
 use std::fmt::{self, Display};
-use std::io;
+use std::ops::Range;
 use std::str::FromStr;
+use std::time::Duration;
 
-use anyhow::{bail, ensure, Context, Result};
-use bytes::{Bytes, BytesMut};
+use anyhow::{ensure, Result};
+use bao_tree::{ByteNum, ChunkNum};
+use bytes::Bytes;
 use derive_more::From;
+use iroh_net::framing::{Framing, Prefix};
 use quinn::VarInt;
 use range_collections::RangeSet2;
 use serde::{Deserialize, Serialize};
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 mod range_spec;
 pub use range_spec::{NonEmptyRequestRangeSpecIter, RangeSpec, RangeSpecSeq};
 
@@ -18,14 +20,21 @@ use crate::util::Hash;
 /// Maximum message size is limited to 100MiB for now.
 pub const MAX_MESSAGE_SIZE: usize = 1024 * 1024 * 100;
 
+/// The framing of the messages of the protocol, with a little endian `u64` length prefix.
+pub const FRAMING: Framing = Framing::new(Prefix::U64Le, MAX_MESSAGE_SIZE);
+
 /// The ALPN used with quic for the iroh bytes protocol.
-pub const ALPN: [u8; 13] = *b"/iroh-bytes/2";
+pub const ALPN: [u8; 13] = *b"/iroh-bytes/3";
+
+/// Maximum number of unidirectional streams a provider will use to answer a single
+/// [`FanOutRequest`].
+pub const MAX_FAN_OUT_STREAMS: u16 = 32;
 
 /// Maximum size of a request token, matches a browser cookie max size:
 /// <https://datatracker.ietf.org/doc/html/rfc2109#section-6.3>.
 const MAX_REQUEST_TOKEN_SIZE: usize = 4096;
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, From)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, From)]
 /// A Request token is an opaque byte sequence associated with a single request.
 /// Applications can use request tokens to implement request authorization,
 /// user association, etc.
@@ -82,6 +91,10 @@ pub enum Request {
     Get(GetRequest),
     /// A get request that allows the receiver to create a collection
     CustomGet(CustomGetRequest),
+    /// A request for a single blob, to be sent over multiple unidirectional streams
+    FanOut(FanOutRequest),
+    /// A request to store a blob sent by the requester
+    Push(PushRequest),
 }
 
 impl Request {
@@ -90,6 +103,8 @@ impl Request {
         match self {
             Request::Get(get) => get.token(),
             Request::CustomGet(get) => get.token.as_ref(),
+            Request::FanOut(fan_out) => fan_out.token(),
+            Request::Push(push) => push.token(),
         }
     }
 
@@ -98,6 +113,29 @@ impl Request {
         match &mut self {
             Request::Get(get) => get.token = value,
             Request::CustomGet(get) => get.token = value,
+            Request::FanOut(fan_out) => fan_out.token = value,
+            Request::Push(push) => push.token = value,
+        }
+        self
+    }
+
+    /// Gets the request deadline.
+    pub fn deadline(&self) -> Option<Duration> {
+        match self {
+            Request::Get(get) => get.deadline(),
+            Request::CustomGet(get) => get.deadline,
+            Request::FanOut(fan_out) => fan_out.deadline(),
+            Request::Push(push) => push.deadline(),
+        }
+    }
+
+    /// Sets the request deadline and returns a new request.
+    pub fn with_deadline(mut self, value: Option<Duration>) -> Self {
+        match &mut self {
+            Request::Get(get) => get.deadline = value,
+            Request::CustomGet(get) => get.deadline = value,
+            Request::FanOut(fan_out) => fan_out.deadline = value,
+            Request::Push(push) => push.deadline = value,
         }
         self
     }
@@ -110,6 +148,8 @@ impl Request {
 pub struct CustomGetRequest {
     /// The optional request token
     pub token: Option<RequestToken>,
+    /// The optional deadline, see [`GetRequest::with_deadline`]
+    pub deadline: Option<Duration>,
     /// The opaque request data
     pub data: Bytes,
 }
@@ -130,6 +170,8 @@ pub struct GetRequest {
     pub ranges: RangeSpecSeq,
     /// Optional Request token
     token: Option<RequestToken>,
+    /// Optional deadline, relative to when the provider receives the request
+    deadline: Option<Duration>,
 }
 
 impl GetRequest {
@@ -139,6 +181,7 @@ impl GetRequest {
             hash,
             ranges,
             token: None,
+            deadline: None,
         }
     }
 
@@ -147,6 +190,7 @@ impl GetRequest {
         Self {
             hash,
             token: None,
+            deadline: None,
             ranges: RangeSpecSeq::all(),
         }
     }
@@ -156,10 +200,29 @@ impl GetRequest {
         Self {
             hash,
             token: None,
+            deadline: None,
             ranges: RangeSpecSeq::new([RangeSet2::all()]),
         }
     }
 
+    /// Request the given chunk ranges of a single blob
+    pub fn blob_ranges(hash: Hash, ranges: RangeSet2<ChunkNum>) -> Self {
+        Self::new(hash, RangeSpecSeq::new([ranges]))
+    }
+
+    /// Request the bytes in `range` of a single blob
+    ///
+    /// The range is extended to whole chunks, so the response contains every chunk that
+    /// overlaps it. Each chunk is verified against the hash, so this can be used to read
+    /// parts of a blob, e.g. to seek in a media file, without getting all of it. See
+    /// [`AtBlobHeader::read_byte_range`](crate::get::fsm::AtBlobHeader::read_byte_range)
+    /// to extract the requested bytes from the response.
+    pub fn byte_range(hash: Hash, range: Range<u64>) -> Self {
+        let start = ByteNum(range.start).full_chunks();
+        let end = ByteNum(range.end).chunks();
+        Self::blob_ranges(hash, RangeSet2::from(start..end))
+    }
+
     /// Set the request token
     pub fn with_token(self, token: Option<RequestToken>) -> Self {
         Self { token, ..self }
@@ -169,64 +232,150 @@ impl GetRequest {
     pub fn token(&self) -> Option<&RequestToken> {
         self.token.as_ref()
     }
+
+    /// Set the request deadline
+    ///
+    /// The provider stops serving the request once it has been working on it for longer
+    /// than this, and resets the stream with [`Closed::DeadlineExceeded`]. The deadline is
+    /// relative to when the provider receives the request, so the clocks of requester and
+    /// provider do not need to agree.
+    pub fn with_deadline(self, deadline: Option<Duration>) -> Self {
+        Self { deadline, ..self }
+    }
+
+    /// Get the request deadline
+    pub fn deadline(&self) -> Option<Duration> {
+        self.deadline
+    }
+}
+
+/// A request for a single blob, sent over multiple unidirectional streams in parallel.
+///
+/// The provider splits the blob into chunk group aligned ranges and sends each range
+/// as an individually verifiable bao response on its own unidirectional stream. This
+/// avoids the per stream flow control window being the bottleneck on paths with a high
+/// bandwidth delay product.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct FanOutRequest {
+    /// blake3 hash
+    pub hash: Hash,
+    /// The number of unidirectional streams the requester would like to receive
+    ///
+    /// The provider may use fewer streams, but never more than [`MAX_FAN_OUT_STREAMS`].
+    pub streams: u16,
+    /// Optional Request token
+    token: Option<RequestToken>,
+    /// Optional deadline, relative to when the provider receives the request
+    deadline: Option<Duration>,
 }
 
-/// Write the given data to the provider sink, with a unsigned varint length prefix.
-pub(crate) async fn write_lp<W: AsyncWrite + Unpin>(writer: &mut W, data: &[u8]) -> Result<()> {
-    ensure!(
-        data.len() < MAX_MESSAGE_SIZE,
-        "sending message is too large"
-    );
+impl FanOutRequest {
+    /// Request a blob over the given number of streams
+    pub fn new(hash: Hash, streams: u16) -> Self {
+        Self {
+            hash,
+            streams,
+            token: None,
+            deadline: None,
+        }
+    }
+
+    /// Set the request token
+    pub fn with_token(self, token: Option<RequestToken>) -> Self {
+        Self { token, ..self }
+    }
+
+    /// Get the request token
+    pub fn token(&self) -> Option<&RequestToken> {
+        self.token.as_ref()
+    }
+
+    /// Set the request deadline, see [`GetRequest::with_deadline`]
+    pub fn with_deadline(self, deadline: Option<Duration>) -> Self {
+        Self { deadline, ..self }
+    }
 
-    // send length prefix
-    let data_len = data.len() as u64;
-    writer.write_u64_le(data_len).await?;
+    /// Get the request deadline
+    pub fn deadline(&self) -> Option<Duration> {
+        self.deadline
+    }
+}
 
-    // write message
-    writer.write_all(data).await?;
-    Ok(())
+/// The response to a [`FanOutRequest`], sent on the bidirectional request stream.
+///
+/// If the blob is not found the provider closes the stream without sending this.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct FanOutResponse {
+    /// The number of unidirectional streams the provider is going to open
+    pub streams: u16,
 }
 
-/// Reads a length prefixed message.
+/// The header at the start of each unidirectional stream of a fan-out response.
 ///
-/// # Returns
+/// It is followed by the bao encoded `ranges` of the requested blob.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct FanOutStreamHeader {
+    /// The id of the bidirectional stream the [`FanOutRequest`] was sent on
+    pub request_id: u64,
+    /// The ranges of the blob sent on this stream
+    pub ranges: RangeSpec,
+}
+
+/// A request to store a blob on the provider, sent by a peer that has the blob.
 ///
-/// The message as raw bytes.  If the end of the stream is reached and there is no partial
-/// message, returns `None`.
-pub(crate) async fn read_lp(
-    mut reader: impl AsyncRead + Unpin,
-    buffer: &mut BytesMut,
-) -> Result<Option<Bytes>> {
-    let size = match reader.read_u64_le().await {
-        Ok(size) => size,
-        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
-        Err(err) => return Err(err.into()),
-    };
-
-    let reader = reader.take(size);
-    read_fixed_size(reader, buffer, size).await
+/// The provider answers with a [`PushResponse`]. If it asks for the blob, the requester
+/// sends the bao encoding of the whole blob on the same stream and finishes it. The
+/// provider finishes its side of the stream once the blob is verified and stored, or
+/// resets it with [`Closed::RequestFailed`] if storing failed. A provider that does not
+/// accept the push closes the stream without a response.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct PushRequest {
+    /// blake3 hash
+    pub hash: Hash,
+    /// Optional Request token
+    token: Option<RequestToken>,
+    /// Optional deadline, relative to when the provider receives the request
+    deadline: Option<Duration>,
 }
 
-pub(crate) async fn read_fixed_size(
-    reader: impl AsyncRead + Unpin,
-    buffer: &mut BytesMut,
-    size: u64,
-) -> Result<Option<Bytes>> {
-    if size > MAX_MESSAGE_SIZE as u64 {
-        bail!("Incoming message exceeds MAX_MESSAGE_SIZE");
+impl PushRequest {
+    /// Request to push the blob with the given hash
+    pub fn new(hash: Hash) -> Self {
+        Self {
+            hash,
+            token: None,
+            deadline: None,
+        }
+    }
+
+    /// Set the request token
+    pub fn with_token(self, token: Option<RequestToken>) -> Self {
+        Self { token, ..self }
+    }
+
+    /// Get the request token
+    pub fn token(&self) -> Option<&RequestToken> {
+        self.token.as_ref()
     }
 
-    let mut reader = reader.take(size);
-    let size = usize::try_from(size).context("frame larger than usize")?;
+    /// Set the request deadline, see [`GetRequest::with_deadline`]
+    pub fn with_deadline(self, deadline: Option<Duration>) -> Self {
+        Self { deadline, ..self }
+    }
 
-    buffer.reserve(size);
-    loop {
-        let r = reader.read_buf(buffer).await?;
-        if r == 0 {
-            break;
-        }
+    /// Get the request deadline
+    pub fn deadline(&self) -> Option<Duration> {
+        self.deadline
     }
-    Ok(Some(buffer.split_to(size).freeze()))
+}
+
+/// The response to a [`PushRequest`], sent on the bidirectional request stream.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PushResponse {
+    /// The provider accepted the push and waits for the blob
+    Send,
+    /// The provider already has the blob, nothing is sent
+    Present,
 }
 
 /// Reasons to close connections or stop streams.
@@ -254,6 +403,20 @@ pub enum Closed {
     /// Only a single request is allowed on a stream, if more data is received after this a
     /// provider may send this error code in a STOP_STREAM frame.
     RequestReceived = 2,
+    /// The provider failed to complete the request.
+    ///
+    /// Used to reset the request stream when the response is sent on other streams, so
+    /// the requester does not wait for those streams forever.
+    RequestFailed = 3,
+    /// The requester is no longer interested in the response.
+    ///
+    /// Sent by a requester in a STOP_SENDING frame to cancel a request, so the provider
+    /// stops serving it.
+    Cancelled = 4,
+    /// The provider did not complete the request before its deadline.
+    ///
+    /// Used to reset the response stream, see [`GetRequest::with_deadline`].
+    DeadlineExceeded = 5,
 }
 
 impl Closed {
@@ -263,6 +426,9 @@ impl Closed {
             Closed::StreamDropped => b"stream dropped",
             Closed::ProviderTerminating => b"provider terminating",
             Closed::RequestReceived => b"request received",
+            Closed::RequestFailed => b"request failed",
+            Closed::Cancelled => b"request cancelled",
+            Closed::DeadlineExceeded => b"deadline exceeded",
         }
     }
 }
@@ -286,6 +452,9 @@ impl TryFrom<VarInt> for Closed {
             0 => Ok(Self::StreamDropped),
             1 => Ok(Self::ProviderTerminating),
             2 => Ok(Self::RequestReceived),
+            3 => Ok(Self::RequestFailed),
+            4 => Ok(Self::Cancelled),
+            5 => Ok(Self::DeadlineExceeded),
             val => Err(UnknownErrorCode(val)),
         }
     }
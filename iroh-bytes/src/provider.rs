@@ -1,25 +1,43 @@
 //! The server side API
 use std::fmt::Debug;
+use std::future::Future;
 use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::task::{self, Poll};
+use std::time::Duration;
 
 use anyhow::{ensure, Context, Result};
-use bao_tree::io::fsm::{encode_ranges_validated, Outboard};
+use bao_tree::io::fsm::{
+    encode_ranges_validated, BaoContentItem, Outboard, ResponseDecoderReadingNext,
+    ResponseDecoderStart,
+};
+use bao_tree::ChunkNum;
 use bytes::{Bytes, BytesMut};
 use futures::future::BoxFuture;
-use iroh_io::AsyncSliceReader;
+use futures::FutureExt;
+use iroh_io::{AsyncSliceReader, AsyncSliceWriter, File};
+use iroh_metrics::{inc, inc_by};
+use iroh_net::magic_endpoint::get_peer_id;
+use iroh_net::tls::PeerId;
+use range_collections::RangeSet2;
 use serde::{Deserialize, Serialize};
 use tokio::io::AsyncWrite;
-use tokio::sync::mpsc;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{Instant, MissedTickBehavior};
 use tracing::{debug, debug_span, warn};
 use tracing_futures::Instrument;
 
 use crate::collection::CollectionParser;
+use crate::metrics::Metrics;
 use crate::protocol::{
-    read_lp, write_lp, CustomGetRequest, GetRequest, RangeSpec, Request, RequestToken,
+    Closed, CustomGetRequest, FanOutRequest, FanOutResponse, FanOutStreamHeader, GetRequest,
+    PushRequest, PushResponse, RangeSpec, Request, RequestToken, FRAMING, MAX_FAN_OUT_STREAMS,
 };
 use crate::util::RpcError;
-use crate::Hash;
+use crate::{Hash, IROH_BLOCK_SIZE};
 
 /// An entry for one hash in a bao collection
 ///
@@ -89,6 +107,17 @@ pub enum Event {
         /// The hash for which the client wants to receive data.
         hash: Hash,
     },
+    /// A push request was received from a client.
+    PushRequestReceived {
+        /// An unique connection id.
+        connection_id: u64,
+        /// An identifier uniquely identifying this transfer request.
+        request_id: u64,
+        /// Token requester gve for this request, if any
+        token: Option<RequestToken>,
+        /// The hash of the blob the client wants to push.
+        hash: Hash,
+    },
     /// A request was received from a client.
     CustomGetRequestReceived {
         /// An unique connection id.
@@ -138,8 +167,24 @@ pub enum Event {
         /// An identifier uniquely identifying this request.
         request_id: u64,
     },
+    /// Progress of a transfer, sent periodically while data is being sent.
+    TransferProgress {
+        /// An unique connection id.
+        connection_id: u64,
+        /// An identifier uniquely identifying this transfer request.
+        request_id: u64,
+        /// The hash of the blob currently being sent.
+        hash: Hash,
+        /// The number of bytes sent for this request so far, including the bao encoding.
+        bytes_sent: u64,
+        /// The average rate at which bytes were sent since the request was received.
+        bytes_per_sec: f64,
+    },
 }
 
+/// How often [`Event::TransferProgress`] is sent during a transfer.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
 /// Progress updates for the provide operation
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ValidateProgress {
@@ -242,21 +287,164 @@ pub trait CustomGetHandler: Send + Sync + Debug + 'static {
     ) -> BoxFuture<'static, anyhow::Result<GetRequest>>;
 }
 
+/// Hook notified about the content transferred by a node.
+///
+/// Higher layers can use this to implement replication policies, e.g. counting the copies
+/// of content, caching popular content or keeping an audit log. Both methods do nothing by
+/// default.
+pub trait TransferHandler: Send + Sync + Debug + 'static {
+    /// Called when the provider finished sending the requested ranges of `hash` to `peer`.
+    ///
+    /// `bytes` is the number of bytes sent for the hash, including the bao encoding.
+    fn served(&self, _peer: PeerId, _hash: Hash, _bytes: u64) -> BoxFuture<'static, ()> {
+        futures::future::ready(()).boxed()
+    }
+
+    /// Called when a get client fetched `hash` from `peer` and added it to its database,
+    /// or when the provider stored `hash` pushed by `peer`.
+    ///
+    /// `bytes` is the size of the fetched blob.
+    fn fetched(&self, _peer: PeerId, _hash: Hash, _bytes: u64) -> BoxFuture<'static, ()> {
+        futures::future::ready(()).boxed()
+    }
+}
+
+/// Hook deciding whether to accept blobs pushed by peers with a [`PushRequest`].
+///
+/// The provider writes the pushed blob to the file returned by [`PushHandler::accept`],
+/// verifying it while it is received, and then hands it to [`PushHandler::stored`]. Push
+/// requests pass through the [`RequestAuthorizationHandler`] before reaching this handler.
+pub trait PushHandler: Send + Sync + Debug + 'static {
+    /// Decides whether to accept `hash` from `peer`, returning the path to write it to.
+    ///
+    /// Any error rejects the push. The file is created, or truncated if it exists, and is
+    /// removed again if receiving the blob fails.
+    fn accept(&self, peer: PeerId, hash: Hash) -> BoxFuture<'static, anyhow::Result<PathBuf>>;
+
+    /// Called when the blob was received and verified, to add the file at `path` to the
+    /// database.
+    ///
+    /// An error is reported to the peer as a failed push.
+    fn stored(
+        &self,
+        peer: PeerId,
+        hash: Hash,
+        path: PathBuf,
+    ) -> BoxFuture<'static, anyhow::Result<()>>;
+}
+
+/// A [`TransferHandler`] ignoring all transfers.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopTransferHandler;
+
+impl TransferHandler for NoopTransferHandler {}
+
+/// An authorization handler that does not do any authorization.
+///
+/// Requests with a token are rejected, since nothing could check the token.
+#[derive(Debug)]
+struct NoopRequestAuthorizationHandler;
+
+impl RequestAuthorizationHandler for NoopRequestAuthorizationHandler {
+    fn authorize(
+        &self,
+        token: Option<RequestToken>,
+        _request: &Request,
+    ) -> BoxFuture<'static, anyhow::Result<()>> {
+        async move {
+            if let Some(token) = token {
+                anyhow::bail!(
+                    "no authorization handler defined, but token was provided: {:?}",
+                    token
+                );
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+/// A custom get handler rejecting all custom get requests.
+#[derive(Debug)]
+struct NoopCustomGetHandler;
+
+impl CustomGetHandler for NoopCustomGetHandler {
+    fn handle(
+        &self,
+        _token: Option<RequestToken>,
+        _request: Bytes,
+    ) -> BoxFuture<'static, anyhow::Result<GetRequest>> {
+        async move { Err(anyhow::anyhow!("no custom get handler defined")) }.boxed()
+    }
+}
+
+/// A push handler rejecting all pushes.
+#[derive(Debug)]
+struct NoopPushHandler;
+
+impl PushHandler for NoopPushHandler {
+    fn accept(&self, _peer: PeerId, _hash: Hash) -> BoxFuture<'static, anyhow::Result<PathBuf>> {
+        async move { Err(anyhow::anyhow!("no push handler defined")) }.boxed()
+    }
+
+    fn stored(
+        &self,
+        _peer: PeerId,
+        _hash: Hash,
+        _path: PathBuf,
+    ) -> BoxFuture<'static, anyhow::Result<()>> {
+        async move { Err(anyhow::anyhow!("no push handler defined")) }.boxed()
+    }
+}
+
+/// The hooks into the request handling of a provider.
+///
+/// The default handlers allow requests without a token, and reject custom get requests
+/// and pushes.
+#[derive(Debug, Clone)]
+pub struct Handlers {
+    /// Turns custom get requests into get requests.
+    pub custom_get: Arc<dyn CustomGetHandler>,
+    /// Authorizes all requests.
+    pub authorization: Arc<dyn RequestAuthorizationHandler>,
+    /// Notified about the content transferred.
+    pub transfer: Arc<dyn TransferHandler>,
+    /// Accepts blobs pushed by peers.
+    pub push: Arc<dyn PushHandler>,
+}
+
+impl Default for Handlers {
+    fn default() -> Self {
+        Self {
+            custom_get: Arc::new(NoopCustomGetHandler),
+            authorization: Arc::new(NoopRequestAuthorizationHandler),
+            transfer: Arc::new(NoopTransferHandler),
+            push: Arc::new(NoopPushHandler),
+        }
+    }
+}
+
 /// Read the request from the getter.
 ///
 /// Will fail if there is an error while reading, if the reader
 /// contains more data than the Request, or if no valid request is sent.
+/// The data of a [`PushRequest`] follows the request, so it is left in the reader.
 ///
 /// When successful, the buffer is empty after this function call.
-pub async fn read_request(mut reader: quinn::RecvStream, buffer: &mut BytesMut) -> Result<Request> {
-    let payload = read_lp(&mut reader, buffer)
+pub async fn read_request(
+    reader: &mut quinn::RecvStream,
+    buffer: &mut BytesMut,
+) -> Result<Request> {
+    let request: Request = FRAMING
+        .read_message(&mut *reader, buffer)
         .await?
         .context("No request received")?;
-    let request: Request = postcard::from_bytes(&payload)?;
-    ensure!(
-        reader.read_chunk(8, false).await?.is_none(),
-        "Extra data past request"
-    );
+    if !matches!(request, Request::Push(_)) {
+        ensure!(
+            reader.read_chunk(8, false).await?.is_none(),
+            "Extra data past request"
+        );
+    }
     Ok(request)
 }
 
@@ -284,6 +472,7 @@ pub async fn transfer_collection<D: BaoMap, E: EventSender, C: CollectionParser>
     collection_parser: C,
 ) -> Result<SentStatus> {
     let hash = request.hash;
+    let progress = Progress::new(writer);
 
     // if the request is just for the root, we don't need to deserialize the collection
     let just_root = matches!(request.ranges.single(), Some((0, _)));
@@ -309,13 +498,16 @@ pub async fn transfer_collection<D: BaoMap, E: EventSender, C: CollectionParser>
         if offset == 0 {
             debug!("writing ranges '{:?}' of collection {}", ranges, hash);
             // send the root
-            encode_ranges_validated(
-                &mut data,
-                &mut outboard,
-                &ranges.to_chunk_ranges(),
-                &mut writer.inner,
-            )
-            .await?;
+            let chunk_ranges = ranges.to_chunk_ranges();
+            let start = progress.bytes_sent();
+            let mut counting = progress.writer(&mut writer.inner);
+            let send =
+                encode_ranges_validated(&mut data, &mut outboard, &chunk_ranges, &mut counting);
+            progress.track(hash, send).await?;
+            inc!(Metrics, blobs_sent);
+            writer
+                .notify_served(hash, progress.bytes_sent() - start)
+                .await;
             debug!(
                 "finished writing ranges '{:?}' of collection {}",
                 ranges, hash
@@ -329,11 +521,17 @@ pub async fn transfer_collection<D: BaoMap, E: EventSender, C: CollectionParser>
             }
             if let Some(hash) = c.next().await? {
                 tokio::task::yield_now().await;
-                let (status, size) = send_blob(db, hash, ranges, &mut writer.inner).await?;
+                let start = progress.bytes_sent();
+                let mut counting = progress.writer(&mut writer.inner);
+                let send = send_blob(db, hash, ranges, &mut counting);
+                let (status, size) = progress.track(hash, send).await?;
                 if SentStatus::NotFound == status {
                     writer.inner.finish().await?;
                     return Ok(status);
                 }
+                writer
+                    .notify_served(hash, progress.bytes_sent() - start)
+                    .await;
 
                 writer
                     .events
@@ -364,25 +562,149 @@ pub trait EventSender: Clone + Sync + Send + 'static {
     fn send(&self, event: Event) -> BoxFuture<()>;
 }
 
-/// Handle a single connection.
+/// An [`EventSender`] broadcasting the events to any number of subscribers.
+///
+/// Events are dropped if there are no subscribers, and subscribers which do not keep up
+/// miss events.
+#[derive(Debug, Clone)]
+pub struct BroadcastEventSender(broadcast::Sender<Event>);
+
+impl BroadcastEventSender {
+    /// Creates a sender buffering up to `capacity` events for each subscriber.
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self(sender)
+    }
+
+    /// Subscribes to the events sent from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.0.subscribe()
+    }
+}
+
+impl EventSender for BroadcastEventSender {
+    fn send(&self, event: Event) -> BoxFuture<()> {
+        self.0.send(event).ok();
+        futures::future::ready(()).boxed()
+    }
+}
+
+/// Tracks the bytes sent for a request, to report [`Event::TransferProgress`].
+#[derive(Debug)]
+struct Progress<E> {
+    events: E,
+    connection_id: u64,
+    request_id: u64,
+    start: Instant,
+    bytes_sent: AtomicU64,
+}
+
+impl<E: EventSender> Progress<E> {
+    fn new(writer: &ResponseWriter<E>) -> Self {
+        Self {
+            events: writer.events.clone(),
+            connection_id: writer.connection_id(),
+            request_id: writer.request_id(),
+            start: Instant::now(),
+            bytes_sent: AtomicU64::new(0),
+        }
+    }
+
+    /// The number of bytes sent for the request so far.
+    fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// Wraps `inner` to count the bytes written to it.
+    fn writer<W>(&self, inner: W) -> CountingWriter<'_, W> {
+        CountingWriter {
+            inner,
+            bytes_sent: &self.bytes_sent,
+        }
+    }
+
+    /// Runs `send`, reporting progress on sending `hash` every [`PROGRESS_INTERVAL`].
+    async fn track<F: Future>(&self, hash: Hash, send: F) -> F::Output {
+        tokio::pin!(send);
+        let mut interval = tokio::time::interval(PROGRESS_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // The first tick completes immediately.
+        interval.tick().await;
+        loop {
+            tokio::select! {
+                res = &mut send => return res,
+                _ = interval.tick() => self.report(hash).await,
+            }
+        }
+    }
+
+    async fn report(&self, hash: Hash) {
+        let bytes_sent = self.bytes_sent();
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let bytes_per_sec = if elapsed > 0.0 {
+            bytes_sent as f64 / elapsed
+        } else {
+            0.0
+        };
+        self.events
+            .send(Event::TransferProgress {
+                connection_id: self.connection_id,
+                request_id: self.request_id,
+                hash,
+                bytes_sent,
+                bytes_per_sec,
+            })
+            .await;
+    }
+}
+
+/// A writer counting the bytes written to it.
+#[derive(Debug)]
+struct CountingWriter<'a, W> {
+    inner: W,
+    bytes_sent: &'a AtomicU64,
+}
+
+impl<'a, W: AsyncWrite + Unpin> AsyncWrite for CountingWriter<'a, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            self.bytes_sent.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Handle a single connection, once the handshake completed.
 pub async fn handle_connection<D: BaoMap, E: EventSender, C: CollectionParser>(
-    connecting: quinn::Connecting,
+    connection: quinn::Connection,
     db: D,
     events: E,
     collection_parser: C,
-    custom_get_handler: Arc<dyn CustomGetHandler>,
-    authorization_handler: Arc<dyn RequestAuthorizationHandler>,
+    handlers: Handlers,
     rt: crate::util::runtime::Handle,
 ) {
-    let remote_addr = connecting.remote_address();
-    let connection = match connecting.await {
-        Ok(conn) => conn,
+    let remote_addr = connection.remote_address();
+    let connection_id = connection.stable_id() as u64;
+    let peer = match get_peer_id(&connection).await {
+        Ok(peer) => peer,
         Err(err) => {
-            warn!(%remote_addr, "Error connecting: {err:#}");
+            warn!(%remote_addr, "Unable to get peer id: {err:#}");
             return;
         }
     };
-    let connection_id = connection.stable_id() as u64;
     let span = debug_span!("connection", connection_id, %remote_addr);
     async move {
         while let Ok((writer, reader)) = connection.accept_bi().await {
@@ -392,25 +714,21 @@ pub async fn handle_connection<D: BaoMap, E: EventSender, C: CollectionParser>(
             let span = debug_span!("stream", stream_id = %request_id);
             let writer = ResponseWriter {
                 connection_id,
+                peer,
                 events: events.clone(),
+                transfer_handler: handlers.transfer.clone(),
                 inner: writer,
             };
             events.send(Event::ClientConnected { connection_id }).await;
             let db = db.clone();
-            let custom_get_handler = custom_get_handler.clone();
-            let authorization_handler = authorization_handler.clone();
+            let handlers = handlers.clone();
             let collection_parser = collection_parser.clone();
+            let connection = connection.clone();
             rt.local_pool().spawn_pinned(|| {
                 async move {
-                    if let Err(err) = handle_stream(
-                        db,
-                        reader,
-                        writer,
-                        custom_get_handler,
-                        authorization_handler,
-                        collection_parser,
-                    )
-                    .await
+                    if let Err(err) =
+                        handle_stream(db, connection, reader, writer, handlers, collection_parser)
+                            .await
                     {
                         warn!("error: {err:#?}",);
                     }
@@ -418,6 +736,7 @@ pub async fn handle_connection<D: BaoMap, E: EventSender, C: CollectionParser>(
                 .instrument(span)
             });
         }
+        inc_by!(Metrics, bytes_sent, connection.stats().udp_tx.bytes);
     }
     .instrument(span)
     .await
@@ -425,17 +744,17 @@ pub async fn handle_connection<D: BaoMap, E: EventSender, C: CollectionParser>(
 
 async fn handle_stream<D: BaoMap, E: EventSender, C: CollectionParser>(
     db: D,
-    reader: quinn::RecvStream,
-    writer: ResponseWriter<E>,
-    custom_get_handler: Arc<dyn CustomGetHandler>,
-    authorization_handler: Arc<dyn RequestAuthorizationHandler>,
+    connection: quinn::Connection,
+    mut reader: quinn::RecvStream,
+    mut writer: ResponseWriter<E>,
+    handlers: Handlers,
     collection_parser: C,
 ) -> Result<()> {
     let mut in_buffer = BytesMut::with_capacity(1024);
 
     // 1. Decode the request.
     debug!("reading request");
-    let request = match read_request(reader, &mut in_buffer).await {
+    let request = match read_request(&mut reader, &mut in_buffer).await {
         Ok(r) => r,
         Err(e) => {
             writer.notify_transfer_aborted().await;
@@ -445,7 +764,8 @@ async fn handle_stream<D: BaoMap, E: EventSender, C: CollectionParser>(
 
     // 2. Authorize the request (may be a no-op)
     debug!("authorizing request");
-    if let Err(e) = authorization_handler
+    if let Err(e) = handlers
+        .authorization
         .authorize(request.token().cloned(), &request)
         .await
     {
@@ -453,17 +773,43 @@ async fn handle_stream<D: BaoMap, E: EventSender, C: CollectionParser>(
         return Err(e);
     }
 
-    match request {
-        Request::Get(request) => handle_get(db, request, collection_parser, writer).await,
-        Request::CustomGet(request) => {
-            handle_custom_get(db, request, writer, custom_get_handler, collection_parser).await
+    let deadline = request.deadline();
+    let handle = async {
+        match request {
+            Request::Get(request) => handle_get(db, request, collection_parser, &mut writer).await,
+            Request::CustomGet(request) => {
+                handle_custom_get(
+                    db,
+                    request,
+                    &mut writer,
+                    handlers.custom_get,
+                    collection_parser,
+                )
+                .await
+            }
+            Request::FanOut(request) => handle_fan_out(db, request, connection, &mut writer).await,
+            Request::Push(request) => {
+                handle_push(db, request, reader, handlers.push, &mut writer).await
+            }
+        }
+    };
+    let Some(deadline) = deadline else {
+        return handle.await;
+    };
+    match tokio::time::timeout(deadline, handle).await {
+        Ok(res) => res,
+        Err(_) => {
+            debug!("deadline of {deadline:?} exceeded");
+            writer.inner.reset(Closed::DeadlineExceeded.into()).ok();
+            writer.notify_transfer_aborted().await;
+            Ok(())
         }
     }
 }
 async fn handle_custom_get<E: EventSender, D: BaoMap, C: CollectionParser>(
     db: D,
     request: CustomGetRequest,
-    mut writer: ResponseWriter<E>,
+    writer: &mut ResponseWriter<E>,
     custom_get_handler: Arc<dyn CustomGetHandler>,
     collection_parser: C,
 ) -> Result<()> {
@@ -481,8 +827,7 @@ async fn handle_custom_get<E: EventSender, D: BaoMap, C: CollectionParser>(
         .handle(request.token, request.data)
         .await?;
     // write it to the requester as the first thing
-    let data = postcard::to_stdvec(&request)?;
-    write_lp(&mut writer.inner, &data).await?;
+    FRAMING.write_message(&mut writer.inner, &request).await?;
     // from now on just handle it like a normal get request
     handle_get(db, request, collection_parser, writer).await
 }
@@ -492,7 +837,7 @@ pub async fn handle_get<D: BaoMap, E: EventSender, C: CollectionParser>(
     db: D,
     request: GetRequest,
     collection_parser: C,
-    mut writer: ResponseWriter<E>,
+    writer: &mut ResponseWriter<E>,
 ) -> Result<()> {
     let hash = request.hash;
     debug!(%hash, "received request");
@@ -505,6 +850,7 @@ pub async fn handle_get<D: BaoMap, E: EventSender, C: CollectionParser>(
             token: request.token().cloned(),
         })
         .await;
+    inc!(Metrics, transfers_started);
 
     // 4. Attempt to find hash
     match db.get(&hash) {
@@ -514,7 +860,7 @@ pub async fn handle_get<D: BaoMap, E: EventSender, C: CollectionParser>(
             match transfer_collection(
                 request,
                 &db,
-                &mut writer,
+                writer,
                 entry.outboard().await?,
                 entry.data_reader().await?,
                 collection_parser,
@@ -545,12 +891,230 @@ pub async fn handle_get<D: BaoMap, E: EventSender, C: CollectionParser>(
     Ok(())
 }
 
+/// Handle a single fan-out request.
+///
+/// The blob is split into chunk group aligned ranges, each of which is sent as a
+/// separate bao response on its own unidirectional stream. The request stream is
+/// finished once all unidirectional streams have been finished, or reset with
+/// [`Closed::RequestFailed`] if sending any of them failed.
+pub async fn handle_fan_out<D: BaoMap, E: EventSender>(
+    db: D,
+    request: FanOutRequest,
+    connection: quinn::Connection,
+    writer: &mut ResponseWriter<E>,
+) -> Result<()> {
+    let hash = request.hash;
+    debug!(%hash, streams = request.streams, "received fan-out request");
+    writer
+        .events
+        .send(Event::GetRequestReceived {
+            hash,
+            connection_id: writer.connection_id(),
+            request_id: writer.request_id(),
+            token: request.token().cloned(),
+        })
+        .await;
+    inc!(Metrics, transfers_started);
+
+    let Some(entry) = db.get(&hash) else {
+        debug!("not found {}", hash);
+        writer.notify_transfer_aborted().await;
+        writer.inner.finish().await?;
+        return Ok(());
+    };
+    let res = async {
+        let size = entry.outboard().await?.tree().size();
+        let parts = fan_out_ranges(size.chunks(), request.streams);
+        let response = FanOutResponse {
+            streams: parts.len() as u16,
+        };
+        FRAMING.write_message(&mut writer.inner, &response).await?;
+        let request_id = writer.request_id();
+        let bytes_sent = AtomicU64::new(0);
+        futures::future::try_join_all(parts.into_iter().map(|ranges| {
+            send_fan_out_part::<D>(&connection, &entry, request_id, ranges, &bytes_sent)
+        }))
+        .await?;
+        writer.inner.finish().await?;
+        anyhow::Ok(bytes_sent.into_inner())
+    }
+    .await;
+    match res {
+        Ok(bytes_sent) => {
+            writer.notify_served(hash, bytes_sent).await;
+            writer.notify_transfer_completed().await;
+            debug!("finished fan-out response");
+            Ok(())
+        }
+        Err(e) => {
+            // parts that were never opened would leave the requester waiting, so tell it
+            // on the request stream that nothing more is coming
+            writer.inner.reset(Closed::RequestFailed.into()).ok();
+            writer.notify_transfer_aborted().await;
+            Err(e)
+        }
+    }
+}
+
+/// Handle a single push request.
+///
+/// Blobs that are already in the database are not received again. Otherwise the blob is
+/// written to the file given by the [`PushHandler`] while verifying it, and the request
+/// stream is finished once the handler stored it.
+pub async fn handle_push<D: BaoMap, E: EventSender>(
+    db: D,
+    request: PushRequest,
+    reader: quinn::RecvStream,
+    push_handler: Arc<dyn PushHandler>,
+    writer: &mut ResponseWriter<E>,
+) -> Result<()> {
+    let hash = request.hash;
+    debug!(%hash, "received push request");
+    writer
+        .events
+        .send(Event::PushRequestReceived {
+            hash,
+            connection_id: writer.connection_id(),
+            request_id: writer.request_id(),
+            token: request.token().cloned(),
+        })
+        .await;
+    inc!(Metrics, transfers_started);
+
+    if db.get(&hash).is_some() {
+        debug!(%hash, "pushed blob is already present");
+        FRAMING
+            .write_message(&mut writer.inner, &PushResponse::Present)
+            .await?;
+        writer.inner.finish().await?;
+        writer.notify_transfer_completed().await;
+        return Ok(());
+    }
+    let path = match push_handler.accept(writer.peer, hash).await {
+        Ok(path) => path,
+        Err(e) => {
+            writer.notify_transfer_aborted().await;
+            return Err(e.context("push rejected"));
+        }
+    };
+    let res = async {
+        FRAMING
+            .write_message(&mut writer.inner, &PushResponse::Send)
+            .await?;
+        let size = receive_blob(hash, reader, &path).await?;
+        push_handler.stored(writer.peer, hash, path.clone()).await?;
+        // notify before finishing, the pusher considers the push done once it is finished
+        writer
+            .transfer_handler
+            .fetched(writer.peer, hash, size)
+            .await;
+        writer.inner.finish().await?;
+        anyhow::Ok(size)
+    }
+    .await;
+    match res {
+        Ok(size) => {
+            writer.notify_transfer_completed().await;
+            debug!(%hash, size, "stored pushed blob");
+            Ok(())
+        }
+        Err(e) => {
+            tokio::fs::remove_file(&path).await.ok();
+            writer.inner.reset(Closed::RequestFailed.into()).ok();
+            writer.notify_transfer_aborted().await;
+            Err(e)
+        }
+    }
+}
+
+/// Receive the bao encoding of the blob `hash` from `reader`, writing the data to `path`.
+///
+/// Returns the size of the blob.
+async fn receive_blob(hash: Hash, reader: quinn::RecvStream, path: &Path) -> Result<u64> {
+    let path2 = path.to_path_buf();
+    let mut file = File::create(move || {
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path2)
+    })
+    .await
+    .with_context(|| format!("failed to create {}", path.display()))?;
+    let start = ResponseDecoderStart::new(hash.into(), RangeSet2::all(), IROH_BLOCK_SIZE, reader);
+    let (mut reading, size) = start.next().await?;
+    let mut reader = loop {
+        let item = match reading.next().await {
+            ResponseDecoderReadingNext::Done(reader) => break reader,
+            ResponseDecoderReadingNext::More((next, item)) => {
+                reading = next;
+                item?
+            }
+        };
+        if let BaoContentItem::Leaf(leaf) = item {
+            file.write_bytes_at(leaf.offset.0, leaf.data).await?;
+        }
+    };
+    file.sync().await?;
+    ensure!(
+        reader.read_chunk(8, false).await?.is_none(),
+        "Extra data past pushed blob"
+    );
+    Ok(size)
+}
+
+/// Send one part of a fan-out response on a new unidirectional stream.
+async fn send_fan_out_part<D: BaoMap>(
+    connection: &quinn::Connection,
+    entry: &D::Entry,
+    request_id: u64,
+    ranges: RangeSet2<ChunkNum>,
+    bytes_sent: &AtomicU64,
+) -> Result<()> {
+    let mut stream = connection.open_uni().await?;
+    let header = FanOutStreamHeader {
+        request_id,
+        ranges: RangeSpec::new(&ranges),
+    };
+    FRAMING.write_message(&mut stream, &header).await?;
+    let outboard = entry.outboard().await?;
+    let data = entry.data_reader().await?;
+    let mut counting = CountingWriter {
+        inner: &mut stream,
+        bytes_sent,
+    };
+    encode_ranges_validated(data, outboard, &ranges, &mut counting).await?;
+    stream.finish().await?;
+    Ok(())
+}
+
+/// Split a blob of `chunks` chunks into at most `streams` chunk group aligned ranges.
+///
+/// The last range is open ended, so the size of the blob is always validated by one
+/// of the parts. There is always at least one range, even for an empty blob.
+fn fan_out_ranges(chunks: ChunkNum, streams: u16) -> Vec<RangeSet2<ChunkNum>> {
+    let group = 1u64 << IROH_BLOCK_SIZE.0;
+    let groups = ((chunks.0 + group - 1) / group).max(1);
+    let streams = u64::from(streams.clamp(1, MAX_FAN_OUT_STREAMS)).min(groups);
+    let per_part = (groups + streams - 1) / streams * group;
+    let mut res = Vec::with_capacity(streams as usize);
+    let mut start = 0;
+    while start + per_part < chunks.0 {
+        res.push(RangeSet2::from(ChunkNum(start)..ChunkNum(start + per_part)));
+        start += per_part;
+    }
+    res.push(RangeSet2::from(ChunkNum(start)..));
+    res
+}
+
 /// A helper struct that combines a quinn::SendStream with auxiliary information
 #[derive(Debug)]
 pub struct ResponseWriter<E> {
     inner: quinn::SendStream,
     events: E,
+    transfer_handler: Arc<dyn TransferHandler>,
     connection_id: u64,
+    peer: PeerId,
 }
 
 impl<E: EventSender> ResponseWriter<E> {
@@ -563,6 +1127,7 @@ impl<E: EventSender> ResponseWriter<E> {
     }
 
     async fn notify_transfer_completed(&self) {
+        inc!(Metrics, transfers_completed);
         self.events
             .send(Event::TransferCollectionCompleted {
                 connection_id: self.connection_id(),
@@ -571,7 +1136,12 @@ impl<E: EventSender> ResponseWriter<E> {
             .await;
     }
 
+    async fn notify_served(&self, hash: Hash, bytes: u64) {
+        self.transfer_handler.served(self.peer, hash, bytes).await;
+    }
+
     async fn notify_transfer_aborted(&self) {
+        inc!(Metrics, transfers_aborted);
         self.events
             .send(Event::TransferAborted {
                 connection_id: self.connection_id(),
@@ -591,7 +1161,7 @@ pub enum SentStatus {
 }
 
 /// Send a
-pub async fn send_blob<D: BaoMap, W: AsyncWrite + Unpin + Send + 'static>(
+pub async fn send_blob<D: BaoMap, W: AsyncWrite + Unpin + Send>(
     db: &D,
     name: Hash,
     ranges: &RangeSpec,
@@ -611,6 +1181,7 @@ pub async fn send_blob<D: BaoMap, W: AsyncWrite + Unpin + Send + 'static>(
             .await;
             debug!("done sending blob {} {:?}", name, res);
             res?;
+            inc!(Metrics, blobs_sent);
 
             Ok((SentStatus::Sent, size))
         }
@@ -620,3 +1191,49 @@ pub async fn send_blob<D: BaoMap, W: AsyncWrite + Unpin + Send + 'static>(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_transfer_progress() {
+        let events = BroadcastEventSender::new(16);
+        let mut subscriber = events.subscribe();
+        let progress = Progress {
+            events,
+            connection_id: 1,
+            request_id: 2,
+            start: Instant::now(),
+            bytes_sent: AtomicU64::new(0),
+        };
+        let hash = Hash::new(b"progress");
+        let send = async {
+            let mut writer = progress.writer(tokio::io::sink());
+            writer.write_all(&[0u8; 1000]).await?;
+            tokio::time::sleep(PROGRESS_INTERVAL * 2 + PROGRESS_INTERVAL / 2).await;
+            io::Result::Ok(())
+        };
+        progress.track(hash, send).await.unwrap();
+
+        for i in 1..=2 {
+            match subscriber.try_recv().unwrap() {
+                Event::TransferProgress {
+                    connection_id: 1,
+                    request_id: 2,
+                    hash: h,
+                    bytes_sent: 1000,
+                    bytes_per_sec,
+                } => {
+                    assert_eq!(h, hash);
+                    let elapsed = (PROGRESS_INTERVAL * i).as_secs_f64();
+                    assert!((bytes_per_sec - 1000.0 / elapsed).abs() < 1.0);
+                }
+                event => panic!("unexpected event {event:?}"),
+            }
+        }
+        assert!(subscriber.try_recv().is_err());
+    }
+}
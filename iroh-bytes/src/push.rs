@@ -0,0 +1,68 @@
+//! The client side of pushing a blob to a peer.
+//!
+//! Instead of waiting for a peer to request content, a node that has a blob can send it
+//! to the peer with [`push`], e.g. to replicate content to a backup node or a mirror. The
+//! peer decides whether to accept the push, see
+//! [`PushHandler`](crate::provider::PushHandler).
+use anyhow::{anyhow, Context, Result};
+use bao_tree::io::fsm::{encode_ranges_validated, Outboard};
+use bytes::BytesMut;
+use range_collections::RangeSet2;
+use tracing::debug;
+
+use crate::protocol::{PushRequest, PushResponse, Request, FRAMING};
+use crate::provider::{BaoMap, BaoMapEntry};
+
+/// The result of a successful [`push`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushOutcome {
+    /// The blob was sent to the peer and stored by it.
+    Sent {
+        /// The size of the blob.
+        size: u64,
+    },
+    /// The peer already had the blob, nothing was sent.
+    Present,
+}
+
+/// Push the blob of `request` from `db` to the peer on the other side of `connection`.
+///
+/// Fails if the blob is not in `db`, if the peer rejects the push, or if the peer failed
+/// to store the blob.
+pub async fn push<D: BaoMap>(
+    connection: &quinn::Connection,
+    db: &D,
+    request: PushRequest,
+) -> Result<PushOutcome> {
+    let hash = request.hash;
+    let entry = db
+        .get(&hash)
+        .with_context(|| format!("blob {hash} not found"))?;
+    let outboard = entry.outboard().await?;
+    let size = outboard.tree().size().0;
+    let data = entry.data_reader().await?;
+    let (mut writer, mut reader) = connection.open_bi().await?;
+    debug!(%hash, "sending push request");
+    FRAMING
+        .write_message(&mut writer, &Request::Push(request))
+        .await?;
+    let response: PushResponse = FRAMING
+        .read_message(&mut reader, &mut BytesMut::new())
+        .await?
+        .context("push rejected by peer")?;
+    if response == PushResponse::Present {
+        writer.finish().await?;
+        return Ok(PushOutcome::Present);
+    }
+    encode_ranges_validated(data, outboard, &RangeSet2::all(), &mut writer)
+        .await
+        .with_context(|| format!("blob {hash} failed to validate"))?;
+    writer.finish().await?;
+    // the peer finishes the stream once it stored the blob
+    reader
+        .read_to_end(0)
+        .await
+        .map_err(|err| anyhow!("peer failed to store blob {hash}: {err}"))?;
+    debug!(%hash, size, "pushed blob");
+    Ok(PushOutcome::Sent { size })
+}
@@ -7,6 +7,8 @@ type Registry = ();
 
 static CORE: OnceCell<Core> = OnceCell::new();
 
+static RECORDER: OnceCell<Box<dyn Recorder>> = OnceCell::new();
+
 /// Core is the base metrics struct.
 /// It manages the mapping between the metrics name and the actual metrics.
 /// It also carries a single prometheus registry to be used by all metrics.
@@ -55,7 +57,10 @@ impl Counter {
             self.counter.inc_by(v)
         }
         #[cfg(not(feature = "metrics"))]
-        0
+        {
+            let _ = v;
+            0
+        }
     }
 
     /// Get the current value of the [`Counter`].
@@ -105,7 +110,7 @@ pub trait Metric:
 
     /// Access to this metrics group to record a metric.
     #[cfg(not(feature = "metrics"))]
-    fn with_metric<T, F: FnOnce(&Self) -> T>(f: F) {
+    fn with_metric<T, F: FnOnce(&Self) -> T>(_f: F) {
         // nothing to do
     }
 
@@ -172,3 +177,35 @@ pub trait HistogramType {
     /// Returns the name of the metric
     fn name(&self) -> &'static str;
 }
+
+/// Receives all metric updates, to bridge the metrics to another telemetry system.
+///
+/// Install a recorder with [`set_recorder`]. Unlike the prometheus registry of [`Core`] it
+/// is also called without the `metrics` feature.
+pub trait Recorder: std::fmt::Debug + Send + Sync + 'static {
+    /// Called when the counter `metric` of the metric group `group` is increased by `value`.
+    fn increment_counter(&self, group: &'static str, metric: &'static str, value: u64);
+}
+
+/// Installs the global [`Recorder`].
+///
+/// Returns an error if a recorder was already installed.
+pub fn set_recorder(recorder: impl Recorder) -> std::io::Result<()> {
+    RECORDER
+        .set(Box::new(recorder))
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "already set"))
+}
+
+/// Returns the global [`Recorder`], if one is installed.
+pub fn recorder() -> Option<&'static dyn Recorder> {
+    RECORDER.get().map(|r| r.as_ref())
+}
+
+/// Reports an increment of a counter of the metric group `M` to the global [`Recorder`].
+///
+/// Used by the [`crate::inc`] and [`crate::inc_by`] macros.
+pub fn record_counter<M: Metric>(metric: &'static str, value: u64) {
+    if let Some(recorder) = recorder() {
+        recorder.increment_counter(M::name(), metric, value);
+    }
+}
@@ -16,15 +16,18 @@ pub use struct_iterable;
 /// Increment the given counter by 1.
 #[macro_export]
 macro_rules! inc {
-    ($m:ty, $f:ident) => {
+    ($m:ty, $f:ident) => {{
         <$m as $crate::core::Metric>::with_metric(|m| m.$f.inc());
-    };
+        $crate::core::record_counter::<$m>(stringify!($f), 1);
+    }};
 }
 
 /// Increment the given counter `n`.
 #[macro_export]
 macro_rules! inc_by {
-    ($m:ty, $f:ident, $n:expr) => {
-        <$m as $crate::core::Metric>::with_metric(|m| m.$f.inc_by($n));
-    };
+    ($m:ty, $f:ident, $n:expr) => {{
+        let n = $n;
+        <$m as $crate::core::Metric>::with_metric(|m| m.$f.inc_by(n));
+        $crate::core::record_counter::<$m>(stringify!($f), n);
+    }};
 }
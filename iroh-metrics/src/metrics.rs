@@ -11,6 +11,9 @@
 //!
 //! To expose the metrics, start the metrics service with `start_metrics_server()`.
 //!
+//! To bridge the metrics to another telemetry system, install a
+//! [`Recorder`](crate::core::Recorder) with [`set_recorder`](crate::core::set_recorder).
+//!
 //! # Example:
 //! ```rust
 //! use iroh_metrics::{inc, inc_by};
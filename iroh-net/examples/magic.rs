@@ -1,11 +1,11 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, time::Duration};
 
 use clap::Parser;
 use ed25519_dalek::SigningKey as SecretKey;
+use futures::StreamExt;
 use iroh_net::{
     defaults::{default_derp_map, TEST_REGION_ID},
     derp::DerpMap,
-    magic_endpoint::accept_conn,
     tls::{Keypair, PeerId},
     MagicEndpoint,
 };
@@ -61,6 +61,7 @@ async fn main() -> anyhow::Result<()> {
         .keypair(keypair)
         .alpns(vec![args.alpn.to_string().into_bytes()])
         .derp_map(Some(derp_map))
+        .close_grace_period(Duration::from_secs(3))
         .bind(args.bind_port)
         .await?;
 
@@ -71,8 +72,21 @@ async fn main() -> anyhow::Result<()> {
 
     match args.command {
         Command::Listen => {
-            while let Some(conn) = endpoint.accept().await {
-                let (peer_id, alpn, conn) = accept_conn(conn).await?;
+            let mut events = endpoint.subscribe();
+            tokio::spawn(async move {
+                while let Some(event) = events.next().await {
+                    info!("connection event: {event:?}");
+                }
+            });
+            loop {
+                let conn = tokio::select! {
+                    conn = endpoint.accept() => match conn {
+                        Some(conn) => conn,
+                        None => break,
+                    },
+                    _ = tokio::signal::ctrl_c() => break,
+                };
+                let (peer_id, alpn, conn) = endpoint.accept_conn(conn).await?;
                 info!(
                     "new connection from {peer_id} with ALPN {alpn} (coming from {})",
                     conn.remote_address()
@@ -114,6 +128,7 @@ async fn main() -> anyhow::Result<()> {
             println!("received: {message}");
         }
     }
+    endpoint.close(0u32.into(), b"bye").await?;
     Ok(())
 }
 
@@ -0,0 +1,502 @@
+//! Certificates from LetsEncrypt using the ACME DNS-01 challenge.
+//!
+//! The TLS-ALPN-01 challenge used by [`CertMode::LetsEncrypt`](super::CertMode) requires
+//! the ACME server to reach the derper on port 443 directly. DNS-01 instead proves control
+//! of the domain with a TXT record, so it works behind load balancers and on hosts where
+//! port 443 is not reachable. It is also the only challenge that can issue wildcard
+//! certificates, e.g. for all derpers of a mesh.
+//!
+//! Setting the TXT records is delegated to a [`DnsProvider`].
+
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context as _, Result};
+use data_encoding::BASE64URL_NOPAD;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use ring::signature::{EcdsaKeyPair, KeyPair};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use serde::{Deserialize, Serialize};
+use tokio_rustls_acme::acme::{
+    Account, AuthStatus, ChallengeType, Directory, Identifier, OrderStatus,
+    LETS_ENCRYPT_PRODUCTION_DIRECTORY, LETS_ENCRYPT_STAGING_DIRECTORY,
+};
+use tracing::{debug, info, warn};
+
+use super::{escape_hostname, load_certs, load_private_key};
+
+/// File name inside the cert dir where the ACME account key is stored.
+const ACCOUNT_KEY_FILE: &str = "acme-dns01-account.pk8";
+/// Renew certificates that expire within this time.
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// How often to check whether the certificate needs to be renewed.
+const RENEW_CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+/// How often to poll the ACME server while it validates challenges or issues the certificate.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// How many times to poll the ACME server before giving up.
+const POLL_ATTEMPTS: usize = 60;
+
+/// Configuration for the DNS-01 challenge.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DnsChallengeConfig {
+    /// Command that sets and removes TXT records, see [`HookDnsProvider`].
+    pub hook: PathBuf,
+    /// Additional names for the certificate besides the hostname, e.g. `*.derp.example.com`.
+    #[serde(default)]
+    pub extra_domains: Vec<String>,
+    /// Seconds to wait after setting the TXT records before asking the ACME server to check
+    /// them, to give the records time to propagate. Defaults to 60.
+    #[serde(default = "default_propagation_delay")]
+    pub propagation_delay_secs: u64,
+}
+
+fn default_propagation_delay() -> u64 {
+    60
+}
+
+/// Sets and removes the TXT records for DNS-01 challenges.
+///
+/// Implement this to support a DNS provider's API.
+pub trait DnsProvider: Send + Sync + 'static {
+    /// Adds a TXT record with `value` for the fully qualified `name`.
+    ///
+    /// There can be multiple records for the same name, e.g. for a wildcard and a regular
+    /// certificate, so this must not replace existing records.
+    fn set_txt_record<'a>(&'a self, name: &'a str, value: &'a str) -> BoxFuture<'a, Result<()>>;
+
+    /// Removes the TXT record with `value` for `name` again.
+    fn remove_txt_record<'a>(&'a self, name: &'a str, value: &'a str) -> BoxFuture<'a, Result<()>>;
+}
+
+/// A [`DnsProvider`] that runs a command to change the records.
+///
+/// The command is run as `<command> set <name> <value>` and `<command> remove <name> <value>`
+/// and must exit successfully.
+#[derive(Debug, Clone)]
+pub struct HookDnsProvider {
+    command: PathBuf,
+}
+
+impl HookDnsProvider {
+    /// Creates a provider running `command`.
+    pub fn new(command: PathBuf) -> Self {
+        Self { command }
+    }
+
+    async fn run(&self, action: &str, name: &str, value: &str) -> Result<()> {
+        let status = tokio::process::Command::new(&self.command)
+            .args([action, name, value])
+            .stdin(Stdio::null())
+            .status()
+            .await
+            .with_context(|| format!("failed to run {}", self.command.display()))?;
+        if !status.success() {
+            bail!(
+                "{} {action} {name} failed: {status}",
+                self.command.display()
+            );
+        }
+        Ok(())
+    }
+}
+
+impl DnsProvider for HookDnsProvider {
+    fn set_txt_record<'a>(&'a self, name: &'a str, value: &'a str) -> BoxFuture<'a, Result<()>> {
+        self.run("set", name, value).boxed()
+    }
+
+    fn remove_txt_record<'a>(&'a self, name: &'a str, value: &'a str) -> BoxFuture<'a, Result<()>> {
+        self.run("remove", name, value).boxed()
+    }
+}
+
+/// Issues and renews a certificate using DNS-01 challenges.
+pub struct Dns01 {
+    /// Names for the certificate, the first one is the hostname.
+    domains: Vec<String>,
+    contact: String,
+    directory_url: &'static str,
+    dir: PathBuf,
+    provider: Arc<dyn DnsProvider>,
+    propagation_delay: Duration,
+    client_config: Arc<rustls::ClientConfig>,
+}
+
+impl Dns01 {
+    /// Creates a new issuer for `hostname` and `config.extra_domains`.
+    ///
+    /// The certificate and key are stored in `dir`, with the same names as for
+    /// [`CertMode::Manual`](super::CertMode).
+    pub fn new(
+        hostname: String,
+        contact: String,
+        is_production: bool,
+        dir: PathBuf,
+        config: DnsChallengeConfig,
+        provider: Arc<dyn DnsProvider>,
+    ) -> Self {
+        let mut domains = vec![hostname];
+        domains.extend(config.extra_domains);
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+            rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        }));
+        let client_config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Self {
+            domains,
+            contact,
+            directory_url: if is_production {
+                LETS_ENCRYPT_PRODUCTION_DIRECTORY
+            } else {
+                LETS_ENCRYPT_STAGING_DIRECTORY
+            },
+            dir,
+            provider,
+            propagation_delay: Duration::from_secs(config.propagation_delay_secs),
+            client_config: Arc::new(client_config),
+        }
+    }
+
+    /// Returns a server config using the certificate, and keeps it renewed.
+    ///
+    /// A stored certificate is used if it does not need renewing yet, otherwise a new one
+    /// is issued before returning.
+    pub async fn server_config(self) -> Result<Arc<rustls::ServerConfig>> {
+        let key = match self.load().await {
+            Ok(key) if !needs_renewal(&key, SystemTime::now()) => key,
+            res => {
+                if let Err(err) = res {
+                    debug!("no usable stored certificate: {err:#}");
+                }
+                self.issue().await?
+            }
+        };
+        let resolver = Arc::new(CertResolver(RwLock::new(Arc::new(key))));
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone());
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(RENEW_CHECK_INTERVAL).await;
+                let current = resolver.0.read().unwrap().clone();
+                if !needs_renewal(&current, SystemTime::now()) {
+                    continue;
+                }
+                match self.issue().await {
+                    Ok(key) => *resolver.0.write().unwrap() = Arc::new(key),
+                    Err(err) => warn!("failed to renew certificate: {err:#}"),
+                }
+            }
+        });
+        Ok(Arc::new(config))
+    }
+
+    fn paths(&self) -> (PathBuf, PathBuf) {
+        let keyname = escape_hostname(&self.domains[0]);
+        (
+            self.dir.join(format!("{keyname}.crt")),
+            self.dir.join(format!("{keyname}.key")),
+        )
+    }
+
+    async fn load(&self) -> Result<CertifiedKey> {
+        let (cert_path, key_path) = self.paths();
+        tokio::task::spawn_blocking(move || certified_key(&cert_path, &key_path)).await?
+    }
+
+    /// Issues a new certificate, stores it and returns it.
+    async fn issue(&self) -> Result<CertifiedKey> {
+        info!(domains = ?self.domains, "requesting certificate using dns-01");
+        let (cert_pem, key_pem) = self.order().await?;
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let (cert_path, key_path) = self.paths();
+        write_private(&key_path, key_pem.as_bytes()).await?;
+        tokio::fs::write(&cert_path, cert_pem).await?;
+        info!("stored new certificate at {}", cert_path.display());
+        self.load().await
+    }
+
+    async fn account(&self) -> Result<Account> {
+        let path = self.dir.join(ACCOUNT_KEY_FILE);
+        let key = match tokio::fs::read(&path).await {
+            Ok(key) => key,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                tokio::fs::create_dir_all(&self.dir).await?;
+                let key = Account::generate_key_pair();
+                write_private(&path, &key).await?;
+                key
+            }
+            Err(err) => return Err(err).context("failed to read acme account key"),
+        };
+        let directory = Directory::discover(&self.client_config, self.directory_url).await?;
+        let contact = [format!("mailto:{}", self.contact)];
+        let account =
+            Account::create_with_keypair(&self.client_config, directory, &contact, &key).await?;
+        Ok(account)
+    }
+
+    /// Runs an ACME order, returning the certificate chain and private key as PEM.
+    async fn order(&self) -> Result<(String, String)> {
+        let account = self.account().await?;
+        let client = &self.client_config;
+        let (order_url, mut order) = account.new_order(client, self.domains.clone()).await?;
+
+        let mut records = Vec::new();
+        let res = async {
+            let mut challenges = Vec::new();
+            for auth_url in &order.authorizations {
+                let auth = account.auth(client, auth_url).await?;
+                if matches!(auth.status, AuthStatus::Valid) {
+                    continue;
+                }
+                let challenge = auth
+                    .challenges
+                    .iter()
+                    .find(|c| c.typ == ChallengeType::Dns01)
+                    .context("acme server offered no dns-01 challenge")?;
+                // for wildcard names the identifier is the name without the `*.`
+                let Identifier::Dns(domain) = &auth.identifier;
+                let name = txt_record_name(domain);
+                let value = key_authorization_digest(&account.key_pair, &challenge.token);
+                self.provider.set_txt_record(&name, &value).await?;
+                records.push((name, value));
+                challenges.push((auth_url.clone(), challenge.url.clone()));
+            }
+            if !challenges.is_empty() {
+                debug!(
+                    "waiting {:?} for txt records to propagate",
+                    self.propagation_delay
+                );
+                tokio::time::sleep(self.propagation_delay).await;
+            }
+            for (_, challenge_url) in &challenges {
+                account.challenge(client, challenge_url).await?;
+            }
+            for (auth_url, _) in &challenges {
+                wait_for_auth(&account, client, auth_url).await?;
+            }
+            anyhow::Ok(())
+        }
+        .await;
+        for (name, value) in &records {
+            if let Err(err) = self.provider.remove_txt_record(name, value).await {
+                warn!("failed to remove txt record {name}: {err:#}");
+            }
+        }
+        res?;
+
+        let mut params = rcgen::CertificateParams::new(self.domains.clone());
+        params.alg = &rcgen::PKCS_ECDSA_P256_SHA256;
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        let cert = rcgen::Certificate::from_params(params)?;
+        let csr = cert.serialize_request_der()?;
+        let mut finalized = false;
+        let mut attempts = 0;
+        let certificate_url = loop {
+            match &order.status {
+                OrderStatus::Valid { certificate } => break certificate.clone(),
+                OrderStatus::Invalid => bail!("order invalid: {:?}", order.error),
+                OrderStatus::Ready if !finalized => {
+                    order = account
+                        .finalize(client, &order.finalize, csr.clone())
+                        .await?;
+                    finalized = true;
+                    continue;
+                }
+                _ => {}
+            }
+            attempts += 1;
+            anyhow::ensure!(
+                attempts < POLL_ATTEMPTS,
+                "timed out waiting for the certificate"
+            );
+            tokio::time::sleep(POLL_INTERVAL).await;
+            order = account.order(client, &order_url).await?;
+        };
+        let cert_pem = account.certificate(client, certificate_url).await?;
+        Ok((cert_pem, cert.serialize_private_key_pem()))
+    }
+}
+
+/// Waits until the ACME server validated the authorization at `url`.
+async fn wait_for_auth(
+    account: &Account,
+    client: &Arc<rustls::ClientConfig>,
+    url: &str,
+) -> Result<()> {
+    for _ in 0..POLL_ATTEMPTS {
+        let auth = account.auth(client, url).await?;
+        match auth.status {
+            AuthStatus::Valid => return Ok(()),
+            AuthStatus::Pending => tokio::time::sleep(POLL_INTERVAL).await,
+            status => {
+                let error = auth.challenges.iter().find_map(|c| c.error.as_ref());
+                bail!("authorization {status:?}: {error:?}");
+            }
+        }
+    }
+    bail!("timed out waiting for the authorization")
+}
+
+/// The name of the TXT record for the challenge for `domain`.
+fn txt_record_name(domain: &str) -> String {
+    let domain = domain.strip_prefix("*.").unwrap_or(domain);
+    format!("_acme-challenge.{domain}")
+}
+
+/// The value of the TXT record for a challenge, see RFC 8555, section 8.4.
+fn key_authorization_digest(key_pair: &EcdsaKeyPair, token: &str) -> String {
+    // the public key is the uncompressed point 0x04 || x || y
+    let (x, y) = key_pair.public_key().as_ref()[1..].split_at(32);
+    let jwk = format!(
+        r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+        BASE64URL_NOPAD.encode(x),
+        BASE64URL_NOPAD.encode(y)
+    );
+    let thumbprint = ring::digest::digest(&ring::digest::SHA256, jwk.as_bytes());
+    let key_authorization = format!("{token}.{}", BASE64URL_NOPAD.encode(thumbprint.as_ref()));
+    let digest = ring::digest::digest(&ring::digest::SHA256, key_authorization.as_bytes());
+    BASE64URL_NOPAD.encode(digest.as_ref())
+}
+
+fn certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey> {
+    let certs = load_certs(cert_path)?;
+    let key = load_private_key(key_path)?;
+    let key = rustls::sign::any_supported_type(&key).context("unsupported private key")?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// Whether the certificate expires within [`RENEW_BEFORE`] of `now`.
+fn needs_renewal(key: &CertifiedKey, now: SystemTime) -> bool {
+    let Some(cert) = key.cert.first() else {
+        return true;
+    };
+    let Ok((_, cert)) = x509_parser::parse_x509_certificate(&cert.0) else {
+        return true;
+    };
+    let not_after = cert.validity().not_after.timestamp();
+    let renew_at = UNIX_EPOCH + Duration::from_secs(not_after.max(0) as u64) - RENEW_BEFORE;
+    now >= renew_at
+}
+
+/// Serves the current certificate, which is replaced when it is renewed.
+struct CertResolver(RwLock<Arc<CertifiedKey>>);
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.read().unwrap().clone())
+    }
+}
+
+/// Writes a private key to `path`, only readable by the current user.
+///
+/// The key is written to a temporary file first and then moved into place, so the file at
+/// `path` is never partially written.
+async fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    let target = path.to_path_buf();
+    let data = data.to_vec();
+    tokio::task::spawn_blocking(move || {
+        use std::io::Write;
+
+        let temp_path = target.with_extension("tmp");
+        // the mode only applies to new files, so never reuse a left over temporary file
+        match std::fs::remove_file(&temp_path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&temp_path)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        drop(file);
+        std::fs::rename(&temp_path, &target)
+    })
+    .await?
+    .with_context(|| format!("failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_txt_record_name() {
+        assert_eq!(
+            txt_record_name("derp.example.com"),
+            "_acme-challenge.derp.example.com"
+        );
+        assert_eq!(
+            txt_record_name("*.derp.example.com"),
+            "_acme-challenge.derp.example.com"
+        );
+    }
+
+    #[test]
+    fn test_key_authorization_digest() {
+        let key = Account::generate_key_pair();
+        let key_pair =
+            EcdsaKeyPair::from_pkcs8(&ring::signature::ECDSA_P256_SHA256_FIXED_SIGNING, &key)
+                .unwrap();
+        let a = key_authorization_digest(&key_pair, "token-a");
+        // a base64url encoded sha256 digest
+        assert_eq!(a.len(), 43);
+        assert_eq!(a, key_authorization_digest(&key_pair, "token-a"));
+        assert_ne!(a, key_authorization_digest(&key_pair, "token-b"));
+    }
+
+    #[test]
+    fn test_needs_renewal() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("derper-dns01-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let mut params = rcgen::CertificateParams::new(vec!["derp.example.com".to_string()]);
+        params.not_before = rcgen::date_time_ymd(2023, 1, 1);
+        params.not_after = rcgen::date_time_ymd(2023, 4, 1);
+        let cert = rcgen::Certificate::from_params(params)?;
+        let (cert_path, key_path) = (dir.join("test.crt"), dir.join("test.key"));
+        std::fs::write(&cert_path, cert.serialize_pem()?)?;
+        std::fs::write(&key_path, cert.serialize_private_key_pem())?;
+        let key = certified_key(&cert_path, &key_path)?;
+        std::fs::remove_dir_all(&dir)?;
+
+        let day = Duration::from_secs(24 * 60 * 60);
+        let jan_15 = UNIX_EPOCH + Duration::from_secs(1_673_740_800);
+        assert!(!needs_renewal(&key, jan_15));
+        assert!(needs_renewal(&key, jan_15 + 50 * day));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_write_private() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("derper-dns01-key-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("test.key");
+        write_private(&path, b"secret").await?;
+        let mode = std::fs::metadata(&path)?.permissions().mode();
+        let data = std::fs::read(&path)?;
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(data, b"secret");
+        assert_eq!(mode & 0o777, 0o600);
+        Ok(())
+    }
+}
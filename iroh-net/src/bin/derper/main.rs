@@ -4,9 +4,11 @@
 
 use std::{
     borrow::Cow,
+    collections::HashSet,
     net::{IpAddr, Ipv6Addr, SocketAddr},
     path::{Path, PathBuf},
     pin::Pin,
+    str::FromStr,
     sync::Arc,
     task::{Context, Poll},
 };
@@ -23,17 +25,23 @@ use iroh_net::{
         http::{
             MeshAddrs, ServerBuilder as DerpServerBuilder, TlsAcceptor, TlsConfig as DerpTlsConfig,
         },
+        ClientAccess,
     },
     key, stun,
+    tls::PeerId,
 };
 
 use reqwest::Url;
 use serde::{Deserialize, Serialize};
 use tokio::net::{TcpListener, UdpSocket};
 use tokio_rustls_acme::{caches::DirCache, AcmeConfig};
+
+use dns01::{Dns01, DnsChallengeConfig, HookDnsProvider};
 use tracing::{debug, debug_span, error, info, trace, warn, Instrument};
 use tracing_subscriber::{prelude::*, EnvFilter};
 
+mod dns01;
+
 type HyperError = Box<dyn std::error::Error + Send + Sync>;
 type HyperResult<T> = std::result::Result<T, HyperError>;
 
@@ -57,6 +65,8 @@ struct Cli {
 enum CertMode {
     Manual,
     LetsEncrypt,
+    /// LetsEncrypt using the DNS-01 challenge, see [`dns01`].
+    LetsEncryptDns,
 }
 
 impl CertMode {
@@ -66,6 +76,7 @@ impl CertMode {
         contact: String,
         is_production: bool,
         dir: PathBuf,
+        dns_challenge: Option<DnsChallengeConfig>,
     ) -> Result<(Arc<rustls::ServerConfig>, TlsAcceptor)> {
         let config = rustls::ServerConfig::builder()
             .with_safe_defaults()
@@ -93,6 +104,24 @@ impl CertMode {
 
                 Ok((Arc::new(config), TlsAcceptor::LetsEncrypt(acceptor)))
             }
+            CertMode::LetsEncryptDns => {
+                let dns_challenge = dns_challenge
+                    .context("cert_mode LetsEncryptDns requires a dns_challenge config")?;
+                let provider = Arc::new(HookDnsProvider::new(dns_challenge.hook.clone()));
+                let config = Dns01::new(
+                    hostname,
+                    contact,
+                    is_production,
+                    dir,
+                    dns_challenge,
+                    provider,
+                )
+                .server_config()
+                .await?;
+                let acceptor = tokio_rustls::TlsAcceptor::from(config.clone());
+
+                Ok((config, TlsAcceptor::Manual(acceptor)))
+            }
             CertMode::Manual => {
                 // load certificates manually
                 let keyname = escape_hostname(&hostname);
@@ -185,6 +214,8 @@ struct Config {
     limits: Option<Limits>,
     /// Mesh network configuration
     mesh: Option<MeshConfig>,
+    /// Restricts which clients may use the DERP server. All clients are allowed if not set.
+    access: Option<AccessConfig>,
     #[cfg(feature = "metrics")]
     /// Metrics serve address. If not set, metrics are not served.
     metrics_addr: Option<SocketAddr>,
@@ -199,11 +230,41 @@ struct MeshConfig {
     mesh_with: Vec<Url>,
 }
 
+#[derive(Serialize, Deserialize)]
+struct AccessConfig {
+    /// Peer ids of the only clients that may connect.
+    allow: Option<Vec<String>>,
+    /// Peer ids of clients that may not connect, cannot be combined with `allow`.
+    deny: Option<Vec<String>>,
+}
+
+impl AccessConfig {
+    fn client_access(&self) -> Result<ClientAccess> {
+        let parse = |peer_ids: &[String]| {
+            peer_ids
+                .iter()
+                .map(|peer_id| {
+                    let peer_id = PeerId::from_str(peer_id)
+                        .with_context(|| format!("invalid peer id {peer_id}"))?;
+                    Ok(key::node::PublicKey::from(peer_id))
+                })
+                .collect::<Result<HashSet<_>>>()
+        };
+        match (&self.allow, &self.deny) {
+            (Some(_), Some(_)) => bail!("access config can not have both `allow` and `deny`"),
+            (Some(allow), None) => Ok(ClientAccess::Allow(parse(allow)?)),
+            (None, Some(deny)) => Ok(ClientAccess::Deny(parse(deny)?)),
+            (None, None) => Ok(ClientAccess::All),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct TlsConfig {
-    /// Mode for getting a cert. possible options: 'Manual', 'LetsEncrypt'
+    /// Mode for getting a cert. possible options: 'Manual', 'LetsEncrypt', 'LetsEncryptDns'
     /// When using manual mode, a certificate will be read from `<hostname>.crt` and a private key from
     /// `<hostname>.key`, with the `<hostname>` being the escaped hostname.
+    /// 'LetsEncryptDns' stores the certificate in the same files and requires `dns_challenge`.
     cert_mode: CertMode,
     /// Whether to use the LetsEncrypt production or staging server.
     ///
@@ -219,6 +280,11 @@ struct TlsConfig {
     contact: String,
     /// Directory to store LetsEncrypt certs or read certificates from, if TLS is used.
     cert_dir: Option<PathBuf>,
+    /// Configuration for the DNS-01 challenge, used by `cert_mode: CertMode::LetsEncryptDns`.
+    ///
+    /// This allows getting certificates when the derper is not reachable on port 443, and
+    /// wildcard certificates.
+    dns_challenge: Option<DnsChallengeConfig>,
     /// The port on which to serve a response for the captive portal probe over HTTP.
     ///
     /// The listener is bound to the same IP as specified in the `addr` field. Defaults to 80.
@@ -246,6 +312,7 @@ impl Default for Config {
             tls: None,
             limits: None,
             mesh: None,
+            access: None,
             #[cfg(feature = "metrics")]
             metrics_addr: None,
         }
@@ -406,6 +473,15 @@ async fn run(
         false => (None, None, None),
     };
 
+    let client_access = match cfg.access {
+        Some(access) => {
+            let access = access.client_access()?;
+            info!("DERP client access configured");
+            access
+        }
+        None => ClientAccess::All,
+    };
+
     // run stun
     let stun_task = if cfg.enable_stun {
         Some(tokio::task::spawn(async move {
@@ -426,6 +502,7 @@ async fn run(
                 contact,
                 is_production,
                 tls_config.cert_dir.unwrap_or_else(|| PathBuf::from(".")),
+                tls_config.dns_challenge,
             )
             .await?;
         let headers: Vec<(&str, &str)> = TLS_HEADERS.into();
@@ -447,6 +524,7 @@ async fn run(
         .tls_config(tls_config.clone())
         .derp_override(Box::new(derp_disabled_handler))
         .mesh_derpers(mesh_derpers)
+        .client_access(client_access)
         .request_handler(Method::GET, "/", Box::new(root_handler))
         .request_handler(Method::GET, "/index.html", Box::new(root_handler))
         .request_handler(Method::GET, "/derp/probe", Box::new(probe_handler))
@@ -0,0 +1,178 @@
+//! Unreliable datagrams on the connections of a [`MagicEndpoint`].
+//!
+//! Besides streams, QUIC connections can carry datagrams, which are neither retransmitted
+//! nor ordered. This suits traffic which is useless once late, like game state or voice.
+//! Datagrams are sent on the same path as the streams of the connection, so they are sent
+//! directly when the [`MagicEndpoint`] holepunched a path, and relayed through DERP
+//! otherwise.
+//!
+//! Datagrams belong to a connection, so they are routed by the ALPN of the connection like
+//! streams: dial with [`ALPN`] or an application specific ALPN, and pass the connections
+//! accepted with that ALPN to [`Datagrams::new`]. A datagram must fit into a single packet,
+//! [`Datagrams::max_size`] tells how large it may be on the current path.
+//!
+//! Receiving datagrams can be disabled with
+//! [`MagicEndpointBuilder::datagram_receive_buffer_size`](crate::magic_endpoint::MagicEndpointBuilder::datagram_receive_buffer_size),
+//! peers then can not send any.
+//!
+//! ```no_run
+//! # async fn example(endpoint: iroh_net::MagicEndpoint, peer_id: iroh_net::tls::PeerId) -> anyhow::Result<()> {
+//! use iroh_net::datagram::{Datagrams, ALPN};
+//!
+//! let conn = endpoint.connect(peer_id, ALPN, None, &[]).await?;
+//! let datagrams = Datagrams::new(conn);
+//! datagrams.send("ping".into())?;
+//! if let Some(pong) = datagrams.recv().await? {
+//!     println!("received {} bytes", pong.len());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`MagicEndpoint`]: crate::MagicEndpoint
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+
+/// The ALPN for connections carrying datagrams.
+pub const ALPN: &[u8] = b"/iroh-datagram/1";
+
+/// Sends and receives the datagrams of a connection.
+///
+/// Can be cheaply cloned, all clones use the same connection.
+#[derive(Debug, Clone)]
+pub struct Datagrams {
+    conn: quinn::Connection,
+}
+
+impl Datagrams {
+    /// Uses `conn` for datagrams.
+    pub fn new(conn: quinn::Connection) -> Self {
+        Self { conn }
+    }
+
+    /// The connection carrying the datagrams.
+    pub fn connection(&self) -> &quinn::Connection {
+        &self.conn
+    }
+
+    /// The maximum size of a datagram which can currently be sent.
+    ///
+    /// Depends on the path MTU, so it can change while the connection is open. `None` if
+    /// the peer does not accept datagrams.
+    pub fn max_size(&self) -> Option<usize> {
+        self.conn.max_datagram_size()
+    }
+
+    /// Queues `data` to be sent as a single datagram.
+    ///
+    /// Fails if the datagram is larger than [`Datagrams::max_size`], if the peer does not
+    /// accept datagrams, or if the connection is closed. Succeeding does not mean the
+    /// datagram will arrive.
+    pub fn send(&self, data: Bytes) -> Result<()> {
+        let len = data.len();
+        self.conn.send_datagram(data).map_err(|err| match err {
+            quinn::SendDatagramError::TooLarge => anyhow!(
+                "datagram of {len} bytes exceeds the maximum size of {:?} bytes",
+                self.max_size()
+            ),
+            err => err.into(),
+        })
+    }
+
+    /// Waits for the next datagram from the peer.
+    ///
+    /// Returns `None` once the connection is closed by either side.
+    pub async fn recv(&self) -> Result<Option<Bytes>> {
+        match self.conn.read_datagram().await {
+            Ok(data) => Ok(Some(data)),
+            Err(quinn::ConnectionError::ApplicationClosed(_))
+            | Err(quinn::ConnectionError::LocallyClosed) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::time::Duration;
+
+    use anyhow::Context;
+
+    use super::*;
+    use crate::test_utils::setup_logging;
+    use crate::MagicEndpoint;
+
+    #[tokio::test]
+    async fn test_datagrams() -> Result<()> {
+        let _guard = setup_logging();
+
+        let bind = || MagicEndpoint::builder().alpns(vec![ALPN.to_vec()]).bind(0);
+        let ep1 = bind().await?;
+        let ep2 = bind().await?;
+        // echo the datagrams back
+        let server = tokio::spawn({
+            let ep2 = ep2.clone();
+            async move {
+                let connecting = ep2.accept().await.context("no connection")?;
+                let (_, alpn, conn) = ep2.accept_conn(connecting).await?;
+                assert_eq!(alpn.as_bytes(), ALPN);
+                let datagrams = Datagrams::new(conn);
+                while let Some(data) = datagrams.recv().await? {
+                    datagrams.send(data)?;
+                }
+                anyhow::Ok(())
+            }
+        });
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), ep2.local_addr()?.0.port());
+        let conn = ep1.connect(ep2.peer_id(), ALPN, None, &[addr]).await?;
+        let datagrams = Datagrams::new(conn);
+
+        let max_size = datagrams.max_size().context("datagrams not supported")?;
+        assert!(datagrams.send(vec![0u8; max_size + 1].into()).is_err());
+        // datagrams may be lost, so retry until one is echoed
+        let echoed = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                datagrams.send(Bytes::from_static(b"hello"))?;
+                let recv = tokio::time::timeout(Duration::from_millis(500), datagrams.recv());
+                if let Ok(res) = recv.await {
+                    break res;
+                }
+            }
+        })
+        .await??;
+        assert_eq!(echoed, Some(Bytes::from_static(b"hello")));
+
+        datagrams.connection().close(0u32.into(), b"done");
+        assert_eq!(datagrams.recv().await?, None);
+        server.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_datagrams_disabled() -> Result<()> {
+        let ep1 = MagicEndpoint::builder().bind(0).await?;
+        let ep2 = MagicEndpoint::builder()
+            .alpns(vec![ALPN.to_vec()])
+            .datagram_receive_buffer_size(None)
+            .bind(0)
+            .await?;
+        let server = tokio::spawn({
+            let ep2 = ep2.clone();
+            async move {
+                let connecting = ep2.accept().await.context("no connection")?;
+                let (_, _, conn) = ep2.accept_conn(connecting).await?;
+                conn.closed().await;
+                anyhow::Ok(())
+            }
+        });
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), ep2.local_addr()?.0.port());
+        let conn = ep1.connect(ep2.peer_id(), ALPN, None, &[addr]).await?;
+        let datagrams = Datagrams::new(conn);
+        assert_eq!(datagrams.max_size(), None);
+        assert!(datagrams.send(Bytes::from_static(b"hello")).is_err());
+        datagrams.connection().close(0u32.into(), b"done");
+        server.await??;
+        Ok(())
+    }
+}
@@ -23,7 +23,8 @@ pub use self::http::Client as HttpClient;
 pub use self::map::{DerpMap, DerpNode, DerpRegion, UseIpv4, UseIpv6};
 pub use self::metrics::Metrics;
 pub use self::server::{
-    ClientConnHandler, MaybeTlsStream as MaybeTlsStreamServer, PacketForwarderHandler, Server,
+    ClientAccess, ClientConnHandler, MaybeTlsStream as MaybeTlsStreamServer,
+    PacketForwarderHandler, Server,
 };
 pub use self::types::{MeshKey, PacketForwarder};
 
@@ -77,6 +78,12 @@ const PROTOCOL_VERSION: usize = 2;
 ///  * clients sends FrameType::SendPacket
 ///  * server then sends FrameType::RecvPacket to recipient
 ///
+///  Shared connections:
+///  * client sends FrameType::AddKey for each additional key it relays packets for
+///  * client sends FrameType::SendPacketFrom for packets from an additional key
+///  * server sends FrameType::RecvPacketFor for packets to an additional key
+///  * client sends FrameType::RemoveKey when it no longer relays for a key
+///
 
 const PREFERRED: u8 = 1u8;
 /// indicates this is NOT the client's home node
@@ -146,6 +153,25 @@ enum FrameType {
     Restarting = 15,
     /// 32B src pub key + 32B dst pub key + packet bytes
     ForwardPacket = 16,
+    /// Registers an additional key on the connection, so that one connection can relay the
+    /// packets of several clients. Proves the ownership of the key like
+    /// [`FrameType::ClientInfo`].
+    ///
+    /// 32B pub key + 24B nonce + chachabox(bytes)
+    AddKey = 17,
+    /// Removes a key registered with [`FrameType::AddKey`].
+    ///
+    /// 32B pub key
+    RemoveKey = 18,
+    /// Like [`FrameType::SendPacket`], but sent by a key registered with
+    /// [`FrameType::AddKey`].
+    ///
+    /// 32B src pub key + 32B dst pub key + packet bytes
+    SendPacketFrom = 19,
+    /// Like [`FrameType::RecvPacket`], but for a key registered with [`FrameType::AddKey`].
+    ///
+    /// 32B dst pub key + 32B src pub key + packet bytes
+    RecvPacketFor = 20,
     Unknown = 255,
 }
 
@@ -174,6 +200,10 @@ impl From<u8> for FrameType {
             14 => FrameType::Health,
             15 => FrameType::Restarting,
             16 => FrameType::ForwardPacket,
+            17 => FrameType::AddKey,
+            18 => FrameType::RemoveKey,
+            19 => FrameType::SendPacketFrom,
+            20 => FrameType::RecvPacketFor,
             _ => FrameType::Unknown,
         }
     }
@@ -290,9 +320,7 @@ pub(crate) async fn send_client_key<W: AsyncWrite + Unpin>(
     server_key: &PublicKey,
     client_info: &ClientInfo,
 ) -> Result<()> {
-    let mut buf = BytesMut::zeroed(ClientInfo::POSTCARD_MAX_SIZE);
-    let msg = postcard::to_slice(client_info, &mut buf)?;
-    let sealed_msg = secret_key.seal_to(server_key, msg);
+    let sealed_msg = seal_client_info(secret_key, server_key, client_info)?;
     write_frame(
         &mut writer,
         FrameType::ClientInfo,
@@ -324,8 +352,28 @@ async fn recv_client_key<R: AsyncRead + Unpin>(
         frame_type == FrameType::ClientInfo,
         "expected FrameType::ClientInfo frame got {frame_type}"
     );
-    let key = PublicKey::try_from(&buf[..PUBLIC_KEY_LENGTH]).context("public key")?;
-    let msg = &buf[PUBLIC_KEY_LENGTH..];
+    open_client_info(&secret_key, &buf)
+}
+
+/// Seals the [`ClientInfo`] of the client with `secret_key` to the server.
+///
+/// Used in `FrameType::ClientInfo` and `FrameType::AddKey` frames, after the client's
+/// [`PublicKey`]. Opening it proves the client owns the key.
+fn seal_client_info(
+    secret_key: &SecretKey,
+    server_key: &PublicKey,
+    client_info: &ClientInfo,
+) -> Result<Vec<u8>> {
+    let mut buf = BytesMut::zeroed(ClientInfo::POSTCARD_MAX_SIZE);
+    let msg = postcard::to_slice(client_info, &mut buf)?;
+    Ok(secret_key.seal_to(server_key, msg))
+}
+
+/// Opens the payload of a `FrameType::ClientInfo` or `FrameType::AddKey` frame.
+fn open_client_info(secret_key: &SecretKey, frame: &[u8]) -> Result<(PublicKey, ClientInfo)> {
+    ensure!(frame.len() >= PUBLIC_KEY_LENGTH, "short client info");
+    let key = PublicKey::try_from(&frame[..PUBLIC_KEY_LENGTH]).context("public key")?;
+    let msg = &frame[PUBLIC_KEY_LENGTH..];
     let msg = secret_key.open_from(&key, msg).context("shared secret")?;
     let info: ClientInfo = postcard::from_bytes(&msg).context("deserialization")?;
     Ok((key, info))
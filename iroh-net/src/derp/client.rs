@@ -70,6 +70,51 @@ impl Client {
         Ok(())
     }
 
+    /// Sends a packet from `srckey` to the node identified by `dstkey`.
+    ///
+    /// `srckey` must have been registered on this connection with [`Client::add_key`].
+    ///
+    /// Errors if the packet is larger than [`super::MAX_PACKET_SIZE`]
+    pub async fn send_from(&self, srckey: PublicKey, dstkey: PublicKey, packet: Bytes) -> Result<()> {
+        debug!("[DERP] {:?} -> {:?} ({}b)", srckey, dstkey, packet.len());
+
+        self.inner
+            .writer_channel
+            .send(ClientWriterMessage::PacketFrom((srckey, dstkey, packet)))
+            .await?;
+        Ok(())
+    }
+
+    /// Registers `secret_key` on this connection, so that packets can be sent and received
+    /// for its public key as well.
+    ///
+    /// Packets for the key are received as [`ReceivedMessage::ReceivedPacketFor`]. The
+    /// server does not confirm the registration, keys it does not allow are ignored.
+    pub async fn add_key(&self, secret_key: &SecretKey) -> Result<()> {
+        let client_info = ClientInfo {
+            version: PROTOCOL_VERSION,
+            mesh_key: None,
+            can_ack_pings: false,
+            is_prober: false,
+        };
+        let sealed =
+            super::seal_client_info(secret_key, &self.inner.server_public_key, &client_info)?;
+        self.inner
+            .writer_channel
+            .send(ClientWriterMessage::AddKey((secret_key.public_key(), sealed)))
+            .await?;
+        Ok(())
+    }
+
+    /// Unregisters a key registered with [`Client::add_key`].
+    pub async fn remove_key(&self, key: PublicKey) -> Result<()> {
+        self.inner
+            .writer_channel
+            .send(ClientWriterMessage::RemoveKey(key))
+            .await?;
+        Ok(())
+    }
+
     /// Used by mesh peers to forward packets.
     ///
     // TODO: this is the only method with a timeout, why? Why does it have a timeout and no rate
@@ -224,6 +269,21 @@ impl Client {
                     let packet = ReceivedMessage::ReceivedPacket { source, data };
                     return Ok(packet);
                 }
+                FrameType::RecvPacketFor => {
+                    if (frame_len) < PUBLIC_KEY_LENGTH * 2 {
+                        tracing::warn!("unexpected: dropping short packet from DERP server");
+                        continue;
+                    }
+                    let destination = PublicKey::try_from(&frame_payload[..PUBLIC_KEY_LENGTH])?;
+                    let (source, data) =
+                        parse_recv_frame(frame_payload.split_off(PUBLIC_KEY_LENGTH))?;
+                    let packet = ReceivedMessage::ReceivedPacketFor {
+                        destination,
+                        source,
+                        data,
+                    };
+                    return Ok(packet);
+                }
                 FrameType::Ping => {
                     if frame_len < 8 {
                         tracing::warn!("unexpected: dropping short PING frame");
@@ -306,6 +366,13 @@ impl Client {
 enum ClientWriterMessage {
     /// Send a packet (addressed to the [`PublicKey`]) to the server
     Packet((PublicKey, Bytes)),
+    /// Send a packet from the src [`PublicKey`], registered with `AddKey`, to the dst
+    /// [`PublicKey`] to the server
+    PacketFrom((PublicKey, PublicKey, Bytes)),
+    /// Register the [`PublicKey`] on the connection, with its sealed client info
+    AddKey((PublicKey, Vec<u8>)),
+    /// Unregister a [`PublicKey`] registered with `AddKey`
+    RemoveKey(PublicKey),
     /// Forward a packet from the src [`PublicKey`] to the dst [`PublicKey`] to the server
     /// Should only be used for mesh clients.
     FwdPacket((PublicKey, PublicKey, Bytes)),
@@ -349,6 +416,16 @@ impl<W: AsyncWrite + Unpin + Send + 'static> ClientWriter<W> {
                     // the `forward_packet` method does not have a rate limiter, but _does_ have a timeout.
                     send_packet(&mut self.writer, &self.rate_limiter, key, &bytes).await?;
                 }
+                Some(ClientWriterMessage::PacketFrom((srckey, dstkey, bytes))) => {
+                    send_packet_from(&mut self.writer, &self.rate_limiter, srckey, dstkey, &bytes)
+                        .await?;
+                }
+                Some(ClientWriterMessage::AddKey((key, sealed))) => {
+                    add_key(&mut self.writer, key, &sealed).await?;
+                }
+                Some(ClientWriterMessage::RemoveKey(key)) => {
+                    remove_key(&mut self.writer, key).await?;
+                }
                 Some(ClientWriterMessage::FwdPacket((srckey, dstkey, bytes))) => {
                     tokio::time::timeout(
                         Duration::from_secs(5),
@@ -557,6 +634,16 @@ pub enum ReceivedMessage {
         #[debug(skip)]
         data: Bytes, // TODO: ref
     },
+    /// Represents an incoming packet for a key registered with [`Client::add_key`].
+    ReceivedPacketFor {
+        /// The [`PublicKey`] of the packet recipient.
+        destination: PublicKey,
+        /// The [`PublicKey`] of the packet sender.
+        source: PublicKey,
+        /// The received packet bytes.
+        #[debug(skip)]
+        data: Bytes,
+    },
     /// Indicates that the client identified by the underlying public key had previously sent you a
     /// packet but has now disconnected from the server.
     PeerGone(PublicKey),
@@ -635,6 +722,56 @@ pub(crate) async fn send_packet<W: AsyncWrite + Unpin>(
     Ok(())
 }
 
+pub(crate) async fn send_packet_from<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    rate_limiter: &Option<RateLimiter>,
+    srckey: PublicKey,
+    dstkey: PublicKey,
+    packet: &[u8],
+) -> Result<()> {
+    ensure!(
+        packet.len() <= MAX_PACKET_SIZE,
+        "packet too big: {}",
+        packet.len()
+    );
+    let frame_len = PUBLIC_KEY_LENGTH * 2 + packet.len();
+    if let Some(rate_limiter) = rate_limiter {
+        if rate_limiter.check_n(frame_len).is_err() {
+            tracing::warn!("dropping send: rate limit reached");
+            return Ok(());
+        }
+    }
+    write_frame(
+        &mut writer,
+        FrameType::SendPacketFrom,
+        &[srckey.as_bytes(), dstkey.as_bytes(), packet],
+    )
+    .await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+pub(crate) async fn add_key<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    key: PublicKey,
+    sealed_client_info: &[u8],
+) -> Result<()> {
+    write_frame(
+        &mut writer,
+        FrameType::AddKey,
+        &[key.as_bytes(), sealed_client_info],
+    )
+    .await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+pub(crate) async fn remove_key<W: AsyncWrite + Unpin>(mut writer: W, key: PublicKey) -> Result<()> {
+    write_frame(&mut writer, FrameType::RemoveKey, &[key.as_bytes()]).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
 pub(crate) async fn forward_packet<W: AsyncWrite + Unpin>(
     mut writer: W,
     srckey: PublicKey,
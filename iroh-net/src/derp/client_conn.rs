@@ -1,6 +1,7 @@
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::{ensure, Context, Result};
 use bytes::{Bytes, BytesMut};
@@ -8,21 +9,22 @@ use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
-use tracing::{trace, Instrument};
+use tracing::{debug, info, trace, Instrument};
 
 use crate::{
     disco::looks_like_disco_wrapper,
-    key::node::{PublicKey, PUBLIC_KEY_LENGTH},
+    key::node::{PublicKey, SecretKey, PUBLIC_KEY_LENGTH},
 };
 
 use iroh_metrics::{inc, inc_by};
 
-use super::server::MaybeTlsStream;
+use super::server::{new_conn_num, ClientAccess, MaybeTlsStream};
 use super::{
     metrics::Metrics,
-    read_frame,
+    open_client_info, read_frame,
     types::{Packet, PacketForwarder, PeerConnState, ServerMessage},
-    write_frame_timeout, FrameType, KEEP_ALIVE, MAX_FRAME_SIZE, MAX_PACKET_SIZE, PREFERRED,
+    write_frame_timeout, FrameType, KEEP_ALIVE, MAX_FRAME_SIZE, MAX_PACKET_SIZE,
+    PER_CLIENT_SEND_QUEUE_DEPTH, PREFERRED,
 };
 
 /// The [`super::server::Server`] side representation of a [`super::client::Client`]'s connection
@@ -74,6 +76,59 @@ pub(crate) struct ClientChannels {
     pub(crate) mesh_update: mpsc::Sender<Vec<PeerConnState>>,
 }
 
+/// Counts the bytes of the packets relayed for a client connection.
+#[derive(Debug, Default)]
+pub(crate) struct ConnStats {
+    /// Bytes of the packets sent to the client.
+    bytes_sent: AtomicU64,
+    /// Bytes of the packets received from the client.
+    bytes_recv: AtomicU64,
+}
+
+/// The keys registered on a connection with [`FrameType::AddKey`], besides the key of the
+/// connection itself.
+///
+/// Each key is registered with the server as a client of its own, whose packets are relayed
+/// to the connection by [`ClientConnManager::new_extra_key`].
+#[derive(Debug)]
+pub(crate) struct ExtraKeys {
+    /// The server's secret key, to check that the client owns the keys it registers
+    secret_key: SecretKey,
+    /// Which keys may be registered
+    client_access: Arc<ClientAccess>,
+    /// The registered keys, with the connection number they are registered with
+    keys: HashMap<PublicKey, usize>,
+    /// Messages for the registered keys
+    recv: mpsc::Receiver<ExtraKeyMessage>,
+    /// Handed to the relays of the registered keys
+    send: mpsc::Sender<ExtraKeyMessage>,
+    /// Cancelled when the connection closes, which unregisters the keys
+    done: CancellationToken,
+}
+
+impl ExtraKeys {
+    pub(crate) fn new(secret_key: SecretKey, client_access: Arc<ClientAccess>) -> Self {
+        let (send, recv) = mpsc::channel(PER_CLIENT_SEND_QUEUE_DEPTH);
+        Self {
+            secret_key,
+            client_access,
+            keys: HashMap::new(),
+            recv,
+            send,
+            done: CancellationToken::new(),
+        }
+    }
+}
+
+/// A message for a key registered with [`FrameType::AddKey`], relayed to its connection.
+#[derive(Debug)]
+pub(crate) enum ExtraKeyMessage {
+    /// A packet for the key
+    Packet { dst: PublicKey, packet: Packet },
+    /// A peer the key sent packets to is gone
+    PeerGone(PublicKey),
+}
+
 pub trait Io: AsyncRead + AsyncWrite + Unpin + std::fmt::Debug {}
 impl<T: AsyncRead + AsyncWrite + Unpin + std::fmt::Debug> Io for T {}
 
@@ -90,6 +145,10 @@ where
     pub(crate) write_timeout: Option<Duration>,
     pub(crate) channel_capacity: usize,
     pub(crate) server_channel: mpsc::Sender<ServerMessage<P>>,
+    /// The server's secret key, to check the keys registered with [`FrameType::AddKey`]
+    pub(crate) secret_key: SecretKey,
+    /// Which keys may be registered with [`FrameType::AddKey`]
+    pub(crate) client_access: Arc<ClientAccess>,
 }
 
 impl<P> ClientConnBuilder<P>
@@ -107,6 +166,7 @@ where
             self.write_timeout,
             self.channel_capacity,
             self.server_channel,
+            ExtraKeys::new(self.secret_key, self.client_access),
         )
     }
 }
@@ -124,6 +184,7 @@ impl ClientConnManager {
         write_timeout: Option<Duration>,
         channel_capacity: usize,
         server_channel: mpsc::Sender<ServerMessage<P>>,
+        extra_keys: ExtraKeys,
     ) -> ClientConnManager
     where
         P: PacketForwarder,
@@ -137,6 +198,7 @@ impl ClientConnManager {
         let (mesh_update_s, mesh_update_r) = mpsc::channel(channel_capacity);
 
         let preferred = Arc::from(AtomicBool::from(false));
+        let stats = Arc::new(ConnStats::default());
 
         let conn_io = ClientConnIo {
             can_mesh,
@@ -151,6 +213,8 @@ impl ClientConnManager {
             key: key.clone(),
             preferred: Arc::clone(&preferred),
             server_channel: server_channel.clone(),
+            stats: Arc::clone(&stats),
+            extra_keys,
         };
 
         // start io loop
@@ -160,10 +224,20 @@ impl ClientConnManager {
             async move {
                 let key = io_client_id.0;
                 let conn_num = io_client_id.1;
+                let connected_at = Instant::now();
+                info!(client = ?key, conn_num, "client connected");
                 let res = conn_io.run(io_done).await;
                 let _ = server_channel
                     .send(ServerMessage::RemoveClient((key.clone(), conn_num)))
                     .await;
+                info!(
+                    client = ?key,
+                    conn_num,
+                    bytes_sent = stats.bytes_sent.load(Ordering::Relaxed),
+                    bytes_recv = stats.bytes_recv.load(Ordering::Relaxed),
+                    duration = ?connected_at.elapsed(),
+                    "client disconnected"
+                );
                 match res {
                     Err(e) => {
                         tracing::warn!(
@@ -194,6 +268,83 @@ impl ClientConnManager {
         }
     }
 
+    /// Creates a client for a key registered on another client's connection with
+    /// [`FrameType::AddKey`].
+    ///
+    /// Instead of writing to a connection of its own, the client relays its packets to the
+    /// connection over `relay`. It is removed from the server when `conn_done` is cancelled,
+    /// which happens when the connection closes.
+    pub(crate) fn new_extra_key<P>(
+        key: PublicKey,
+        conn_num: usize,
+        relay: mpsc::Sender<ExtraKeyMessage>,
+        conn_done: &CancellationToken,
+        channel_capacity: usize,
+        server_channel: mpsc::Sender<ServerMessage<P>>,
+    ) -> ClientConnManager
+    where
+        P: PacketForwarder,
+    {
+        // cancelled by the connection closing or by shutting down this client
+        let done = conn_done.child_token();
+        let (send_queue_s, mut send_queue_r) = mpsc::channel(channel_capacity);
+        let (disco_send_queue_s, mut disco_send_queue_r) = mpsc::channel(channel_capacity);
+        let (peer_gone_s, mut peer_gone_r) = mpsc::channel(channel_capacity);
+        // keys registered on a connection can not mesh, so there are no mesh updates
+        let (mesh_update_s, _) = mpsc::channel(1);
+
+        let relay_done = done.clone();
+        let relay_key = key.clone();
+        let io_handle = tokio::task::spawn(
+            async move {
+                info!(client = ?relay_key, conn_num, "client key added");
+                loop {
+                    let msg = tokio::select! {
+                        biased;
+                        _ = relay_done.cancelled() => break,
+                        Some(packet) = disco_send_queue_r.recv() => ExtraKeyMessage::Packet {
+                            dst: relay_key.clone(),
+                            packet,
+                        },
+                        Some(packet) = send_queue_r.recv() => ExtraKeyMessage::Packet {
+                            dst: relay_key.clone(),
+                            packet,
+                        },
+                        Some(peer) = peer_gone_r.recv() => ExtraKeyMessage::PeerGone(peer),
+                        else => break,
+                    };
+                    tokio::select! {
+                        _ = relay_done.cancelled() => break,
+                        res = relay.send(msg) => {
+                            if res.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+                let _ = server_channel
+                    .send(ServerMessage::RemoveClient((relay_key.clone(), conn_num)))
+                    .await;
+                info!(client = ?relay_key, conn_num, "client key removed");
+                Ok(())
+            }
+            .instrument(tracing::debug_span!("extra_key")),
+        );
+
+        ClientConnManager {
+            conn_num,
+            key,
+            io_handle,
+            done,
+            client_channels: ClientChannels {
+                send_queue: send_queue_s,
+                disco_send_queue: disco_send_queue_s,
+                peer_gone: peer_gone_s,
+                mesh_update: mesh_update_s,
+            },
+        }
+    }
+
     /// Shutdown the [`ClientConnManager`] reader and writer loops and closes the "actual" connection.
     ///
     /// Logs any shutdown errors as warnings.
@@ -275,6 +426,12 @@ pub(crate) struct ClientConnIo<P: PacketForwarder> {
     // might find that the alternative is better, once I have a better idea of how this is supposed
     // to be read.
     preferred: Arc<AtomicBool>,
+
+    /// Bytes relayed for this client, logged when the connection closes
+    stats: Arc<ConnStats>,
+
+    /// Additional keys the client relays packets for over this connection
+    extra_keys: ExtraKeys,
 }
 
 impl<P> ClientConnIo<P>
@@ -282,6 +439,13 @@ where
     P: PacketForwarder,
 {
     async fn run(mut self, done: CancellationToken) -> Result<()> {
+        let res = self.run_0(done).await;
+        // the keys registered on this connection are no longer reachable
+        self.extra_keys.done.cancel();
+        res
+    }
+
+    async fn run_0(&mut self, done: CancellationToken) -> Result<()> {
         let jitter = Duration::from_secs(5);
         let mut keep_alive = tokio::time::interval(KEEP_ALIVE + jitter);
         // ticks immediately
@@ -328,6 +492,11 @@ where
                     // TODO: stats
                     // record `packet.enqueuedAt`
                 }
+                msg = self.extra_keys.recv.recv() => {
+                    let msg = msg.context("extra keys channel dropped")?;
+                    trace!("send message for extra key");
+                    self.send_extra_key_message(msg).await?;
+                }
                 _ = keep_alive.tick() => {
                     trace!("keep alive");
                     self.send_keep_alive().await?;
@@ -433,6 +602,9 @@ where
         let srckey = packet.src;
         let contents = packet.bytes;
         inc_by!(Metrics, bytes_sent, contents.len().try_into().unwrap());
+        self.stats
+            .bytes_sent
+            .fetch_add(contents.len() as u64, Ordering::Relaxed);
         if srckey.is_zero() {
             // TODO: ensure we handle this correctly on the client side
             write_frame_timeout(
@@ -453,6 +625,31 @@ where
         }
     }
 
+    /// Writes a message for a key registered with [`FrameType::AddKey`], does not flush.
+    ///
+    /// Packets are sent in a `RECV_PACKET_FOR` frame, which tells the client the key the
+    /// packet is for.
+    async fn send_extra_key_message(&mut self, msg: ExtraKeyMessage) -> Result<()> {
+        match msg {
+            ExtraKeyMessage::Packet { dst, packet } => {
+                let contents = packet.bytes;
+                inc_by!(Metrics, bytes_sent, contents.len().try_into().unwrap());
+                self.stats
+                    .bytes_sent
+                    .fetch_add(contents.len() as u64, Ordering::Relaxed);
+                write_frame_timeout(
+                    &mut self.io,
+                    FrameType::RecvPacketFor,
+                    &[dst.as_bytes(), packet.src.as_bytes(), &contents],
+                    self.timeout,
+                )
+                .await
+            }
+            // the client can not tell which of its keys this is for, it applies to all
+            ExtraKeyMessage::PeerGone(peer) => self.send_peer_gone(peer).await,
+        }
+    }
+
     /// Handles read results.
     async fn handle_read(
         &mut self,
@@ -474,11 +671,29 @@ where
                     FrameType::SendPacket => {
                         self.handle_frame_send_packet(&frame).await?;
                         inc_by!(Metrics, bytes_recv, frame_len as u64);
+                        self.stats
+                            .bytes_recv
+                            .fetch_add(frame_len as u64, Ordering::Relaxed);
                     }
                     FrameType::ForwardPacket => {
                         self.handle_frame_forward_packet(&frame).await?;
                         inc!(Metrics, packets_forwarded_in);
                     }
+                    FrameType::SendPacketFrom => {
+                        self.handle_frame_send_packet_from(&frame).await?;
+                        inc_by!(Metrics, bytes_recv, frame_len as u64);
+                        self.stats
+                            .bytes_recv
+                            .fetch_add(frame_len as u64, Ordering::Relaxed);
+                    }
+                    FrameType::AddKey => {
+                        self.handle_frame_add_key(&frame).await?;
+                        inc!(Metrics, other_packets_recv);
+                    }
+                    FrameType::RemoveKey => {
+                        self.handle_frame_remove_key(&frame).await?;
+                        inc!(Metrics, other_packets_recv);
+                    }
                     FrameType::WatchConns => {
                         self.handle_frame_watch_conns(&frame).await?;
                         inc!(Metrics, other_packets_recv);
@@ -624,6 +839,63 @@ where
         self.transfer_packet(dstkey, packet).await
     }
 
+    /// Parse the SEND_PACKET_FROM frame, getting the source, destination and packet content.
+    /// Then sends the packet to the server, who directs it to the destination.
+    ///
+    /// Packets from keys that are not registered on this connection are dropped.
+    async fn handle_frame_send_packet_from(&self, data: &[u8]) -> Result<()> {
+        // same layout as a FORWARD_PACKET frame
+        let (srckey, dstkey, data) = parse_forward_packet(data)?;
+        if !self.extra_keys.keys.contains_key(&srckey) {
+            debug!(src = ?srckey, "dropping packet from a key that is not registered");
+            inc!(Metrics, send_packets_dropped);
+            return Ok(());
+        }
+        let packet = Packet {
+            src: srckey,
+            bytes: Bytes::from(data.to_owned()),
+        };
+        self.transfer_packet(dstkey, packet).await
+    }
+
+    /// Registers the key of an ADD_KEY frame on this connection.
+    ///
+    /// Errors if the client can not prove it owns the key. Keys that are not allowed by the
+    /// [`ClientAccess`] of the server are ignored.
+    async fn handle_frame_add_key(&mut self, data: &[u8]) -> Result<()> {
+        let (key, _) = open_client_info(&self.extra_keys.secret_key, data)
+            .context("FrameType::AddKey proof of ownership")?;
+        if key == self.key || self.extra_keys.keys.contains_key(&key) {
+            return Ok(());
+        }
+        if !self.extra_keys.client_access.is_allowed(&key) {
+            inc!(Metrics, rejected_clients);
+            info!(client = ?key, "rejected client key: not allowed");
+            return Ok(());
+        }
+        let conn_num = new_conn_num();
+        let client = ClientConnManager::new_extra_key(
+            key.clone(),
+            conn_num,
+            self.extra_keys.send.clone(),
+            &self.extra_keys.done,
+            PER_CLIENT_SEND_QUEUE_DEPTH,
+            self.server_channel.clone(),
+        );
+        self.extra_keys.keys.insert(key, conn_num);
+        self.send_server(ServerMessage::CreateClient(client)).await
+    }
+
+    /// Unregisters the key of a REMOVE_KEY frame from this connection.
+    async fn handle_frame_remove_key(&mut self, data: &[u8]) -> Result<()> {
+        let key = PublicKey::try_from(data)?;
+        if let Some(conn_num) = self.extra_keys.keys.remove(&key) {
+            self.send_server(ServerMessage::RemoveClient((key, conn_num)))
+                .await?;
+        }
+        Ok(())
+    }
+
     /// Send the given packet to the server. The server will attempt to
     /// send the packet to the destination, dropping the packet if the
     /// destination is not connected, or if the destination client can
@@ -713,6 +985,8 @@ mod tests {
             key: key.clone(),
             server_channel: server_channel_s,
             preferred: Arc::clone(&preferred),
+            stats: Default::default(),
+            extra_keys: ExtraKeys::new(SecretKey::generate(), Default::default()),
         };
 
         let done = CancellationToken::new();
@@ -930,6 +1204,8 @@ mod tests {
             key: key.clone(),
             server_channel: server_channel_s,
             preferred: Arc::clone(&preferred),
+            stats: Default::default(),
+            extra_keys: ExtraKeys::new(SecretKey::generate(), Default::default()),
         };
 
         let done = CancellationToken::new();
@@ -321,6 +321,8 @@ mod tests {
                 write_timeout: None,
                 channel_capacity: 10,
                 server_channel,
+                secret_key: crate::key::node::SecretKey::generate(),
+                client_access: Default::default(),
             },
             test_io,
         )
@@ -133,6 +133,109 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_http_clients_extra_keys() -> Result<()> {
+        tracing_subscriber::registry()
+            .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+            .with(EnvFilter::from_default_env())
+            .try_init()
+            .ok();
+
+        let server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .secret_key(Some(SecretKey::generate()))
+            .spawn()
+            .await?;
+        let port = server.addr().port();
+        let region = DerpRegion {
+            region_id: 1,
+            avoid: false,
+            nodes: vec![DerpNode {
+                name: "test_node".to_string(),
+                region_id: 1,
+                url: format!("http://localhost:{port}").parse().unwrap(),
+                stun_only: false,
+                stun_port: 0,
+                stun_test_ip: None,
+                ipv4: UseIpv4::Some("127.0.0.1".parse().unwrap()),
+                ipv6: UseIpv6::Disabled,
+            }],
+            region_code: "test_region".to_string(),
+        };
+        let derp_addr: Url = format!("http://127.0.0.1:{port}").parse().unwrap();
+
+        // the carrier connection, with the key `x` registered on it
+        let carrier = ClientBuilder::new()
+            .server_url(derp_addr.clone())
+            .get_region({
+                let region = region.clone();
+                move || {
+                    let region = region.clone();
+                    Box::pin(async move { Some(region) })
+                }
+            })
+            .build(SecretKey::generate())?;
+        let (carrier_s, mut carrier_r) = mpsc::channel(10);
+        let carrier_task = tokio::spawn({
+            let carrier = carrier.clone();
+            async move {
+                while let Ok((msg, _)) = carrier.recv_detail().await {
+                    if let ReceivedMessage::ReceivedPacketFor {
+                        destination,
+                        source,
+                        data,
+                    } = msg
+                    {
+                        carrier_s.send((destination, source, data)).await.ok();
+                    }
+                }
+            }
+        });
+        let x_key = SecretKey::generate();
+        carrier.add_key(x_key.clone()).await?;
+        // the server handles the frames of a connection in order, so the key is registered
+        // once the ping is answered
+        carrier.ping().await?;
+
+        let (b_key, mut b_recv, client_b_task, client_b) =
+            create_test_client(SecretKey::generate(), region, Some(derp_addr));
+        client_b.ping().await?;
+
+        let msg = Bytes::from_static(b"hi there, client x!");
+        client_b.send(x_key.public_key(), msg.clone()).await?;
+        let (got_dst, got_src, got_msg) = carrier_r.recv().await.expect("expected message for x");
+        assert_eq!(got_dst, x_key.public_key());
+        assert_eq!(got_src, b_key);
+        assert_eq!(got_msg, msg);
+
+        let msg = Bytes::from_static(b"right back at ya, client b!");
+        carrier
+            .send_from(x_key.public_key(), b_key.clone(), msg.clone())
+            .await?;
+        let (got_src, got_msg) = b_recv.recv().await.expect("expected message from x");
+        assert_eq!(got_src, x_key.public_key());
+        assert_eq!(got_msg, msg);
+
+        // packets from keys which are not registered are dropped
+        let y_key = SecretKey::generate();
+        carrier
+            .send_from(y_key.public_key(), b_key.clone(), Bytes::from_static(b"y"))
+            .await?;
+        carrier.remove_key(&x_key.public_key()).await?;
+        carrier
+            .send_from(x_key.public_key(), b_key.clone(), Bytes::from_static(b"x"))
+            .await?;
+        carrier.ping().await?;
+        client_b.ping().await?;
+        assert!(b_recv.try_recv().is_err());
+
+        server.shutdown().await;
+        carrier.close().await;
+        carrier_task.abort();
+        client_b.close().await;
+        client_b_task.abort();
+        Ok(())
+    }
+
     fn create_test_client(
         key: SecretKey,
         region: DerpRegion,
@@ -32,6 +32,8 @@ const DIAL_NODE_TIMEOUT: Duration = Duration::from_millis(1500);
 const PING_TIMEOUT: Duration = Duration::from_secs(5);
 const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 const MESH_CLIENT_REDIAL_DELAY: Duration = Duration::from_secs(5);
+/// How long a failed dial moves a DERP node behind the other nodes of its region.
+const NODE_FAILURE_BACKOFF: Duration = Duration::from_secs(60);
 
 /// Possible connection errors on the [`Client`]
 #[derive(Debug, thiserror::Error)]
@@ -136,6 +138,49 @@ struct InnerClient {
     is_prober: bool,
     server_public_key: Option<key::node::PublicKey>,
     url: Option<Url>,
+    /// Dial health of the DERP nodes, by node name.
+    node_health: std::sync::Mutex<HashMap<String, NodeHealth>>,
+    /// Additional keys registered on the connection, registered again on reconnects.
+    extra_keys: std::sync::Mutex<HashMap<key::node::PublicKey, key::node::SecretKey>>,
+}
+
+/// Dial health of a DERP node, used to order the nodes of a region.
+#[derive(Debug, Clone, Copy, Default)]
+struct NodeHealth {
+    /// Smoothed time to connect, of the successful dials.
+    latency: Option<Duration>,
+    /// Failed dials since the last successful one.
+    failures: u32,
+    /// When the last dial failed.
+    last_failure: Option<Instant>,
+}
+
+impl NodeHealth {
+    fn record_success(&mut self, latency: Duration) {
+        self.latency = Some(match self.latency {
+            Some(prev) => (prev * 3 + latency) / 4,
+            None => latency,
+        });
+        self.failures = 0;
+        self.last_failure = None;
+    }
+
+    fn record_failure(&mut self, now: Instant) {
+        self.failures += 1;
+        self.last_failure = Some(now);
+    }
+
+    /// Nodes with a lower rank are dialed first.
+    ///
+    /// Nodes that failed recently go last, the others are ordered by latency, with nodes
+    /// that were never dialed after those with a known latency.
+    fn rank(&self, now: Instant) -> (u32, Duration) {
+        let failures = match self.last_failure {
+            Some(at) if now.duration_since(at) < NODE_FAILURE_BACKOFF => self.failures,
+            _ => 0,
+        };
+        (failures, self.latency.unwrap_or(Duration::MAX))
+    }
 }
 
 /// Build a Client.
@@ -259,6 +304,8 @@ impl ClientBuilder {
                 is_prober: self.is_prober,
                 server_public_key: self.server_public_key,
                 url: self.url,
+                node_health: Default::default(),
+                extra_keys: Default::default(),
             }),
         })
     }
@@ -551,6 +598,13 @@ impl Client {
             derp_client.close().await;
             return Err(ClientError::Send);
         }
+        let extra_keys: Vec<_> = self.inner.extra_keys.lock().unwrap().values().cloned().collect();
+        for secret_key in extra_keys {
+            if derp_client.add_key(&secret_key).await.is_err() {
+                derp_client.close().await;
+                return Err(ClientError::Send);
+            }
+        }
         debug!("built");
         Ok(derp_client)
     }
@@ -595,8 +649,8 @@ impl Client {
 
     /// Creates the uri string from a [`DerpNode`]
     ///
-    /// Return a TCP stream to the provided region, trying each node in order
-    /// (using [`Client::dial_node`]) until one connects
+    /// Return a TCP stream to the provided region, trying each node in order of its
+    /// health (using [`Client::dial_node`]) until one connects
     async fn dial_region(&self, reg: DerpRegion) -> Result<(TcpStream, DerpNode), ClientError> {
         debug!("dial region: {:?}", reg);
         let target = self.target_string(&reg);
@@ -606,23 +660,52 @@ impl Client {
         let mut first_err: Option<ClientError> = None;
         // TODO (ramfox): these dials should probably happen in parallel, and we should return the
         // first one to respond.
-        for node in reg.nodes {
+        for node in self.ordered_nodes(reg.nodes) {
             if node.stun_only {
                 if first_err.is_none() {
                     first_err = Some(ClientError::StunOnlyNodesFound(target.clone()));
                 }
                 continue;
             }
+            let start = Instant::now();
             let conn = self.dial_node(&node).await;
+            let mut node_health = self.inner.node_health.lock().unwrap();
+            let health = node_health.entry(node.name.clone()).or_default();
             match conn {
-                Ok(conn) => return Ok((conn, node)),
-                Err(e) => first_err = Some(e),
+                Ok(conn) => {
+                    health.record_success(start.elapsed());
+                    if first_err.is_some() {
+                        debug!("failed over to node {}", node.name);
+                        inc!(Metrics, node_failovers);
+                    }
+                    return Ok((conn, node));
+                }
+                Err(e) => {
+                    debug!("failed to dial node {}: {:?}", node.name, e);
+                    health.record_failure(Instant::now());
+                    inc!(Metrics, node_dial_failures);
+                    first_err = Some(e)
+                }
             }
         }
         let err = first_err.unwrap();
         Err(err)
     }
 
+    /// Orders the nodes of a region by their health, keeping the configured order for
+    /// nodes of the same health.
+    fn ordered_nodes(&self, mut nodes: Vec<DerpNode>) -> Vec<DerpNode> {
+        let node_health = self.inner.node_health.lock().unwrap();
+        let now = Instant::now();
+        nodes.sort_by_key(|node| {
+            node_health
+                .get(&node.name)
+                .map(|health| health.rank(now))
+                .unwrap_or((0, Duration::MAX))
+        });
+        nodes
+    }
+
     /// Returns a TCP connection to node n, racing IPv4 and IPv6
     /// (both as applicable) against each other.
     /// A node is only given `DIAL_NODE_TIMEOUT` to connect.
@@ -859,6 +942,66 @@ impl Client {
         Ok(())
     }
 
+    /// Send a packet from a key registered with [`Client::add_key`] to the server.
+    ///
+    /// If there is no underlying active derp connection, it creates one before attempting to
+    /// send the message.
+    ///
+    /// If there is an error sending the packet, it closes the underlying derp connection before
+    /// returning.
+    pub async fn send_from(
+        &self,
+        src_key: key::node::PublicKey,
+        dst_key: key::node::PublicKey,
+        b: Bytes,
+    ) -> Result<(), ClientError> {
+        debug!("send_from");
+        let (client, _) = self.connect().await?;
+        if client.send_from(src_key, dst_key, b).await.is_err() {
+            self.close_for_reconnect().await;
+            return Err(ClientError::Send);
+        }
+        Ok(())
+    }
+
+    /// Registers `secret_key` on the connection, so that packets for its public key are
+    /// received on this client as well, see [`DerpClient::add_key`].
+    ///
+    /// The key is registered again whenever the client reconnects. If there is no
+    /// underlying active derp connection, the key is registered when it is created.
+    pub async fn add_key(&self, secret_key: key::node::SecretKey) -> Result<(), ClientError> {
+        debug!("add_key");
+        self.inner
+            .extra_keys
+            .lock()
+            .unwrap()
+            .insert(secret_key.public_key(), secret_key.clone());
+        let client = self.inner.derp_client.lock().await.clone();
+        if let Some(client) = client {
+            if client.add_key(&secret_key).await.is_err() {
+                self.close_for_reconnect().await;
+                return Err(ClientError::Send);
+            }
+        }
+        Ok(())
+    }
+
+    /// Unregisters a key registered with [`Client::add_key`].
+    pub async fn remove_key(&self, key: &key::node::PublicKey) -> Result<(), ClientError> {
+        debug!("remove_key");
+        if self.inner.extra_keys.lock().unwrap().remove(key).is_none() {
+            return Ok(());
+        }
+        let client = self.inner.derp_client.lock().await.clone();
+        if let Some(client) = client {
+            if client.remove_key(key.clone()).await.is_err() {
+                self.close_for_reconnect().await;
+                return Err(ClientError::Send);
+            }
+        }
+        Ok(())
+    }
+
     /// Close the underlying derp connection. The next time the client takes some action that
     /// requires a connection, it will call `connect`.
     async fn close_for_reconnect(&self) {
@@ -1210,6 +1353,71 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_node_failover() -> Result<()> {
+        let http_server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .secret_key(Some(SecretKey::generate()))
+            .spawn()
+            .await?;
+        // nothing listens on the port of a dropped listener
+        let closed_port = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .port();
+        let node = |name: &str, port: u16| DerpNode {
+            name: name.to_string(),
+            region_id: 1,
+            url: format!("http://127.0.0.1:{port}").parse().unwrap(),
+            stun_only: false,
+            stun_port: 0,
+            stun_test_ip: None,
+            ipv4: UseIpv4::Some("127.0.0.1".parse().unwrap()),
+            ipv6: UseIpv6::Disabled,
+        };
+        let region = DerpRegion {
+            region_id: 1,
+            avoid: false,
+            nodes: vec![
+                node("down", closed_port),
+                node("up", http_server.addr().port()),
+            ],
+            region_code: "test_region".to_string(),
+        };
+        let nodes = region.nodes.clone();
+        let client = ClientBuilder::new()
+            .get_region(move || {
+                let region = region.clone();
+                Box::pin(async move { Some(region) })
+            })
+            .build(SecretKey::generate())?;
+
+        // the client fails over to the second node, and dials it first from then on
+        client.connect().await?;
+        let names = |nodes: Vec<DerpNode>| nodes.into_iter().map(|n| n.name).collect::<Vec<_>>();
+        assert_eq!(names(client.ordered_nodes(nodes.clone())), ["up", "down"]);
+        {
+            let node_health = client.inner.node_health.lock().unwrap();
+            assert_eq!(node_health["down"].failures, 1);
+            assert!(node_health["up"].latency.is_some());
+        }
+
+        // failures are forgotten after the backoff, but a node with a known latency is
+        // still preferred over one that never connected
+        let failed_at = Instant::now();
+        let mut health = NodeHealth::default();
+        health.record_failure(failed_at);
+        assert_eq!(health.rank(failed_at), (1, Duration::MAX));
+        assert_eq!(
+            health.rank(failed_at + NODE_FAILURE_BACKOFF),
+            (0, Duration::MAX)
+        );
+        health.record_success(Duration::from_millis(10));
+        assert_eq!(health.rank(Instant::now()), (0, Duration::from_millis(10)));
+
+        client.close().await;
+        http_server.shutdown().await;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_run_mesh_client() -> Result<()> {
         tracing_subscriber::registry()
@@ -30,6 +30,7 @@ use crate::{
     derp::{
         http::client::Client as HttpClient,
         http::mesh_clients::{MeshAddrs, MeshClients},
+        server::ClientAccess,
         server::ClientConnHandler,
         server::MaybeTlsStream,
         types::MeshKey,
@@ -165,6 +166,8 @@ pub struct ServerBuilder {
     /// Having a `mesh_depers` but no `mesh_key` when attempting to `spawn` a
     /// [`Server`] results in an error.
     mesh_derpers: Option<MeshAddrs>,
+    /// Which clients may connect to the derp server.
+    client_access: ClientAccess,
     /// Optional tls configuration/TlsAcceptor combination.
     ///
     /// When `None`, the server will serve HTTP, otherwise it will serve HTTPS.
@@ -195,6 +198,7 @@ impl ServerBuilder {
             addr,
             mesh_key: None,
             mesh_derpers: None,
+            client_access: ClientAccess::All,
             tls_config: None,
             handlers: Default::default(),
             derp_endpoint: "/derp",
@@ -225,6 +229,12 @@ impl ServerBuilder {
         self
     }
 
+    /// Restricts which clients may connect to the derp server, see [`ClientAccess`].
+    pub fn client_access(mut self, access: ClientAccess) -> Self {
+        self.client_access = access;
+        self
+    }
+
     /// Serve derp content using TLS.
     pub fn tls_config(mut self, config: Option<TlsConfig>) -> Self {
         self.tls_config = config;
@@ -271,7 +281,8 @@ impl ServerBuilder {
     pub async fn spawn(self) -> Result<Server> {
         ensure!(self.secret_key.is_some() || self.derp_override.is_some(), "Must provide a `SecretKey` for the derp server OR pass in an override function for the 'derp' endpoint");
         let (derp_handler, derp_server, mesh_clients) = if let Some(secret_key) = self.secret_key {
-            let server = crate::derp::server::Server::new(secret_key.clone(), self.mesh_key);
+            let mut server = crate::derp::server::Server::new(secret_key.clone(), self.mesh_key);
+            server.set_client_access(self.client_access);
             let header_map: HeaderMap = HeaderMap::from_iter(
                 self.headers
                     .iter()
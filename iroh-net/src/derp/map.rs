@@ -3,7 +3,7 @@
 use std::{
     collections::HashMap,
     fmt,
-    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
 };
 
 use serde::{Deserialize, Serialize};
@@ -26,6 +26,60 @@ impl DerpMap {
         ids
     }
 
+    /// Returns the sorted IDs of the regions with a DERP server, excluding regions with only
+    /// STUN servers.
+    pub fn derp_region_ids(&self) -> Vec<u16> {
+        let mut ids: Vec<_> = self
+            .regions
+            .values()
+            .filter(|r| r.has_derp_node())
+            .map(|r| r.region_id)
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    /// Adds a region of standalone STUN servers.
+    ///
+    /// The servers are only used to discover the public addresses of this node for hole
+    /// punching, never to relay packets. This allows hole punching in deployments which do
+    /// not run DERP servers.
+    pub fn add_stun_servers(
+        &mut self,
+        region_id: u16,
+        servers: impl IntoIterator<Item = SocketAddr>,
+    ) {
+        let nodes = servers
+            .into_iter()
+            .enumerate()
+            .map(|(i, addr)| {
+                let (ipv4, ipv6) = match addr.ip() {
+                    IpAddr::V4(ip) => (UseIpv4::Some(ip), UseIpv6::Disabled),
+                    IpAddr::V6(ip) => (UseIpv4::Disabled, UseIpv6::Some(ip)),
+                };
+                DerpNode {
+                    name: format!("stun-{region_id}-{i}"),
+                    region_id,
+                    url: format!("stun://{addr}").parse().expect("valid url"),
+                    stun_only: true,
+                    stun_port: addr.port(),
+                    stun_test_ip: None,
+                    ipv4,
+                    ipv6,
+                }
+            })
+            .collect();
+        self.regions.insert(
+            region_id,
+            DerpRegion {
+                region_id,
+                nodes,
+                avoid: false,
+                region_code: "stun".into(),
+            },
+        );
+    }
+
     /// Creates a new [`DerpMap`] with a single Derp server configured.
     ///
     /// Allows to set a custom STUN port and different IP addresses for IPv4 and IPv6.
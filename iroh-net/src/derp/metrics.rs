@@ -60,6 +60,16 @@ pub struct Metrics {
     pub accepts: Counter,
     /// Number of connections we have removed because of an error
     pub disconnects: Counter,
+    /// Number of connections we have rejected because the client is not allowed
+    pub rejected_clients: Counter,
+
+    /*
+     * Metrics about clients
+     */
+    /// Number of failed dials to DERP nodes
+    pub node_dial_failures: Counter,
+    /// Number of connections to a DERP node made after another node of the region failed
+    pub node_failovers: Counter,
     // TODO: enable when we can have multiple connections for one peer id
     // pub duplicate_client_keys: Counter,
     // pub duplicate_client_conns: Counter,
@@ -115,6 +125,17 @@ impl Default for Metrics {
 
             accepts: Counter::new("Number of times this server has accepted a connection."),
             disconnects: Counter::new("Number of clients that have then disconnected."),
+            rejected_clients: Counter::new(
+                "Number of connections rejected because the client is not allowed.",
+            ),
+
+            /*
+             * Metrics about clients
+             */
+            node_dial_failures: Counter::new("Number of times a client failed to dial a node."),
+            node_failovers: Counter::new(
+                "Number of times a client connected to a node after another node of the region failed.",
+            ),
             // TODO: enable when we can have multiple connections for one peer id
             // pub duplicate_client_keys: Counter::new("Number of dupliate client keys."),
             // pub duplicate_client_conns: Counter::new("Number of duplicate client connections."),
@@ -15,7 +15,7 @@ use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
-use tracing::{instrument, trace};
+use tracing::{info, instrument, trace};
 
 use crate::key::node::{PublicKey, SecretKey};
 
@@ -34,12 +34,38 @@ use super::{
 // TODO: skiping `verboseDropKeys` for now
 
 static CONN_NUM: AtomicUsize = AtomicUsize::new(1);
-fn new_conn_num() -> usize {
+pub(crate) fn new_conn_num() -> usize {
     CONN_NUM.fetch_add(1, Ordering::Relaxed)
 }
 
 pub(crate) const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
 
+/// Restricts which clients may use a [`Server`], by their [`PublicKey`].
+///
+/// Clients of the same mesh network are always accepted, so meshed servers do not need to
+/// be listed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum ClientAccess {
+    /// Accept all clients.
+    #[default]
+    All,
+    /// Only accept the listed clients.
+    Allow(HashSet<PublicKey>),
+    /// Accept all clients except the listed ones.
+    Deny(HashSet<PublicKey>),
+}
+
+impl ClientAccess {
+    /// Reports whether the client with `key` may connect.
+    pub fn is_allowed(&self, key: &PublicKey) -> bool {
+        match self {
+            ClientAccess::All => true,
+            ClientAccess::Allow(keys) => keys.contains(key),
+            ClientAccess::Deny(keys) => !keys.contains(key),
+        }
+    }
+}
+
 /// A DERP server.
 ///
 /// Responsible for managing connections to derp [`super::client::Client`]s, sending/forwarding packets
@@ -62,6 +88,8 @@ where
     // be discussed and worked out.
     // from go impl: log.Fatalf("key in %s must contain 64+ hex digits", *meshPSKFile)
     mesh_key: Option<MeshKey>,
+    /// Which clients may connect.
+    client_access: Arc<ClientAccess>,
     /// The DER encoded x509 cert to send after `LetsEncrypt` cert+intermediate.
     meta_cert: Vec<u8>,
     /// Channel on which to communicate to the [`ServerActor`]
@@ -127,6 +155,7 @@ where
             write_timeout: Some(WRITE_TIMEOUT),
             secret_key: key,
             mesh_key,
+            client_access: Default::default(),
             meta_cert,
             server_channel: server_channel_s,
             closed: false,
@@ -147,6 +176,18 @@ where
         self.mesh_key
     }
 
+    /// Restricts which clients may connect.
+    ///
+    /// Only applies to the [`ClientConnHandler`]s created after this is called.
+    pub fn set_client_access(&mut self, access: ClientAccess) {
+        self.client_access = Arc::new(access);
+    }
+
+    /// Returns which clients may connect.
+    pub fn client_access(&self) -> &ClientAccess {
+        &self.client_access
+    }
+
     /// Returns the server's private key.
     pub fn private_key(&self) -> SecretKey {
         self.secret_key.clone()
@@ -192,6 +233,7 @@ where
     pub fn client_conn_handler(&self, default_headers: HeaderMap) -> ClientConnHandler<P> {
         ClientConnHandler {
             mesh_key: self.mesh_key,
+            client_access: Arc::clone(&self.client_access),
             server_channel: self.server_channel.clone(),
             secret_key: self.secret_key.clone(),
             write_timeout: self.write_timeout,
@@ -271,6 +313,7 @@ where
     P: PacketForwarder,
 {
     mesh_key: Option<MeshKey>,
+    client_access: Arc<ClientAccess>,
     server_channel: mpsc::Sender<ServerMessage<P>>,
     secret_key: SecretKey,
     write_timeout: Option<Duration>,
@@ -285,6 +328,7 @@ where
     fn clone(&self) -> Self {
         Self {
             mesh_key: self.mesh_key,
+            client_access: Arc::clone(&self.client_access),
             server_channel: self.server_channel.clone(),
             secret_key: self.secret_key.clone(),
             write_timeout: self.write_timeout,
@@ -302,7 +346,8 @@ where
     ///
     /// Will error if it takes too long (10 sec) to write or read to the connection, if there is
     /// some read or write error to the connection,  if the server is meant to verify clients,
-    /// and is unable to verify this one, or if there is some issue communicating with the server.
+    /// and is unable to verify this one, if the client is not allowed by the [`ClientAccess`]
+    /// of the server, or if there is some issue communicating with the server.
     ///
     /// The provided [`AsyncRead`] and [`AsyncWrite`] must be already connected to the connection.
     pub async fn accept(&self, mut io: MaybeTlsStream) -> Result<()> {
@@ -314,6 +359,12 @@ where
         let (client_key, client_info) = recv_client_key(self.secret_key.clone(), &mut io)
             .await
             .context("unable to receive client information")?;
+        let can_mesh = self.can_mesh(client_info.mesh_key);
+        if !can_mesh && !self.client_access.is_allowed(&client_key) {
+            inc!(Metrics, rejected_clients);
+            info!(client = ?client_key, "rejected client: not allowed");
+            anyhow::bail!("client {client_key:?} is not allowed");
+        }
         trace!("accept: send server info");
         self.send_server_info(&mut io, &client_key)
             .await
@@ -323,10 +374,12 @@ where
             key: client_key,
             conn_num: new_conn_num(),
             io,
-            can_mesh: self.can_mesh(client_info.mesh_key),
+            can_mesh,
             write_timeout: self.write_timeout,
             channel_capacity: PER_CLIENT_SEND_QUEUE_DEPTH,
             server_channel: self.server_channel.clone(),
+            secret_key: self.secret_key.clone(),
+            client_access: Arc::clone(&self.client_access),
         };
         trace!("accept: create client");
         let client = client_conn_builder.build();
@@ -723,6 +776,8 @@ mod tests {
                 write_timeout: None,
                 channel_capacity: 10,
                 server_channel,
+                secret_key: SecretKey::generate(),
+                client_access: Default::default(),
             },
             test_io,
         )
@@ -882,6 +937,7 @@ mod tests {
         let (server_channel_s, mut server_channel_r) = mpsc::channel(10);
         let handler = ClientConnHandler::<MockPacketForwarder> {
             mesh_key: Some([1u8; 32]),
+            client_access: Default::default(),
             secret_key: SecretKey::generate(),
             write_timeout: None,
             server_info: ServerInfo::no_rate_limit(),
@@ -1048,6 +1104,34 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_server_client_access() -> Result<()> {
+        let mut server: Server<MockPacketForwarder> = Server::new(SecretKey::generate(), None);
+        let key_a = SecretKey::generate();
+        let key_b = SecretKey::generate();
+        server.set_client_access(ClientAccess::Allow(HashSet::from([key_a.public_key()])));
+
+        // client a is allowed
+        let (rw_a, client_a_builder) = make_test_client(key_a);
+        let handler = server.client_conn_handler(Default::default());
+        let handler_task =
+            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(rw_a)).await });
+        let _client_a = client_a_builder.build(None).await?;
+        handler_task.await??;
+
+        // client b is rejected before it receives the server info
+        let (rw_b, client_b_builder) = make_test_client(key_b);
+        let handler = server.client_conn_handler(Default::default());
+        let handler_task =
+            tokio::spawn(async move { handler.accept(MaybeTlsStream::Test(rw_b)).await });
+        let (client_b, handled) = tokio::join!(client_b_builder.build(None), handler_task);
+        assert!(handled?.is_err());
+        assert!(client_b.is_err());
+
+        server.close().await;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_server_replace_client() -> Result<()> {
         tracing_subscriber::registry()
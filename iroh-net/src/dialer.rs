@@ -0,0 +1,178 @@
+//! A dialer shared by all subsystems of a node.
+//!
+//! The [`Dialer`] connects to peers by their [`PeerId`] with
+//! [`MagicEndpoint::connect_by_id`], and keeps the established connections: asking for a
+//! connection to a peer on an ALPN which already has an open one returns that connection
+//! instead of dialing again. Concurrent requests for the same peer and ALPN share a single
+//! dial, and the number of dials running at the same time is limited.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, Weak};
+
+use anyhow::anyhow;
+use futures::future::{BoxFuture, Shared};
+use futures::FutureExt;
+use tokio::sync::Semaphore;
+use tracing::debug;
+
+use crate::tls::PeerId;
+use crate::util::AbortingJoinHandle;
+use crate::MagicEndpoint;
+
+/// The default maximum number of dials a [`Dialer`] runs at the same time.
+pub const DEFAULT_MAX_CONCURRENT_DIALS: usize = 16;
+
+type DialResult = Result<quinn::Connection, Arc<anyhow::Error>>;
+
+type Slots = Mutex<HashMap<(PeerId, Vec<u8>), Slot>>;
+
+/// The connection to a peer on an ALPN.
+enum Slot {
+    /// The connection is established, it might have been closed since.
+    Connected(quinn::Connection),
+    /// The peer is being dialed.
+    Dialing(Shared<BoxFuture<'static, DialResult>>),
+}
+
+/// Dials peers, reusing open connections.
+///
+/// Cloning the dialer is cheap, clones share the connections and the concurrency limit.
+///
+/// [`Dialer::connect`] is cancel safe: dropping its future does not abort the dial, which
+/// is completed and its connection kept for the next request. Dials still running when the
+/// last clone of the dialer is dropped are aborted.
+#[derive(Clone)]
+pub struct Dialer {
+    endpoint: MagicEndpoint,
+    slots: Arc<Slots>,
+    limit: Arc<Semaphore>,
+}
+
+impl fmt::Debug for Dialer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let slots = self.slots.lock().unwrap();
+        f.debug_struct("Dialer")
+            .field("peer_id", &self.endpoint.peer_id())
+            .field("connections", &slots.len())
+            .field("available_permits", &self.limit.available_permits())
+            .finish()
+    }
+}
+
+impl Dialer {
+    /// Creates a dialer running at most [`DEFAULT_MAX_CONCURRENT_DIALS`] dials at a time.
+    pub fn new(endpoint: MagicEndpoint) -> Self {
+        Self::with_limit(endpoint, DEFAULT_MAX_CONCURRENT_DIALS)
+    }
+
+    /// Creates a dialer running at most `max_concurrent_dials` dials at a time.
+    ///
+    /// Further dials wait until a running one finishes.
+    pub fn with_limit(endpoint: MagicEndpoint, max_concurrent_dials: usize) -> Self {
+        Self {
+            endpoint,
+            slots: Default::default(),
+            limit: Arc::new(Semaphore::new(max_concurrent_dials)),
+        }
+    }
+
+    /// Returns a connection to `peer_id` on `alpn`.
+    ///
+    /// Returns the open connection if there is one, waits for the running dial if the peer
+    /// is being dialed already, and dials it otherwise.
+    pub async fn connect(&self, peer_id: PeerId, alpn: &[u8]) -> anyhow::Result<quinn::Connection> {
+        let dial = {
+            let mut slots = self.slots.lock().unwrap();
+            let key = (peer_id, alpn.to_vec());
+            match slots.get(&key) {
+                Some(Slot::Connected(conn)) if conn.close_reason().is_none() => {
+                    return Ok(conn.clone());
+                }
+                Some(Slot::Dialing(dial)) => dial.clone(),
+                _ => {
+                    let dial = self.start_dial(peer_id, alpn.to_vec());
+                    slots.insert(key, Slot::Dialing(dial.clone()));
+                    dial
+                }
+            }
+        };
+        dial.await.map_err(|err| anyhow!("{err:#}"))
+    }
+
+    /// Adds `conn` to `peer_id` on `alpn`, which was established otherwise, e.g. accepted.
+    ///
+    /// It is returned by [`Dialer::connect`] unless there already is an open connection or a
+    /// running dial, which take precedence.
+    pub fn insert(&self, peer_id: PeerId, alpn: &[u8], conn: quinn::Connection) {
+        let mut slots = self.slots.lock().unwrap();
+        let key = (peer_id, alpn.to_vec());
+        match slots.get(&key) {
+            Some(Slot::Connected(current)) if current.close_reason().is_none() => {}
+            Some(Slot::Dialing(_)) => {}
+            _ => {
+                slots.insert(key, Slot::Connected(conn));
+            }
+        }
+    }
+
+    /// Whether `peer_id` is being dialed on `alpn`.
+    pub fn is_pending(&self, peer_id: PeerId, alpn: &[u8]) -> bool {
+        let slots = self.slots.lock().unwrap();
+        matches!(slots.get(&(peer_id, alpn.to_vec())), Some(Slot::Dialing(_)))
+    }
+
+    /// The endpoint the dialer connects with.
+    pub fn endpoint(&self) -> &MagicEndpoint {
+        &self.endpoint
+    }
+
+    fn start_dial(&self, peer_id: PeerId, alpn: Vec<u8>) -> Shared<BoxFuture<'static, DialResult>> {
+        let endpoint = self.endpoint.clone();
+        let limit = self.limit.clone();
+        // The slots own the dial, a strong reference would keep them alive until it is done.
+        let slots = Arc::downgrade(&self.slots);
+        let task = tokio::spawn(async move {
+            let _permit = limit
+                .acquire_owned()
+                .await
+                .expect("semaphore is never closed");
+            let res = match endpoint.connect_by_id(peer_id, &alpn).await {
+                Ok((conn, source)) => {
+                    debug!("connected to {peer_id} via {source:?}");
+                    Ok(conn)
+                }
+                Err(err) => {
+                    debug!("failed to connect to {peer_id}: {err:#}");
+                    Err(Arc::new(err))
+                }
+            };
+            finish_dial(&slots, peer_id, alpn, &res);
+            res
+        });
+        let task = AbortingJoinHandle::from(task);
+        async move {
+            task.await
+                .unwrap_or_else(|err| Err(Arc::new(anyhow!("dial failed: {err}"))))
+        }
+        .boxed()
+        .shared()
+    }
+}
+
+/// Replaces the slot of a finished dial with its connection, or clears it if it failed.
+fn finish_dial(slots: &Weak<Slots>, peer_id: PeerId, alpn: Vec<u8>, res: &DialResult) {
+    let Some(slots) = slots.upgrade() else {
+        return;
+    };
+    let mut slots = slots.lock().unwrap();
+    let key = (peer_id, alpn);
+    match res {
+        Ok(conn) => {
+            slots.insert(key, Slot::Connected(conn.clone()));
+        }
+        Err(_) => {
+            slots.remove(&key);
+        }
+    }
+}
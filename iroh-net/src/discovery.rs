@@ -0,0 +1,264 @@
+//! Discovery of the addresses of peers by their [`PeerId`].
+//!
+//! A [`Discovery`] service publishes the addresses of this node, and resolves the addresses
+//! of other peers. A [`MagicEndpoint`] with a discovery service publishes its addresses
+//! whenever they change, and resolves peers it is asked to connect to without any
+//! addresses or DERP region.
+//!
+//! [`PkarrDiscovery`] publishes signed DNS records to a [pkarr] relay.
+//! [`LocalDiscovery`](crate::local_discovery::LocalDiscovery) discovers peers on the local
+//! network.
+//!
+//! [`MagicEndpoint`]: crate::MagicEndpoint
+//! [pkarr]: https://github.com/nuhvi/pkarr
+
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, ensure, Context, Result};
+use ed25519_dalek::{Signer, Verifier};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use tracing::{debug, warn};
+use trust_dns_proto::op::{Message, MessageType};
+use trust_dns_proto::rr::rdata::TXT;
+use trust_dns_proto::rr::{Name, RData, Record};
+use trust_dns_proto::serialize::binary::{BinDecodable, BinEncodable};
+use url::Url;
+
+use crate::tls::{Keypair, PeerId, PublicKey, Signature};
+use crate::util::AbortingJoinHandle;
+
+/// The addresses at which a peer can be reached.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerAddr {
+    /// The peer.
+    pub peer_id: PeerId,
+    /// The DERP region of the peer.
+    pub derp_region: Option<u16>,
+    /// The direct addresses of the peer.
+    pub addrs: Vec<SocketAddr>,
+}
+
+/// A service to publish the addresses of this node, and to resolve the addresses of peers.
+pub trait Discovery: std::fmt::Debug + Send + Sync + 'static {
+    /// Publishes the addresses of this node.
+    ///
+    /// Called whenever the addresses change. Publishing happens in the background, failures
+    /// are logged. The default implementation does nothing.
+    fn publish(&self, _info: &PeerAddr) {}
+
+    /// Resolves the addresses of a peer.
+    fn resolve(&self, peer_id: PeerId) -> BoxFuture<'_, Result<PeerAddr>>;
+}
+
+/// The name of the records holding the addresses, relative to the public key.
+const RECORD_NAME: &str = "_iroh";
+
+/// The time to live of the published records.
+const PUBLISH_TTL: u32 = 30 * 60;
+
+/// Timeout for requests to the relay.
+const RELAY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum size of the DNS packet of a signed packet, as accepted by pkarr relays.
+const MAX_PACKET_SIZE: usize = 1000;
+
+/// The z-base-32 encoding pkarr uses for public keys.
+static Z_BASE_32: Lazy<data_encoding::Encoding> = Lazy::new(|| {
+    let mut spec = data_encoding::Specification::new();
+    spec.symbols.push_str("ybndrfg8ejkmcpqxot1uwisza345h769");
+    spec.encoding().expect("valid z-base-32 specification")
+});
+
+/// Publishes and resolves addresses as signed DNS records on a [pkarr] relay.
+///
+/// The addresses are stored as `TXT` records named `_iroh`, one `derp=<region>` and one
+/// `addr=<socket addr>` record per address, signed by the key of the peer. Anyone can
+/// resolve the addresses of a peer, publishing requires its keypair.
+///
+/// [pkarr]: https://github.com/nuhvi/pkarr
+#[derive(Debug)]
+pub struct PkarrDiscovery {
+    relay: Url,
+    keypair: Option<Keypair>,
+    client: reqwest::Client,
+    /// The running publish request, replaced by newer ones.
+    publish_task: Mutex<Option<AbortingJoinHandle<()>>>,
+}
+
+impl PkarrDiscovery {
+    /// Creates a discovery service which only resolves peers through the `relay`.
+    pub fn new(relay: Url) -> Self {
+        Self {
+            relay,
+            keypair: None,
+            client: reqwest::Client::new(),
+            publish_task: Default::default(),
+        }
+    }
+
+    /// Creates a discovery service which also publishes the addresses of the node with
+    /// the given keypair.
+    pub fn with_keypair(relay: Url, keypair: Keypair) -> Self {
+        Self {
+            keypair: Some(keypair),
+            ..Self::new(relay)
+        }
+    }
+
+    fn url(&self, peer_id: PeerId) -> Result<Url> {
+        let key: PublicKey = peer_id.into();
+        let relay = self.relay.as_str().trim_end_matches('/');
+        let url = format!("{relay}/{}", Z_BASE_32.encode(key.as_bytes())).parse()?;
+        Ok(url)
+    }
+}
+
+impl Discovery for PkarrDiscovery {
+    fn publish(&self, info: &PeerAddr) {
+        let Some(keypair) = &self.keypair else {
+            return;
+        };
+        let request = encode_signed_packet(keypair, info, timestamp()).and_then(|packet| {
+            let url = self.url(info.peer_id)?;
+            Ok(self.client.put(url).body(packet).timeout(RELAY_TIMEOUT))
+        });
+        let request = match request {
+            Ok(request) => request,
+            Err(err) => {
+                warn!("failed to publish addresses: {err:#}");
+                return;
+            }
+        };
+        let task = tokio::spawn(async move {
+            match request.send().await.and_then(|res| res.error_for_status()) {
+                Ok(_) => debug!("published addresses"),
+                Err(err) => warn!("failed to publish addresses: {err}"),
+            }
+        });
+        *self.publish_task.lock().unwrap() = Some(task.into());
+    }
+
+    fn resolve(&self, peer_id: PeerId) -> BoxFuture<'_, Result<PeerAddr>> {
+        async move {
+            let res = self
+                .client
+                .get(self.url(peer_id)?)
+                .timeout(RELAY_TIMEOUT)
+                .send()
+                .await?
+                .error_for_status()
+                .with_context(|| format!("failed to resolve {peer_id}"))?;
+            let packet = res.bytes().await?;
+            decode_signed_packet(peer_id, &packet)
+        }
+        .boxed()
+    }
+}
+
+/// Microseconds since the unix epoch, the sequence number of a signed packet.
+fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or_default()
+}
+
+/// The message signed in a signed packet, the bencoded sequence number and DNS packet.
+fn signable(seq: u64, packet: &[u8]) -> Vec<u8> {
+    let mut signable = format!("3:seqi{seq}e1:v{}:", packet.len()).into_bytes();
+    signable.extend_from_slice(packet);
+    signable
+}
+
+/// Encodes `info` as a signed packet: the signature, the sequence number and the DNS packet.
+fn encode_signed_packet(keypair: &Keypair, info: &PeerAddr, seq: u64) -> Result<Vec<u8>> {
+    let key = Z_BASE_32.encode(keypair.public().as_bytes());
+    let name = Name::from_ascii(format!("{RECORD_NAME}.{key}."))?;
+    let mut msg = Message::new();
+    msg.set_message_type(MessageType::Response);
+    msg.set_authoritative(true);
+    let values = info
+        .derp_region
+        .map(|region| format!("derp={region}"))
+        .into_iter()
+        .chain(info.addrs.iter().map(|addr| format!("addr={addr}")));
+    for value in values {
+        let txt = RData::TXT(TXT::new(vec![value]));
+        msg.add_answer(Record::from_rdata(name.clone(), PUBLISH_TTL, txt));
+    }
+    let packet = msg.to_bytes()?;
+    ensure!(packet.len() <= MAX_PACKET_SIZE, "too many addresses");
+
+    let signature = keypair.secret().sign(&signable(seq, &packet));
+    let mut signed = signature.to_bytes().to_vec();
+    signed.extend_from_slice(&seq.to_be_bytes());
+    signed.extend_from_slice(&packet);
+    Ok(signed)
+}
+
+/// Verifies a signed packet of `peer_id` and extracts the addresses.
+fn decode_signed_packet(peer_id: PeerId, signed: &[u8]) -> Result<PeerAddr> {
+    ensure!(signed.len() >= 72, "signed packet too short");
+    let (signature, rest) = signed.split_at(64);
+    let (seq, packet) = rest.split_at(8);
+    let seq = u64::from_be_bytes(seq.try_into()?);
+    let signature = Signature::from_slice(signature)?;
+    let key: PublicKey = peer_id.into();
+    key.verify(&signable(seq, packet), &signature)
+        .context("invalid signature")?;
+
+    let mut info = PeerAddr {
+        peer_id,
+        derp_region: None,
+        addrs: Vec::new(),
+    };
+    let msg = Message::from_bytes(packet)?;
+    for record in msg.answers() {
+        if record.name().iter().next() != Some(RECORD_NAME.as_bytes()) {
+            continue;
+        }
+        let Some(RData::TXT(txt)) = record.data() else {
+            continue;
+        };
+        for value in txt.iter() {
+            let value = std::str::from_utf8(value)?;
+            match value.split_once('=') {
+                Some(("derp", region)) => info.derp_region = Some(region.parse()?),
+                Some(("addr", addr)) => info.addrs.push(addr.parse()?),
+                _ => bail!("invalid record {value:?}"),
+            }
+        }
+    }
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signed_packet_roundtrip() -> Result<()> {
+        let keypair = Keypair::generate();
+        let info = PeerAddr {
+            peer_id: keypair.public().into(),
+            derp_region: Some(2),
+            addrs: vec!["1.2.3.4:5".parse()?, "[::1]:6".parse()?],
+        };
+        let mut packet = encode_signed_packet(&keypair, &info, timestamp())?;
+        assert_eq!(decode_signed_packet(info.peer_id, &packet)?, info);
+
+        // Signed by someone else.
+        let other: PeerId = Keypair::generate().public().into();
+        assert!(decode_signed_packet(other, &packet).is_err());
+
+        // Tampered with.
+        let last = packet.len() - 1;
+        packet[last] ^= 1;
+        assert!(decode_signed_packet(info.peer_id, &packet).is_err());
+        Ok(())
+    }
+}
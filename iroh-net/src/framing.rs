@@ -0,0 +1,339 @@
+//! Length prefixed framing of messages on byte streams.
+//!
+//! A [`Framing`] describes how frames are delimited on a stream: which [`Prefix`] encodes
+//! the length of a frame and how large a frame may be. Frames are either raw bytes, written
+//! with [`Framing::write_frame`] and read with [`Framing::read_frame`], or [postcard] encoded
+//! messages, written with [`Framing::write_message`] and read with [`Framing::read_message`].
+//!
+//! Reading never consumes bytes past the end of the frame, so a stream can continue with
+//! data in another format after a frame. Reads are cancel safe: the partially read frame is
+//! kept in the buffer passed to the read, and a later read with the same buffer continues
+//! where the cancelled one stopped.
+//!
+//! ```
+//! # async fn example() -> anyhow::Result<()> {
+//! use bytes::BytesMut;
+//! use iroh_net::framing::{Framing, Prefix};
+//!
+//! let framing = Framing::new(Prefix::Varint, 1024);
+//! let mut stream = Vec::new();
+//! framing.write_message(&mut stream, &(1u8, "hello")).await?;
+//!
+//! let mut buffer = BytesMut::new();
+//! let message: Option<(u8, String)> = framing.read_message(&stream[..], &mut buffer).await?;
+//! assert_eq!(message, Some((1, "hello".to_string())));
+//! # Ok(())
+//! # }
+//! ```
+use anyhow::{bail, ensure, Context, Result};
+use bytes::{Buf, Bytes, BytesMut};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The maximum length of an encoded [`Prefix`].
+const MAX_PREFIX_LEN: usize = 10;
+
+/// The encoding of the length prefix of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Prefix {
+    /// An unsigned LEB128 varint.
+    Varint,
+    /// A big endian `u32`.
+    U32,
+    /// A little endian `u64`.
+    U64Le,
+}
+
+/// The result of decoding a [`Prefix`] from the start of a buffer.
+#[derive(Debug)]
+enum Decoded {
+    /// The buffer does not contain the complete prefix, at least this many bytes are missing.
+    Incomplete(usize),
+    /// The prefix is complete.
+    Complete { prefix_len: usize, len: u64 },
+}
+
+impl Prefix {
+    fn encode(self, len: u64, out: &mut [u8; MAX_PREFIX_LEN]) -> usize {
+        match self {
+            Prefix::Varint => {
+                let mut len = len;
+                let mut i = 0;
+                loop {
+                    let byte = (len & 0x7f) as u8;
+                    len >>= 7;
+                    if len == 0 {
+                        out[i] = byte;
+                        return i + 1;
+                    }
+                    out[i] = byte | 0x80;
+                    i += 1;
+                }
+            }
+            Prefix::U32 => {
+                out[..4].copy_from_slice(&(len as u32).to_be_bytes());
+                4
+            }
+            Prefix::U64Le => {
+                out[..8].copy_from_slice(&len.to_le_bytes());
+                8
+            }
+        }
+    }
+
+    fn decode(self, buf: &[u8]) -> Result<Decoded> {
+        let fixed_len = match self {
+            Prefix::Varint => {
+                let mut len = 0u64;
+                for (i, byte) in buf.iter().enumerate() {
+                    ensure!(
+                        i < MAX_PREFIX_LEN && (i < MAX_PREFIX_LEN - 1 || *byte <= 1),
+                        "varint length prefix overflows u64"
+                    );
+                    len |= u64::from(byte & 0x7f) << (7 * i);
+                    if byte & 0x80 == 0 {
+                        return Ok(Decoded::Complete {
+                            prefix_len: i + 1,
+                            len,
+                        });
+                    }
+                }
+                // the length of the varint is only known once its last byte is read
+                return Ok(Decoded::Incomplete(1));
+            }
+            Prefix::U32 => 4,
+            Prefix::U64Le => 8,
+        };
+        if buf.len() < fixed_len {
+            return Ok(Decoded::Incomplete(fixed_len - buf.len()));
+        }
+        let len = match self {
+            Prefix::U32 => u32::from_be_bytes(buf[..4].try_into().unwrap()).into(),
+            _ => u64::from_le_bytes(buf[..8].try_into().unwrap()),
+        };
+        Ok(Decoded::Complete {
+            prefix_len: fixed_len,
+            len,
+        })
+    }
+}
+
+/// The framing of a stream of length prefixed frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Framing {
+    prefix: Prefix,
+    max_size: usize,
+}
+
+impl Framing {
+    /// Creates a framing with length prefixes encoded as `prefix`, and frames of at most
+    /// `max_size` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `prefix` is [`Prefix::U32`] and `max_size` does not fit into it.
+    pub const fn new(prefix: Prefix, max_size: usize) -> Self {
+        assert!(
+            !matches!(prefix, Prefix::U32) || max_size as u64 <= u32::MAX as u64,
+            "the maximum frame size does not fit into a u32 length prefix"
+        );
+        Self { prefix, max_size }
+    }
+
+    /// The encoding of the length prefixes.
+    pub fn prefix(&self) -> Prefix {
+        self.prefix
+    }
+
+    /// The maximum size of a frame, not including its length prefix.
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Writes `data` as a single frame.
+    ///
+    /// Fails without writing anything if `data` is larger than the maximum frame size.
+    pub async fn write_frame(
+        &self,
+        mut writer: impl AsyncWrite + Unpin,
+        data: &[u8],
+    ) -> Result<()> {
+        ensure!(
+            data.len() <= self.max_size,
+            "frame of {} bytes exceeds the maximum size of {} bytes",
+            data.len(),
+            self.max_size
+        );
+        let mut prefix = [0u8; MAX_PREFIX_LEN];
+        let prefix_len = self.prefix.encode(data.len() as u64, &mut prefix);
+        writer.write_all(&prefix[..prefix_len]).await?;
+        writer.write_all(data).await?;
+        Ok(())
+    }
+
+    /// Reads a single frame.
+    ///
+    /// Returns `None` if the end of the stream is reached before a new frame starts, and
+    /// fails if it is reached in the middle of a frame.
+    ///
+    /// `buffer` must be empty when reading a new frame. This is cancel safe: if the future is
+    /// dropped before it completes, the bytes read so far are left in `buffer` and calling
+    /// this again with the same buffer completes the frame.
+    pub async fn read_frame(
+        &self,
+        mut reader: impl AsyncRead + Unpin,
+        buffer: &mut BytesMut,
+    ) -> Result<Option<Bytes>> {
+        loop {
+            let missing = match self.prefix.decode(buffer)? {
+                Decoded::Incomplete(missing) => missing,
+                Decoded::Complete { prefix_len, len } => {
+                    let len = usize::try_from(len)
+                        .ok()
+                        .filter(|len| *len <= self.max_size)
+                        .with_context(|| {
+                            format!(
+                                "incoming frame of {len} bytes exceeds the maximum size of {} bytes",
+                                self.max_size
+                            )
+                        })?;
+                    let frame_len = prefix_len + len;
+                    if buffer.len() >= frame_len {
+                        let mut frame = buffer.split_to(frame_len);
+                        frame.advance(prefix_len);
+                        return Ok(Some(frame.freeze()));
+                    }
+                    frame_len - buffer.len()
+                }
+            };
+            // only read up to the end of the frame, to leave any following data in the reader
+            buffer.reserve(missing);
+            let read = (&mut reader).take(missing as u64).read_buf(buffer).await?;
+            if read == 0 {
+                if buffer.is_empty() {
+                    return Ok(None);
+                }
+                bail!("stream ended in the middle of a frame");
+            }
+        }
+    }
+
+    /// Writes a [postcard] encoded message as a single frame.
+    pub async fn write_message<T: Serialize>(
+        &self,
+        writer: impl AsyncWrite + Unpin,
+        message: &T,
+    ) -> Result<()> {
+        let data = postcard::to_stdvec(message)?;
+        self.write_frame(writer, &data).await
+    }
+
+    /// Reads a single frame containing a [postcard] encoded message.
+    ///
+    /// Behaves like [`Framing::read_frame`], including its cancel safety.
+    pub async fn read_message<T: DeserializeOwned>(
+        &self,
+        reader: impl AsyncRead + Unpin,
+        buffer: &mut BytesMut,
+    ) -> Result<Option<T>> {
+        let Some(frame) = self.read_frame(reader, buffer).await? else {
+            return Ok(None);
+        };
+        let message = postcard::from_bytes(&frame).context("invalid message")?;
+        Ok(Some(message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const PREFIXES: [Prefix; 3] = [Prefix::Varint, Prefix::U32, Prefix::U64Le];
+
+    #[tokio::test]
+    async fn test_frame_roundtrip() -> Result<()> {
+        for prefix in PREFIXES {
+            let framing = Framing::new(prefix, 1024);
+            let mut stream = Vec::new();
+            for len in [0, 1, 127, 128, 300, 1024] {
+                framing.write_frame(&mut stream, &vec![7u8; len]).await?;
+            }
+            stream.extend_from_slice(b"trailer");
+            assert!(framing
+                .write_frame(&mut stream, &[0u8; 1025])
+                .await
+                .is_err());
+
+            let mut reader = &stream[..];
+            let mut buffer = BytesMut::new();
+            for len in [0, 1, 127, 128, 300, 1024] {
+                let frame = framing.read_frame(&mut reader, &mut buffer).await?;
+                assert_eq!(frame, Some(vec![7u8; len].into()), "{prefix:?}");
+            }
+            assert_eq!(reader, b"trailer");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_errors() -> Result<()> {
+        for prefix in PREFIXES {
+            let framing = Framing::new(prefix, 16);
+            let mut buffer = BytesMut::new();
+            assert_eq!(framing.read_frame(&[][..], &mut buffer).await?, None);
+
+            let mut stream = Vec::new();
+            Framing::new(prefix, 32)
+                .write_frame(&mut stream, &[0u8; 17])
+                .await?;
+            assert!(framing.read_frame(&stream[..], &mut buffer).await.is_err());
+
+            let mut stream = Vec::new();
+            framing.write_frame(&mut stream, &[0u8; 16]).await?;
+            let mut buffer = BytesMut::new();
+            let res = framing.read_frame(&stream[..10], &mut buffer).await;
+            assert!(res.is_err(), "{prefix:?}");
+        }
+
+        let framing = Framing::new(Prefix::Varint, usize::MAX);
+        let mut buffer = BytesMut::new();
+        let res = framing.read_frame(&[0xff; 11][..], &mut buffer).await;
+        assert!(res.is_err());
+        Ok(())
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    #[should_panic(expected = "u32 length prefix")]
+    fn test_u32_max_size() {
+        Framing::new(Prefix::U32, u32::MAX as usize + 1);
+    }
+
+    #[tokio::test]
+    async fn test_read_cancel_safe() -> Result<()> {
+        let framing = Framing::new(Prefix::Varint, 1024);
+        let (mut writer, mut reader) = tokio::io::duplex(1024);
+        let mut buffer = BytesMut::new();
+
+        let mut data = Vec::new();
+        framing.write_message(&mut data, &"hello world").await?;
+        let (first, second) = data.split_at(3);
+        writer.write_all(first).await?;
+        let res = tokio::time::timeout(
+            Duration::from_millis(10),
+            framing.read_message::<String>(&mut reader, &mut buffer),
+        )
+        .await;
+        assert!(res.is_err());
+        assert_eq!(&buffer[..], first);
+
+        writer.write_all(second).await?;
+        drop(writer);
+        let message: Option<String> = framing.read_message(&mut reader, &mut buffer).await?;
+        assert_eq!(message.as_deref(), Some("hello world"));
+        assert_eq!(framing.read_frame(&mut reader, &mut buffer).await?, None);
+        Ok(())
+    }
+}
@@ -8,19 +8,28 @@
 #![deny(missing_docs, rustdoc::broken_intra_doc_links)]
 
 pub mod config;
+pub mod datagram;
 pub mod defaults;
 pub mod derp;
+pub mod dialer;
 mod disco;
+pub mod discovery;
 mod dns;
+pub mod framing;
 pub mod key;
+pub mod local_discovery;
 pub mod magic_endpoint;
 pub mod magicsock;
 pub mod metrics;
 pub mod net;
 pub mod netcheck;
 pub mod netmap;
+pub mod peer_store;
 pub mod ping;
+pub mod pool;
 pub mod portmapper;
+pub mod request;
+pub mod streams;
 pub mod stun;
 pub mod tls;
 pub mod util;
@@ -0,0 +1,350 @@
+//! Discovery of peers on the local network with mDNS.
+//!
+//! [`LocalDiscovery`] announces the [`PeerId`] and the QUIC port of a node as a DNS-SD
+//! service of type [`SERVICE_NAME`] on the mDNS multicast group, and listens for the
+//! announcements of other nodes on the same network. A [`MagicEndpoint`] with local
+//! discovery enabled adds the addresses of discovered peers to its magic socket, so
+//! connections to them are made directly without going through DERP.
+//!
+//! Only IPv4 is supported. The address of a peer is the source address of its announcement
+//! combined with the announced port.
+//!
+//! [`MagicEndpoint`]: crate::MagicEndpoint
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, Notify};
+use tracing::{debug, trace, warn};
+use trust_dns_proto::op::{Message, MessageType, Query};
+use trust_dns_proto::rr::rdata::SRV;
+use trust_dns_proto::rr::{Name, RData, Record, RecordType};
+use trust_dns_proto::serialize::binary::{BinDecodable, BinEncodable};
+
+use crate::discovery::{Discovery, PeerAddr};
+use crate::tls::PeerId;
+use crate::util::AbortingJoinHandle;
+
+/// The DNS-SD service type under which iroh nodes announce themselves.
+pub const SERVICE_NAME: &str = "_iroh._udp.local.";
+
+/// The mDNS multicast group.
+const MDNS_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 251), 5353);
+
+/// How often other nodes are queried and our node is announced.
+const QUERY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The time to live of the announced records, peers not heard from for longer are
+/// forgotten.
+const TTL: Duration = Duration::from_secs(120);
+
+/// How long [`LocalDiscovery::resolve`](Discovery::resolve) waits for a peer to answer.
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Number of addresses kept per peer, older addresses are dropped.
+const MAX_ADDRS: usize = 4;
+
+/// A peer found on the local network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredPeer {
+    /// The peer.
+    pub peer_id: PeerId,
+    /// The address the peer announced itself from.
+    pub addr: SocketAddr,
+}
+
+#[derive(Debug)]
+struct PeerEntry {
+    /// Most recent first.
+    addrs: Vec<SocketAddr>,
+    last_seen: Instant,
+}
+
+/// Announces this node and discovers other nodes on the local network.
+///
+/// The announcements stop when the last clone is dropped.
+#[derive(Debug, Clone)]
+pub struct LocalDiscovery {
+    peers: Arc<Mutex<HashMap<PeerId, PeerEntry>>>,
+    events: broadcast::Sender<DiscoveredPeer>,
+    /// Makes the actor query the network right away.
+    query_now: Arc<Notify>,
+    _task: Arc<AbortingJoinHandle<()>>,
+}
+
+impl LocalDiscovery {
+    /// Starts announcing `peer_id` reachable on `port`, and listening for other nodes.
+    pub fn spawn(peer_id: PeerId, port: u16) -> Result<Self> {
+        let socket = bind_mdns().context("failed to bind the mDNS socket")?;
+        let peers: Arc<Mutex<HashMap<PeerId, PeerEntry>>> = Default::default();
+        let events = broadcast::channel(16).0;
+        let query_now = Arc::new(Notify::new());
+        let actor = Actor {
+            socket,
+            peer_id,
+            port,
+            service: Name::from_ascii(SERVICE_NAME)?,
+            peers: peers.clone(),
+            events: events.clone(),
+            query_now: query_now.clone(),
+        };
+        let task = tokio::spawn(async move {
+            if let Err(err) = actor.run().await {
+                warn!("local discovery stopped: {err:#}");
+            }
+        });
+        Ok(Self {
+            peers,
+            events,
+            query_now,
+            _task: Arc::new(task.into()),
+        })
+    }
+
+    /// Returns the addresses a peer was discovered at, most recent first.
+    ///
+    /// Returns `None` if the peer was not discovered, or was not heard from for a while.
+    pub fn get(&self, peer_id: &PeerId) -> Option<Vec<SocketAddr>> {
+        let peers = self.peers.lock().unwrap();
+        peers
+            .get(peer_id)
+            .filter(|entry| entry.last_seen.elapsed() <= TTL)
+            .map(|entry| entry.addrs.clone())
+    }
+
+    /// Subscribes to the peers discovered from now on.
+    ///
+    /// A peer is reported when it is first discovered, and whenever it announces itself
+    /// from a new address.
+    pub fn subscribe(&self) -> broadcast::Receiver<DiscoveredPeer> {
+        self.events.subscribe()
+    }
+}
+
+impl Discovery for LocalDiscovery {
+    /// Only the port of the node is announced, publishing does nothing.
+    fn publish(&self, _info: &PeerAddr) {}
+
+    /// Queries the local network, and waits a few seconds for the peer to answer.
+    fn resolve(&self, peer_id: PeerId) -> BoxFuture<'_, anyhow::Result<PeerAddr>> {
+        async move {
+            let mut events = self.subscribe();
+            let addrs = match self.get(&peer_id) {
+                Some(addrs) => addrs,
+                None => {
+                    self.query_now.notify_one();
+                    let discovered = tokio::time::timeout(RESOLVE_TIMEOUT, async {
+                        loop {
+                            match events.recv().await {
+                                Ok(peer) if peer.peer_id == peer_id => break Ok(vec![peer.addr]),
+                                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                                Err(err) => break Err(err),
+                            }
+                        }
+                    });
+                    discovered
+                        .await
+                        .with_context(|| format!("{peer_id} not found on the local network"))??
+                }
+            };
+            Ok(PeerAddr {
+                peer_id,
+                derp_region: None,
+                addrs,
+            })
+        }
+        .boxed()
+    }
+}
+
+/// Binds a socket to the mDNS port and joins the multicast group.
+///
+/// The port is shared with any other mDNS responder on this host.
+fn bind_mdns() -> std::io::Result<UdpSocket> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::IPV4,
+        socket2::Type::DGRAM,
+        Some(socket2::Protocol::UDP),
+    )?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_ADDR.port())).into())?;
+    socket.join_multicast_v4(MDNS_ADDR.ip(), &Ipv4Addr::UNSPECIFIED)?;
+    // Other nodes may run on this host.
+    socket.set_multicast_loop_v4(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+struct Actor {
+    socket: UdpSocket,
+    peer_id: PeerId,
+    port: u16,
+    service: Name,
+    peers: Arc<Mutex<HashMap<PeerId, PeerEntry>>>,
+    events: broadcast::Sender<DiscoveredPeer>,
+    query_now: Arc<Notify>,
+}
+
+impl Actor {
+    async fn run(self) -> Result<()> {
+        let mut buf = vec![0u8; 9000];
+        let mut interval = tokio::time::interval(QUERY_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    self.send(&self.query()).await;
+                    self.send(&self.announcement()?).await;
+                }
+                _ = self.query_now.notified() => {
+                    self.send(&self.query()).await;
+                }
+                res = self.socket.recv_from(&mut buf) => {
+                    let (len, src) = res.context("failed to receive from the mDNS socket")?;
+                    match Message::from_bytes(&buf[..len]) {
+                        Ok(msg) => self.handle_message(msg, src).await?,
+                        Err(err) => trace!("invalid mDNS packet from {src}: {err}"),
+                    }
+                }
+            }
+        }
+    }
+
+    async fn handle_message(&self, msg: Message, src: SocketAddr) -> Result<()> {
+        match msg.message_type() {
+            MessageType::Query => {
+                let asks_for_us = msg.queries().iter().any(|q| {
+                    q.name() == &self.service
+                        && matches!(q.query_type(), RecordType::PTR | RecordType::ANY)
+                });
+                if asks_for_us {
+                    trace!("answering mDNS query from {src}");
+                    self.send(&self.announcement()?).await;
+                }
+            }
+            MessageType::Response => {
+                for record in msg.answers().iter().chain(msg.additionals()) {
+                    if let Some(peer) = self.parse_srv(record, src) {
+                        self.discovered(peer);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Extracts a peer from a SRV record of an instance of our service.
+    fn parse_srv(&self, record: &Record, src: SocketAddr) -> Option<DiscoveredPeer> {
+        let Some(RData::SRV(srv)) = record.data() else {
+            return None;
+        };
+        let name = record.name();
+        if name.num_labels() != self.service.num_labels() + 1 || !self.service.zone_of(name) {
+            return None;
+        }
+        let label = std::str::from_utf8(name.iter().next()?).ok()?;
+        let peer_id = PeerId::from_str(label).ok()?;
+        if peer_id == self.peer_id {
+            return None;
+        }
+        Some(DiscoveredPeer {
+            peer_id,
+            addr: SocketAddr::new(src.ip(), srv.port()),
+        })
+    }
+
+    fn discovered(&self, peer: DiscoveredPeer) {
+        let mut peers = self.peers.lock().unwrap();
+        peers.retain(|_, entry| entry.last_seen.elapsed() <= TTL);
+        let entry = peers.entry(peer.peer_id).or_insert_with(|| PeerEntry {
+            addrs: Vec::new(),
+            last_seen: Instant::now(),
+        });
+        entry.last_seen = Instant::now();
+        if entry.addrs.first() != Some(&peer.addr) {
+            debug!("discovered {} at {}", peer.peer_id, peer.addr);
+            entry.addrs.retain(|addr| *addr != peer.addr);
+            entry.addrs.insert(0, peer.addr);
+            entry.addrs.truncate(MAX_ADDRS);
+            self.events.send(peer).ok();
+        }
+    }
+
+    fn query(&self) -> Message {
+        let mut msg = Message::new();
+        msg.set_message_type(MessageType::Query);
+        msg.add_query(Query::query(self.service.clone(), RecordType::PTR));
+        msg
+    }
+
+    fn announcement(&self) -> Result<Message> {
+        let instance = Name::from_ascii(format!("{}.{SERVICE_NAME}", self.peer_id))?;
+        let host = Name::from_ascii(format!("{}.local.", self.peer_id))?;
+        let ttl = TTL.as_secs() as u32;
+        let mut msg = Message::new();
+        msg.set_message_type(MessageType::Response);
+        msg.set_authoritative(true);
+        msg.add_answer(Record::from_rdata(
+            self.service.clone(),
+            ttl,
+            RData::PTR(instance.clone()),
+        ));
+        msg.add_answer(Record::from_rdata(
+            instance,
+            ttl,
+            RData::SRV(SRV::new(0, 0, self.port, host)),
+        ));
+        Ok(msg)
+    }
+
+    async fn send(&self, msg: &Message) {
+        let res = match msg.to_bytes() {
+            Ok(bytes) => self.socket.send_to(&bytes, MDNS_ADDR).await.map(|_| ()),
+            Err(err) => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, err)),
+        };
+        if let Err(err) = res {
+            debug!("failed to send mDNS packet: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::Keypair;
+
+    #[tokio::test]
+    async fn test_local_discovery() -> Result<()> {
+        let a: PeerId = Keypair::generate().public().into();
+        let b: PeerId = Keypair::generate().public().into();
+        let disco_a = LocalDiscovery::spawn(a, 1111)?;
+        let mut events = disco_a.subscribe();
+        let _disco_b = LocalDiscovery::spawn(b, 2222)?;
+
+        let peer = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let peer = events.recv().await?;
+                if peer.peer_id == b {
+                    return anyhow::Ok(peer);
+                }
+            }
+        })
+        .await??;
+        assert_eq!(peer.addr.port(), 2222);
+        assert_eq!(disco_a.get(&b), Some(vec![peer.addr]));
+        assert!(disco_a.get(&a).is_none());
+
+        let c: PeerId = Keypair::generate().public().into();
+        let _disco_c = LocalDiscovery::spawn(c, 3333)?;
+        let resolved = disco_a.resolve(c).await?;
+        assert_eq!(resolved.addrs.len(), 1);
+        assert_eq!(resolved.addrs[0].port(), 3333);
+        Ok(())
+    }
+}
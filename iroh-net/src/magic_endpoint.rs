@@ -1,22 +1,31 @@
 //! An endpoint that leverages a [quinn::Endpoint] backed by a [magicsock::MagicSock].
 
 use std::{
+    collections::HashMap,
     net::SocketAddr,
     sync::{Arc, Mutex},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, Context};
+use futures::{future, Stream, StreamExt};
 use quinn_proto::VarInt;
-use tracing::{debug, trace};
+use tokio::sync::{broadcast, watch};
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, trace, warn};
 
 use crate::{
     config,
     derp::DerpMap,
+    discovery::{Discovery, PeerAddr},
     key,
-    magicsock::{self, Callbacks, MagicSock},
+    local_discovery::LocalDiscovery,
+    magicsock::{self, Callbacks, EndpointInfo, MagicSock, SharedDerp},
     netmap::NetworkMap,
+    peer_store::PeerStore,
     tls::{self, Keypair, PeerId},
+    util::AbortingJoinHandle,
 };
 
 /// Builder for [MagicEndpoint]
@@ -26,8 +35,15 @@ pub struct MagicEndpointBuilder {
     derp_map: Option<DerpMap>,
     alpn_protocols: Vec<Vec<u8>>,
     transport_config: Option<quinn::TransportConfig>,
+    transport_tuning: TransportTuning,
     concurrent_connections: Option<u32>,
     keylog: bool,
+    close_grace_period: Duration,
+    connect_timeout: Option<Duration>,
+    peer_store: Option<PeerStore>,
+    local_discovery: bool,
+    discovery: Option<Box<dyn Discovery>>,
+    shared_derp: Option<SharedDerp>,
     callbacks: Callbacks,
 }
 
@@ -69,7 +85,9 @@ impl MagicEndpointBuilder {
 
     /// Set a custom [quinn::TransportConfig] for this endpoint.
     ///
-    /// The transport config contains parameters governing the QUIC state machine.
+    /// The transport config contains parameters governing the QUIC state machine. It is
+    /// used for incoming connections, with the tuning options of this builder, like
+    /// [`Self::congestion_controller`], applied on top.
     ///
     /// If unset, the default config is used. Default values should be suitable for most internet
     /// applications. Applications protocols which forbid remotely-initiated streams should set
@@ -79,6 +97,71 @@ impl MagicEndpointBuilder {
         self
     }
 
+    /// Set the congestion controller used by the connections of this endpoint.
+    ///
+    /// [`CongestionController::Bbr`] tends to achieve more throughput for bulk transfers
+    /// on lossy links. Defaults to [`CongestionController::Cubic`].
+    pub fn congestion_controller(mut self, congestion_controller: CongestionController) -> Self {
+        self.transport_tuning.congestion_controller = Some(congestion_controller);
+        self
+    }
+
+    /// Set the maximum number of bytes a peer may send on a single stream before it is
+    /// acknowledged by the application.
+    ///
+    /// Larger windows allow more throughput per stream on links with a high bandwidth-delay
+    /// product, at the cost of memory.
+    pub fn stream_receive_window(mut self, stream_receive_window: u32) -> Self {
+        self.transport_tuning.stream_receive_window = Some(stream_receive_window);
+        self
+    }
+
+    /// Set the maximum number of bytes a peer may send on all streams of a connection
+    /// before they are acknowledged by the application.
+    pub fn receive_window(mut self, receive_window: u32) -> Self {
+        self.transport_tuning.receive_window = Some(receive_window);
+        self
+    }
+
+    /// Set the maximum number of bytes to transmit to a peer without acknowledgment.
+    pub fn send_window(mut self, send_window: u64) -> Self {
+        self.transport_tuning.send_window = Some(send_window);
+        self
+    }
+
+    /// Set the interval at which keep-alive packets are sent on idle connections.
+    ///
+    /// `None` disables keep-alives. Outgoing connections send them every second by default.
+    pub fn keep_alive_interval(mut self, keep_alive_interval: Option<Duration>) -> Self {
+        self.transport_tuning.keep_alive_interval = Some(keep_alive_interval);
+        self
+    }
+
+    /// Set how long a connection may be idle before it is closed.
+    ///
+    /// `None` disables the timeout. Must be below 2^62 milliseconds.
+    pub fn max_idle_timeout(mut self, max_idle_timeout: Option<Duration>) -> Self {
+        self.transport_tuning.max_idle_timeout = Some(max_idle_timeout);
+        self
+    }
+
+    /// Set the maximum number of bytes of incoming datagrams to buffer per connection.
+    ///
+    /// `None` disables receiving datagrams, peers then can not send any, see
+    /// [`crate::datagram`]. Defaults to quinn's default, which enables them.
+    pub fn datagram_receive_buffer_size(mut self, size: Option<usize>) -> Self {
+        self.transport_tuning.datagram_receive_buffer_size = Some(size);
+        self
+    }
+
+    /// Set the maximum number of bytes of outgoing datagrams to buffer per connection.
+    ///
+    /// When the buffer is full, the oldest datagrams are dropped to make room for new ones.
+    pub fn datagram_send_buffer_size(mut self, size: usize) -> Self {
+        self.transport_tuning.datagram_send_buffer_size = Some(size);
+        self
+    }
+
     /// Maximum number of simultaneous connections to accept.
     ///
     /// New incoming connections are only accepted if the total number of incoming or outgoing
@@ -88,6 +171,62 @@ impl MagicEndpointBuilder {
         self
     }
 
+    /// How long [`MagicEndpoint::close`] waits for open connections to finish.
+    ///
+    /// Connections still open after this period are closed, dropping any data in flight.
+    /// By default connections are closed right away.
+    pub fn close_grace_period(mut self, close_grace_period: Duration) -> Self {
+        self.close_grace_period = close_grace_period;
+        self
+    }
+
+    /// How long [`MagicEndpoint::connect`] waits for a connection to be established.
+    ///
+    /// Dialing a peer which is offline otherwise only fails when the QUIC idle timeout
+    /// expires. By default there is no timeout besides the idle timeout.
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    /// Set a [`PeerStore`] to remember the addresses of peers across restarts.
+    ///
+    /// The addresses peers are reached at are recorded in the store, and used when
+    /// connecting to them again.
+    pub fn peer_store(mut self, peer_store: PeerStore) -> Self {
+        self.peer_store = Some(peer_store);
+        self
+    }
+
+    /// Discover peers on the local network with mDNS, and announce this endpoint to them.
+    ///
+    /// The addresses of discovered peers are added to the magic socket, so connections to
+    /// them can be made directly, see [`crate::local_discovery`]. Disabled by default.
+    pub fn local_discovery(mut self, local_discovery: bool) -> Self {
+        self.local_discovery = local_discovery;
+        self
+    }
+
+    /// Set a [`Discovery`] service to publish the addresses of this endpoint, and to resolve
+    /// the addresses of peers.
+    ///
+    /// The addresses are published whenever they change. Peers are resolved by
+    /// [`MagicEndpoint::connect`] when neither addresses nor a DERP region are known.
+    pub fn discovery(mut self, discovery: Box<dyn Discovery>) -> Self {
+        self.discovery = Some(discovery);
+        self
+    }
+
+    /// Relay through DERP connections shared with the other endpoints using `shared_derp`.
+    ///
+    /// Instead of connecting to each DERP region on its own, the endpoint registers its key
+    /// on the connection of the [`SharedDerp`], which saves connections when a process runs
+    /// many endpoints. Regions not part of the [`SharedDerp`] are connected to as usual.
+    pub fn shared_derp(mut self, shared_derp: SharedDerp) -> Self {
+        self.shared_derp = Some(shared_derp);
+        self
+    }
+
     /// Optionally set a callback function to be called when endpoints change.
     #[allow(clippy::type_complexity)]
     pub fn on_endpoints(
@@ -121,24 +260,39 @@ impl MagicEndpointBuilder {
     /// NOTE: This will be improved soon to add support for binding on specific addresses.
     pub async fn bind(self, bind_port: u16) -> anyhow::Result<MagicEndpoint> {
         let keypair = self.keypair.unwrap_or_else(Keypair::generate);
+        let mut transport_config = self.transport_config.unwrap_or_default();
+        self.transport_tuning.apply(&mut transport_config)?;
         let mut server_config = make_server_config(
             &keypair,
             self.alpn_protocols,
-            self.transport_config,
+            Some(transport_config),
             self.keylog,
         )?;
         if let Some(c) = self.concurrent_connections {
             server_config.concurrent_connections(c);
         }
-        MagicEndpoint::bind(
+        let mut endpoint = MagicEndpoint::bind(
             keypair,
             bind_port,
             Some(server_config),
             self.derp_map,
             Some(self.callbacks),
             self.keylog,
+            self.close_grace_period,
+            self.shared_derp,
         )
-        .await
+        .await?;
+        endpoint.client_transport_config = Arc::new(self.transport_tuning.client_config()?);
+        endpoint.connect_timeout = self.connect_timeout;
+        endpoint.peer_store = self.peer_store;
+        endpoint.start_path_watcher();
+        if self.local_discovery {
+            endpoint.start_local_discovery()?;
+        }
+        if let Some(discovery) = self.discovery {
+            endpoint.start_discovery(discovery.into());
+        }
+        Ok(endpoint)
     }
 }
 
@@ -154,6 +308,193 @@ fn make_server_config(
     Ok(server_config)
 }
 
+/// How often the addresses of the endpoint are checked for changes, to publish them.
+const PUBLISH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Why [`MagicEndpoint::connect`] failed.
+#[derive(Debug, thiserror::Error)]
+pub enum ConnectError {
+    /// No addresses or DERP region to reach the peer are known.
+    #[error("no route to the peer: {0:#}")]
+    NoRoute(anyhow::Error),
+    /// The connection was not established within the
+    /// [`MagicEndpointBuilder::connect_timeout`], or the peer did not answer before the
+    /// QUIC idle timeout.
+    #[error("timed out connecting to the peer")]
+    Timeout,
+    /// The connection attempt was cancelled.
+    #[error("connecting was cancelled")]
+    Cancelled,
+    /// The peer was reached, but the QUIC or TLS handshake failed.
+    #[error("handshake failed: {0}")]
+    Handshake(quinn::ConnectionError),
+    /// The endpoint could not start connecting, for example because it is closed.
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+/// The congestion controller used by QUIC connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CongestionController {
+    /// CUBIC, as in RFC 8312.
+    #[default]
+    Cubic,
+    /// NewReno, as in RFC 6582.
+    NewReno,
+    /// BBR, an experimental controller which paces by the estimated bandwidth.
+    Bbr,
+}
+
+/// The QUIC transport options set on the [`MagicEndpointBuilder`].
+#[derive(Debug, Clone, Default)]
+struct TransportTuning {
+    congestion_controller: Option<CongestionController>,
+    stream_receive_window: Option<u32>,
+    receive_window: Option<u32>,
+    send_window: Option<u64>,
+    keep_alive_interval: Option<Option<Duration>>,
+    max_idle_timeout: Option<Option<Duration>>,
+    datagram_receive_buffer_size: Option<Option<usize>>,
+    datagram_send_buffer_size: Option<usize>,
+}
+
+impl TransportTuning {
+    /// Apply the options which are set to `config`.
+    fn apply(&self, config: &mut quinn::TransportConfig) -> anyhow::Result<()> {
+        use quinn::congestion::{BbrConfig, CubicConfig, NewRenoConfig};
+        match self.congestion_controller {
+            Some(CongestionController::Cubic) => {
+                config.congestion_controller_factory(Arc::new(CubicConfig::default()))
+            }
+            Some(CongestionController::NewReno) => {
+                config.congestion_controller_factory(Arc::new(NewRenoConfig::default()))
+            }
+            Some(CongestionController::Bbr) => {
+                config.congestion_controller_factory(Arc::new(BbrConfig::default()))
+            }
+            None => config,
+        };
+        if let Some(window) = self.stream_receive_window {
+            config.stream_receive_window(window.into());
+        }
+        if let Some(window) = self.receive_window {
+            config.receive_window(window.into());
+        }
+        if let Some(window) = self.send_window {
+            config.send_window(window);
+        }
+        if let Some(interval) = self.keep_alive_interval {
+            config.keep_alive_interval(interval);
+        }
+        if let Some(timeout) = self.max_idle_timeout {
+            let timeout = timeout
+                .map(quinn::IdleTimeout::try_from)
+                .transpose()
+                .context("max idle timeout too large")?;
+            config.max_idle_timeout(timeout);
+        }
+        if let Some(size) = self.datagram_receive_buffer_size {
+            config.datagram_receive_buffer_size(size);
+        }
+        if let Some(size) = self.datagram_send_buffer_size {
+            config.datagram_send_buffer_size(size);
+        }
+        Ok(())
+    }
+
+    /// The transport config for outgoing connections.
+    fn client_config(&self) -> anyhow::Result<quinn::TransportConfig> {
+        let mut config = quinn::TransportConfig::default();
+        config.keep_alive_interval(Some(Duration::from_secs(1)));
+        self.apply(&mut config)?;
+        Ok(config)
+    }
+}
+
+/// Capacity of the channel for [`ConnectionEvent`]s, slow subscribers miss older events.
+const EVENTS_CAP: usize = 64;
+
+/// How often the paths of the connections are checked for changes.
+const PATH_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The tracked connections to a peer, and the path last reported for it.
+#[derive(Debug, Default)]
+struct TrackedPeer {
+    connections: usize,
+    path: Option<ConnectionPath>,
+}
+
+/// An event in the lifecycle of a connection of a [`MagicEndpoint`].
+#[derive(Debug, Clone)]
+pub enum ConnectionEvent {
+    /// A connection to a peer was established.
+    Connected {
+        /// The peer at the other end of the connection.
+        peer: PeerId,
+    },
+    /// The path the connection's packets take changed.
+    ///
+    /// All connections to a peer share its path, so this is reported once per peer.
+    /// A change from [`ConnectionPath::Derp`] to [`ConnectionPath::Direct`] is an upgrade
+    /// to a direct connection, the reverse a downgrade to relaying through DERP.
+    PathChanged {
+        /// The peer at the other end of the connection.
+        peer: PeerId,
+        /// The new path.
+        path: ConnectionPath,
+    },
+    /// The connection was closed.
+    Closed {
+        /// The peer at the other end of the connection.
+        peer: PeerId,
+        /// Why the connection was closed.
+        reason: quinn::ConnectionError,
+    },
+}
+
+/// The path the packets of a connection take to the peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionPath {
+    /// Packets are sent directly to this address.
+    Direct(SocketAddr),
+    /// Packets are relayed by the DERP server of this region.
+    Derp(u16),
+}
+
+impl ConnectionPath {
+    fn from_info(info: &EndpointInfo) -> Option<Self> {
+        match info.direct_addr {
+            Some(addr) => Some(Self::Direct(addr)),
+            None => info.derp_addr.map(Self::Derp),
+        }
+    }
+}
+
+/// Where [`MagicEndpoint::connect_by_id`] found the addresses it reached a peer with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddrSource {
+    /// Addresses added before, recorded in the [`PeerStore`] or discovered on the local
+    /// network.
+    AddressBook,
+    /// Addresses resolved by the [`Discovery`] service.
+    Discovery,
+    /// No addresses, the peer was dialed through the DERP region of this endpoint.
+    DerpOnly,
+}
+
+/// Information about the connection to a peer, see [`MagicEndpoint::connection_info`].
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    /// The peer.
+    pub peer_id: PeerId,
+    /// The path packets to the peer take, `None` if the peer cannot be reached yet.
+    pub path: Option<ConnectionPath>,
+    /// The average round trip time of the recent pings on the current path.
+    pub rtt: Option<Duration>,
+    /// Last time data was sent to the peer.
+    pub last_active: Instant,
+}
+
 /// An endpoint that leverages a [quinn::Endpoint] backed by a [magicsock::MagicSock].
 #[derive(Clone, Debug)]
 pub struct MagicEndpoint {
@@ -162,6 +503,24 @@ pub struct MagicEndpoint {
     endpoint: quinn::Endpoint,
     netmap: Arc<Mutex<NetworkMap>>,
     keylog: bool,
+    /// The transport config of outgoing connections.
+    client_transport_config: Arc<quinn::TransportConfig>,
+    events: broadcast::Sender<ConnectionEvent>,
+    /// The peer ids of the node keys the magicsock knows about.
+    peer_ids: Arc<Mutex<HashMap<key::node::PublicKey, PeerId>>>,
+    /// The number of tracked connections which are not closed yet.
+    open_connections: Arc<watch::Sender<usize>>,
+    /// The peers with tracked connections which are not closed yet.
+    tracked: Arc<Mutex<HashMap<PeerId, TrackedPeer>>>,
+    /// Reports the path changes of the tracked peers.
+    path_task: Option<Arc<AbortingJoinHandle<()>>>,
+    close_grace_period: Duration,
+    peer_store: Option<PeerStore>,
+    local_discovery: Option<LocalDiscovery>,
+    discovery: Option<Arc<dyn Discovery>>,
+    /// Publishes the addresses to the discovery service.
+    publish_task: Option<Arc<AbortingJoinHandle<()>>>,
+    connect_timeout: Option<Duration>,
 }
 
 impl MagicEndpoint {
@@ -174,6 +533,7 @@ impl MagicEndpoint {
     ///
     /// This is for internal use, the public interface is the [MagicEndpointBuilder] obtained from
     /// [Self::builder]. See the methods on the builder for documentation of the parameters.
+    #[allow(clippy::too_many_arguments)]
     async fn bind(
         keypair: Keypair,
         bind_port: u16,
@@ -181,11 +541,14 @@ impl MagicEndpoint {
         derp_map: Option<DerpMap>,
         callbacks: Option<Callbacks>,
         keylog: bool,
+        close_grace_period: Duration,
+        shared_derp: Option<SharedDerp>,
     ) -> anyhow::Result<Self> {
         let conn = magicsock::MagicSock::new(magicsock::Options {
             port: bind_port,
             private_key: keypair.secret().clone().into(),
             callbacks: callbacks.unwrap_or_default(),
+            shared_derp,
         })
         .await?;
         trace!("created magicsock");
@@ -209,14 +572,255 @@ impl MagicEndpoint {
             endpoint,
             netmap: Arc::new(Mutex::new(NetworkMap { peers: vec![] })),
             keylog,
+            client_transport_config: Arc::new(TransportTuning::default().client_config()?),
+            events: broadcast::channel(EVENTS_CAP).0,
+            peer_ids: Default::default(),
+            open_connections: Arc::new(watch::channel(0).0),
+            tracked: Default::default(),
+            path_task: None,
+            close_grace_period,
+            peer_store: None,
+            local_discovery: None,
+            discovery: None,
+            publish_task: None,
+            connect_timeout: None,
         })
     }
 
+    /// Use a discovery service, publishing the addresses of this endpoint to it whenever
+    /// they change.
+    fn start_discovery(&mut self, discovery: Arc<dyn Discovery>) {
+        let peer_id = self.peer_id();
+        let conn = self.conn.clone();
+        let publish = discovery.clone();
+        let task = tokio::spawn(async move {
+            let mut published = None;
+            let mut interval = tokio::time::interval(PUBLISH_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                let Ok(endpoints) = conn.local_endpoints().await else {
+                    continue;
+                };
+                let info = PeerAddr {
+                    peer_id,
+                    derp_region: conn.my_derp().await,
+                    addrs: endpoints.into_iter().map(|ep| ep.addr).collect(),
+                };
+                if published.as_ref() != Some(&info) {
+                    debug!("publishing addresses {info:?}");
+                    publish.publish(&info);
+                    published = Some(info);
+                }
+            }
+        });
+        self.discovery = Some(discovery);
+        self.publish_task = Some(Arc::new(task.into()));
+    }
+
+    /// Announce this endpoint on the local network, and add the addresses of the peers
+    /// discovered there to the magic socket.
+    fn start_local_discovery(&mut self) -> anyhow::Result<()> {
+        let (addr, _) = self.conn.local_addr()?;
+        let discovery = LocalDiscovery::spawn(self.peer_id(), addr.port())?;
+        // Holds no clone of the discovery, so the task ends when the discovery is dropped.
+        let mut discovered = discovery.subscribe();
+        let conn = self.conn.clone();
+        let netmap = self.netmap.clone();
+        let peer_ids = self.peer_ids.clone();
+        tokio::spawn(async move {
+            loop {
+                let peer = match discovered.recv().await {
+                    Ok(peer) => peer,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                let res =
+                    update_netmap(&conn, &netmap, &peer_ids, peer.peer_id, None, &[peer.addr])
+                        .await;
+                if let Err(err) = res {
+                    warn!("failed to add discovered peer {}: {err:#}", peer.peer_id);
+                }
+            }
+        });
+        self.local_discovery = Some(discovery);
+        Ok(())
+    }
+
     /// Accept an incoming connection on the socket.
     pub fn accept(&self) -> quinn::Accept<'_> {
         self.endpoint.accept()
     }
 
+    /// Accept an incoming connection, and extract the client-provided [`PeerId`] and ALPN protocol.
+    ///
+    /// Unlike the free [`accept_conn`] function the connection is reported to
+    /// [`Self::subscribe`]rs.
+    pub async fn accept_conn(
+        &self,
+        conn: quinn::Connecting,
+    ) -> anyhow::Result<(PeerId, String, quinn::Connection)> {
+        let (peer_id, alpn, conn) = accept_conn(conn).await?;
+        self.peer_ids
+            .lock()
+            .unwrap()
+            .insert(peer_id.into(), peer_id);
+        self.track(peer_id, &conn);
+        Ok((peer_id, alpn, conn))
+    }
+
+    /// Subscribe to the lifecycle events of the connections of this endpoint.
+    ///
+    /// Covers the connections made with [`Self::connect`] and accepted with
+    /// [`Self::accept_conn`], from the time of the subscription. A subscriber that does not
+    /// keep up misses events.
+    pub fn subscribe(&self) -> impl Stream<Item = ConnectionEvent> + Send + Unpin + 'static {
+        BroadcastStream::new(self.events.subscribe()).filter_map(|event| {
+            future::ready(match event {
+                Ok(event) => Some(event),
+                Err(BroadcastStreamRecvError::Lagged(n)) => {
+                    warn!("connection event subscriber lagged, missed {n} events");
+                    None
+                }
+            })
+        })
+    }
+
+    /// Count a new connection as open and report its events to the subscribers, until it
+    /// is closed.
+    fn track(&self, peer: PeerId, conn: &quinn::Connection) {
+        self.open_connections.send_modify(|n| *n += 1);
+        self.tracked
+            .lock()
+            .unwrap()
+            .entry(peer)
+            .or_default()
+            .connections += 1;
+        self.events.send(ConnectionEvent::Connected { peer }).ok();
+        let open_connections = self.open_connections.clone();
+        let tracked = self.tracked.clone();
+        let events = self.events.clone();
+        let conn = conn.clone();
+        tokio::spawn(async move {
+            let reason = conn.closed().await;
+            {
+                let mut tracked = tracked.lock().unwrap();
+                if let Some(entry) = tracked.get_mut(&peer) {
+                    entry.connections -= 1;
+                    if entry.connections == 0 {
+                        tracked.remove(&peer);
+                    }
+                }
+            }
+            open_connections.send_modify(|n| *n -= 1);
+            events.send(ConnectionEvent::Closed { peer, reason }).ok();
+        });
+    }
+
+    /// Check the paths of all tracked peers with a single task, reporting the changes to
+    /// the subscribers and recording them in the peer store.
+    fn start_path_watcher(&mut self) {
+        let tracked = self.tracked.clone();
+        let events = self.events.clone();
+        let peer_store = self.peer_store.clone();
+        let magicsock = self.conn.clone();
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PATH_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                if tracked.lock().unwrap().is_empty()
+                    || (events.receiver_count() == 0 && peer_store.is_none())
+                {
+                    continue;
+                }
+                let Ok(endpoints) = magicsock.tracked_endpoints().await else {
+                    continue;
+                };
+                let paths: HashMap<_, _> = endpoints
+                    .iter()
+                    .filter_map(|info| {
+                        Some((info.public_key.clone(), ConnectionPath::from_info(info)?))
+                    })
+                    .collect();
+                let changed: Vec<_> = tracked
+                    .lock()
+                    .unwrap()
+                    .iter_mut()
+                    .filter_map(|(peer, entry)| {
+                        let node_key: key::node::PublicKey = (*peer).into();
+                        let current = *paths.get(&node_key)?;
+                        if entry.path == Some(current) {
+                            return None;
+                        }
+                        entry.path = Some(current);
+                        Some((*peer, current))
+                    })
+                    .collect();
+                for (peer, path) in changed {
+                    events
+                        .send(ConnectionEvent::PathChanged { peer, path })
+                        .ok();
+                    let Some(peer_store) = &peer_store else {
+                        continue;
+                    };
+                    let res = match path {
+                        ConnectionPath::Direct(addr) => {
+                            peer_store.record(peer, &[addr], None).await
+                        }
+                        ConnectionPath::Derp(region) => {
+                            peer_store.record(peer, &[], Some(region)).await
+                        }
+                    };
+                    if let Err(err) = res {
+                        warn!("failed to record the path to {peer}: {err:#}");
+                    }
+                }
+            }
+        });
+        self.path_task = Some(Arc::new(task.into()));
+    }
+
+    /// Get information about the connection to a peer.
+    ///
+    /// Returns `None` if the peer is not known, peers become known when connecting to them,
+    /// when adding their addresses with [`Self::add_known_addrs`] and when accepting their
+    /// connections with [`Self::accept_conn`].
+    pub async fn connection_info(&self, peer_id: PeerId) -> anyhow::Result<Option<ConnectionInfo>> {
+        let node_key: key::node::PublicKey = peer_id.into();
+        let info = self
+            .conn
+            .tracked_endpoints()
+            .await?
+            .into_iter()
+            .find(|info| info.public_key == node_key)
+            .map(|info| Self::connection_info_from(peer_id, &info));
+        Ok(info)
+    }
+
+    /// Get information about the connections to all known peers.
+    ///
+    /// See [`Self::connection_info`] for which peers are known.
+    pub async fn connection_infos(&self) -> anyhow::Result<Vec<ConnectionInfo>> {
+        let endpoints = self.conn.tracked_endpoints().await?;
+        let peer_ids = self.peer_ids.lock().unwrap();
+        let infos = endpoints
+            .iter()
+            .filter_map(|info| {
+                let peer_id = peer_ids.get(&info.public_key)?;
+                Some(Self::connection_info_from(*peer_id, info))
+            })
+            .collect();
+        Ok(infos)
+    }
+
+    fn connection_info_from(peer_id: PeerId, info: &EndpointInfo) -> ConnectionInfo {
+        ConnectionInfo {
+            peer_id,
+            path: ConnectionPath::from_info(info),
+            rtt: info.rtt,
+            last_active: info.last_active,
+        }
+    }
+
     /// Get the peer id of this endpoint.
     pub fn peer_id(&self) -> PeerId {
         self.keypair.public().into()
@@ -261,29 +865,215 @@ impl MagicEndpoint {
     /// If the `derp_region` is not `None` and the configured DERP servers do not include a DERP node from the given `derp_region`, it will error.
     ///
     /// If no UDP addresses and no DERP region is provided, it will error.
+    ///
+    /// The addresses and DERP region in the address book of the endpoint are used in addition
+    /// to the given ones: those added before, those the peer was last reached at if the
+    /// endpoint has a [`PeerStore`], and those the peer was discovered at on the local network
+    /// if [`MagicEndpointBuilder::local_discovery`] is enabled.
+    ///
+    /// If no addresses and no DERP region are known for the peer, and the endpoint has a
+    /// [`Discovery`] service, the peer is resolved with it.
+    ///
+    /// Establishing the connection is limited by the
+    /// [`MagicEndpointBuilder::connect_timeout`]. The returned [`ConnectError`] tells
+    /// whether the peer could not be routed to, did not answer in time, or failed the
+    /// handshake.
     pub async fn connect(
         &self,
         peer_id: PeerId,
         alpn: &[u8],
         derp_region: Option<u16>,
         known_addrs: &[SocketAddr],
-    ) -> anyhow::Result<quinn::Connection> {
+    ) -> Result<quinn::Connection, ConnectError> {
+        let cancel = CancellationToken::new();
+        self.connect_with_cancel(peer_id, alpn, derp_region, known_addrs, &cancel)
+            .await
+    }
+
+    /// Connect to a remote endpoint, until `cancel` is cancelled.
+    ///
+    /// See [`Self::connect`], this returns [`ConnectError::Cancelled`] if the token is
+    /// cancelled before the connection is established.
+    pub async fn connect_with_cancel(
+        &self,
+        peer_id: PeerId,
+        alpn: &[u8],
+        derp_region: Option<u16>,
+        known_addrs: &[SocketAddr],
+        cancel: &CancellationToken,
+    ) -> Result<quinn::Connection, ConnectError> {
+        tokio::select! {
+            biased;
+            _ = cancel.cancelled() => Err(ConnectError::Cancelled),
+            res = self.connect_inner(peer_id, alpn, derp_region, known_addrs) => res,
+        }
+    }
+
+    async fn connect_inner(
+        &self,
+        peer_id: PeerId,
+        alpn: &[u8],
+        derp_region: Option<u16>,
+        known_addrs: &[SocketAddr],
+    ) -> Result<quinn::Connection, ConnectError> {
+        let (book_region, book_addrs) = self.address_book(peer_id);
+        let mut known_addrs = known_addrs.to_vec();
+        for addr in book_addrs {
+            if !known_addrs.contains(&addr) {
+                known_addrs.push(addr);
+            }
+        }
+        let mut derp_region = derp_region.or(book_region);
+        if let (true, None, Some(discovery)) =
+            (known_addrs.is_empty(), derp_region, &self.discovery)
+        {
+            let resolved = discovery
+                .resolve(peer_id)
+                .await
+                .with_context(|| format!("failed to discover {peer_id}"))
+                .map_err(ConnectError::NoRoute)?;
+            debug!("discovered {peer_id}: {resolved:?}");
+            known_addrs = resolved.addrs;
+            derp_region = resolved.derp_region;
+        }
+        self.dial(peer_id, alpn, derp_region, &known_addrs).await
+    }
+
+    /// Connect to a remote endpoint knowing nothing but its [`PeerId`].
+    ///
+    /// The addresses of the peer are looked up in turn, until a connection succeeds:
+    ///
+    /// 1. In the address book: addresses added with [`Self::add_known_addrs`] or used by
+    ///    earlier connections, recorded in the [`PeerStore`] and discovered on the local
+    ///    network.
+    /// 2. With the [`Discovery`] service of the endpoint, if any.
+    /// 3. Through the DERP region of this endpoint, assuming the peer is connected to the
+    ///    same region.
+    ///
+    /// Returns the connection and which of these found the peer. If all fail, the error of
+    /// the last attempt is returned.
+    pub async fn connect_by_id(
+        &self,
+        peer_id: PeerId,
+        alpn: &[u8],
+    ) -> anyhow::Result<(quinn::Connection, AddrSource)> {
+        let mut err = anyhow!("no addresses or DERP region known for {peer_id}");
+
+        let (derp_region, addrs) = self.address_book(peer_id);
+        if derp_region.is_some() || !addrs.is_empty() {
+            match self.dial(peer_id, alpn, derp_region, &addrs).await {
+                Ok(conn) => return Ok((conn, AddrSource::AddressBook)),
+                Err(e) => {
+                    debug!("failed to connect to {peer_id} from the address book: {e:#}");
+                    err = e.into();
+                }
+            }
+        }
+
+        if let Some(discovery) = &self.discovery {
+            let res = match discovery.resolve(peer_id).await {
+                Ok(resolved) => self
+                    .dial(peer_id, alpn, resolved.derp_region, &resolved.addrs)
+                    .await
+                    .map_err(Into::into),
+                Err(e) => Err(e.context(format!("failed to discover {peer_id}"))),
+            };
+            match res {
+                Ok(conn) => return Ok((conn, AddrSource::Discovery)),
+                Err(e) => {
+                    debug!("failed to connect to {peer_id} through discovery: {e:#}");
+                    err = e;
+                }
+            }
+        }
+
+        if let Some(region) = self.my_derp().await {
+            match self.dial(peer_id, alpn, Some(region), &[]).await {
+                Ok(conn) => return Ok((conn, AddrSource::DerpOnly)),
+                Err(e) => {
+                    debug!("failed to connect to {peer_id} through DERP region {region}: {e:#}");
+                    err = e.into();
+                }
+            }
+        }
+        Err(err)
+    }
+
+    /// The addresses and DERP region of a peer in the address book.
+    ///
+    /// That is, in the network map, the [`PeerStore`] and the peers discovered on the local
+    /// network.
+    fn address_book(&self, peer_id: PeerId) -> (Option<u16>, Vec<SocketAddr>) {
+        let node_key: key::node::PublicKey = peer_id.into();
+        let (mut derp_region, mut addrs) = {
+            let netmap = self.netmap.lock().unwrap();
+            match netmap.peers.iter().find(|peer| peer.key == node_key) {
+                Some(node) => (node.derp, node.endpoints.clone()),
+                None => (None, Vec::new()),
+            }
+        };
+        let mut add = |more: Vec<SocketAddr>| {
+            for addr in more {
+                if !addrs.contains(&addr) {
+                    addrs.push(addr);
+                }
+            }
+        };
+        if let Some(stored) = self.peer_store.as_ref().and_then(|s| s.get(&peer_id)) {
+            debug!("adding stored addresses of {peer_id}: {stored:?}");
+            add(stored.addrs);
+            derp_region = derp_region.or(stored.derp_region);
+        }
+        if let Some(discovered) = self.local_discovery.as_ref().and_then(|d| d.get(&peer_id)) {
+            debug!("adding local addresses of {peer_id}: {discovered:?}");
+            add(discovered);
+        }
+        (derp_region, addrs)
+    }
+
+    /// Add the addresses of the peer to the magic socket, and connect to it within the
+    /// connect timeout.
+    async fn dial(
+        &self,
+        peer_id: PeerId,
+        alpn: &[u8],
+        derp_region: Option<u16>,
+        known_addrs: &[SocketAddr],
+    ) -> Result<quinn::Connection, ConnectError> {
+        let dial = self.dial_inner(peer_id, alpn, derp_region, known_addrs);
+        match self.connect_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, dial)
+                .await
+                .map_err(|_| ConnectError::Timeout)?,
+            None => dial.await,
+        }
+    }
+
+    async fn dial_inner(
+        &self,
+        peer_id: PeerId,
+        alpn: &[u8],
+        derp_region: Option<u16>,
+        known_addrs: &[SocketAddr],
+    ) -> Result<quinn::Connection, ConnectError> {
         self.add_known_addrs(peer_id, derp_region, known_addrs)
-            .await?;
+            .await
+            .map_err(ConnectError::NoRoute)?;
 
         let node_key: key::node::PublicKey = peer_id.into();
         let addr = self.conn.get_mapping_addr(&node_key).await.ok_or_else(|| {
-            anyhow!("failed to retrieve the mapped address from the magic socket")
+            ConnectError::NoRoute(anyhow!(
+                "failed to retrieve the mapped address from the magic socket"
+            ))
         })?;
 
         let client_config = {
             let alpn_protocols = vec![alpn.to_vec()];
             let tls_client_config =
-                tls::make_client_config(&self.keypair, Some(peer_id), alpn_protocols, self.keylog)?;
+                tls::make_client_config(&self.keypair, Some(peer_id), alpn_protocols, self.keylog)
+                    .map_err(anyhow::Error::from)?;
             let mut client_config = quinn::ClientConfig::new(Arc::new(tls_client_config));
-            let mut transport_config = quinn::TransportConfig::default();
-            transport_config.keep_alive_interval(Some(Duration::from_secs(1)));
-            client_config.transport_config(Arc::new(transport_config));
+            client_config.transport_config(self.client_transport_config.clone());
             client_config
         };
 
@@ -295,9 +1085,15 @@ impl MagicEndpoint {
         // TODO: We'd eventually want to replace "localhost" with something that makes more sense.
         let connect = self
             .endpoint
-            .connect_with(client_config, addr, "localhost")?;
+            .connect_with(client_config, addr, "localhost")
+            .map_err(anyhow::Error::from)?;
 
-        connect.await.context("failed connecting to provider")
+        let conn = connect.await.map_err(|err| match err {
+            quinn::ConnectionError::TimedOut => ConnectError::Timeout,
+            err => ConnectError::Handshake(err),
+        })?;
+        self.track(peer_id, &conn);
+        Ok(conn)
     }
 
     /// Inform the magic socket about addresses of the peer.
@@ -331,46 +1127,42 @@ impl MagicEndpoint {
             _ => {}
         }
 
-        let node_key: key::node::PublicKey = peer_id.into();
-        let netmap = {
-            let mut netmap = self.netmap.lock().unwrap();
-            let node = netmap.peers.iter_mut().find(|peer| peer.key == node_key);
-            if let Some(node) = node {
-                for endpoint in endpoints {
-                    if !node.endpoints.contains(endpoint) {
-                        node.endpoints.push(*endpoint);
-                        node.addresses.push(endpoint.ip());
-                    }
-                }
-            } else {
-                let endpoints = endpoints.to_vec();
-                let addresses = endpoints.iter().map(|ep| ep.ip()).collect();
-                let node = config::Node {
-                    name: None,
-                    addresses,
-                    endpoints,
-                    key: node_key.clone(),
-                    derp: derp_region,
-                };
-                netmap.peers.push(node)
-            }
-            netmap.clone()
-        };
-        self.conn.set_network_map(netmap).await?;
-        Ok(())
+        update_netmap(
+            &self.conn,
+            &self.netmap,
+            &self.peer_ids,
+            peer_id,
+            derp_region,
+            endpoints,
+        )
+        .await
     }
 
     /// Close the QUIC endpoint and the magic socket.
     ///
-    /// This will close all open QUIC connections with the provided error_code and reason. See
+    /// This stops accepting new connections, and waits up to the
+    /// [`MagicEndpointBuilder::close_grace_period`] for the connections made with
+    /// [`Self::connect`] and accepted with [`Self::accept_conn`] to be closed. It will then
+    /// close all open QUIC connections with the provided error_code and reason. See
     /// [quinn::Connection] for details on how these are interpreted.
     ///
     /// It will then wait for all connections to actually be shutdown, and afterwards
-    /// close the magic socket.
+    /// close the magic socket, which releases its port mapping.
     ///
     /// Returns an error if closing the magic socket failed.
     /// TODO: Document error cases.
     pub async fn close(&self, error_code: VarInt, reason: &[u8]) -> anyhow::Result<()> {
+        self.endpoint.set_server_config(None);
+        if !self.close_grace_period.is_zero() {
+            let mut open_connections = self.open_connections.subscribe();
+            let drained = open_connections.wait_for(|n| *n == 0);
+            if tokio::time::timeout(self.close_grace_period, drained)
+                .await
+                .is_err()
+            {
+                debug!("grace period over, closing open connections");
+            }
+        }
         self.endpoint.close(error_code, reason);
         self.endpoint.wait_idle().await;
         self.conn.close().await?;
@@ -387,6 +1179,46 @@ impl MagicEndpoint {
     }
 }
 
+/// Add the addresses and DERP region of a peer to the network map of the magic socket.
+async fn update_netmap(
+    conn: &MagicSock,
+    netmap: &Mutex<NetworkMap>,
+    peer_ids: &Mutex<HashMap<key::node::PublicKey, PeerId>>,
+    peer_id: PeerId,
+    derp_region: Option<u16>,
+    endpoints: &[SocketAddr],
+) -> anyhow::Result<()> {
+    let node_key: key::node::PublicKey = peer_id.into();
+    peer_ids.lock().unwrap().insert(node_key.clone(), peer_id);
+    let netmap = {
+        let mut netmap = netmap.lock().unwrap();
+        let node = netmap.peers.iter_mut().find(|peer| peer.key == node_key);
+        if let Some(node) = node {
+            node.derp = derp_region.or(node.derp);
+            for endpoint in endpoints {
+                if !node.endpoints.contains(endpoint) {
+                    node.endpoints.push(*endpoint);
+                    node.addresses.push(endpoint.ip());
+                }
+            }
+        } else {
+            let endpoints = endpoints.to_vec();
+            let addresses = endpoints.iter().map(|ep| ep.ip()).collect();
+            let node = config::Node {
+                name: None,
+                addresses,
+                endpoints,
+                key: node_key.clone(),
+                derp: derp_region,
+            };
+            netmap.peers.push(node)
+        }
+        netmap.clone()
+    };
+    conn.set_network_map(netmap).await?;
+    Ok(())
+}
+
 /// Accept an incoming connection and extract the client-provided [`PeerId`] and ALPN protocol.
 pub async fn accept_conn(
     mut conn: quinn::Connecting,
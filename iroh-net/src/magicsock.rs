@@ -34,7 +34,11 @@ use crate::{
     config::{self, DERP_MAGIC_IP},
     derp::{self, DerpMap, DerpRegion},
     disco, key,
-    net::ip::LocalAddresses,
+    net::{
+        interfaces,
+        ip::LocalAddresses,
+        netmon::{self, NetworkChange},
+    },
     netcheck, netmap, portmapper, stun,
     util::AbortingJoinHandle,
 };
@@ -51,11 +55,13 @@ mod derp_actor;
 mod endpoint;
 mod metrics;
 mod rebinding_conn;
+mod shared_derp;
 mod timer;
 mod udp_actor;
 
 pub use self::endpoint::EndpointInfo;
 pub use self::metrics::Metrics;
+pub use self::shared_derp::SharedDerp;
 pub use self::timer::Timer;
 
 /// How long we consider a STUN-derived endpoint valid for. UDP NAT mappings typically
@@ -64,6 +70,9 @@ const ENDPOINTS_FRESH_ENOUGH_DURATION: Duration = Duration::from_secs(27);
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 
+/// How long to wait on shutdown for the gateway to release our port mapping.
+const PORT_MAPPING_RELEASE_TIMEOUT: Duration = Duration::from_secs(3);
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub(self) enum CurrentPortFate {
     Keep,
@@ -123,6 +132,11 @@ pub struct Options {
 
     /// Callbacks to emit on various socket events
     pub callbacks: Callbacks,
+
+    /// DERP connections to share with other endpoints.
+    ///
+    /// If `None`, the endpoint makes its own connections to the DERP regions.
+    pub shared_derp: Option<SharedDerp>,
 }
 
 /// Contains options for `MagicSock::listen`.
@@ -148,6 +162,7 @@ impl Default for Options {
             port: 0,
             private_key: key::node::SecretKey::generate(),
             callbacks: Default::default(),
+            shared_derp: None,
         }
     }
 }
@@ -213,6 +228,8 @@ pub(self) struct Inner {
     pub(self) derp_map: tokio::sync::RwLock<Option<DerpMap>>,
     /// Nearest DERP region ID; 0 means none/unknown.
     my_derp: AtomicU16,
+    /// DERP connections shared with other endpoints, used instead of our own ones.
+    pub(self) shared_derp: Option<SharedDerp>,
 }
 
 impl Inner {
@@ -231,8 +248,12 @@ impl Inner {
     }
 
     /// Returns `true` if we have DERP configuration for the given DERP `region`.
+    ///
+    /// Regions with only STUN servers do not count, packets cannot be relayed through them.
     pub(self) async fn has_derp_region(&self, region: u16) -> bool {
-        self.get_derp_region(region).await.is_some()
+        self.get_derp_region(region)
+            .await
+            .map_or(false, |region| region.has_derp_node())
     }
 
     pub(self) async fn get_derp_region(&self, region: u16) -> Option<DerpRegion> {
@@ -291,6 +312,8 @@ impl MagicSock {
     }
 
     /// Returns `true` if we have DERP configuration for the given DERP `region`.
+    ///
+    /// Regions with only STUN servers do not count, packets cannot be relayed through them.
     pub async fn has_derp_region(&self, region: u16) -> bool {
         self.inner.has_derp_region(region).await
     }
@@ -307,6 +330,7 @@ impl MagicSock {
                     on_derp_active,
                     on_net_info,
                 },
+            shared_derp,
         } = opts;
 
         let (network_recv_ch_sender, network_recv_ch_receiver) = flume::bounded(128);
@@ -325,6 +349,7 @@ impl MagicSock {
         let ipv6_addr = pconn6.as_ref().and_then(|c| c.local_addr().ok());
 
         let net_checker = netcheck::Client::new(Some(port_mapper.clone())).await?;
+        let net_monitor = netmon::Monitor::new().await;
         let (actor_sender, actor_receiver) = mpsc::channel(128);
         let (network_sender, network_receiver) = mpsc::channel(128);
 
@@ -347,6 +372,7 @@ impl MagicSock {
             ipv6_reported: Arc::new(AtomicBool::new(false)),
             derp_map: Default::default(),
             my_derp: AtomicU16::new(0),
+            shared_derp,
         });
 
         let udp_state = quinn_udp::UdpState::default();
@@ -403,6 +429,7 @@ impl MagicSock {
                     udp_state,
                     no_v4_send: false,
                     net_checker,
+                    net_monitor,
                 };
 
                 if let Err(err) = actor.run().await {
@@ -892,6 +919,9 @@ struct Actor {
 
     /// The prober that discovers local network conditions, including the closest DERP relay and NAT mappings.
     net_checker: netcheck::Client,
+
+    /// Reports changes of the network interfaces, after which the sockets are rebound.
+    net_monitor: netmon::Monitor,
 }
 
 impl Actor {
@@ -947,6 +977,10 @@ impl Actor {
                     debug!("external address updated: {new_external_address:?}");
                     self.re_stun("portmap_updated").await;
                 },
+                Some(change) = self.net_monitor.changed() => {
+                    trace!("tick: network change {:?}", change);
+                    self.handle_network_change(change).await;
+                }
                 _ = endpoint_heartbeat_timer.tick() => {
                     trace!("tick: endpoint heartbeat {} endpoints", self.peer_map.node_count());
                     // TODO: this might trigger too many packets at once, pace this
@@ -997,7 +1031,12 @@ impl Actor {
                 for (_, ep) in self.peer_map.endpoints_mut() {
                     ep.stop_and_reset();
                 }
-                self.port_mapper.deactivate();
+                if time::timeout(PORT_MAPPING_RELEASE_TIMEOUT, self.port_mapper.release())
+                    .await
+                    .is_err()
+                {
+                    debug!("timed out releasing the port mapping");
+                }
                 self.derp_actor_sender
                     .send(DerpActorMessage::Shutdown)
                     .await
@@ -1043,7 +1082,7 @@ impl Actor {
             }
             ActorMessage::SetNetworkMap(nm, s) => {
                 self.set_network_map(nm);
-                s.send(()).unwrap();
+                let _ = s.send(());
             }
             ActorMessage::ReceiveDerp(read_result) => {
                 let passthroughs = self.process_derp_read_result(read_result).await;
@@ -1595,6 +1634,8 @@ impl Actor {
             ni.derp_latency.insert(format!("{rid}-v6"), d.as_secs_f64());
         }
 
+        // Regions with only STUN servers can not be our home.
+        ni.preferred_derp = self.pick_home_derp(r).await;
         if ni.preferred_derp == 0 {
             // Perhaps UDP is blocked. Pick a deterministic but arbitrary one.
             ni.preferred_derp = self.pick_derp_fallback().await;
@@ -1653,6 +1694,32 @@ impl Actor {
         true
     }
 
+    /// Returns the region preferred by the netcheck report if it has a DERP server, and
+    /// otherwise the region with a DERP server with the lowest latency.
+    ///
+    /// Returns `0` if no region with a DERP server was reached.
+    async fn pick_home_derp(&self, report: &netcheck::Report) -> u16 {
+        let derp_map = self.conn.derp_map.read().await;
+        let Some(derp_map) = derp_map.as_ref() else {
+            return 0;
+        };
+        let has_derp_node = |id: u16| {
+            derp_map
+                .regions
+                .get(&id)
+                .map_or(false, |r| r.has_derp_node())
+        };
+        if report.preferred_derp != 0 && has_derp_node(report.preferred_derp) {
+            return report.preferred_derp;
+        }
+        report
+            .region_latency
+            .iter()
+            .filter(|(id, _)| has_derp_node(*id))
+            .min_by_key(|(_, latency)| *latency)
+            .map_or(0, |(id, _)| id)
+    }
+
     /// Returns a deterministic DERP node to connect to. This is only used if netcheck
     /// couldn't find the nearest one, for instance, if UDP is blocked and thus STUN
     /// latency checks aren't working.
@@ -1666,10 +1733,10 @@ impl Actor {
             }
             let ids = derp_map
                 .as_ref()
-                .map(|d| d.region_ids())
+                .map(|d| d.derp_region_ids())
                 .unwrap_or_default();
             if ids.is_empty() {
-                // No DERP regions in map.
+                // No DERP regions in map, or only STUN servers.
                 return 0;
             }
             ids
@@ -1686,7 +1753,7 @@ impl Actor {
         // We used to do the above for legacy clients, but never updated it for disco.
 
         let my_derp = self.conn.my_derp();
-        if my_derp > 0 {
+        if ids.contains(&my_derp) {
             return my_derp;
         }
 
@@ -1776,6 +1843,18 @@ impl Actor {
         }
     }
 
+    /// Moves the connections to the new network after the interfaces changed or the machine
+    /// woke up.
+    ///
+    /// The sockets are rebound on the same port, the DERP connections no longer usable are
+    /// reconnected, the paths to all peers are rediscovered and our endpoints are refreshed.
+    /// QUIC only sees the mapped addresses of the peers, so its connections survive the change.
+    async fn handle_network_change(&mut self, change: NetworkChange) {
+        info!("network changed ({:?}), rebinding", change);
+        self.rebind_all().await;
+        self.re_stun("link-change").await;
+    }
+
     #[instrument(skip_all, fields(self.name = %self.conn.name))]
     async fn rebind_all(&mut self) {
         inc!(MagicsockMetrics, rebind_calls);
@@ -1784,7 +1863,13 @@ impl Actor {
             return;
         }
 
-        let ifs = Default::default(); // TODO: load actual interfaces from the monitor
+        let ifs = interfaces::State::new()
+            .await
+            .interface_ips
+            .values()
+            .flatten()
+            .map(|net| net.addr())
+            .collect();
         self.send_derp_actor(DerpActorMessage::MaybeCloseDerpsOnRebind(ifs));
         self.reset_endpoint_states();
     }
@@ -2578,8 +2663,15 @@ pub(crate) mod tests {
     use super::*;
     use crate::{
         derp::{DerpNode, DerpRegion, UseIpv4, UseIpv6},
-        stun, tls, MagicEndpoint,
+        dialer::Dialer,
+        magic_endpoint::{
+            AddrSource, CongestionController, ConnectError, ConnectionEvent, ConnectionPath,
+        },
+        stun,
+        tls::{self, Keypair, PeerId},
+        MagicEndpoint,
     };
+    use tokio_util::sync::CancellationToken;
 
     fn make_transmit(destination: SocketAddr) -> quinn_udp::Transmit {
         quinn_udp::Transmit {
@@ -3129,6 +3221,467 @@ pub(crate) mod tests {
         Ok(())
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_connection_events() -> Result<()> {
+        use futures::StreamExt;
+
+        setup_logging();
+
+        let (derp_map, region, cleanup) = run_derp_and_stun("127.0.0.1".parse()?).await?;
+        let m1 = MagicStack::new(derp_map.clone()).await?;
+        let m2 = MagicStack::new(derp_map.clone()).await?;
+        let cleanup_mesh = mesh_stacks(vec![m1.clone(), m2.clone()]).await?;
+        time::timeout(Duration::from_secs(10), async {
+            while !m1.tracked_endpoints().await.contains(&m2.public()) {
+                time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .context("failed to connect peers")?;
+
+        let mut events = m1.endpoint.subscribe();
+        let mut accept_events = m2.endpoint.subscribe();
+        let m2_peer_id = m2.endpoint.peer_id();
+        let accept = tokio::spawn({
+            let endpoint = m2.endpoint.clone();
+            async move {
+                let connecting = endpoint.accept().await.context("no conn")?;
+                let (peer_id, _, conn) = endpoint.accept_conn(connecting).await?;
+                conn.closed().await;
+                anyhow::Ok(peer_id)
+            }
+        });
+        let conn = m1.endpoint.connect(m2_peer_id, &ALPN, region, &[]).await?;
+
+        async fn next(
+            events: &mut (impl futures::Stream<Item = ConnectionEvent> + Unpin),
+        ) -> Result<ConnectionEvent> {
+            time::timeout(Duration::from_secs(10), events.next())
+                .await
+                .context("no event")?
+                .context("stream ended")
+        }
+        assert!(
+            matches!(next(&mut events).await?, ConnectionEvent::Connected { peer } if peer == m2_peer_id)
+        );
+        match next(&mut events).await? {
+            ConnectionEvent::PathChanged { peer, path } => {
+                assert_eq!(peer, m2_peer_id);
+                if let ConnectionPath::Derp(r) = path {
+                    assert_eq!(Some(r), region);
+                }
+            }
+            event => anyhow::bail!("unexpected event {event:?}"),
+        }
+        let info = m1
+            .endpoint
+            .connection_info(m2_peer_id)
+            .await?
+            .context("no connection info")?;
+        assert!(info.path.is_some());
+        let infos = m1.endpoint.connection_infos().await?;
+        assert_eq!(infos.len(), 1);
+        assert_eq!(infos[0].peer_id, m2_peer_id);
+
+        conn.close(0u32.into(), b"done");
+        loop {
+            if let ConnectionEvent::Closed { peer, reason } = next(&mut events).await? {
+                assert_eq!(peer, m2_peer_id);
+                assert_eq!(reason, quinn::ConnectionError::LocallyClosed);
+                break;
+            }
+        }
+        assert_eq!(accept.await??, m1.endpoint.peer_id());
+        assert!(matches!(
+            next(&mut accept_events).await?,
+            ConnectionEvent::Connected { .. }
+        ));
+
+        cleanup().await;
+        cleanup_mesh();
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_local_discovery_connect() -> Result<()> {
+        setup_logging();
+
+        let bind = || {
+            MagicEndpoint::builder()
+                .alpns(vec![ALPN.to_vec()])
+                .local_discovery(true)
+                .bind(0)
+        };
+        let ep1 = bind().await?;
+        let ep2 = bind().await?;
+        let accept = tokio::spawn({
+            let ep2 = ep2.clone();
+            async move {
+                let connecting = ep2.accept().await.context("no conn")?;
+                let (peer_id, _, _conn) = ep2.accept_conn(connecting).await?;
+                anyhow::Ok(peer_id)
+            }
+        });
+
+        // Neither addresses nor a DERP region are given, the peer has to be discovered.
+        let conn = time::timeout(Duration::from_secs(10), async {
+            loop {
+                match ep1.connect(ep2.peer_id(), &ALPN, None, &[]).await {
+                    Ok(conn) => break conn,
+                    Err(_) => time::sleep(Duration::from_millis(100)).await,
+                }
+            }
+        })
+        .await
+        .context("failed to discover peer")?;
+        assert_eq!(accept.await??, ep1.peer_id());
+
+        conn.close(0u32.into(), b"done");
+        ep1.close(0u32.into(), b"done").await?;
+        ep2.close(0u32.into(), b"done").await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_discovery_connect() -> Result<()> {
+        use crate::discovery::{Discovery, PeerAddr};
+
+        /// Peers published to one [`TestDiscovery`] are resolved by all its clones.
+        #[derive(Debug, Clone, Default)]
+        struct TestDiscovery(Arc<std::sync::Mutex<HashMap<PeerId, PeerAddr>>>);
+
+        impl Discovery for TestDiscovery {
+            fn publish(&self, info: &PeerAddr) {
+                self.0.lock().unwrap().insert(info.peer_id, info.clone());
+            }
+
+            fn resolve(&self, peer_id: PeerId) -> BoxFuture<'_, Result<PeerAddr>> {
+                let info = self.0.lock().unwrap().get(&peer_id).cloned();
+                Box::pin(async move { info.context("not published") })
+            }
+        }
+
+        setup_logging();
+
+        let discovery = TestDiscovery::default();
+        let bind = || {
+            MagicEndpoint::builder()
+                .alpns(vec![ALPN.to_vec()])
+                .discovery(Box::new(discovery.clone()))
+                .bind(0)
+        };
+        let ep1 = bind().await?;
+        let ep2 = bind().await?;
+        let accept = tokio::spawn({
+            let ep2 = ep2.clone();
+            async move {
+                let mut peers = Vec::new();
+                let mut conns = Vec::new();
+                for _ in 0..3 {
+                    let connecting = ep2.accept().await.context("no conn")?;
+                    let (peer_id, _, conn) = ep2.accept_conn(connecting).await?;
+                    peers.push(peer_id);
+                    conns.push(conn);
+                }
+                anyhow::Ok(peers)
+            }
+        });
+
+        // Neither addresses nor a DERP region are given, the peer has to be resolved.
+        let conn = time::timeout(Duration::from_secs(10), async {
+            loop {
+                match ep1.connect(ep2.peer_id(), &ALPN, None, &[]).await {
+                    Ok(conn) => break conn,
+                    Err(_) => time::sleep(Duration::from_millis(100)).await,
+                }
+            }
+        })
+        .await
+        .context("failed to resolve peer")?;
+
+        let ep3 = bind().await?;
+        let (conn3, source) = ep3.connect_by_id(ep2.peer_id(), &ALPN).await?;
+        assert_eq!(source, AddrSource::Discovery);
+        let (conn3b, source) = ep3.connect_by_id(ep2.peer_id(), &ALPN).await?;
+        assert_eq!(source, AddrSource::AddressBook);
+        assert_eq!(
+            accept.await??,
+            vec![ep1.peer_id(), ep3.peer_id(), ep3.peer_id()]
+        );
+
+        for conn in [conn, conn3, conn3b] {
+            conn.close(0u32.into(), b"done");
+        }
+        for ep in [ep1, ep2, ep3] {
+            ep.close(0u32.into(), b"done").await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_discovered_then_connect_with_region() -> Result<()> {
+        setup_logging();
+
+        let (derp_map, region, cleanup) = run_derp_and_stun("127.0.0.1".parse()?).await?;
+        let m1 = MagicStack::new(derp_map.clone()).await?;
+        let m2 = MagicStack::new(derp_map).await?;
+        let accept = tokio::spawn({
+            let endpoint = m2.endpoint.clone();
+            async move {
+                let connecting = endpoint.accept().await.context("no conn")?;
+                let (peer_id, _, _conn) = endpoint.accept_conn(connecting).await?;
+                anyhow::Ok(peer_id)
+            }
+        });
+
+        // The peer was discovered with an address it can not be reached on, and without a
+        // DERP region. Connecting with the region has to use it.
+        let unreachable = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+        let m2_peer_id = m2.endpoint.peer_id();
+        m1.endpoint
+            .add_known_addrs(m2_peer_id, None, &[unreachable.local_addr()?])
+            .await?;
+        let conn = time::timeout(
+            Duration::from_secs(10),
+            m1.endpoint.connect(m2_peer_id, &ALPN, region, &[]),
+        )
+        .await
+        .context("failed to connect through the DERP region")??;
+        assert_eq!(accept.await??, m1.endpoint.peer_id());
+
+        conn.close(0u32.into(), b"done");
+        cleanup().await;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_shared_derp_connect() -> Result<()> {
+        setup_logging();
+
+        let (derp_map, region, cleanup) = run_derp_and_stun("127.0.0.1".parse()?).await?;
+        let shared_derp = SharedDerp::new(derp_map.clone());
+        let bind = || async {
+            let (on_derp_s, mut on_derp_r) = mpsc::channel(8);
+            let ep = MagicEndpoint::builder()
+                .alpns(vec![ALPN.to_vec()])
+                .derp_map(Some(derp_map.clone()))
+                .shared_derp(shared_derp.clone())
+                .on_derp_active(Box::new(move || {
+                    on_derp_s.try_send(()).ok();
+                }))
+                .bind(0)
+                .await?;
+            time::timeout(Duration::from_secs(10), on_derp_r.recv())
+                .await
+                .context("wait for derp connection")?;
+            anyhow::Ok(ep)
+        };
+        let ep1 = bind().await?;
+        let ep2 = bind().await?;
+        assert_eq!(shared_derp.num_connections().await, 1);
+
+        let accept = tokio::spawn({
+            let ep2 = ep2.clone();
+            async move {
+                let connecting = ep2.accept().await.context("no conn")?;
+                let (peer_id, _, conn) = ep2.accept_conn(connecting).await?;
+                let (mut send, mut recv) = conn.accept_bi().await?;
+                tokio::io::copy(&mut recv, &mut send).await?;
+                send.finish().await?;
+                conn.closed().await;
+                anyhow::Ok(peer_id)
+            }
+        });
+        // Only the DERP region is given, the packets are relayed through the shared connection.
+        let conn = ep1.connect(ep2.peer_id(), &ALPN, region, &[]).await?;
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(b"hello").await?;
+        send.finish().await?;
+        assert_eq!(recv.read_to_end(1024).await?, b"hello");
+        conn.close(0u32.into(), b"done");
+        assert_eq!(accept.await??, ep1.peer_id());
+
+        for ep in [ep1, ep2] {
+            ep.close(0u32.into(), b"done").await?;
+        }
+        time::timeout(Duration::from_secs(10), async {
+            while shared_derp.num_connections().await > 0 {
+                time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .context("shared connection not closed")?;
+
+        cleanup().await;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_stun_only() -> Result<()> {
+        setup_logging();
+
+        let (stun_addr, _, stun_cleanup) = stun::test::serve("127.0.0.1".parse()?).await?;
+        let mut derp_map = DerpMap::default();
+        derp_map.add_stun_servers(1, [stun_addr]);
+        assert!(derp_map.derp_region_ids().is_empty());
+
+        let ep = MagicEndpoint::builder()
+            .derp_map(Some(derp_map))
+            .bind(0)
+            .await?;
+        time::timeout(Duration::from_secs(10), async {
+            loop {
+                let endpoints = ep.local_endpoints().await?;
+                if endpoints
+                    .iter()
+                    .any(|ep| ep.typ == config::EndpointType::Stun)
+                {
+                    break anyhow::Ok(());
+                }
+                time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .context("no STUN endpoint")??;
+        // The STUN-only region is not used as a home.
+        assert_eq!(ep.my_derp().await, None);
+
+        ep.close(0u32.into(), b"done").await?;
+        stun_cleanup.send(()).ok();
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_transport_tuning() -> Result<()> {
+        setup_logging();
+
+        let bind = || {
+            MagicEndpoint::builder()
+                .alpns(vec![ALPN.to_vec()])
+                .congestion_controller(CongestionController::Bbr)
+                .stream_receive_window(4 * 1024 * 1024)
+                .receive_window(16 * 1024 * 1024)
+                .send_window(16 * 1024 * 1024)
+                .keep_alive_interval(Some(Duration::from_millis(500)))
+                .max_idle_timeout(Some(Duration::from_secs(5)))
+                .bind(0)
+        };
+        let ep1 = bind().await?;
+        let ep2 = bind().await?;
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), ep2.local_addr()?.0.port());
+        let accept = tokio::spawn({
+            let ep2 = ep2.clone();
+            async move {
+                let connecting = ep2.accept().await.context("no conn")?;
+                let (_, _, conn) = ep2.accept_conn(connecting).await?;
+                let mut recv = conn.accept_uni().await?;
+                anyhow::Ok(recv.read_to_end(1024 * 1024).await?)
+            }
+        });
+        let conn = ep1.connect(ep2.peer_id(), &ALPN, None, &[addr]).await?;
+        let mut send = conn.open_uni().await?;
+        send.write_all(&[7u8; 100_000]).await?;
+        send.finish().await?;
+        assert_eq!(accept.await??, vec![7u8; 100_000]);
+
+        // QUIC limits the idle timeout to 2^62 milliseconds.
+        let res = MagicEndpoint::builder()
+            .max_idle_timeout(Some(Duration::from_secs(u64::MAX)))
+            .bind(0)
+            .await;
+        assert!(res.is_err());
+
+        ep1.close(0u32.into(), b"done").await?;
+        ep2.close(0u32.into(), b"done").await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_connect_errors() -> Result<()> {
+        setup_logging();
+
+        let ep = MagicEndpoint::builder()
+            .connect_timeout(Duration::from_millis(500))
+            .bind(0)
+            .await?;
+        let offline: PeerId = Keypair::generate().public().into();
+
+        let res = ep.connect(offline, &ALPN, None, &[]).await;
+        assert!(matches!(res, Err(ConnectError::NoRoute(_))), "{res:?}");
+
+        // Nobody answers on this socket.
+        let blackhole = net::UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = blackhole.local_addr()?;
+        let res = ep.connect(offline, &ALPN, None, &[addr]).await;
+        assert!(matches!(res, Err(ConnectError::Timeout)), "{res:?}");
+
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let res = ep
+            .connect_with_cancel(offline, &ALPN, None, &[addr], &cancel)
+            .await;
+        assert!(matches!(res, Err(ConnectError::Cancelled)), "{res:?}");
+
+        ep.close(0u32.into(), b"done").await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_dialer_reuses_connections() -> Result<()> {
+        setup_logging();
+
+        let bind = || MagicEndpoint::builder().alpns(vec![ALPN.to_vec()]).bind(0);
+        let ep1 = bind().await?;
+        let ep2 = bind().await?;
+        let accept = tokio::spawn({
+            let ep2 = ep2.clone();
+            async move {
+                let mut conns = Vec::new();
+                while let Some(connecting) = ep2.accept().await {
+                    let (_, _, conn) = ep2.accept_conn(connecting).await?;
+                    conns.push(conn);
+                }
+                anyhow::Ok(())
+            }
+        });
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), ep2.local_addr()?.0.port());
+        ep1.add_known_addrs(ep2.peer_id(), None, &[addr]).await?;
+
+        let dialer = Dialer::with_limit(ep1.clone(), 1);
+        let peer_id = ep2.peer_id();
+
+        // A cancelled request does not abort the dial.
+        let res = time::timeout(Duration::ZERO, dialer.connect(peer_id, &ALPN)).await;
+        assert!(res.is_err());
+        assert!(dialer.is_pending(peer_id, &ALPN));
+
+        let dialer2 = dialer.clone();
+        let (conn1, conn2) = tokio::join!(
+            dialer.connect(peer_id, &ALPN),
+            dialer2.connect(peer_id, &ALPN)
+        );
+        let (conn1, conn2) = (conn1?, conn2?);
+        assert_eq!(conn1.stable_id(), conn2.stable_id());
+        assert!(!dialer.is_pending(peer_id, &ALPN));
+
+        let conn3 = dialer.connect(peer_id, &ALPN).await?;
+        assert_eq!(conn1.stable_id(), conn3.stable_id());
+
+        // A closed connection is replaced.
+        conn1.close(0u32.into(), b"done");
+        let conn4 = dialer.connect(peer_id, &ALPN).await?;
+        assert_ne!(conn1.stable_id(), conn4.stable_id());
+
+        let offline: PeerId = Keypair::generate().public().into();
+        assert!(dialer.connect(offline, &ALPN).await.is_err());
+        assert!(!dialer.is_pending(offline, &ALPN));
+
+        ep1.close(0u32.into(), b"done").await?;
+        ep2.close(0u32.into(), b"done").await?;
+        accept.abort();
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_two_devices_roundtrip_quinn_raw() -> Result<()> {
         setup_logging();
@@ -18,7 +18,7 @@ use crate::{
 };
 
 use super::Metrics as MagicsockMetrics;
-use super::{ActorMessage, Inner};
+use super::{shared_derp::DerpConn, ActorMessage, Inner};
 
 /// How long a non-home DERP connection needs to be idle (last written to) before we close it.
 const DERP_INACTIVE_CLEANUP_TIME: Duration = Duration::from_secs(60);
@@ -49,7 +49,7 @@ pub(super) enum DerpActorMessage {
 /// Contains fields for an active DERP connection.
 #[derive(Debug)]
 struct ActiveDerp {
-    c: derp::http::Client,
+    c: DerpConn,
     cancel: CancellationToken,
     /// The time of the last request for its write
     /// channel (currently even if there was no write).
@@ -64,7 +64,7 @@ struct ActiveDerp {
 #[derive(Debug)]
 struct DerpRoute {
     derp_id: u16,
-    dc: derp::http::Client, // don't use directly; see comment above
+    dc: DerpConn, // don't use directly; see comment above
 }
 
 pub(super) struct DerpActor {
@@ -293,7 +293,7 @@ impl DerpActor {
         &mut self,
         region_id: u16,
         peer: Option<&key::node::PublicKey>,
-    ) -> derp::http::Client {
+    ) -> DerpConn {
         // See if we have a connection open to that DERP node ID first. If so, might as
         // well use it. (It's a little arbitrary whether we use this one vs. the reverse route
         // below when we have both.)
@@ -327,30 +327,18 @@ impl DerpActor {
         };
         info!("adding connection to derp-{region_id} for {why}");
 
-        let my_derp = self.conn.my_derp();
-        let conn1 = self.conn.clone();
-        let ipv6_reported = self.conn.ipv6_reported.clone();
-
-        // building a client does not dial
-        let dc = derp::http::ClientBuilder::new()
-            .address_family_selector(move || {
-                let ipv6_reported = ipv6_reported.clone();
-                Box::pin(async move { ipv6_reported.load(Ordering::Relaxed) })
-            })
-            .can_ack_pings(true)
-            .is_preferred(my_derp == region_id)
-            .get_region(move || {
-                let conn = conn1.clone();
-                Box::pin(async move {
-                    if conn.is_closing() {
-                        // We're closing anyway; return to stop dialing.
-                        return None;
-                    }
-                    conn.get_derp_region(region_id).await
-                })
-            })
-            .build(self.conn.private_key.clone())
-            .expect("will only fail is a `get_region` callback is not supplied");
+        let shared = match self.conn.shared_derp {
+            Some(ref shared_derp) => {
+                shared_derp
+                    .client(region_id, self.conn.private_key.clone())
+                    .await
+            }
+            None => None,
+        };
+        let dc = match shared {
+            Some(client) => DerpConn::Shared(client),
+            None => DerpConn::Own(self.build_derp_client(region_id)),
+        };
 
         let cancel = CancellationToken::new();
         let ad = ActiveDerp {
@@ -386,6 +374,34 @@ impl DerpActor {
         dc
     }
 
+    /// Builds a DERP client of our own for the given derp region.
+    fn build_derp_client(&self, region_id: u16) -> derp::http::Client {
+        let my_derp = self.conn.my_derp();
+        let conn1 = self.conn.clone();
+        let ipv6_reported = self.conn.ipv6_reported.clone();
+
+        // building a client does not dial
+        derp::http::ClientBuilder::new()
+            .address_family_selector(move || {
+                let ipv6_reported = ipv6_reported.clone();
+                Box::pin(async move { ipv6_reported.load(Ordering::Relaxed) })
+            })
+            .can_ack_pings(true)
+            .is_preferred(my_derp == region_id)
+            .get_region(move || {
+                let conn = conn1.clone();
+                Box::pin(async move {
+                    if conn.is_closing() {
+                        // We're closing anyway; return to stop dialing.
+                        return None;
+                    }
+                    conn.get_derp_region(region_id).await
+                })
+            })
+            .build(self.conn.private_key.clone())
+            .expect("will only fail is a `get_region` callback is not supplied")
+    }
+
     /// Called in response to a rebind, closes all DERP connections that don't have a local address in okay_local_ips
     /// and pings all those that do.
     async fn maybe_close_derps_on_rebind(&mut self, okay_local_ips: &[IpAddr]) {
@@ -526,7 +542,7 @@ impl DerpActor {
         &mut self,
         peers: Vec<key::node::PublicKey>,
         derp_id: u16,
-        dc: &derp::http::Client,
+        dc: &DerpConn,
     ) {
         for peer in peers {
             if let hash_map::Entry::Occupied(r) = self.derp_route.entry(peer) {
@@ -543,7 +559,7 @@ impl DerpActor {
         &mut self,
         peers: Vec<key::node::PublicKey>,
         derp_id: u16,
-        dc: derp::http::Client,
+        dc: DerpConn,
     ) {
         for peer in peers {
             self.derp_route.insert(
@@ -570,7 +586,7 @@ pub(super) struct DerpReadResult {
 #[derive(Debug)]
 struct ReaderState {
     region: u16,
-    derp_client: derp::http::Client,
+    derp_client: DerpConn,
     /// The set of senders we know are present on this connection, based on
     /// messages we've received from the server.
     peer_present: HashSet<key::node::PublicKey>,
@@ -593,17 +609,17 @@ pub(super) enum ReadAction {
     RemovePeerRoutes {
         peers: Vec<key::node::PublicKey>,
         region: u16,
-        derp_client: derp::http::Client,
+        derp_client: DerpConn,
     },
     AddPeerRoutes {
         peers: Vec<key::node::PublicKey>,
         region: u16,
-        derp_client: derp::http::Client,
+        derp_client: DerpConn,
     },
 }
 
 impl ReaderState {
-    fn new(region: u16, cancel: CancellationToken, derp_client: derp::http::Client) -> Self {
+    fn new(region: u16, cancel: CancellationToken, derp_client: DerpConn) -> Self {
         ReaderState {
             region,
             derp_client,
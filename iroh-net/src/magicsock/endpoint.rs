@@ -7,13 +7,14 @@ use std::{
 };
 
 use futures::future::BoxFuture;
+use iroh_metrics::inc;
 use rand::seq::IteratorRandom;
 use tokio::{sync::mpsc, time::Instant};
 use tracing::{debug, info, trace, warn};
 
 use crate::{config, disco, key, magicsock::Timer, net::ip::is_unicast_link_local, stun};
 
-use super::{ActorMessage, DiscoInfo, QuicMappedAddr, SendAddr};
+use super::{ActorMessage, DiscoInfo, Metrics as MagicsockMetrics, QuicMappedAddr, SendAddr};
 
 /// How long we wait for a pong reply before assuming it's never coming.
 const PING_TIMEOUT_DURATION: Duration = Duration::from_secs(5);
@@ -129,13 +130,26 @@ impl Endpoint {
                 _ => None,
             })
             .collect();
+        let direct_addr = self
+            .best_addr
+            .as_ref()
+            .filter(|_| self.is_best_addr_valid(Instant::now()))
+            .map(|a| a.addr);
+        let rtt = direct_addr
+            .map(SendAddr::Udp)
+            .or(self.derp_addr.map(SendAddr::Derp))
+            .and_then(|addr| self.endpoint_state.get(&addr))
+            .and_then(|state| state.average_latency());
 
         EndpointInfo {
             public_key: self.public_key.clone(),
             derp_addr: self.derp_addr,
             addrs,
-            has_direct_connection: self.is_best_addr_valid(Instant::now()),
+            has_direct_connection: direct_addr.is_some(),
+            direct_addr,
             latency: self.best_addr.as_ref().and_then(|a| a.latency),
+            rtt,
+            last_active: self.last_active.into_std(),
         }
     }
 
@@ -467,6 +481,9 @@ impl Endpoint {
                 // message to our peer via DERP informing them that we've
                 // sent so our firewall ports are probably open and now
                 // would be a good time for them to connect.
+                if self.best_addr.is_none() {
+                    inc!(MagicsockMetrics, holepunch_attempts);
+                }
                 let id = self.id;
                 let sender = self.conn_sender.clone();
                 if let Err(err) = sender
@@ -709,6 +726,9 @@ impl Endpoint {
 
                     if is_better {
                         info!("disco: node {:?} now using {:?}", self.public_key, sp.to);
+                        if self.best_addr.is_none() {
+                            inc!(MagicsockMetrics, holepunch_success);
+                        }
                         self.best_addr.replace(this_pong.clone());
                     }
                     let best_addr = self.best_addr.as_mut().expect("just set");
@@ -1046,8 +1066,14 @@ pub struct EndpointInfo {
     pub addrs: Vec<SocketAddr>,
     /// Is this node currently direcly reachable?
     pub has_direct_connection: bool,
+    /// The address the node is directly reached at, if it is.
+    pub direct_addr: Option<SocketAddr>,
     /// Current latency information, for a direct connection if available.
     pub latency: Option<Duration>,
+    /// Average latency of the recent pings on the path currently in use.
+    pub rtt: Option<Duration>,
+    /// Last time data was sent to this node.
+    pub last_active: std::time::Instant,
 }
 
 #[derive(Default, Debug, PartialEq, Eq, Clone, Copy, Hash)]
@@ -1087,6 +1113,15 @@ impl EndpointState {
         self.last_got_ping.as_ref().unwrap().elapsed() > SESSION_ACTIVE_TIMEOUT
     }
 
+    /// Returns the average latency of the recent pongs.
+    fn average_latency(&self) -> Option<Duration> {
+        let n = u32::try_from(self.recent_pongs.len())
+            .ok()
+            .filter(|n| *n > 0)?;
+        let total: Duration = self.recent_pongs.iter().map(|pong| pong.latency).sum();
+        Some(total / n)
+    }
+
     /// Returns the most recent pong if available.
     fn recent_pong(&self) -> Option<&PongReply> {
         self.recent_pongs.get(self.recent_pong)
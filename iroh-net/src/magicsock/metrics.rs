@@ -55,6 +55,11 @@ pub struct Metrics {
 
     // How many times our DERP home region DI has changed from non-zero to a different non-zero.
     pub derp_home_change: Counter,
+
+    // Hole punching: CallMeMaybe sent to peers without a direct path, and direct paths
+    // established to peers which had none.
+    pub holepunch_attempts: Counter,
+    pub holepunch_success: Counter,
 }
 
 impl Default for Metrics {
@@ -108,6 +113,9 @@ impl Default for Metrics {
 
             // How many times our DERP home region DI has changed from non-zero to a different non-zero.
             derp_home_change: Counter::new("derp_home_change"),
+
+            holepunch_attempts: Counter::new("holepunch_attempts"),
+            holepunch_success: Counter::new("holepunch_success"),
         }
     }
 }
@@ -0,0 +1,380 @@
+//! DERP connections shared by several [`super::MagicSock`]s.
+//!
+//! Every [`super::MagicSock`] normally keeps its own connection to each DERP region it
+//! talks to. Processes which run many endpoints can instead share one connection per
+//! region between them: the connection is made with a key of its own, and the keys of the
+//! endpoints are registered on it, see [`crate::derp::client::Client::add_key`]. Packets
+//! for the registered keys are demultiplexed to the endpoints by their destination key.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use backoff::backoff::Backoff;
+use bytes::Bytes;
+use tokio::sync::mpsc;
+use tracing::{debug, info, info_span, warn, Instrument};
+
+use crate::{
+    derp::{
+        http::{self, ClientError},
+        DerpMap, ReceivedMessage,
+    },
+    key::node::{PublicKey, SecretKey},
+    util::AbortingJoinHandle,
+};
+
+/// Capacity of the channel of received messages of each endpoint.
+///
+/// Packets for an endpoint which does not keep up are dropped, like on a congested network.
+const RECV_CHANNEL_CAPACITY: usize = 128;
+
+type RecvResult = Result<(ReceivedMessage, usize), ClientError>;
+
+/// One connection per DERP region, shared by all [`super::MagicSock`]s it is passed to.
+///
+/// The regions are looked up in the [`DerpMap`] given to [`SharedDerp::new`], regions the
+/// map does not contain are connected to by each endpoint on its own. Connections are made
+/// when the first endpoint needs a region and closed when the last one no longer does.
+///
+/// Can be cheaply cloned, all clones share the same connections.
+#[derive(Clone)]
+pub struct SharedDerp {
+    inner: Arc<SharedDerpInner>,
+}
+
+struct SharedDerpInner {
+    derp_map: DerpMap,
+    /// The key the shared connections are made with.
+    secret_key: SecretKey,
+    regions: tokio::sync::Mutex<HashMap<u16, Arc<SharedRegion>>>,
+}
+
+impl fmt::Debug for SharedDerp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedDerp")
+            .field("public_key", &self.inner.secret_key.public_key())
+            .finish()
+    }
+}
+
+/// The shared connection to a region.
+struct SharedRegion {
+    region_id: u16,
+    client: http::Client,
+    subscribers: Arc<Mutex<HashMap<PublicKey, Subscriber>>>,
+    _reader: AbortingJoinHandle<()>,
+}
+
+struct Subscriber {
+    sender: mpsc::Sender<RecvResult>,
+    preferred: bool,
+}
+
+impl SharedDerp {
+    /// Creates connections to the regions of `derp_map` on demand.
+    pub fn new(derp_map: DerpMap) -> Self {
+        SharedDerp {
+            inner: Arc::new(SharedDerpInner {
+                derp_map,
+                secret_key: SecretKey::generate(),
+                regions: Default::default(),
+            }),
+        }
+    }
+
+    /// Registers `secret_key` on the connection to `region_id`, creating the connection if
+    /// it does not exist yet.
+    ///
+    /// Returns `None` if the region is not part of the shared [`DerpMap`].
+    pub(super) async fn client(
+        &self,
+        region_id: u16,
+        secret_key: SecretKey,
+    ) -> Option<SharedDerpClient> {
+        let derp_region = self.inner.derp_map.regions.get(&region_id)?.clone();
+        let key = secret_key.public_key();
+        let mut regions = self.inner.regions.lock().await;
+        let region = regions
+            .entry(region_id)
+            .or_insert_with(|| {
+                let client = http::ClientBuilder::new()
+                    .can_ack_pings(true)
+                    .get_region(move || {
+                        let derp_region = derp_region.clone();
+                        Box::pin(async move { Some(derp_region) })
+                    })
+                    .build(self.inner.secret_key.clone())
+                    .expect("will only fail is a `get_region` callback is not supplied");
+                let subscribers: Arc<Mutex<HashMap<_, Subscriber>>> = Default::default();
+                let reader = tokio::task::spawn(
+                    read_loop(client.clone(), subscribers.clone())
+                        .instrument(info_span!(parent: None, "shared-derp", region_id)),
+                );
+                info!("adding shared connection to derp-{region_id}");
+                Arc::new(SharedRegion {
+                    region_id,
+                    client,
+                    subscribers,
+                    _reader: reader.into(),
+                })
+            })
+            .clone();
+
+        let (sender, receiver) = mpsc::channel(RECV_CHANNEL_CAPACITY);
+        region.subscribers.lock().unwrap().insert(
+            key.clone(),
+            Subscriber {
+                sender,
+                preferred: false,
+            },
+        );
+        if let Err(err) = region.client.add_key(secret_key).await {
+            // registered again once the connection is re-established
+            warn!("failed to add key to derp-{region_id}: {err:?}");
+        }
+        Some(SharedDerpClient {
+            shared: self.clone(),
+            region,
+            key,
+            receiver: Arc::new(tokio::sync::Mutex::new(receiver)),
+        })
+    }
+
+    /// The number of regions with an open shared connection.
+    #[cfg(test)]
+    pub(super) async fn num_connections(&self) -> usize {
+        self.inner.regions.lock().await.len()
+    }
+
+    async fn release(&self, region_id: u16, key: &PublicKey) {
+        let mut regions = self.inner.regions.lock().await;
+        let Some(region) = regions.get(&region_id) else {
+            return;
+        };
+        let empty = {
+            let mut subscribers = region.subscribers.lock().unwrap();
+            subscribers.remove(key);
+            subscribers.is_empty()
+        };
+        if empty {
+            info!("closing shared connection to derp-{region_id}");
+            if let Some(region) = regions.remove(&region_id) {
+                region.client.clone().close().await;
+            }
+        } else if let Err(err) = region.client.remove_key(key).await {
+            warn!("failed to remove key from derp-{region_id}: {err:?}");
+        }
+    }
+}
+
+/// Reads from the shared connection and dispatches the messages to the endpoints.
+async fn read_loop(client: http::Client, subscribers: Arc<Mutex<HashMap<PublicKey, Subscriber>>>) {
+    let mut backoff =
+        backoff::exponential::ExponentialBackoffBuilder::<backoff::SystemClock>::new()
+            .with_initial_interval(Duration::from_millis(10))
+            .with_max_interval(Duration::from_secs(5))
+            .with_max_elapsed_time(None)
+            .build();
+    loop {
+        match client.recv_detail().await {
+            Ok((
+                ReceivedMessage::ReceivedPacketFor {
+                    destination,
+                    source,
+                    data,
+                },
+                conn_gen,
+            )) => {
+                backoff.reset();
+                let subscribers = subscribers.lock().unwrap();
+                if let Some(subscriber) = subscribers.get(&destination) {
+                    let msg = ReceivedMessage::ReceivedPacket { source, data };
+                    subscriber.sender.try_send(Ok((msg, conn_gen))).ok();
+                }
+            }
+            Ok((ReceivedMessage::Ping(data), _)) => {
+                backoff.reset();
+                let client = client.clone();
+                tokio::task::spawn(async move {
+                    if let Err(err) = client.send_pong(data).await {
+                        info!("send_pong error: {err:?}");
+                    }
+                });
+            }
+            Ok((ReceivedMessage::ReceivedPacket { source, .. }, _)) => {
+                backoff.reset();
+                debug!("dropping packet from {source:?} for the shared connection key");
+            }
+            Ok((msg, conn_gen)) => {
+                backoff.reset();
+                for subscriber in subscribers.lock().unwrap().values() {
+                    subscriber.sender.try_send(Ok((msg.clone(), conn_gen))).ok();
+                }
+            }
+            Err(err) => {
+                let closed = matches!(err, ClientError::Closed | ClientError::IPDisabled);
+                debug!("recv error: {err:?}");
+                for subscriber in subscribers.lock().unwrap().values() {
+                    let err = if closed {
+                        ClientError::Closed
+                    } else {
+                        ClientError::Receive
+                    };
+                    subscriber.sender.try_send(Err(err)).ok();
+                }
+                if closed {
+                    break;
+                }
+                if let Some(t) = backoff.next_backoff() {
+                    tokio::time::sleep(t).await;
+                }
+            }
+        }
+    }
+}
+
+/// The lease of an endpoint on a shared DERP connection, used like a [`http::Client`].
+///
+/// Cheaply clonable, all clones use the same lease.
+#[derive(Clone)]
+pub(super) struct SharedDerpClient {
+    shared: SharedDerp,
+    region: Arc<SharedRegion>,
+    key: PublicKey,
+    receiver: Arc<tokio::sync::Mutex<mpsc::Receiver<RecvResult>>>,
+}
+
+impl fmt::Debug for SharedDerpClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedDerpClient")
+            .field("region_id", &self.region.region_id)
+            .field("key", &self.key)
+            .finish()
+    }
+}
+
+impl PartialEq for SharedDerpClient {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.region, &other.region) && self.key == other.key
+    }
+}
+
+impl Eq for SharedDerpClient {}
+
+impl SharedDerpClient {
+    pub(super) async fn connect(&self) -> Result<(), ClientError> {
+        self.region.client.connect().await.map(|_| ())
+    }
+
+    pub(super) async fn send(&self, dst_key: PublicKey, b: Bytes) -> Result<(), ClientError> {
+        self.region
+            .client
+            .send_from(self.key.clone(), dst_key, b)
+            .await
+    }
+
+    pub(super) async fn recv_detail(&self) -> RecvResult {
+        let mut receiver = self.receiver.lock().await;
+        receiver.recv().await.unwrap_or(Err(ClientError::Closed))
+    }
+
+    /// The shared connection is preferred as long as it is the home region of any endpoint.
+    pub(super) async fn note_preferred(&self, is_preferred: bool) {
+        let any_preferred = {
+            let mut subscribers = self.region.subscribers.lock().unwrap();
+            if let Some(subscriber) = subscribers.get_mut(&self.key) {
+                subscriber.preferred = is_preferred;
+            }
+            subscribers.values().any(|s| s.preferred)
+        };
+        self.region.client.note_preferred(any_preferred).await;
+    }
+
+    pub(super) async fn local_addr(&self) -> Option<SocketAddr> {
+        self.region.client.local_addr().await
+    }
+
+    pub(super) async fn ping(&self) -> Result<(), ClientError> {
+        self.region.client.ping().await
+    }
+
+    pub(super) async fn send_pong(&self, data: [u8; 8]) -> Result<(), ClientError> {
+        self.region.client.send_pong(data).await
+    }
+
+    /// Removes the key of the endpoint from the connection, closing the connection if no
+    /// other endpoint uses it.
+    pub(super) async fn close(self) {
+        self.shared.release(self.region.region_id, &self.key).await;
+    }
+}
+
+/// A DERP connection of a [`super::MagicSock`], either its own or a shared one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum DerpConn {
+    Own(http::Client),
+    Shared(SharedDerpClient),
+}
+
+impl DerpConn {
+    pub(super) async fn connect(&self) -> Result<(), ClientError> {
+        match self {
+            DerpConn::Own(c) => c.connect().await.map(|_| ()),
+            DerpConn::Shared(c) => c.connect().await,
+        }
+    }
+
+    pub(super) async fn send(&self, dst_key: PublicKey, b: Bytes) -> Result<(), ClientError> {
+        match self {
+            DerpConn::Own(c) => c.send(dst_key, b).await,
+            DerpConn::Shared(c) => c.send(dst_key, b).await,
+        }
+    }
+
+    pub(super) async fn recv_detail(&self) -> RecvResult {
+        match self {
+            DerpConn::Own(c) => c.recv_detail().await,
+            DerpConn::Shared(c) => c.recv_detail().await,
+        }
+    }
+
+    pub(super) async fn note_preferred(&self, is_preferred: bool) {
+        match self {
+            DerpConn::Own(c) => c.note_preferred(is_preferred).await,
+            DerpConn::Shared(c) => c.note_preferred(is_preferred).await,
+        }
+    }
+
+    pub(super) async fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            DerpConn::Own(c) => c.local_addr().await,
+            DerpConn::Shared(c) => c.local_addr().await,
+        }
+    }
+
+    pub(super) async fn ping(&self) -> Result<(), ClientError> {
+        match self {
+            DerpConn::Own(c) => c.ping().await,
+            DerpConn::Shared(c) => c.ping().await,
+        }
+    }
+
+    pub(super) async fn send_pong(&self, data: [u8; 8]) -> Result<(), ClientError> {
+        match self {
+            DerpConn::Own(c) => c.send_pong(data).await,
+            DerpConn::Shared(c) => c.send_pong(data).await,
+        }
+    }
+
+    pub(super) async fn close(self) {
+        match self {
+            DerpConn::Own(c) => c.close().await,
+            DerpConn::Shared(c) => c.close().await,
+        }
+    }
+}
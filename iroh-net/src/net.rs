@@ -2,3 +2,4 @@
 
 pub mod interfaces;
 pub mod ip;
+pub mod netmon;
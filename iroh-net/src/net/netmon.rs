@@ -0,0 +1,151 @@
+//! Monitoring of changes to the network configuration of the machine.
+//!
+//! The [`Monitor`] polls the [`State`] of the network interfaces and reports major
+//! changes, like a switch from Wi-Fi to Ethernet, as well as wake-ups from sleep, after
+//! which sockets and NAT mappings are likely stale.
+
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+use std::time::{Duration, Instant, SystemTime};
+
+use tokio::sync::mpsc;
+use tracing::{debug, trace};
+
+use super::interfaces::State;
+use crate::util::AbortingJoinHandle;
+
+/// How often the network state is polled.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How much more wall clock time than monotonic time has to pass between two polls for
+/// the machine to be considered to have slept.
+///
+/// The monotonic clock does not advance while the machine is suspended.
+const SLEEP_THRESHOLD: Duration = Duration::from_secs(10);
+
+/// A change of the network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetworkChange {
+    /// The default route or the addresses of the interfaces changed.
+    Interfaces,
+    /// The machine woke up from sleep.
+    WakeFromSleep,
+}
+
+/// Reports changes of the network configuration.
+///
+/// Polling stops when the monitor is dropped.
+#[derive(Debug)]
+pub struct Monitor {
+    changes: mpsc::Receiver<NetworkChange>,
+    _task: AbortingJoinHandle<()>,
+}
+
+impl Monitor {
+    /// Starts monitoring the network.
+    pub async fn new() -> Self {
+        let state = State::new().await;
+        // A pending change is enough, later changes are coalesced into it.
+        let (tx, changes) = mpsc::channel(1);
+        let task = tokio::spawn(poll(state, tx));
+        Self {
+            changes,
+            _task: task.into(),
+        }
+    }
+
+    /// Waits for the next change.
+    ///
+    /// Multiple changes happening before this is called are reported once.
+    pub async fn changed(&mut self) -> Option<NetworkChange> {
+        self.changes.recv().await
+    }
+}
+
+async fn poll(mut state: State, tx: mpsc::Sender<NetworkChange>) {
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    interval.tick().await;
+    let mut last_mono = Instant::now();
+    let mut last_wall = SystemTime::now();
+    loop {
+        interval.tick().await;
+        let mono_elapsed = last_mono.elapsed();
+        let wall_elapsed = last_wall.elapsed().unwrap_or_default();
+        last_mono = Instant::now();
+        last_wall = SystemTime::now();
+
+        let new_state = State::new().await;
+        let change = if wall_elapsed > mono_elapsed + SLEEP_THRESHOLD {
+            Some(NetworkChange::WakeFromSleep)
+        } else if is_major_change(&state, &new_state) {
+            Some(NetworkChange::Interfaces)
+        } else {
+            None
+        };
+        state = new_state;
+        if let Some(change) = change {
+            debug!("network changed: {change:?}");
+            if tx.try_send(change).is_err() && tx.is_closed() {
+                break;
+            }
+        } else {
+            trace!("network unchanged");
+        }
+    }
+}
+
+/// Whether the change from `old` to `new` breaks existing sockets or paths.
+///
+/// That is, whether the default route moved to another interface, or the usable addresses
+/// of the interfaces changed.
+pub fn is_major_change(old: &State, new: &State) -> bool {
+    old.default_route_interface != new.default_route_interface
+        || old.have_v4 != new.have_v4
+        || old.have_v6 != new.have_v6
+        || usable_ips(old) != usable_ips(new)
+}
+
+/// The addresses of the interfaces which are up, excluding loopback and link-local
+/// addresses.
+fn usable_ips(state: &State) -> BTreeSet<IpAddr> {
+    state
+        .interface
+        .iter()
+        .filter(|(_, iface)| iface.is_up() && !iface.is_loopback())
+        .filter_map(|(name, _)| state.interface_ips.get(name))
+        .flatten()
+        .map(|net| net.addr())
+        .filter(|ip| match ip {
+            IpAddr::V4(ip) => !ip.is_loopback() && !ip.is_link_local(),
+            IpAddr::V6(ip) => !ip.is_loopback() && (ip.segments()[0] & 0xffc0) != 0xfe80,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::super::interfaces::{IpNet, Ipv4Net};
+    use super::*;
+
+    #[test]
+    fn test_is_major_change() {
+        let old = State::fake();
+        assert!(!is_major_change(&old, &State::fake()));
+
+        let mut new = State::fake();
+        new.default_route_interface = Some("eth0".into());
+        assert!(is_major_change(&old, &new));
+
+        let mut new = State::fake();
+        for ips in new.interface_ips.values_mut() {
+            ips[0] = IpNet::V4(Ipv4Net {
+                addr: Ipv4Addr::new(10, 0, 0, 2),
+                prefix_len: 24,
+                netmask: Ipv4Addr::new(255, 255, 255, 0),
+            });
+        }
+        assert!(is_major_change(&old, &new));
+    }
+}
@@ -25,8 +25,10 @@ use super::stun;
 
 mod metrics;
 mod reportgen;
+mod throughput;
 
 pub use metrics::Metrics;
+pub use throughput::{probe_stun_server, probe_throughput, ThroughputProbe, ThroughputReport};
 use Metrics as NetcheckMetrics;
 
 const FULL_REPORT_INTERVAL: Duration = Duration::from_secs(5 * 60);
@@ -46,7 +46,8 @@ use crate::{portmapper, stun};
 mod hairpin;
 mod probes;
 
-use probes::{Probe, ProbePlan, ProbeProto};
+pub(super) use probes::ProbeProto;
+use probes::{Probe, ProbePlan};
 
 /// Fake DNS TLD used in tests for an invalid hostname.
 const DOT_INVALID: &str = ".invalid";
@@ -941,7 +942,7 @@ async fn check_captive_portal(dm: &DerpMap, preferred_derp: Option<u16>) -> Resu
 /// Returns the IP address to use to communicate to this derp node.
 ///
 /// *proto* specifies the protocol we want to use to talk to the node.
-async fn get_derp_addr(n: &DerpNode, proto: ProbeProto) -> Result<SocketAddr> {
+pub(super) async fn get_derp_addr(n: &DerpNode, proto: ProbeProto) -> Result<SocketAddr> {
     let mut port = n.stun_port;
     if port == 0 {
         port = DEFAULT_DERP_STUN_PORT;
@@ -46,7 +46,7 @@ const NUM_INCREMENTAL_REGIONS: usize = 3;
 /// The protocol used to time a node's latency.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, derive_more::Display)]
 #[repr(u8)]
-pub(super) enum ProbeProto {
+pub(in crate::netcheck) enum ProbeProto {
     /// STUN IPv4
     StunIpv4,
     /// STUN IPv6
@@ -0,0 +1,208 @@
+//! A short probe of the throughput, loss and jitter towards a DERP region.
+//!
+//! A train of padded STUN binding requests is sent back to back to the STUN server of
+//! the region. The spacing with which the responses arrive reflects the rate at which the
+//! bottleneck of the path forwarded the requests, which gives an estimate of the upstream
+//! throughput. Unanswered requests are counted as lost, and the variation of the round
+//! trip times gives the jitter.
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{Context, Result};
+use tokio::net::UdpSocket;
+use tokio::time::{Duration, Instant};
+use tracing::{debug, trace};
+
+use super::reportgen::{get_derp_addr, ProbeProto};
+use crate::derp::DerpMap;
+use crate::stun;
+
+/// Options for a throughput probe.
+#[derive(Debug, Clone)]
+pub struct ThroughputProbe {
+    /// Number of packets sent back to back.
+    pub packets: u32,
+    /// Padding added to each STUN request, capped at [`stun::MAX_PADDING`].
+    pub padding: usize,
+    /// How long to wait for responses after the last packet was sent.
+    pub timeout: Duration,
+}
+
+impl Default for ThroughputProbe {
+    fn default() -> Self {
+        Self {
+            packets: 64,
+            padding: stun::MAX_PADDING,
+            timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+/// The result of a throughput probe.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThroughputReport {
+    /// The STUN server that was probed.
+    pub addr: SocketAddr,
+    /// Size of the sent packets, in bytes of UDP payload.
+    pub packet_size: usize,
+    /// Number of packets sent.
+    pub sent: u32,
+    /// Number of packets that were answered.
+    pub received: u32,
+    /// Smallest round trip time.
+    pub rtt_min: Duration,
+    /// Average round trip time.
+    pub rtt_avg: Duration,
+    /// Largest round trip time.
+    pub rtt_max: Duration,
+    /// Mean difference between the round trip times of consecutive responses.
+    pub jitter: Duration,
+    /// Time between the first and the last response.
+    pub dispersion: Duration,
+}
+
+impl ThroughputReport {
+    /// The fraction of packets that were not answered, between 0 and 1.
+    pub fn loss(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        1.0 - self.received as f64 / self.sent as f64
+    }
+
+    /// The estimated upstream throughput in bytes per second.
+    ///
+    /// This is `None` if less than two responses were received, or if they arrived too
+    /// close together to measure.
+    pub fn throughput(&self) -> Option<f64> {
+        if self.received < 2 || self.dispersion.is_zero() {
+            return None;
+        }
+        let bytes = (self.received - 1) as f64 * self.packet_size as f64;
+        Some(bytes / self.dispersion.as_secs_f64())
+    }
+}
+
+/// Probes the throughput towards the STUN server of a DERP region, using IPv4.
+pub async fn probe_throughput(
+    derp_map: &DerpMap,
+    region_id: u16,
+    opts: &ThroughputProbe,
+) -> Result<ThroughputReport> {
+    let region = derp_map
+        .regions
+        .get(&region_id)
+        .with_context(|| format!("unknown DERP region {region_id}"))?;
+    let node = region
+        .nodes
+        .first()
+        .with_context(|| format!("DERP region {region_id} has no nodes"))?;
+    let addr = get_derp_addr(node, ProbeProto::StunIpv4).await?;
+    probe_stun_server(addr, opts).await
+}
+
+/// Probes the throughput towards a STUN server.
+pub async fn probe_stun_server(
+    addr: SocketAddr,
+    opts: &ThroughputProbe,
+) -> Result<ThroughputReport> {
+    let bind_addr = match addr {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let sock = UdpSocket::bind(bind_addr)
+        .await
+        .context("failed to bind probe socket")?;
+
+    let mut in_flight = HashMap::new();
+    let mut packet_size = 0;
+    for _ in 0..opts.packets {
+        let txid = stun::TransactionId::default();
+        let request = stun::padded_request(txid, opts.padding);
+        packet_size = request.len();
+        sock.send_to(&request, addr).await?;
+        in_flight.insert(txid, Instant::now());
+    }
+    debug!(%addr, packets = opts.packets, packet_size, "sent throughput probe");
+
+    let deadline = Instant::now() + opts.timeout;
+    let mut arrivals = Vec::new();
+    let mut rtts = Vec::new();
+    let mut buf = vec![0u8; 64 << 10];
+    while !in_flight.is_empty() {
+        let Ok(res) = tokio::time::timeout_at(deadline, sock.recv_from(&mut buf)).await else {
+            break;
+        };
+        let (n, from) = res?;
+        let now = Instant::now();
+        let Ok((txid, _)) = stun::parse_response(&buf[..n]) else {
+            trace!(%from, "ignoring invalid STUN response");
+            continue;
+        };
+        if let Some(sent) = in_flight.remove(&txid) {
+            arrivals.push(now);
+            rtts.push(now - sent);
+        }
+    }
+
+    let received = rtts.len() as u32;
+    let jitter = if rtts.len() < 2 {
+        Duration::ZERO
+    } else {
+        let total: Duration = rtts
+            .windows(2)
+            .map(|w| w[0].max(w[1]) - w[0].min(w[1]))
+            .sum();
+        total / (rtts.len() as u32 - 1)
+    };
+    let dispersion = match (arrivals.first(), arrivals.last()) {
+        (Some(first), Some(last)) => *last - *first,
+        _ => Duration::ZERO,
+    };
+    Ok(ThroughputReport {
+        addr,
+        packet_size,
+        sent: opts.packets,
+        received,
+        rtt_min: rtts.iter().min().copied().unwrap_or_default(),
+        rtt_avg: rtts
+            .iter()
+            .sum::<Duration>()
+            .checked_div(received)
+            .unwrap_or_default(),
+        rtt_max: rtts.iter().max().copied().unwrap_or_default(),
+        jitter,
+        dispersion,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::setup_logging;
+
+    #[tokio::test]
+    async fn test_probe_throughput() -> Result<()> {
+        let _guard = setup_logging();
+        let (stun_addr, stun_stats, done) = stun::test::serve_v4().await?;
+        let dm = stun::test::derp_map_of([stun_addr].into_iter());
+
+        let opts = ThroughputProbe {
+            packets: 16,
+            ..Default::default()
+        };
+        let report = probe_throughput(&dm, 1, &opts).await?;
+        assert_eq!(report.addr, stun_addr);
+        assert_eq!(report.sent, 16);
+        assert_eq!(stun_stats.total().await, 16);
+        assert!(report.received > 0, "no responses: {report:?}");
+        assert!(report.packet_size > stun::MAX_PADDING);
+        assert!(report.rtt_min <= report.rtt_avg && report.rtt_avg <= report.rtt_max);
+        assert!((0.0..=1.0).contains(&report.loss()));
+
+        assert!(probe_throughput(&dm, 2, &opts).await.is_err());
+        done.send(()).ok();
+        Ok(())
+    }
+}
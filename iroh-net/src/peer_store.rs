@@ -0,0 +1,173 @@
+//! A store of the addresses of peers, which survives restarts.
+//!
+//! The [`MagicEndpoint`](crate::MagicEndpoint) records the addresses peers were reached at
+//! in its [`PeerStore`], and uses them when dialing the peers again, so a direct connection
+//! can be attempted right away instead of having to go through DERP first.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::tls::PeerId;
+
+/// How long peers which were not seen are kept by default, one week.
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Number of direct addresses kept per peer, older addresses are dropped.
+const MAX_ADDRS: usize = 8;
+
+/// What is known about how to reach a peer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerInfo {
+    /// The direct addresses the peer was last reached at, most recent first.
+    pub addrs: Vec<SocketAddr>,
+    /// The DERP region of the peer.
+    pub derp_region: Option<u16>,
+    /// When the peer was last seen.
+    pub last_seen: SystemTime,
+}
+
+/// The last known addresses of peers, persisted to a file.
+///
+/// Peers not seen for longer than the maximum age are pruned when the store is loaded and
+/// when it is updated.
+#[derive(Debug, Clone)]
+pub struct PeerStore {
+    peers: Arc<Mutex<HashMap<PeerId, PeerInfo>>>,
+    /// Serializes writes of the file, so an older state never overwrites a newer one.
+    save_lock: Arc<tokio::sync::Mutex<()>>,
+    path: PathBuf,
+    max_age: Duration,
+}
+
+impl PeerStore {
+    /// Loads the store from `path`, keeping peers for [`DEFAULT_MAX_AGE`].
+    ///
+    /// Returns an empty store if the file does not exist.
+    pub async fn load(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::load_with_max_age(path, DEFAULT_MAX_AGE).await
+    }
+
+    /// Loads the store from `path`, keeping peers that were not seen for up to `max_age`.
+    pub async fn load_with_max_age(path: impl Into<PathBuf>, max_age: Duration) -> Result<Self> {
+        let path = path.into();
+        let mut peers = match tokio::fs::read(&path).await {
+            Ok(data) => postcard::from_bytes::<HashMap<PeerId, PeerInfo>>(&data)
+                .with_context(|| format!("invalid peer store in {}", path.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", path.display()))
+            }
+        };
+        prune(&mut peers, max_age);
+        Ok(Self {
+            peers: Arc::new(Mutex::new(peers)),
+            save_lock: Default::default(),
+            path,
+            max_age,
+        })
+    }
+
+    /// Returns what is known about a peer.
+    pub fn get(&self, peer_id: &PeerId) -> Option<PeerInfo> {
+        self.peers.lock().unwrap().get(peer_id).cloned()
+    }
+
+    /// Returns all known peers.
+    pub fn peers(&self) -> Vec<(PeerId, PeerInfo)> {
+        let peers = self.peers.lock().unwrap();
+        peers.iter().map(|(id, info)| (*id, info.clone())).collect()
+    }
+
+    /// Records that a peer was seen at the given addresses, and writes the store.
+    ///
+    /// The addresses are added in front of the addresses already known. The DERP region is
+    /// only replaced if one is given.
+    pub async fn record(
+        &self,
+        peer_id: PeerId,
+        addrs: &[SocketAddr],
+        derp_region: Option<u16>,
+    ) -> Result<()> {
+        {
+            let mut peers = self.peers.lock().unwrap();
+            let info = peers.entry(peer_id).or_insert_with(|| PeerInfo {
+                addrs: Vec::new(),
+                derp_region: None,
+                last_seen: SystemTime::now(),
+            });
+            info.addrs.retain(|addr| !addrs.contains(addr));
+            info.addrs.splice(0..0, addrs.iter().copied());
+            info.addrs.truncate(MAX_ADDRS);
+            info.derp_region = derp_region.or(info.derp_region);
+            info.last_seen = SystemTime::now();
+            prune(&mut peers, self.max_age);
+        }
+        self.save().await
+    }
+
+    async fn save(&self) -> Result<()> {
+        let _guard = self.save_lock.lock().await;
+        let data = postcard::to_stdvec(&*self.peers.lock().unwrap())?;
+        write_atomic(&self.path, &data)
+            .await
+            .with_context(|| format!("failed to write {}", self.path.display()))
+    }
+}
+
+fn prune(peers: &mut HashMap<PeerId, PeerInfo>, max_age: Duration) {
+    let now = SystemTime::now();
+    peers.retain(|_, info| {
+        now.duration_since(info.last_seen)
+            .map(|age| age <= max_age)
+            .unwrap_or(true)
+    });
+}
+
+/// Writes `data` to a temporary file next to `path`, and moves it into place.
+async fn write_atomic(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, data).await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::Keypair;
+
+    #[tokio::test]
+    async fn test_peer_store() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("peers");
+        let store = PeerStore::load(&path).await?;
+        assert!(store.peers().is_empty());
+
+        let peer: PeerId = Keypair::generate().public().into();
+        let a: SocketAddr = "10.0.0.1:1".parse()?;
+        let b: SocketAddr = "10.0.0.2:1".parse()?;
+        store.record(peer, &[a], Some(1)).await?;
+        store.record(peer, &[b, a], None).await?;
+        let info = store.get(&peer).unwrap();
+        assert_eq!(info.addrs, vec![b, a]);
+        assert_eq!(info.derp_region, Some(1));
+
+        // survives a restart
+        let store = PeerStore::load(&path).await?;
+        assert_eq!(store.get(&peer), Some(info));
+
+        // stale peers are pruned
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let store = PeerStore::load_with_max_age(&path, Duration::ZERO).await?;
+        assert!(store.get(&peer).is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,396 @@
+//! Sharing a single connection per peer between several protocols.
+//!
+//! Dialing a connection per protocol multiplies the handshakes and holepunching attempts
+//! between two peers. A [`ConnectionPool`] instead keeps one connection with [`ALPN`] per
+//! peer, and the protocols open streams on it in their own namespace: the [`StreamType`]
+//! of [`crate::streams`]. The incoming streams are dispatched by the [`StreamRouter`] of the
+//! pool, which holds the handler of every protocol.
+//!
+//! Connections are dialed with a [`Dialer`], so the addresses of the peer must be in the
+//! address book of the endpoint, or be found by its discovery service. The dialer can be
+//! shared with other subsystems, which then share its limit of concurrent dials.
+//! Connections the endpoint accepts with [`ALPN`] must be passed to [`ConnectionPool::accept`].
+//!
+//! With [`ConnectionPool::keep_alive`] the pool probes its peers on a stream of type
+//! [`KEEP_ALIVE`]. A peer which does not answer in time is considered dead: its connection
+//! is closed and removed from the pool, and the callback set with
+//! [`ConnectionPool::on_dead`] is called, without waiting for the QUIC idle timeout.
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use quinn::{RecvStream, SendStream, VarInt};
+use tracing::debug;
+
+use crate::dialer::Dialer;
+use crate::streams::{self, StreamRouter, StreamType};
+use crate::tls::PeerId;
+
+/// The ALPN for connections shared between protocols.
+pub const ALPN: &[u8] = b"/iroh-pool/1";
+
+/// The stream type of keep-alive probes, reserved by the pool.
+pub const KEEP_ALIVE: StreamType = StreamType::MAX;
+
+/// The error code used to close the connection to a peer which stopped responding.
+pub const DEAD_PEER: VarInt = VarInt::from_u32(2);
+
+/// Keeps a single connection per peer, shared by the protocols of the [`StreamRouter`].
+///
+/// Can be cheaply cloned, all clones share the same connections.
+///
+/// [`ConnectionPool::connect`] is cancel safe like [`Dialer::connect`]: a dial completed
+/// after its future was dropped is kept, and its streams are dispatched.
+#[derive(Clone)]
+pub struct ConnectionPool {
+    dialer: Dialer,
+    router: StreamRouter,
+    /// The connections whose streams are dispatched, dialed as well as accepted.
+    served: Arc<Mutex<HashMap<PeerId, Vec<quinn::Connection>>>>,
+    /// The interval and timeout of keep-alive probes.
+    keep_alive: Option<(Duration, Duration)>,
+    on_dead: Option<Arc<dyn Fn(PeerId) + Send + Sync + 'static>>,
+}
+
+impl fmt::Debug for ConnectionPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectionPool")
+            .field("dialer", &self.dialer)
+            .field("router", &self.router)
+            .field("served", &self.served)
+            .field("keep_alive", &self.keep_alive)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ConnectionPool {
+    /// Creates a pool dialing with `dialer`, which dispatches incoming streams with `router`.
+    ///
+    /// The endpoint of the dialer must accept [`ALPN`] for peers to be able to connect.
+    pub fn new(dialer: Dialer, router: StreamRouter) -> Self {
+        // always answer probes, peers may probe even if this pool does not
+        let router = router.bi(KEEP_ALIVE, |mut send, mut recv| async move {
+            recv.read_to_end(0).await?;
+            send.finish().await?;
+            Ok(())
+        });
+        Self {
+            dialer,
+            router,
+            served: Default::default(),
+            keep_alive: None,
+            on_dead: None,
+        }
+    }
+
+    /// Probes every connected peer each `interval`, closing the connection if the peer does
+    /// not answer within `timeout`.
+    ///
+    /// Only applies to connections added after this is called.
+    pub fn keep_alive(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.keep_alive = Some((interval, timeout));
+        self
+    }
+
+    /// Calls `on_dead` with the peer whenever a peer stops answering keep-alive probes.
+    pub fn on_dead(mut self, on_dead: impl Fn(PeerId) + Send + Sync + 'static) -> Self {
+        self.on_dead = Some(Arc::new(on_dead));
+        self
+    }
+
+    /// Returns the connection to `peer_id`, dialing it if there is none.
+    pub async fn connect(&self, peer_id: PeerId) -> Result<quinn::Connection> {
+        // dispatch the streams in a task, so a connection dialed after this future was
+        // dropped is served as well
+        let pool = self.clone();
+        tokio::spawn(async move {
+            let conn = pool.dialer.connect(peer_id, ALPN).await?;
+            pool.serve(peer_id, conn.clone());
+            anyhow::Ok(conn)
+        })
+        .await?
+    }
+
+    /// Opens a bidirectional stream of `stream_type` to `peer_id`, see [`streams::open_bi`].
+    pub async fn open_bi(
+        &self,
+        peer_id: PeerId,
+        stream_type: StreamType,
+    ) -> Result<(SendStream, RecvStream)> {
+        let conn = self.connect(peer_id).await?;
+        streams::open_bi(&conn, stream_type).await
+    }
+
+    /// Opens a unidirectional stream of `stream_type` to `peer_id`, see [`streams::open_uni`].
+    pub async fn open_uni(&self, peer_id: PeerId, stream_type: StreamType) -> Result<SendStream> {
+        let conn = self.connect(peer_id).await?;
+        streams::open_uni(&conn, stream_type).await
+    }
+
+    /// Adds a connection accepted with [`ALPN`] from `peer_id`, and dispatches its streams.
+    ///
+    /// It is used for streams to the peer unless there already is an open connection to it,
+    /// for example when both peers dialed each other at the same time.
+    pub fn accept(&self, peer_id: PeerId, conn: quinn::Connection) {
+        self.serve(peer_id, conn.clone());
+        self.dialer.insert(peer_id, ALPN, conn);
+    }
+
+    /// The peers with an open connection.
+    pub fn peers(&self) -> Vec<PeerId> {
+        let served = self.served.lock().unwrap();
+        served
+            .iter()
+            .filter(|(_, conns)| conns.iter().any(|conn| conn.close_reason().is_none()))
+            .map(|(peer_id, _)| *peer_id)
+            .collect()
+    }
+
+    /// Dispatches the streams of `conn` and probes the peer, unless this already happens.
+    fn serve(&self, peer_id: PeerId, conn: quinn::Connection) {
+        {
+            let mut served = self.served.lock().unwrap();
+            let conns = served.entry(peer_id).or_default();
+            if conns.iter().any(|c| c.stable_id() == conn.stable_id()) {
+                return;
+            }
+            conns.push(conn.clone());
+        }
+        let pool = self.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                res = pool.router.serve(conn.clone()) => {
+                    if let Err(err) = res {
+                        debug!(%peer_id, "shared connection closed: {err:#}");
+                    }
+                }
+                err = pool.probe(&conn) => {
+                    debug!(%peer_id, "peer stopped responding: {err:#}");
+                    conn.close(DEAD_PEER, b"peer stopped responding");
+                    if let Some(on_dead) = &pool.on_dead {
+                        on_dead(peer_id);
+                    }
+                }
+            }
+            let mut served = pool.served.lock().unwrap();
+            if let Some(conns) = served.get_mut(&peer_id) {
+                conns.retain(|c| c.stable_id() != conn.stable_id());
+                if conns.is_empty() {
+                    served.remove(&peer_id);
+                }
+            }
+        });
+    }
+
+    /// Probes the peer of `conn` until it fails to answer, never returns without keep-alives
+    /// or once the connection is closed.
+    async fn probe(&self, conn: &quinn::Connection) -> anyhow::Error {
+        let Some((interval, timeout)) = self.keep_alive else {
+            return futures::future::pending().await;
+        };
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let probe = async {
+                let (mut send, mut recv) = streams::open_bi(conn, KEEP_ALIVE).await?;
+                send.finish().await?;
+                recv.read_to_end(0).await?;
+                anyhow::Ok(())
+            };
+            let err = match tokio::time::timeout(timeout, probe).await {
+                Ok(Ok(())) => continue,
+                Ok(Err(err)) => err,
+                Err(_) => anyhow::anyhow!("no answer within {timeout:?}"),
+            };
+            if conn.close_reason().is_some() {
+                // closed normally, the router stops serving it
+                return futures::future::pending().await;
+            }
+            return err;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use anyhow::Context;
+
+    use super::*;
+    use crate::test_utils::setup_logging;
+    use crate::MagicEndpoint;
+
+    const ECHO: StreamType = 1;
+    const LEN: StreamType = 2;
+
+    fn router() -> StreamRouter {
+        StreamRouter::new()
+            .bi(ECHO, |mut send, mut recv| async move {
+                tokio::io::copy(&mut recv, &mut send).await?;
+                send.finish().await?;
+                Ok(())
+            })
+            .bi(LEN, |mut send, mut recv| async move {
+                let data = recv.read_to_end(1024).await?;
+                send.write_all(&(data.len() as u64).to_be_bytes()).await?;
+                send.finish().await?;
+                Ok(())
+            })
+    }
+
+    async fn request(
+        pool: &ConnectionPool,
+        peer_id: PeerId,
+        stream_type: StreamType,
+    ) -> Result<Vec<u8>> {
+        let (mut send, mut recv) = pool.open_bi(peer_id, stream_type).await?;
+        send.write_all(b"hello").await?;
+        send.finish().await?;
+        Ok(recv.read_to_end(1024).await?)
+    }
+
+    #[tokio::test]
+    async fn test_connection_pool() -> Result<()> {
+        let _guard = setup_logging();
+
+        let bind = || MagicEndpoint::builder().alpns(vec![ALPN.to_vec()]).bind(0);
+        let ep1 = bind().await?;
+        let ep2 = bind().await?;
+        let pool1 = ConnectionPool::new(Dialer::new(ep1.clone()), router());
+        let pool2 = ConnectionPool::new(Dialer::new(ep2.clone()), router());
+        let accepts = tokio::spawn({
+            let ep2 = ep2.clone();
+            let pool2 = pool2.clone();
+            async move {
+                let mut accepted = 0;
+                while let Some(connecting) = ep2.accept().await {
+                    let (peer_id, _, conn) = ep2.accept_conn(connecting).await?;
+                    pool2.accept(peer_id, conn);
+                    accepted += 1;
+                }
+                anyhow::Ok(accepted)
+            }
+        });
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), ep2.local_addr()?.0.port());
+        ep1.add_known_addrs(ep2.peer_id(), None, &[addr]).await?;
+
+        // both protocols, and both directions, use the same connection
+        let (echo, len) = tokio::join!(
+            request(&pool1, ep2.peer_id(), ECHO),
+            request(&pool1, ep2.peer_id(), LEN)
+        );
+        assert_eq!(echo?, b"hello");
+        assert_eq!(len?, 5u64.to_be_bytes());
+        assert_eq!(request(&pool2, ep1.peer_id(), ECHO).await?, b"hello");
+        let conn = pool1.connect(ep2.peer_id()).await?;
+        assert_eq!(
+            conn.stable_id(),
+            pool1.connect(ep2.peer_id()).await?.stable_id()
+        );
+        assert_eq!(pool1.peers(), vec![ep2.peer_id()]);
+        assert_eq!(pool2.peers(), vec![ep1.peer_id()]);
+
+        // a closed connection is dialed again
+        conn.close(0u32.into(), b"done");
+        assert_eq!(request(&pool1, ep2.peer_id(), ECHO).await?, b"hello");
+
+        ep2.close(0u32.into(), b"done").await?;
+        let accepted = accepts.await?.context("accept failed")?;
+        assert_eq!(accepted, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_cancelled() -> Result<()> {
+        let _guard = setup_logging();
+
+        let bind = || MagicEndpoint::builder().alpns(vec![ALPN.to_vec()]).bind(0);
+        let ep1 = bind().await?;
+        let ep2 = bind().await?;
+        let pool1 = ConnectionPool::new(Dialer::new(ep1.clone()), router());
+        let pool2 = ConnectionPool::new(Dialer::new(ep2.clone()), router());
+        tokio::spawn({
+            let ep2 = ep2.clone();
+            let pool2 = pool2.clone();
+            async move {
+                while let Some(connecting) = ep2.accept().await {
+                    let (peer_id, _, conn) = ep2.accept_conn(connecting).await?;
+                    pool2.accept(peer_id, conn);
+                }
+                anyhow::Ok(())
+            }
+        });
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), ep2.local_addr()?.0.port());
+        ep1.add_known_addrs(ep2.peer_id(), None, &[addr]).await?;
+
+        // the dial completes without its caller, and the connection is served
+        let connect = pool1.connect(ep2.peer_id());
+        assert!(tokio::time::timeout(Duration::from_millis(1), connect)
+            .await
+            .is_err());
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while pool1.peers().is_empty() || pool2.peers().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        assert_eq!(request(&pool2, ep1.peer_id(), ECHO).await?, b"hello");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive() -> Result<()> {
+        let _guard = setup_logging();
+
+        let bind = || MagicEndpoint::builder().alpns(vec![ALPN.to_vec()]).bind(0);
+        let ep1 = bind().await?;
+        let ep2 = bind().await?;
+        let ep3 = bind().await?;
+        let (dead_s, mut dead_r) = tokio::sync::mpsc::unbounded_channel();
+        let pool1 = ConnectionPool::new(Dialer::new(ep1.clone()), router())
+            .keep_alive(Duration::from_millis(100), Duration::from_millis(500))
+            .on_dead(move |peer_id| dead_s.send(peer_id).unwrap());
+
+        // ep2 answers probes
+        let pool2 = ConnectionPool::new(Dialer::new(ep2.clone()), router());
+        tokio::spawn({
+            let ep2 = ep2.clone();
+            async move {
+                while let Some(connecting) = ep2.accept().await {
+                    let (peer_id, _, conn) = ep2.accept_conn(connecting).await?;
+                    pool2.accept(peer_id, conn);
+                }
+                anyhow::Ok(())
+            }
+        });
+        // ep3 holds the connection, but never answers
+        let (conn_s, conn_r) = tokio::sync::oneshot::channel();
+        tokio::spawn({
+            let ep3 = ep3.clone();
+            async move {
+                let connecting = ep3.accept().await.context("no connection")?;
+                let (_, _, conn) = ep3.accept_conn(connecting).await?;
+                conn_s.send(conn).ok();
+                anyhow::Ok(())
+            }
+        });
+        for ep in [&ep2, &ep3] {
+            let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), ep.local_addr()?.0.port());
+            ep1.add_known_addrs(ep.peer_id(), None, &[addr]).await?;
+        }
+
+        pool1.connect(ep2.peer_id()).await?;
+        pool1.connect(ep3.peer_id()).await?;
+        let _conn3 = conn_r.await?;
+        let dead = tokio::time::timeout(Duration::from_secs(5), dead_r.recv()).await?;
+        assert_eq!(dead, Some(ep3.peer_id()));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(pool1.peers(), vec![ep2.peer_id()]);
+        assert!(dead_r.try_recv().is_err());
+        Ok(())
+    }
+}
@@ -75,6 +75,13 @@ enum Message {
         #[debug("_")]
         result_tx: oneshot::Sender<Result<ProbeOutput, String>>,
     },
+    /// Request to deactivate port mapping and release the current mapping.
+    ///
+    /// The [`oneshot::Sender`] is dropped once the mapping is released.
+    Release {
+        #[debug("_")]
+        _done_tx: oneshot::Sender<()>,
+    },
 }
 
 /// Configures which port mapping protocols are enabled in the [`Service`].
@@ -200,6 +207,18 @@ impl Client {
         }
     }
 
+    /// Deactivate port mapping, and wait for the current mapping to be released.
+    ///
+    /// Unlike [`Self::deactivate`] this makes sure the mapping is released before the client
+    /// is dropped, which stops the service.
+    pub async fn release(&self) {
+        let (done_tx, done_rx) = oneshot::channel();
+        let msg = Message::Release { _done_tx: done_tx };
+        if self.service_tx.send(msg).await.is_ok() {
+            done_rx.await.ok();
+        }
+    }
+
     /// Watch the external address for changes in the mappings.
     pub fn watch_external_address(&self) -> watch::Receiver<Option<SocketAddrV4>> {
         self.port_mapping.clone()
@@ -526,6 +545,7 @@ impl Service {
             Message::ProcureMapping => self.update_local_port(self.local_port).await,
             Message::UpdateLocalPort { local_port } => self.update_local_port(local_port).await,
             Message::Probe { result_tx } => self.probe_request(result_tx),
+            Message::Release { _done_tx } => self.update_local_port(None).await,
         }
     }
 
@@ -0,0 +1,178 @@
+//! A protocol for sending a single request to a peer and receiving its response.
+//!
+//! Each request is sent on its own bidirectional stream of a QUIC connection. Requests and
+//! responses are [postcard] encoded messages framed with [`FRAMING`], written with
+//! [`write_message`] and read with [`read_message`].
+//!
+//! The requesting side calls [`request`] on a connection, the responding side passes every
+//! connection it accepts to [`serve`] with a handler for the requests. The connections can
+//! use [`ALPN`] or an application specific ALPN.
+//!
+//! ```no_run
+//! # async fn example(endpoint: iroh_net::MagicEndpoint, peer_id: iroh_net::tls::PeerId) -> anyhow::Result<()> {
+//! use iroh_net::request::{self, ALPN};
+//!
+//! let conn = endpoint.connect(peer_id, ALPN, None, &[]).await?;
+//! let status: String = request::request(&conn, &"status").await?;
+//! # Ok(())
+//! # }
+//! ```
+use std::future::Future;
+
+use anyhow::{anyhow, Context, Result};
+use bytes::BytesMut;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::debug;
+
+use crate::framing::{Framing, Prefix};
+
+/// The ALPN for connections carrying requests.
+pub const ALPN: &[u8] = b"/iroh-request/1";
+
+/// The maximum size of an encoded request or response.
+pub const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// The framing of requests and responses, with a `u32` length prefix.
+pub const FRAMING: Framing = Framing::new(Prefix::U32, MAX_MESSAGE_SIZE);
+
+/// The response to a request as sent on the wire, the error of a failed handler is sent as
+/// a string.
+type Response<T> = Result<T, String>;
+
+/// Writes a length prefixed, postcard encoded message.
+pub async fn write_message<T: Serialize>(
+    writer: &mut (impl AsyncWrite + Unpin),
+    message: &T,
+) -> Result<()> {
+    FRAMING.write_message(writer, message).await
+}
+
+/// Reads a length prefixed, postcard encoded message.
+///
+/// Returns `None` if the end of the stream is reached before a new message starts. This is
+/// not cancel safe, use [`Framing::read_message`] of [`FRAMING`] to keep partial messages.
+pub async fn read_message<T: DeserializeOwned>(
+    reader: &mut (impl AsyncRead + Unpin),
+) -> Result<Option<T>> {
+    FRAMING.read_message(reader, &mut BytesMut::new()).await
+}
+
+/// Sends `request` on a new stream of `conn` and waits for the response.
+///
+/// Fails if the handler of the peer fails, with the error message of the handler.
+pub async fn request<Req: Serialize, Res: DeserializeOwned>(
+    conn: &quinn::Connection,
+    request: &Req,
+) -> Result<Res> {
+    let (mut send, mut recv) = conn.open_bi().await?;
+    write_message(&mut send, request).await?;
+    send.finish().await?;
+    let response: Response<Res> = read_message(&mut recv)
+        .await?
+        .context("peer closed the stream without responding")?;
+    response.map_err(|err| anyhow!("peer failed to handle request: {err}"))
+}
+
+/// Answers the requests sent on `conn` with `handler`, until the connection is closed.
+///
+/// Each request is handled in its own task, so requests on the same connection do not wait
+/// for each other.
+pub async fn serve<Req, Res, F, Fut>(conn: quinn::Connection, handler: F) -> Result<()>
+where
+    Req: DeserializeOwned + Send + 'static,
+    Res: Serialize + Send + Sync + 'static,
+    F: Fn(Req) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = Result<Res>> + Send + 'static,
+{
+    loop {
+        let (mut send, mut recv) = match conn.accept_bi().await {
+            Ok(streams) => streams,
+            Err(quinn::ConnectionError::ApplicationClosed(_)) => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let res = async move {
+                let request = read_message(&mut recv)
+                    .await?
+                    .context("stream closed without a request")?;
+                let response = handler(request);
+                let response: Response<Res> = response.await.map_err(|err| {
+                    debug!("request handler failed: {err:#}");
+                    format!("{err:#}")
+                });
+                write_message(&mut send, &response).await?;
+                send.finish().await?;
+                anyhow::Ok(())
+            };
+            if let Err(err) = res.await {
+                debug!("failed to answer request: {err:#}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use anyhow::bail;
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::test_utils::setup_logging;
+    use crate::MagicEndpoint;
+
+    #[tokio::test]
+    async fn test_message_roundtrip() -> Result<()> {
+        let (mut a, mut b) = tokio::io::duplex(1024);
+        write_message(&mut a, &(1u64, "hello".to_string())).await?;
+        a.write_u32(MAX_MESSAGE_SIZE as u32 + 1).await?;
+        drop(a);
+        let message: Option<(u64, String)> = read_message(&mut b).await?;
+        assert_eq!(message, Some((1, "hello".to_string())));
+        assert!(read_message::<u64>(&mut b).await.is_err());
+        assert_eq!(read_message::<u64>(&mut b).await?, None);
+
+        let too_large = vec![0u8; MAX_MESSAGE_SIZE];
+        assert!(write_message(&mut tokio::io::sink(), &too_large)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_request() -> Result<()> {
+        let _guard = setup_logging();
+
+        let bind = || MagicEndpoint::builder().alpns(vec![ALPN.to_vec()]).bind(0);
+        let ep1 = bind().await?;
+        let ep2 = bind().await?;
+        let server = tokio::spawn({
+            let ep2 = ep2.clone();
+            async move {
+                let connecting = ep2.accept().await.context("no connection")?;
+                let (_, _, conn) = ep2.accept_conn(connecting).await?;
+                serve(conn, |n: u64| async move {
+                    if n == 0 {
+                        bail!("zero is not allowed");
+                    }
+                    Ok(n * 2)
+                })
+                .await
+            }
+        });
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), ep2.local_addr()?.0.port());
+        let conn = ep1.connect(ep2.peer_id(), ALPN, None, &[addr]).await?;
+
+        let (a, b) = tokio::join!(request(&conn, &21u64), request(&conn, &4u64));
+        assert_eq!((a?, b?), (42u64, 8u64));
+        let err = request::<_, u64>(&conn, &0u64).await.unwrap_err();
+        assert!(err.to_string().contains("zero is not allowed"));
+
+        conn.close(0u32.into(), b"done");
+        server.await??;
+        Ok(())
+    }
+}
@@ -0,0 +1,264 @@
+//! Routing of the streams of a connection to handlers by their type.
+//!
+//! Protocols which multiplex several kinds of messages on one connection open a stream for
+//! each exchange and tag it with a [`StreamType`], written as a short header at the start of
+//! the stream by [`open_bi`] and [`open_uni`]. The accepting side passes the connection to
+//! [`StreamRouter::serve`], which reads the header of every incoming stream and hands the
+//! rest of the stream to the handler registered for its type.
+//!
+//! Streams of a type without a handler are rejected with [`UNKNOWN_STREAM_TYPE`].
+//!
+//! ```no_run
+//! # async fn example(conn: quinn::Connection) -> anyhow::Result<()> {
+//! use iroh_net::streams::{self, StreamRouter};
+//!
+//! const ECHO: u16 = 1;
+//!
+//! let router = StreamRouter::new().bi(ECHO, |mut send, mut recv| async move {
+//!     tokio::io::copy(&mut recv, &mut send).await?;
+//!     send.finish().await?;
+//!     Ok(())
+//! });
+//! router.serve(conn.clone()).await?;
+//!
+//! // on the other side of the connection
+//! let (mut send, mut recv) = streams::open_bi(&conn, ECHO).await?;
+//! # Ok(())
+//! # }
+//! ```
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+
+use anyhow::Result;
+use futures::future::{BoxFuture, FutureExt};
+use quinn::{RecvStream, SendStream, VarInt};
+use tokio::io::AsyncReadExt;
+use tracing::debug;
+
+/// The type of a stream, sent as a big endian `u16` at the start of the stream.
+pub type StreamType = u16;
+
+/// The error code used to reject streams of a type without a handler.
+pub const UNKNOWN_STREAM_TYPE: VarInt = VarInt::from_u32(1);
+
+type BiHandler =
+    Arc<dyn Fn(SendStream, RecvStream) -> BoxFuture<'static, Result<()>> + Send + Sync>;
+type UniHandler = Arc<dyn Fn(RecvStream) -> BoxFuture<'static, Result<()>> + Send + Sync>;
+
+/// Opens a bidirectional stream on `conn` and writes the header for `stream_type`.
+pub async fn open_bi(
+    conn: &quinn::Connection,
+    stream_type: StreamType,
+) -> Result<(SendStream, RecvStream)> {
+    let (mut send, recv) = conn.open_bi().await?;
+    send.write_all(&stream_type.to_be_bytes()).await?;
+    Ok((send, recv))
+}
+
+/// Opens a unidirectional stream on `conn` and writes the header for `stream_type`.
+pub async fn open_uni(conn: &quinn::Connection, stream_type: StreamType) -> Result<SendStream> {
+    let mut send = conn.open_uni().await?;
+    send.write_all(&stream_type.to_be_bytes()).await?;
+    Ok(send)
+}
+
+#[derive(Default, Clone)]
+struct Handlers {
+    bi: HashMap<StreamType, BiHandler>,
+    uni: HashMap<StreamType, UniHandler>,
+}
+
+/// Dispatches the incoming streams of connections to the handlers registered for their
+/// [`StreamType`].
+///
+/// Can be cheaply cloned, to serve several connections with the same handlers.
+#[derive(Default, Clone)]
+pub struct StreamRouter {
+    handlers: Arc<Handlers>,
+}
+
+impl fmt::Debug for StreamRouter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamRouter")
+            .field("bi", &self.handlers.bi.keys().collect::<Vec<_>>())
+            .field("uni", &self.handlers.uni.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl StreamRouter {
+    /// Creates a router without handlers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handles bidirectional streams of `stream_type` with `handler`, replacing any
+    /// previous handler for the type.
+    ///
+    /// The handler receives the streams after the header was read.
+    pub fn bi<F, Fut>(mut self, stream_type: StreamType, handler: F) -> Self
+    where
+        F: Fn(SendStream, RecvStream) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let handler: BiHandler = Arc::new(move |send, recv| handler(send, recv).boxed());
+        Arc::make_mut(&mut self.handlers)
+            .bi
+            .insert(stream_type, handler);
+        self
+    }
+
+    /// Handles unidirectional streams of `stream_type` with `handler`, replacing any
+    /// previous handler for the type.
+    ///
+    /// The handler receives the stream after the header was read.
+    pub fn uni<F, Fut>(mut self, stream_type: StreamType, handler: F) -> Self
+    where
+        F: Fn(RecvStream) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let handler: UniHandler = Arc::new(move |recv| handler(recv).boxed());
+        Arc::make_mut(&mut self.handlers)
+            .uni
+            .insert(stream_type, handler);
+        self
+    }
+
+    /// Dispatches the streams opened by the peer of `conn`, until the connection is closed.
+    ///
+    /// Each stream is handled in its own task, so streams do not wait for each other. Errors
+    /// of the handlers are logged and do not affect other streams.
+    pub async fn serve(&self, conn: quinn::Connection) -> Result<()> {
+        loop {
+            let res = tokio::select! {
+                bi = conn.accept_bi() => bi.map(|(send, recv)| {
+                    tokio::spawn(self.clone().handle_bi(send, recv));
+                }),
+                uni = conn.accept_uni() => uni.map(|recv| {
+                    tokio::spawn(self.clone().handle_uni(recv));
+                }),
+            };
+            match res {
+                Ok(()) => {}
+                Err(quinn::ConnectionError::ApplicationClosed(_)) => return Ok(()),
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    async fn handle_bi(self, mut send: SendStream, mut recv: RecvStream) {
+        let res = async {
+            let stream_type = recv.read_u16().await?;
+            let Some(handler) = self.handlers.bi.get(&stream_type) else {
+                debug!(
+                    stream_type,
+                    "rejecting bidirectional stream of unknown type"
+                );
+                send.reset(UNKNOWN_STREAM_TYPE).ok();
+                recv.stop(UNKNOWN_STREAM_TYPE).ok();
+                return Ok(());
+            };
+            handler(send, recv).await
+        };
+        if let Err(err) = res.await {
+            debug!("failed to handle bidirectional stream: {err:#}");
+        }
+    }
+
+    async fn handle_uni(self, mut recv: RecvStream) {
+        let res = async {
+            let stream_type = recv.read_u16().await?;
+            let Some(handler) = self.handlers.uni.get(&stream_type) else {
+                debug!(
+                    stream_type,
+                    "rejecting unidirectional stream of unknown type"
+                );
+                recv.stop(UNKNOWN_STREAM_TYPE).ok();
+                return Ok(());
+            };
+            handler(recv).await
+        };
+        if let Err(err) = res.await {
+            debug!("failed to handle unidirectional stream: {err:#}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use anyhow::Context;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::test_utils::setup_logging;
+    use crate::MagicEndpoint;
+
+    const ALPN: &[u8] = b"/iroh-test-streams/1";
+
+    #[tokio::test]
+    async fn test_stream_router() -> Result<()> {
+        let _guard = setup_logging();
+
+        let bind = || MagicEndpoint::builder().alpns(vec![ALPN.to_vec()]).bind(0);
+        let ep1 = bind().await?;
+        let ep2 = bind().await?;
+        let (uni_s, mut uni_r) = mpsc::channel(1);
+        let router = StreamRouter::new()
+            .bi(1, |mut send, mut recv| async move {
+                tokio::io::copy(&mut recv, &mut send).await?;
+                send.finish().await?;
+                Ok(())
+            })
+            .bi(2, |mut send, mut recv| async move {
+                let data = recv.read_to_end(1024).await?;
+                send.write_all(&(data.len() as u64).to_be_bytes()).await?;
+                send.finish().await?;
+                Ok(())
+            })
+            .uni(1, move |mut recv| {
+                let uni_s = uni_s.clone();
+                async move {
+                    uni_s.send(recv.read_to_end(1024).await?).await?;
+                    Ok(())
+                }
+            });
+        let server = tokio::spawn({
+            let ep2 = ep2.clone();
+            async move {
+                let connecting = ep2.accept().await.context("no connection")?;
+                let (_, _, conn) = ep2.accept_conn(connecting).await?;
+                router.serve(conn).await
+            }
+        });
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), ep2.local_addr()?.0.port());
+        let conn = ep1.connect(ep2.peer_id(), ALPN, None, &[addr]).await?;
+
+        let (mut send, mut recv) = open_bi(&conn, 1).await?;
+        send.write_all(b"hello").await?;
+        send.finish().await?;
+        assert_eq!(recv.read_to_end(1024).await?, b"hello");
+
+        let (mut send, mut recv) = open_bi(&conn, 2).await?;
+        send.write_all(b"hello").await?;
+        send.finish().await?;
+        assert_eq!(recv.read_to_end(1024).await?, 5u64.to_be_bytes());
+
+        let mut send = open_uni(&conn, 1).await?;
+        send.write_all(b"uni").await?;
+        send.finish().await?;
+        assert_eq!(uni_r.recv().await.context("no uni stream")?, b"uni");
+
+        // no handler for this type
+        let (mut send, mut recv) = open_bi(&conn, 3).await?;
+        send.finish().await.ok();
+        assert!(recv.read_to_end(1024).await.is_err());
+
+        conn.close(0u32.into(), b"done");
+        server.await??;
+        Ok(())
+    }
+}
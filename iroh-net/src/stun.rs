@@ -3,7 +3,7 @@
 use std::net::SocketAddr;
 
 use stun_rs::{
-    attributes::stun::{Fingerprint, XorMappedAddress},
+    attributes::stun::{Fingerprint, Software, XorMappedAddress},
     DecoderContextBuilder, MessageDecoderBuilder, MessageEncoderBuilder, StunMessageBuilder,
 };
 pub use stun_rs::{
@@ -51,6 +51,28 @@ pub fn request(tx: TransactionId) -> Vec<u8> {
     buffer
 }
 
+/// The maximum padding of [`padded_request`].
+pub const MAX_PADDING: usize = 509;
+
+/// Generates a binding request STUN packet padded by up to [`MAX_PADDING`] bytes.
+///
+/// The padding is sent in a SOFTWARE attribute, which servers ignore. This allows
+/// probing the network with larger packets while still getting STUN responses.
+pub fn padded_request(tx: TransactionId, padding: usize) -> Vec<u8> {
+    let software = Software::new("x".repeat(padding.min(MAX_PADDING))).expect("valid length");
+    let msg = StunMessageBuilder::new(methods::BINDING, MessageClass::Request)
+        .with_transaction_id(tx)
+        .with_attribute(software)
+        .with_attribute(Fingerprint::default())
+        .build();
+
+    let encoder = MessageEncoderBuilder::default().build();
+    let mut buffer = vec![0u8; 150 + MAX_PADDING];
+    let size = encoder.encode(&mut buffer, &msg).expect("invalid encoding");
+    buffer.truncate(size);
+    buffer
+}
+
 /// Generates a binding response.
 pub fn response(tx: TransactionId, addr: SocketAddr) -> Vec<u8> {
     let msg = StunMessageBuilder::new(methods::BINDING, MessageClass::SuccessResponse)
@@ -291,6 +313,14 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_padded_request() {
+        let txid = TransactionId::default();
+        let request = padded_request(txid, 1000);
+        assert!(request.len() > MAX_PADDING);
+        assert_eq!(parse_binding_request(&request).unwrap(), txid);
+    }
+
     // Test to check if an existing stun server works
     // #[tokio::test]
     // async fn test_stun_server() {
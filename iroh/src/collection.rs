@@ -1,10 +1,17 @@
 //! The collection type used by iroh
+#[cfg(feature = "flat-db")]
+use std::path::PathBuf;
+
 use anyhow::{Context, Result};
+#[cfg(feature = "flat-db")]
+use bytes::Bytes;
 use futures::{
     future::{self, LocalBoxFuture},
     FutureExt,
 };
 use iroh_bytes::collection::{CollectionParser, CollectionStats, LinkStream};
+#[cfg(feature = "flat-db")]
+use iroh_bytes::protocol::MAX_MESSAGE_SIZE;
 use iroh_bytes::Hash;
 use iroh_io::{AsyncSliceReader, AsyncSliceReaderExt};
 use serde::{Deserialize, Serialize};
@@ -76,6 +83,94 @@ pub struct Blob {
     pub hash: Hash,
 }
 
+/// Builds a [`Collection`] from named blobs and files and adds it to a [`Database`].
+///
+/// ```no_run
+/// # async fn example(db: iroh::database::flat::Database) -> anyhow::Result<()> {
+/// use iroh::collection::CollectionBuilder;
+///
+/// let hash = CollectionBuilder::new()
+///     .add_bytes("hello.txt", "hello world")
+///     .add_file("data.bin", "/tmp/data.bin".into())
+///     .build(&db)
+///     .await?;
+/// println!("collection: {hash}");
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`Database`]: crate::database::flat::Database
+#[cfg(feature = "flat-db")]
+#[derive(Debug, Default)]
+pub struct CollectionBuilder {
+    entries: Vec<(String, BuilderEntry)>,
+}
+
+#[cfg(feature = "flat-db")]
+#[derive(Debug)]
+enum BuilderEntry {
+    Bytes(Bytes),
+    File(PathBuf),
+}
+
+#[cfg(feature = "flat-db")]
+impl CollectionBuilder {
+    /// Creates a new, empty builder.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a blob with the given name and content.
+    ///
+    /// The content is stored inside the database.
+    pub fn add_bytes(mut self, name: impl Into<String>, data: impl Into<Bytes>) -> Self {
+        self.entries
+            .push((name.into(), BuilderEntry::Bytes(data.into())));
+        self
+    }
+
+    /// Adds the file at `path` as a blob with the given name.
+    ///
+    /// The file is referenced by the database and must not change while it is in use.
+    pub fn add_file(mut self, name: impl Into<String>, path: PathBuf) -> Self {
+        self.entries.push((name.into(), BuilderEntry::File(path)));
+        self
+    }
+
+    /// Adds all blobs and the collection to `db`, returning the hash of the collection.
+    ///
+    /// Fails if two blobs have the same name.
+    pub async fn build(self, db: &crate::database::flat::Database) -> Result<Hash> {
+        let mut blobs = Vec::with_capacity(self.entries.len());
+        let mut total_blobs_size = 0u64;
+        for (name, entry) in self.entries {
+            let (hash, size) = match entry {
+                BuilderEntry::Bytes(data) => {
+                    let size = data.len() as u64;
+                    (db.import_bytes(data)?, size)
+                }
+                BuilderEntry::File(path) => {
+                    let hash = db
+                        .import_file(path.clone())
+                        .await
+                        .with_context(|| format!("failed to import {}", path.display()))?;
+                    let size = db.get(&hash).context("imported blob missing")?.size().await;
+                    (hash, size)
+                }
+            };
+            total_blobs_size += size;
+            blobs.push(Blob { name, hash });
+        }
+        let collection = Collection::new(blobs, total_blobs_size)?;
+        let data = collection.to_bytes()?;
+        anyhow::ensure!(
+            data.len() <= MAX_MESSAGE_SIZE,
+            "serialised collection exceeds {MAX_MESSAGE_SIZE}"
+        );
+        db.import_bytes(data.into())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -96,6 +191,42 @@ mod tests {
         let deserialize_b: Blob = postcard::from_bytes(&buf).unwrap();
         assert_eq!(b, deserialize_b);
     }
+
+    #[cfg(feature = "flat-db")]
+    #[tokio::test]
+    async fn collection_builder() -> Result<()> {
+        let dir = testdir::testdir!();
+        let path = dir.join("file");
+        std::fs::write(&path, b"world")?;
+        let db = crate::database::flat::Database::default();
+        let hash = CollectionBuilder::new()
+            .add_file("b", path.clone())
+            .add_bytes("a", "hello")
+            .build(&db)
+            .await?;
+
+        let entry = db.get(&hash).context("collection missing")?;
+        let data = entry.data_reader().await?.read_to_end().await?;
+        let collection = Collection::from_bytes(&data)?;
+        let names = collection.blobs().iter().map(|b| b.name.as_str());
+        assert_eq!(names.collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(collection.total_blobs_size(), 10);
+        for blob in collection.blobs() {
+            assert!(db.get(&blob.hash).is_some());
+        }
+        assert_eq!(
+            db.get(&collection.blobs()[1].hash).unwrap().blob_path(),
+            Some(path.as_path())
+        );
+
+        let res = CollectionBuilder::new()
+            .add_bytes("a", "hello")
+            .add_bytes("a", "world")
+            .build(&db)
+            .await;
+        assert!(res.is_err());
+        Ok(())
+    }
 }
 
 /// Parser for the current iroh default collections
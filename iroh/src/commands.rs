@@ -6,6 +6,7 @@ use std::{net::SocketAddr, path::PathBuf};
 
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
+use iroh::config::Config;
 use iroh::dial::Ticket;
 use iroh::rpc_protocol::*;
 use iroh_bytes::{protocol::RequestToken, util::runtime, Hash};
@@ -13,8 +14,6 @@ use iroh_net::tls::{Keypair, PeerId};
 use quic_rpc::transport::quinn::QuinnConnection;
 use quic_rpc::RpcClient;
 
-use crate::config::Config;
-
 use self::provide::{ProvideOptions, ProviderRpcPort};
 
 const DEFAULT_RPC_PORT: u16 = 0x1337;
@@ -23,10 +22,16 @@ const MAX_RPC_CONNECTIONS: u32 = 16;
 const MAX_RPC_STREAMS: u64 = 1024;
 
 pub mod add;
+pub mod blob;
 pub mod doctor;
 pub mod get;
+pub mod history;
 pub mod list;
+#[cfg(feature = "fuse")]
+pub mod mount;
+pub mod peer;
 pub mod provide;
+pub mod tag;
 pub mod validate;
 
 /// Send data.
@@ -69,8 +74,10 @@ impl Cli {
                 token,
                 out,
                 single,
+                rpc_port,
             } => {
                 let get = if let Some(ticket) = ticket {
+                    anyhow::ensure!(!ticket.is_expired(), "ticket has expired");
                     self::get::GetInteractive {
                         hash: ticket.hash(),
                         opts: ticket.as_get_options(Keypair::generate(), config.derp_map()),
@@ -94,20 +101,15 @@ impl Cli {
                 } else {
                     anyhow::bail!("Either ticket or hash and peer must be specified")
                 };
-                tokio::select! {
-                    biased;
-                    res = get.get_interactive(out) => res,
-                    _ = tokio::signal::ctrl_c() => {
-                        println!("Ending transfer early...");
-                        Ok(())
-                    }
-                }
+                self::history::get_recorded(get, out, rpc_port).await
             }
             Commands::Provide {
                 path,
                 addr,
                 rpc_port,
                 request_token,
+                import_addr,
+                gateway_addr,
             } => {
                 let request_token = match request_token {
                     Some(RequestTokenOptions::Random) => Some(RequestToken::generate()),
@@ -117,17 +119,22 @@ impl Cli {
                 self::provide::run(
                     rt,
                     path,
+                    config,
                     ProvideOptions {
                         addr,
                         rpc_port,
                         keylog: self.keylog,
                         request_token,
-                        derp_map: config.derp_map(),
+                        import_addr,
+                        gateway_addr,
                     },
                 )
                 .await
             }
             Commands::List(cmd) => cmd.run().await,
+            Commands::Tag(cmd) => cmd.run().await,
+            Commands::Blob(cmd) => cmd.run().await,
+            Commands::Peer(cmd) => cmd.run().await,
             Commands::Validate { rpc_port } => self::validate::run(rpc_port).await,
             Commands::Shutdown { force, rpc_port } => {
                 let client = make_rpc_client(rpc_port).await?;
@@ -149,7 +156,13 @@ impl Cli {
                 println!("Listening addresses: {:?}", response.addrs);
                 Ok(())
             }
+            Commands::History { rpc_port } => self::history::list(rpc_port).await,
+            Commands::Resume { id, rpc_port } => {
+                self::history::resume(id, rpc_port, self.keylog, config).await
+            }
             Commands::Doctor { command } => self::doctor::run(command, config).await,
+            #[cfg(feature = "fuse")]
+            Commands::Mount { hash, path } => self::mount::run(hash, path, config).await,
         }
     }
 }
@@ -167,13 +180,16 @@ pub enum Commands {
     ///
     /// If PATH is a folder all files in that folder will be served.  If no PATH is
     /// specified reads from STDIN.
+    ///
+    /// The provider keeps running until it is shut down, and is controlled by the other
+    /// commands through its RPC port.
+    #[clap(visible_alias = "start")]
     Provide {
         /// Path to initial file or directory to provide
         path: Option<PathBuf>,
+        /// Listening address to bind to, defaults to the `bind_port` of the config
         #[clap(long, short)]
-        /// Listening address to bind to
-        #[clap(long, short, default_value_t = SocketAddr::from(iroh::node::DEFAULT_BIND_ADDR))]
-        addr: SocketAddr,
+        addr: Option<SocketAddr>,
         /// RPC port, set to "disabled" to disable RPC
         #[clap(long, default_value_t = ProviderRpcPort::Enabled(DEFAULT_RPC_PORT))]
         rpc_port: ProviderRpcPort,
@@ -182,10 +198,42 @@ pub enum Commands {
         /// Pass "random" to generate a random token, or base32-encoded bytes to use as a token
         #[clap(long)]
         request_token: Option<RequestTokenOptions>,
+        /// Serve a local HTTP endpoint on this address to import blobs
+        ///
+        /// Must be a loopback address. Data posted to /blobs with the token from the
+        /// import-token file in the data directory is added to the provider, and its hash is
+        /// returned.
+        #[clap(long)]
+        import_addr: Option<SocketAddr>,
+        /// Serve an HTTP gateway on this address
+        ///
+        /// Blobs are served at /blob/<hash> and entries of collections at
+        /// /collection/<hash>/<path>, without authentication.
+        #[clap(long)]
+        gateway_addr: Option<SocketAddr>,
     },
     /// List availble content on the provider.
     #[clap(subcommand)]
     List(self::list::Commands),
+    /// Manage named tags, which keep content on the provider until they expire.
+    #[clap(subcommand)]
+    Tag(self::tag::Commands),
+    /// Add, read and share blobs of the running provider.
+    #[clap(subcommand)]
+    Blob(self::blob::Commands),
+    /// Inspect the peers of the running provider.
+    #[clap(subcommand)]
+    Peer(self::peer::Commands),
+    /// Mount a collection from the local database as a read-only filesystem.
+    ///
+    /// The provider does not need to be running, the collection is unmounted on Ctrl-C.
+    #[cfg(feature = "fuse")]
+    Mount {
+        /// The hash of the collection to mount
+        hash: Hash,
+        /// The directory to mount the collection at
+        path: PathBuf,
+    },
     /// Validate hashes on the running provider.
     Validate {
         /// RPC port of the provider
@@ -256,6 +304,25 @@ pub enum Commands {
         /// True to download a single blob, false (default) to download a collection and its children.
         #[clap(long, default_value_t = false)]
         single: bool,
+        /// RPC port of a running provider, in whose history the transfer is recorded
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// List recent transfers of the provider.
+    History {
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// Restart a transfer from the history of the provider.
+    ///
+    /// Gets are resumed from the data already in their output directory.
+    Resume {
+        /// The id of the transfer, as listed by the history command
+        id: u64,
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
     },
     /// List listening addresses of the provider.
     Addresses {
@@ -310,6 +377,7 @@ pub fn init_metrics_collection(
     if let Some(metrics_addr) = metrics_addr {
         iroh_metrics::core::Core::init(|reg, metrics| {
             metrics.insert(iroh::metrics::Metrics::new(reg));
+            metrics.insert(iroh_bytes::metrics::Metrics::new(reg));
             metrics.insert(iroh_net::metrics::MagicsockMetrics::new(reg));
             metrics.insert(iroh_net::metrics::NetcheckMetrics::new(reg));
             metrics.insert(iroh_net::metrics::PortmapMetrics::new(reg));
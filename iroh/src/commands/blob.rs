@@ -0,0 +1,96 @@
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use clap::Subcommand;
+use futures::StreamExt;
+use iroh::dial::Ticket;
+use iroh::rpc_protocol::{BlobReadRequest, IdRequest};
+use iroh_bytes::{protocol::RequestToken, Hash};
+use tokio::io::AsyncWriteExt;
+
+use super::{make_rpc_client, DEFAULT_RPC_PORT};
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Commands {
+    /// Add data from PATH to the running provider's database.
+    Add {
+        /// The path to the file or folder to add
+        path: PathBuf,
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// Read a blob from the running provider's database.
+    Get {
+        /// The hash of the blob
+        hash: Hash,
+        /// File to write the blob to, defaults to writing to STDOUT
+        #[clap(long, short)]
+        out: Option<PathBuf>,
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// Print a ticket to fetch a blob from the running provider.
+    Share {
+        /// The hash of the blob
+        hash: Hash,
+        /// base32-encoded request token the provider requires, if any
+        #[clap(long)]
+        token: Option<RequestToken>,
+        /// Share the collection with this hash and its children instead of a single blob
+        #[clap(long, default_value_t = false)]
+        collection: bool,
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+}
+
+impl Commands {
+    pub async fn run(self) -> Result<()> {
+        match self {
+            Commands::Add { path, rpc_port } => super::add::run(path, rpc_port).await?,
+            Commands::Get {
+                hash,
+                out,
+                rpc_port,
+            } => {
+                let client = make_rpc_client(rpc_port).await?;
+                let mut response = client.server_streaming(BlobReadRequest { hash }).await?;
+                let mut writer: Box<dyn tokio::io::AsyncWrite + Unpin> = match &out {
+                    Some(out) => Box::new(
+                        tokio::fs::File::create(out)
+                            .await
+                            .with_context(|| format!("failed to create {}", out.display()))?,
+                    ),
+                    None => Box::new(tokio::io::stdout()),
+                };
+                while let Some(item) = response.next().await {
+                    let item = item??;
+                    writer.write_all(&item.data).await?;
+                }
+                writer.flush().await?;
+            }
+            Commands::Share {
+                hash,
+                token,
+                collection,
+                rpc_port,
+            } => {
+                let client = make_rpc_client(rpc_port).await?;
+                let response = client.rpc(IdRequest).await?;
+                let ticket = Ticket::new(
+                    hash,
+                    *response.peer_id,
+                    response.listen_addrs,
+                    token,
+                    collection,
+                    response.derp_region,
+                )?;
+                println!("{ticket}");
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,17 +1,15 @@
 //! Tool to get information about the current network environment of a node,
 //! and to test connectivity to specific other nodes.
 use std::{
-    collections::HashMap,
     net::SocketAddr,
     num::NonZeroU16,
     time::{Duration, Instant},
 };
 
-use crate::config::{iroh_config_path, iroh_data_root, Config, CONFIG_FILE_NAME, ENV_PREFIX};
-
 use anyhow::Context;
 use clap::Subcommand;
 use indicatif::{HumanBytes, MultiProgress, ProgressBar};
+use iroh::config::Config;
 use iroh::util::progress::ProgressWriter;
 use iroh_net::{
     config,
@@ -66,6 +64,9 @@ pub enum Commands {
         /// The port of the STUN server.
         #[clap(long, default_value_t = DEFAULT_DERP_STUN_PORT)]
         stun_port: u16,
+        /// Also probe throughput, loss and jitter towards the preferred DERP region.
+        #[clap(long)]
+        throughput: bool,
     },
     /// Wait for incoming requests from iroh doctor connect
     Accept {
@@ -235,7 +236,12 @@ async fn send_blocks(
     Ok(())
 }
 
-async fn report(stun_host: Option<String>, stun_port: u16, config: &Config) -> anyhow::Result<()> {
+async fn report(
+    stun_host: Option<String>,
+    stun_port: u16,
+    throughput: bool,
+    config: &Config,
+) -> anyhow::Result<()> {
     let port_mapper = portmapper::Client::default().await;
     let mut client = netcheck::Client::new(Some(port_mapper)).await?;
 
@@ -249,8 +255,38 @@ async fn report(stun_host: Option<String>, stun_port: u16, config: &Config) -> a
     };
     println!("getting report using derp map {dm:#?}");
 
-    let r = client.get_report(dm, None, None).await?;
+    let r = client.get_report(dm.clone(), None, None).await?;
     println!("{r:#?}");
+    if throughput {
+        let region = match r.preferred_derp {
+            0 => dm
+                .region_ids()
+                .first()
+                .copied()
+                .context("no DERP regions")?,
+            region => region,
+        };
+        let probe = netcheck::ThroughputProbe::default();
+        let t = netcheck::probe_throughput(&dm, region, &probe).await?;
+        println!("throughput probe of region {region} ({}):", t.addr);
+        println!(
+            "  received {}/{} packets ({:.1}% loss)",
+            t.received,
+            t.sent,
+            t.loss() * 100.0
+        );
+        println!(
+            "  rtt min/avg/max {:?}/{:?}/{:?}, jitter {:?}",
+            t.rtt_min, t.rtt_avg, t.rtt_max, t.jitter
+        );
+        match t.throughput() {
+            Some(bps) => println!(
+                "  estimated upstream throughput {}/s",
+                HumanBytes(bps as u64)
+            ),
+            None => println!("  estimated upstream throughput unknown"),
+        }
+    }
     Ok(())
 }
 
@@ -769,7 +805,7 @@ impl std::fmt::Display for RegionDetails {
     }
 }
 
-fn create_secret_key(private_key: PrivateKey) -> anyhow::Result<SecretKey> {
+fn create_secret_key(private_key: PrivateKey, config: &Config) -> anyhow::Result<SecretKey> {
     Ok(match private_key {
         PrivateKey::Random => SecretKey::generate(),
         PrivateKey::Hex(hex) => {
@@ -778,8 +814,7 @@ fn create_secret_key(private_key: PrivateKey) -> anyhow::Result<SecretKey> {
             SecretKey::from(bytes)
         }
         PrivateKey::Local => {
-            let iroh_data_root = iroh_data_root()?;
-            let path = iroh_data_root.join("keypair");
+            let path = config.keypair_file()?;
             if path.exists() {
                 let bytes = std::fs::read(&path)?;
                 let keypair = Keypair::try_from_openssh(bytes)?;
@@ -800,7 +835,8 @@ pub async fn run(command: Commands, config: &Config) -> anyhow::Result<()> {
         Commands::Report {
             stun_host,
             stun_port,
-        } => report(stun_host, stun_port, config).await,
+            throughput,
+        } => report(stun_host, stun_port, throughput, config).await,
         Commands::Connect {
             dial,
             private_key,
@@ -813,7 +849,7 @@ pub async fn run(command: Commands, config: &Config) -> anyhow::Result<()> {
             } else {
                 (config.derp_map(), derp_region)
             };
-            let private_key = create_secret_key(private_key)?;
+            let private_key = create_secret_key(private_key, config)?;
             connect(dial, private_key, remote_endpoint, derp_region, derp_map).await
         }
         Commands::Accept {
@@ -827,7 +863,7 @@ pub async fn run(command: Commands, config: &Config) -> anyhow::Result<()> {
             } else {
                 config.derp_map()
             };
-            let private_key = create_secret_key(private_key)?;
+            let private_key = create_secret_key(private_key, config)?;
             let config = TestConfig { size, iterations };
             accept(private_key, config, derp_map).await
         }
@@ -849,21 +885,6 @@ pub async fn run(command: Commands, config: &Config) -> anyhow::Result<()> {
 
             port_map_probe(config).await
         }
-        Commands::DerpRegions => {
-            let default_config_path =
-                iroh_config_path(CONFIG_FILE_NAME).context("invalid config path")?;
-
-            let sources = [Some(default_config_path.as_path())];
-            let config = Config::load(
-                // potential config files
-                &sources,
-                // env var prefix for this config
-                ENV_PREFIX,
-                // map of present command line arguments
-                // args.make_overrides_map(),
-                HashMap::<String, String>::new(),
-            )?;
-            derp_regions(config).await
-        }
+        Commands::DerpRegions => derp_regions(config.clone()).await,
     }
 }
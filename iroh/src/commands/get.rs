@@ -8,6 +8,8 @@ use indicatif::{
 };
 use iroh::{
     collection::Collection,
+    config::iroh_data_path,
+    dial::DialBackoff,
     util::{io::pathbuf_from_name, progress::ProgressSliceWriter},
 };
 use iroh_bytes::{
@@ -22,6 +24,9 @@ use iroh_io::{AsyncSliceWriter, ConcatenateSliceWriter, File};
 use range_collections::RangeSet2;
 use tokio::sync::mpsc;
 
+/// File name inside `IROH_DATA_DIR` where failed dials are recorded.
+const DIAL_BACKOFF_FILE: &str = "dial-backoff";
+
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 pub struct GetInteractive {
@@ -43,8 +48,25 @@ impl GetInteractive {
             .into()
     }
 
+    /// Dial the provider, skipping addresses that failed recently.
+    ///
+    /// The outcome is recorded in the data directory, so that it is remembered by later
+    /// invocations.
+    async fn dial(&self) -> Result<quinn::Connection> {
+        let path = iroh_data_path(Path::new(DIAL_BACKOFF_FILE))?;
+        let backoff = DialBackoff::load(&path).await.unwrap_or_else(|err| {
+            tracing::warn!("ignoring dial backoff state: {err:#}");
+            DialBackoff::default()
+        });
+        let res = iroh::dial::dial_with_backoff(self.opts.clone(), &backoff).await;
+        if let Err(err) = backoff.save(&path).await {
+            tracing::warn!("failed to save dial backoff state: {err:#}");
+        }
+        res
+    }
+
     /// Get a single file.
-    async fn get_to_file_single(self, out_dir: PathBuf, temp_dir: PathBuf) -> Result<()> {
+    async fn get_to_file_single(self, out_dir: PathBuf, temp_dir: PathBuf) -> Result<get::Stats> {
         let hash = self.hash;
         write(format!("Fetching: {}", hash));
         write(format!("{} Connecting ...", style("[1/3]").bold().dim()));
@@ -67,7 +89,7 @@ impl GetInteractive {
         let collection_info = Some((1, 0));
 
         let request = self.new_request(query).with_token(self.token.clone());
-        let connection = iroh::dial::dial(self.opts).await?;
+        let connection = self.dial().await?;
         let response = fsm::start(connection, request);
         let connected = response.next().await?;
         write(format!("{} Requesting ...", style("[2/3]").bold().dim()));
@@ -134,11 +156,11 @@ impl GetInteractive {
             HumanBytes((stats.bytes_read as f64 / stats.elapsed.as_secs_f64()) as u64)
         ));
 
-        Ok(())
+        Ok(stats)
     }
 
     /// Get into a file or directory
-    async fn get_to_dir_multi(self, out_dir: PathBuf, temp_dir: PathBuf) -> Result<()> {
+    async fn get_to_dir_multi(self, out_dir: PathBuf, temp_dir: PathBuf) -> Result<get::Stats> {
         let hash = self.hash;
         write(format!("Fetching: {}", hash));
         write(format!("{} Connecting ...", style("[1/3]").bold().dim()));
@@ -155,7 +177,7 @@ impl GetInteractive {
         };
 
         let request = self.new_request(query).with_token(self.token.clone());
-        let connection = iroh::dial::dial(self.opts).await?;
+        let connection = self.dial().await?;
         let response = fsm::start(connection, request);
         let connected = response.next().await?;
         write(format!("{} Requesting ...", style("[2/3]").bold().dim()));
@@ -287,11 +309,11 @@ impl GetInteractive {
             HumanBytes((stats.bytes_read as f64 / stats.elapsed.as_secs_f64()) as u64)
         ));
 
-        Ok(())
+        Ok(stats)
     }
 
     /// Get into a file or directory
-    async fn get_to_dir(self, out_dir: PathBuf) -> Result<()> {
+    async fn get_to_dir(self, out_dir: PathBuf) -> Result<get::Stats> {
         let temp_dir = out_dir.join(".iroh-tmp");
         if self.single {
             self.get_to_file_single(out_dir, temp_dir).await
@@ -300,7 +322,7 @@ impl GetInteractive {
         }
     }
 
-    pub async fn get_interactive(self, out_dir: Option<PathBuf>) -> Result<()> {
+    pub async fn get_interactive(self, out_dir: Option<PathBuf>) -> Result<get::Stats> {
         if let Some(out_dir) = out_dir {
             self.get_to_dir(out_dir).await
         } else {
@@ -309,7 +331,7 @@ impl GetInteractive {
     }
 
     /// Get to stdout, no resume possible.
-    async fn get_to_stdout(self) -> Result<()> {
+    async fn get_to_stdout(self) -> Result<get::Stats> {
         write(format!("Fetching: {}", self.hash));
         write(format!("{} Connecting ...", style("[1/3]").bold().dim()));
         let query = if self.single {
@@ -322,13 +344,13 @@ impl GetInteractive {
 
         let pb = make_download_pb();
         let request = self.new_request(query).with_token(self.token.clone());
-        let connection = iroh::dial::dial(self.opts).await?;
+        let connection = self.dial().await?;
         let response = fsm::start(connection, request);
         let connected = response.next().await?;
         write(format!("{} Requesting ...", style("[2/3]").bold().dim()));
         let ConnectedNext::StartRoot(curr) = connected.next().await? else {
-        anyhow::bail!("expected root to be present");
-    };
+            anyhow::bail!("expected root to be present");
+        };
         let stats = if self.single {
             get_to_stdout_single(curr).await?
         } else {
@@ -342,7 +364,7 @@ impl GetInteractive {
             HumanBytes((stats.bytes_read as f64 / stats.elapsed.as_secs_f64()) as u64)
         ));
 
-        Ok(())
+        Ok(stats)
     }
 }
 
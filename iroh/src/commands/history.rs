@@ -0,0 +1,151 @@
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use indicatif::{HumanBytes, HumanDuration};
+use iroh::config::Config;
+use iroh::history::{Operation, Outcome, Record};
+use iroh::rpc_protocol::{
+    HistoryFinishRequest, HistoryGetRequest, HistoryListRequest, HistoryStartRequest,
+};
+use iroh_net::tls::Keypair;
+
+use super::{add, get::GetInteractive, make_rpc_client};
+
+/// Lists the transfers in the history of the running provider.
+pub async fn list(rpc_port: u16) -> Result<()> {
+    let client = make_rpc_client(rpc_port).await?;
+    let mut response = client.server_streaming(HistoryListRequest).await?;
+    while let Some(item) = response.next().await {
+        println!("{}", format_record(&item?.record));
+    }
+    Ok(())
+}
+
+/// Restarts the transfer with the given id from the history of the running provider.
+///
+/// Gets are resumed from the data already in their output directory.
+pub async fn resume(id: u64, rpc_port: u16, keylog: bool, config: &Config) -> Result<()> {
+    let client = make_rpc_client(rpc_port).await?;
+    let record = client
+        .rpc(HistoryGetRequest { id })
+        .await?
+        .record
+        .with_context(|| format!("no transfer with id {id} in the history"))?;
+    println!("Resuming {}", format_record(&record));
+    match record.op {
+        Operation::Add { path } => add::run(path, rpc_port).await,
+        Operation::Get {
+            hash,
+            peer,
+            addrs,
+            derp_region,
+            token,
+            single,
+            out,
+        } => {
+            let get = GetInteractive {
+                hash,
+                opts: iroh::dial::Options {
+                    addrs,
+                    peer_id: peer,
+                    keylog,
+                    derp_region,
+                    derp_map: config.derp_map(),
+                    keypair: Keypair::generate(),
+                },
+                token,
+                single,
+            };
+            get_recorded(get, out, rpc_port).await
+        }
+    }
+}
+
+/// Runs a get, recording it in the history of the provider running at `rpc_port`.
+///
+/// If no provider is running the get is not recorded.
+pub async fn get_recorded(get: GetInteractive, out: Option<PathBuf>, rpc_port: u16) -> Result<()> {
+    let op = Operation::Get {
+        hash: get.hash,
+        peer: get.opts.peer_id,
+        addrs: get.opts.addrs.clone(),
+        derp_region: get.opts.derp_region,
+        token: get.token.clone(),
+        single: get.single,
+        out: out.clone(),
+    };
+    let recorder = match make_rpc_client(rpc_port).await {
+        Ok(client) => match client.rpc(HistoryStartRequest { op }).await {
+            Ok(Ok(response)) => Some((client, response.id)),
+            Ok(Err(err)) => {
+                tracing::warn!("failed to record get in history: {err}");
+                None
+            }
+            Err(err) => {
+                tracing::warn!("failed to record get in history: {err}");
+                None
+            }
+        },
+        Err(err) => {
+            tracing::debug!("not recording get in history: {err:#}");
+            None
+        }
+    };
+
+    let hash = get.hash;
+    let (res, outcome, bytes) = tokio::select! {
+        biased;
+        res = get.get_interactive(out) => match res {
+            Ok(stats) => (Ok(()), Outcome::Done { hash }, stats.bytes_read),
+            Err(err) => {
+                let reason = format!("{err:#}");
+                (Err(err), Outcome::Failed { reason }, 0)
+            }
+        },
+        _ = tokio::signal::ctrl_c() => {
+            println!("Ending transfer early...");
+            (Ok(()), Outcome::Interrupted, 0)
+        }
+    };
+
+    if let Some((client, id)) = recorder {
+        let incomplete = outcome.is_incomplete();
+        let request = HistoryFinishRequest { id, outcome, bytes };
+        match client.rpc(request).await {
+            Ok(Ok(_)) => {
+                if incomplete {
+                    println!("Recorded as transfer {id}, run `iroh resume {id}` to retry");
+                }
+            }
+            Ok(Err(err)) => tracing::warn!("failed to record get in history: {err}"),
+            Err(err) => tracing::warn!("failed to record get in history: {err}"),
+        }
+    }
+    res
+}
+
+fn format_record(record: &Record) -> String {
+    let age = SystemTime::now()
+        .duration_since(record.started)
+        .unwrap_or_default();
+    let op = match &record.op {
+        Operation::Add { path } => format!("add {}", path.display()),
+        Operation::Get { hash, peer, .. } => format!("get {hash} from {peer}"),
+    };
+    let outcome = match &record.outcome {
+        Outcome::Running => "running".to_string(),
+        Outcome::Done { hash } => format!("done {hash}"),
+        Outcome::Failed { reason } => format!("failed: {reason}"),
+        Outcome::Interrupted => "interrupted".to_string(),
+    };
+    format!(
+        "{} {} ago: {} ({}, {})",
+        record.id,
+        HumanDuration(age),
+        op,
+        outcome,
+        HumanBytes(record.bytes)
+    )
+}
@@ -0,0 +1,22 @@
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use iroh::{config::Config, database::flat::Database, fuse::CollectionFs};
+use iroh_bytes::Hash;
+
+/// Mounts the collection `hash` from the local database at `path` until Ctrl-C is pressed.
+pub async fn run(hash: Hash, path: PathBuf, config: &Config) -> Result<()> {
+    let iroh_data_root = config.data_root()?;
+    let db = Database::load(&iroh_data_root).await.with_context(|| {
+        format!(
+            "Failed to load iroh database from {}",
+            iroh_data_root.display()
+        )
+    })?;
+    let fs = CollectionFs::new(db, hash).await?;
+    let session = fs.mount(&path)?;
+    println!("Mounted {hash} at {}, press Ctrl-C to unmount", path.display());
+    tokio::signal::ctrl_c().await?;
+    drop(session);
+    Ok(())
+}
@@ -0,0 +1,46 @@
+use anyhow::Result;
+use clap::Subcommand;
+use futures::StreamExt;
+use indicatif::HumanDuration;
+use iroh::rpc_protocol::PeersRequest;
+
+use super::{make_rpc_client, DEFAULT_RPC_PORT};
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Commands {
+    /// List the peers known to the running provider.
+    Ls {
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+}
+
+impl Commands {
+    pub async fn run(self) -> Result<()> {
+        match self {
+            Commands::Ls { rpc_port } => {
+                let client = make_rpc_client(rpc_port).await?;
+                let mut response = client.server_streaming(PeersRequest).await?;
+                while let Some(item) = response.next().await {
+                    let item = item?;
+                    let path = match (item.direct_addr, item.derp_region) {
+                        (Some(addr), _) => format!("direct {addr}"),
+                        (None, Some(region)) => format!("derp region {region}"),
+                        (None, None) => "no path".to_string(),
+                    };
+                    let rtt = item
+                        .rtt
+                        .map(|rtt| format!("{}ms", rtt.as_millis()))
+                        .unwrap_or_else(|| "-".to_string());
+                    println!(
+                        "{} {path} rtt {rtt} (active {} ago)",
+                        item.peer_id,
+                        HumanDuration(item.last_active)
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
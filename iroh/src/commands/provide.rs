@@ -6,35 +6,52 @@ use std::{
     sync::Arc,
 };
 
-use anyhow::{anyhow, ensure, Context, Result};
+use anyhow::{ensure, Context, Result};
 use iroh::{
     collection::IrohCollectionParser,
-    database::flat::{Database, FNAME_PATHS},
+    config::Config,
+    database::flat::{CompactOptions, Database, FNAME_PATHS},
+    gateway::Gateway,
+    history::History,
+    http_import::ImportEndpoint,
     node::{Node, StaticTokenAuthHandler},
     rpc_protocol::{ProvideRequest, ProviderRequest, ProviderResponse, ProviderService},
+    util::io::write_private,
 };
 use iroh_bytes::{protocol::RequestToken, provider::BaoReadonlyDb, util::runtime};
-use iroh_net::{derp::DerpMap, tls::Keypair};
+use iroh_net::tls::Keypair;
 use quic_rpc::{transport::quinn::QuinnServerEndpoint, ServiceEndpoint};
-use tokio::io::AsyncWriteExt;
-
-use crate::config::iroh_data_root;
 
 use super::{
     add::{aggregate_add_response, print_add_response},
     MAX_RPC_CONNECTIONS, MAX_RPC_STREAMS, RPC_ALPN,
 };
 
+/// File name inside `IROH_DATA_DIR` where the token for the import endpoint is stored.
+const IMPORT_TOKEN_FILE: &str = "import-token";
+
+/// Directory inside `IROH_DATA_DIR` where data posted to the import endpoint is stored.
+const IMPORTS_DIR: &str = "imports";
+
+/// File name inside `IROH_DATA_DIR` where the transfer history is stored.
+const HISTORY_FILE: &str = "history";
+
 #[derive(Debug)]
 pub struct ProvideOptions {
-    pub addr: SocketAddr,
+    pub addr: Option<SocketAddr>,
     pub rpc_port: ProviderRpcPort,
     pub keylog: bool,
     pub request_token: Option<RequestToken>,
-    pub derp_map: Option<DerpMap>,
+    pub import_addr: Option<SocketAddr>,
+    pub gateway_addr: Option<SocketAddr>,
 }
 
-pub async fn run(rt: &runtime::Handle, path: Option<PathBuf>, opts: ProvideOptions) -> Result<()> {
+pub async fn run(
+    rt: &runtime::Handle,
+    path: Option<PathBuf>,
+    config: &Config,
+    opts: ProvideOptions,
+) -> Result<()> {
     if let Some(ref path) = path {
         ensure!(
             path.exists(),
@@ -43,7 +60,7 @@ pub async fn run(rt: &runtime::Handle, path: Option<PathBuf>, opts: ProvideOptio
         );
     }
 
-    let iroh_data_root = iroh_data_root()?;
+    let iroh_data_root = config.data_root()?;
     let marker = iroh_data_root.join(FNAME_PATHS);
     let db = {
         if iroh_data_root.is_dir() && marker.exists() {
@@ -59,13 +76,61 @@ pub async fn run(rt: &runtime::Handle, path: Option<PathBuf>, opts: ProvideOptio
             Database::default()
         }
     };
-    let key = Some(iroh_data_root.join("keypair"));
+    let db = db.with_io_limits(config.io_limits).with_quota(config.quota);
+    let history = History::load(iroh_data_root.join(HISTORY_FILE)).await?;
     let token = opts.request_token.clone();
-    let provider = provide(db.clone(), rt, key, opts).await?;
+    let import_addr = opts.import_addr;
+    let gateway_addr = opts.gateway_addr;
+    let provider = provide(db.clone(), rt, config, history, opts).await?;
     let controller = provider.controller();
     if let Some(t) = token.as_ref() {
         println!("Request token: {}", t);
     }
+    if let Some(addr) = import_addr {
+        ensure!(
+            addr.ip().is_loopback(),
+            "the import endpoint must listen on a loopback address, not {addr}"
+        );
+        let listener = std::net::TcpListener::bind(addr)
+            .with_context(|| format!("failed to bind import endpoint to {addr}"))?;
+        let import_token = RequestToken::generate();
+        let token_path = iroh_data_root.join(IMPORT_TOKEN_FILE);
+        write_private(&token_path, import_token.to_string().as_bytes())
+            .await
+            .with_context(|| format!("failed to write {}", token_path.display()))?;
+        println!("Import endpoint: http://{}/blobs", listener.local_addr()?);
+        println!(
+            "Import token: {} (in {})",
+            import_token,
+            token_path.display()
+        );
+        let endpoint =
+            ImportEndpoint::new(db.clone(), import_token, iroh_data_root.join(IMPORTS_DIR));
+        let cancel = provider.cancel_token();
+        tokio::spawn(async move {
+            if let Err(err) = endpoint
+                .serve(listener, async move { cancel.cancelled().await })
+                .await
+            {
+                tracing::error!("import endpoint failed: {err:#}");
+            }
+        });
+    }
+    if let Some(addr) = gateway_addr {
+        let listener = std::net::TcpListener::bind(addr)
+            .with_context(|| format!("failed to bind gateway to {addr}"))?;
+        println!("Gateway: http://{}", listener.local_addr()?);
+        let gateway = Gateway::new(db.clone());
+        let cancel = provider.cancel_token();
+        tokio::spawn(async move {
+            if let Err(err) = gateway
+                .serve(listener, async move { cancel.cancelled().await })
+                .await
+            {
+                tracing::error!("gateway failed: {err:#}");
+            }
+        });
+    }
 
     // task that will add data to the provider, either from a file or from stdin
     let fut = {
@@ -107,8 +172,13 @@ pub async fn run(rt: &runtime::Handle, path: Option<PathBuf>, opts: ProvideOptio
             res?;
         }
     }
-    // persist the db to disk.
+    // persist the db to disk, and remove the files of entries that are gone.
     db.save(&iroh_data_root).await?;
+    let opts = CompactOptions {
+        owned_dirs: vec![iroh_data_root.join(IMPORTS_DIR)],
+        ..Default::default()
+    };
+    db.compact(&iroh_data_root, opts).await?;
 
     // the future holds a reference to the temp file, so we need to
     // keep it for as long as the provider is running. The drop(fut)
@@ -121,19 +191,22 @@ pub async fn run(rt: &runtime::Handle, path: Option<PathBuf>, opts: ProvideOptio
 async fn provide<D: BaoReadonlyDb>(
     db: D,
     rt: &runtime::Handle,
-    key: Option<PathBuf>,
+    config: &Config,
+    history: History,
     opts: ProvideOptions,
 ) -> Result<Node<D>> {
-    let keypair = get_keypair(key).await?;
+    let keypair = config.keypair().await?;
 
     let mut builder = Node::builder(db)
+        .config(config)
         .collection_parser(IrohCollectionParser)
         .custom_auth_handler(Arc::new(StaticTokenAuthHandler::new(opts.request_token)))
-        .keylog(opts.keylog);
-    if let Some(dm) = opts.derp_map {
-        builder = builder.derp_map(dm);
+        .keylog(opts.keylog)
+        .history(history);
+    if let Some(addr) = opts.addr {
+        builder = builder.bind_addr(addr);
     }
-    let builder = builder.bind_addr(opts.addr).runtime(rt);
+    let builder = builder.runtime(rt);
 
     let provider = if let Some(rpc_port) = opts.rpc_port.into() {
         let rpc_endpoint = make_rpc_endpoint(&keypair, rpc_port)?;
@@ -160,50 +233,6 @@ async fn provide<D: BaoReadonlyDb>(
     Ok(provider)
 }
 
-async fn get_keypair(key: Option<PathBuf>) -> Result<Keypair> {
-    match key {
-        Some(key_path) => {
-            if key_path.exists() {
-                let keystr = tokio::fs::read(key_path).await?;
-                let keypair = Keypair::try_from_openssh(keystr).context("invalid keyfile")?;
-                Ok(keypair)
-            } else {
-                let keypair = Keypair::generate();
-                let ser_key = keypair.to_openssh()?;
-
-                // Try to canoncialize if possible
-                let key_path = key_path.canonicalize().unwrap_or(key_path);
-                let key_path_parent = key_path.parent().ok_or_else(|| {
-                    anyhow!("no parent directory found for '{}'", key_path.display())
-                })?;
-                tokio::fs::create_dir_all(&key_path_parent).await?;
-
-                // write to tempfile
-                let (file, temp_file_path) = tempfile::NamedTempFile::new_in(key_path_parent)
-                    .context("unable to create tempfile")?
-                    .into_parts();
-                let mut file = tokio::fs::File::from_std(file);
-                file.write_all(ser_key.as_bytes())
-                    .await
-                    .context("unable to write keyfile")?;
-                file.flush().await?;
-                drop(file);
-
-                // move file
-                tokio::fs::rename(temp_file_path, key_path)
-                    .await
-                    .context("failed to rename keyfile")?;
-
-                Ok(keypair)
-            }
-        }
-        None => {
-            // No path provided, just generate one
-            Ok(Keypair::generate())
-        }
-    }
-}
-
 /// Makes a an RPC endpoint that uses a QUIC transport
 fn make_rpc_endpoint(
     keypair: &Keypair,
@@ -0,0 +1,101 @@
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use clap::Subcommand;
+use futures::StreamExt;
+use indicatif::HumanDuration;
+use iroh::rpc_protocol::{TagListRequest, TagRemoveRequest, TagSetRequest};
+use iroh_bytes::Hash;
+
+use super::{make_rpc_client, DEFAULT_RPC_PORT};
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Commands {
+    /// Tag a hash on the running provider, so it is not evicted.
+    ///
+    /// Tagging a collection also keeps the blobs it contains. An existing tag with the
+    /// same name is replaced.
+    Set {
+        /// The name of the tag
+        name: String,
+        /// The hash to tag
+        hash: Hash,
+        /// Remove the tag after this many seconds, by default it is kept until removed
+        #[clap(long)]
+        expires_secs: Option<u64>,
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// Remove a tag from the running provider.
+    Remove {
+        /// The name of the tag
+        name: String,
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+    /// List the tags on the running provider.
+    List {
+        /// RPC port of the provider
+        #[clap(long, default_value_t = DEFAULT_RPC_PORT)]
+        rpc_port: u16,
+    },
+}
+
+impl Commands {
+    pub async fn run(self) -> Result<()> {
+        match self {
+            Commands::Set {
+                name,
+                hash,
+                expires_secs,
+                rpc_port,
+            } => {
+                let client = make_rpc_client(rpc_port).await?;
+                let expires =
+                    expires_secs.map(|secs| SystemTime::now() + Duration::from_secs(secs));
+                let response = client
+                    .rpc(TagSetRequest {
+                        name: name.clone(),
+                        hash,
+                        expires,
+                    })
+                    .await??;
+                if let Some(previous) = response.previous {
+                    println!("Moved tag {name} from {previous} to {hash}");
+                } else {
+                    println!("Tagged {hash} as {name}");
+                }
+            }
+            Commands::Remove { name, rpc_port } => {
+                let client = make_rpc_client(rpc_port).await?;
+                let response = client
+                    .rpc(TagRemoveRequest { name: name.clone() })
+                    .await??;
+                match response.removed {
+                    Some(hash) => println!("Removed tag {name} from {hash}"),
+                    None => anyhow::bail!("no tag named {name}"),
+                }
+            }
+            Commands::List { rpc_port } => {
+                let client = make_rpc_client(rpc_port).await?;
+                let mut response = client.server_streaming(TagListRequest).await?;
+                while let Some(item) = response.next().await {
+                    let item = item?;
+                    let expires = match item.expires {
+                        Some(expires) => {
+                            let left = expires
+                                .duration_since(SystemTime::now())
+                                .unwrap_or_default();
+                            format!("expires in {}", HumanDuration(left))
+                        }
+                        None => "does not expire".to_string(),
+                    };
+                    println!("{} {} ({})", item.name, item.hash, expires);
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,33 +1,57 @@
-//! Configuration for the iroh CLI.
+//! Configuration for iroh nodes and the iroh CLI.
+//!
+//! The [`Config`] is loaded from TOML files and environment variables, and is used both by
+//! the binaries and to configure a [`crate::node::Builder`] with [`crate::node::Builder::config`].
 
 use std::{
     collections::HashMap,
     env,
+    net::SocketAddr,
     path::{Path, PathBuf},
 };
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use config::{Environment, File, Value};
 use iroh_net::{
     defaults::{default_eu_derp_region, default_na_derp_region},
     derp::{DerpMap, DerpRegion},
+    tls::Keypair,
 };
 use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
 use tracing::debug;
 
+use crate::database::flat::Quota;
+use crate::node::DEFAULT_BIND_ADDR;
+use crate::util::throttle::IoLimits;
+
 /// CONFIG_FILE_NAME is the name of the optional config file located in the iroh home directory
 pub const CONFIG_FILE_NAME: &str = "iroh.config.toml";
 /// ENV_PREFIX should be used along side the config field name to set a config field using
 /// environment variables
-/// For example, `IROH_PATH=/path/to/config` would set the value of the `Config.path` field
+/// For example, `IROH_DATA_DIR=/path/to/data` would set the value of the `Config.data_dir` field
 pub const ENV_PREFIX: &str = "IROH";
 
+/// KEYPAIR_FILE_NAME is the name of the keypair file located in the iroh data directory
+pub const KEYPAIR_FILE_NAME: &str = "keypair";
+
 /// The configuration for the iroh cli.
 #[derive(PartialEq, Eq, Debug, Deserialize, Serialize, Clone)]
 #[serde(default)]
 pub struct Config {
     /// The regions for DERP to use.
     pub derp_regions: Vec<DerpRegion>,
+    /// Limits for background disk io of the database, e.g. while validating.
+    pub io_limits: IoLimits,
+    /// Limit for the total size of the database, unlimited if not set.
+    pub quota: Option<Quota>,
+    /// The port the node binds to.
+    pub bind_port: u16,
+    /// The directory the node stores its data in, [`iroh_data_root`] if not set.
+    pub data_dir: Option<PathBuf>,
+    /// The file the keypair of the node is stored in, `keypair` in the data directory if
+    /// not set.
+    pub keypair_path: Option<PathBuf>,
 }
 
 impl Default for Config {
@@ -35,6 +59,11 @@ impl Default for Config {
         Self {
             // TODO(ramfox): this should probably just be a derp map
             derp_regions: vec![default_na_derp_region(), default_eu_derp_region()],
+            io_limits: IoLimits::default(),
+            quota: None,
+            bind_port: DEFAULT_BIND_ADDR.1,
+            data_dir: None,
+            keypair_path: None,
         }
     }
 }
@@ -44,8 +73,8 @@ impl Config {
     ///
     /// Later items in the *file_paths* slice will have a higher priority than earlier ones.
     ///
-    /// Environment variables are expected to start with the *env_prefix*. Nested fields can be
-    /// accessed using `.`, if your environment allows env vars with `.`
+    /// Environment variables are expected to start with the *env_prefix* followed by `_`.
+    /// Nested fields are separated by `__`, e.g. `IROH_QUOTA__MAX_SIZE`.
     ///
     /// Note: For the metrics configuration env vars, it is recommended to use the metrics
     /// specific prefix `IROH_METRICS` to set a field in the metrics config. You can use the
@@ -73,6 +102,7 @@ impl Config {
         // next, add any environment variables
         builder = builder.add_source(
             Environment::with_prefix(env_prefix)
+                .prefix_separator("_")
                 .separator("__")
                 .try_parsing(true),
         );
@@ -101,6 +131,70 @@ impl Config {
 
         Some(DerpMap { regions })
     }
+
+    /// The address the node binds to.
+    pub fn bind_addr(&self) -> SocketAddr {
+        (DEFAULT_BIND_ADDR.0, self.bind_port).into()
+    }
+
+    /// The directory the node stores its data in.
+    pub fn data_root(&self) -> Result<PathBuf> {
+        match self.data_dir {
+            Some(ref dir) => Ok(dir.clone()),
+            None => iroh_data_root(),
+        }
+    }
+
+    /// The file the keypair of the node is stored in.
+    pub fn keypair_file(&self) -> Result<PathBuf> {
+        match self.keypair_path {
+            Some(ref path) => Ok(path.clone()),
+            None => Ok(self.data_root()?.join(KEYPAIR_FILE_NAME)),
+        }
+    }
+
+    /// Loads the keypair of the node, generating and storing a new one if there is none.
+    pub async fn keypair(&self) -> Result<Keypair> {
+        load_or_create_keypair(self.keypair_file()?).await
+    }
+}
+
+/// Loads the keypair stored at `key_path`, generating and storing a new one if the file
+/// does not exist.
+pub async fn load_or_create_keypair(key_path: PathBuf) -> Result<Keypair> {
+    if key_path.exists() {
+        let keystr = tokio::fs::read(key_path).await?;
+        let keypair = Keypair::try_from_openssh(keystr).context("invalid keyfile")?;
+        Ok(keypair)
+    } else {
+        let keypair = Keypair::generate();
+        let ser_key = keypair.to_openssh()?;
+
+        // Try to canoncialize if possible
+        let key_path = key_path.canonicalize().unwrap_or(key_path);
+        let key_path_parent = key_path
+            .parent()
+            .ok_or_else(|| anyhow!("no parent directory found for '{}'", key_path.display()))?;
+        tokio::fs::create_dir_all(&key_path_parent).await?;
+
+        // write to tempfile
+        let (file, temp_file_path) = tempfile::NamedTempFile::new_in(key_path_parent)
+            .context("unable to create tempfile")?
+            .into_parts();
+        let mut file = tokio::fs::File::from_std(file);
+        file.write_all(ser_key.as_bytes())
+            .await
+            .context("unable to write keyfile")?;
+        file.flush().await?;
+        drop(file);
+
+        // move file
+        tokio::fs::rename(temp_file_path, key_path)
+            .await
+            .context("failed to rename keyfile")?;
+
+        Ok(keypair)
+    }
 }
 
 /// Name of directory that wraps all iroh files in a given application directory
@@ -154,7 +248,6 @@ pub fn iroh_data_root() -> Result<PathBuf> {
 }
 
 /// Path that leads to a file in the iroh data directory.
-#[allow(dead_code)]
 pub fn iroh_data_path(file_name: &Path) -> Result<PathBuf> {
     let path = iroh_data_root()?.join(file_name);
     Ok(path)
@@ -198,5 +291,34 @@ mod tests {
         let config = Config::load::<String, String>(&[][..], "__FOO", Default::default()).unwrap();
 
         assert_eq!(config.derp_regions.len(), 2);
+        assert_eq!(config.io_limits, IoLimits::default());
+        assert_eq!(config.quota, None);
+        assert_eq!(config.bind_addr(), SocketAddr::from(DEFAULT_BIND_ADDR));
+        assert_eq!(config.data_dir, None);
+        assert_eq!(config.keypair_path, None);
+    }
+
+    #[test]
+    fn test_load_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CONFIG_FILE_NAME);
+        std::fs::write(
+            &path,
+            "bind_port = 4433\ndata_dir = \"/data/iroh\"\nderp_regions = []\n",
+        )
+        .unwrap();
+        std::env::set_var("__TEST_LOAD_KEYPAIR_PATH", "/keys/iroh");
+
+        let config = Config::load::<String, String>(
+            &[Some(path.as_path())],
+            "__TEST_LOAD",
+            Default::default(),
+        )
+        .unwrap();
+
+        assert_eq!(config.bind_addr().port(), 4433);
+        assert_eq!(config.derp_map(), None);
+        assert_eq!(config.data_root().unwrap(), PathBuf::from("/data/iroh"));
+        assert_eq!(config.keypair_file().unwrap(), PathBuf::from("/keys/iroh"));
     }
 }
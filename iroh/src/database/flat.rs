@@ -1,9 +1,10 @@
 //! The concrete database used by the iroh binary.
 use std::borrow::Cow;
-use std::collections::{BTreeSet, HashMap};
-use std::io::BufReader;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::{BufReader, Write};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 use std::{fmt, io, result};
 
 use anyhow::Context;
@@ -17,6 +18,8 @@ use iroh_bytes::provider::{BaoMap, BaoMapEntry, BaoReadonlyDb};
 use iroh_bytes::provider::{ProvideProgress, ValidateProgress};
 use iroh_bytes::{Hash, IROH_BLOCK_SIZE};
 use iroh_io::File;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWriteExt};
 use tokio::sync::mpsc;
 use tracing::{trace, trace_span};
 use walkdir::WalkDir;
@@ -24,9 +27,15 @@ use walkdir::WalkDir;
 use crate::collection::Blob;
 use crate::collection::Collection;
 use crate::util::io::canonicalize_path;
+use crate::util::io::pathbuf_from_name;
 use crate::util::io::validate_bao;
 use crate::util::io::BaoValidationError;
 use crate::util::progress::{Progress, ProgressReader, ProgressReaderUpdate};
+use crate::util::throttle::{IoLimits, IoThrottle, ThrottledReader, ThrottledWriter};
+
+mod verify;
+
+pub use verify::DamagedBlob;
 
 /// File name of directory inside `IROH_DATA_DIR` where outboards are stored.
 const FNAME_OUTBOARDS: &str = "outboards";
@@ -39,9 +48,164 @@ const FNAME_COLLECTIONS: &str = "collections";
 /// File name inside `IROH_DATA_DIR` where paths to data are stored.
 pub const FNAME_PATHS: &str = "paths.bin";
 
+/// File name inside `IROH_DATA_DIR` where modification times of external data are stored.
+///
+/// This is separate from [`FNAME_PATHS`] so databases persisted before modification times
+/// were recorded can still be loaded.
+pub const FNAME_MTIMES: &str = "mtimes.bin";
+
+/// File name inside `IROH_DATA_DIR` where pinned hashes are stored.
+pub const FNAME_PINS: &str = "pins.bin";
+
+/// File name inside `IROH_DATA_DIR` where tags are stored.
+pub const FNAME_TAGS: &str = "tags.bin";
+
+/// File name inside `IROH_DATA_DIR` where small internal entries are stored together with
+/// their outboards, see [`Database::with_inline_threshold`].
+pub const FNAME_INLINE: &str = "inline.bin";
+
 /// Database containing content-addressed data (blobs or collections).
 #[derive(Debug, Clone, Default)]
-pub struct Database(Arc<RwLock<HashMap<Hash, DbEntry>>>);
+pub struct Database {
+    entries: Arc<RwLock<HashMap<Hash, DbEntry>>>,
+    /// Throttle for background io, such as validating and persisting.
+    throttle: Arc<IoThrottle>,
+    /// When entries were last added or served, used to pick entries to evict.
+    last_access: Arc<Mutex<HashMap<Hash, Instant>>>,
+    /// Limit for the size of the database, enforced when importing.
+    quota: Option<Quota>,
+    /// Blobs smaller than this are stored inline, see [`Database::with_inline_threshold`].
+    inline_threshold: u64,
+    /// Pinned hashes, which are never evicted.
+    pins: Arc<RwLock<BTreeSet<Hash>>>,
+    /// Named tags, whose hashes are not evicted until the tag expires.
+    tags: Arc<RwLock<BTreeMap<String, Tag>>>,
+    /// Held while persisting or compacting, so they don't remove each other's files.
+    persist_lock: Arc<Mutex<()>>,
+}
+
+/// A limit for the total size of a [`Database`], see [`Database::with_quota`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quota {
+    /// Maximum number of bytes of data in the database, see [`DbStats::total_bytes`].
+    pub max_bytes: u64,
+    /// What to do when an import would exceed `max_bytes`.
+    #[serde(default)]
+    pub policy: QuotaPolicy,
+}
+
+/// What to do when an import would exceed the [`Quota`] of a [`Database`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QuotaPolicy {
+    /// Reject the import.
+    #[default]
+    Reject,
+    /// Remove the entries that were added or served the longest time ago until the import
+    /// fits, rejecting it if it does not fit even after evicting all unpinned and untagged
+    /// entries.
+    ///
+    /// Evicting an external entry only removes it from the database, the referenced file
+    /// is left alone.
+    EvictLeastRecentlyUsed,
+}
+
+/// A named tag on an entry of a [`Database`], see [`Database::tag`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tag {
+    /// The tagged hash.
+    pub hash: Hash,
+    /// When the tag is removed, `None` to keep it until it is removed explicitly.
+    pub expires: Option<SystemTime>,
+}
+
+impl Tag {
+    /// True if the tag has expired at `now`.
+    pub fn is_expired_at(&self, now: SystemTime) -> bool {
+        self.expires.map_or(false, |expires| expires <= now)
+    }
+}
+
+/// Statistics about the content of a [`Database`].
+///
+/// This database only stores complete blobs, so there are no partial blobs to account for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DbStats {
+    /// Number of externally stored blobs.
+    pub blobs: u64,
+    /// Number of internally stored entries, mostly collections.
+    pub collections: u64,
+    /// Total size of the data of all externally stored blobs.
+    pub external_bytes: u64,
+    /// Total size of the data of all internally stored entries.
+    pub internal_bytes: u64,
+    /// Total size of all outboards.
+    pub outboard_bytes: u64,
+}
+
+impl DbStats {
+    /// The total size of the data in the database, excluding outboards.
+    pub fn total_bytes(&self) -> u64 {
+        self.external_bytes + self.internal_bytes
+    }
+
+    fn add(&mut self, entry: &DbEntry) {
+        match entry {
+            DbEntry::External { outboard, size, .. } => {
+                self.blobs += 1;
+                self.external_bytes += size;
+                self.outboard_bytes += outboard.len() as u64;
+            }
+            DbEntry::Internal { outboard, data } => {
+                self.collections += 1;
+                self.internal_bytes += data.len() as u64;
+                self.outboard_bytes += outboard.len() as u64;
+            }
+        }
+    }
+}
+/// Options for [`Database::compact`].
+#[derive(Debug, Clone)]
+pub struct CompactOptions {
+    /// Directories with blob files that belong to the database.
+    ///
+    /// Files in these directories that are not referenced by any entry are removed, e.g.
+    /// the files of evicted entries that were imported through the
+    /// [`ImportEndpoint`](crate::http_import::ImportEndpoint).
+    pub owned_dirs: Vec<PathBuf>,
+    /// Files in `owned_dirs` modified more recently than this are kept, as they might
+    /// still be in the process of being imported.
+    pub min_age: Duration,
+}
+
+impl Default for CompactOptions {
+    fn default() -> Self {
+        Self {
+            owned_dirs: Vec::new(),
+            min_age: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+/// The files removed by [`Database::compact`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactStats {
+    /// Number of removed files.
+    pub files: u64,
+    /// Total size of the removed files.
+    pub bytes: u64,
+}
+
+impl CompactStats {
+    fn remove(&mut self, path: &Path) -> io::Result<()> {
+        let size = std::fs::metadata(path)?.len();
+        std::fs::remove_file(path)?;
+        tracing::debug!("compaction removed {}", path.display());
+        self.files += 1;
+        self.bytes += size;
+        Ok(())
+    }
+}
+
 /// The [BaoMapEntry] implementation for [Database].
 #[derive(Debug, Clone)]
 pub struct DbPair {
@@ -89,6 +253,12 @@ pub enum DbEntry {
         path: PathBuf,
         /// Size of the original data.
         size: u64,
+        /// Modification time of the original data when it was added.
+        ///
+        /// The data is only read if the file still has this modification time and size,
+        /// so a changed file is detected before serving it. `None` skips the check of
+        /// the modification time.
+        mtime: Option<SystemTime>,
     },
     /// A collection.
     Internal {
@@ -126,12 +296,25 @@ impl DbEntry {
         let this = self.clone();
         async move {
             Ok(match this {
-                DbEntry::External { path, .. } => Either::Right(File::open(path).await?),
+                DbEntry::External {
+                    path, size, mtime, ..
+                } => {
+                    check_unchanged(&path, size, mtime).await?;
+                    Either::Right(File::open(path).await?)
+                }
                 DbEntry::Internal { data, .. } => Either::Left(data),
             })
         }
     }
 
+    /// Size of the data of this entry, without awaiting.
+    fn data_size(&self) -> u64 {
+        match self {
+            DbEntry::External { size, .. } => *size,
+            DbEntry::Internal { data, .. } => data.len() as u64,
+        }
+    }
+
     /// Returns the size of the blob or collection.
     ///
     /// For collections this is the size of the serialized collection.
@@ -144,12 +327,27 @@ impl DbEntry {
     }
 }
 
+/// Check that the file at `path` still has the given size and modification time.
+async fn check_unchanged(path: &Path, size: u64, mtime: Option<SystemTime>) -> io::Result<()> {
+    let meta = tokio::fs::metadata(path).await?;
+    let unchanged = meta.len() == size && (mtime.is_none() || meta.modified().ok() == mtime);
+    if unchanged {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} changed since it was added", path.display()),
+        ))
+    }
+}
+
 impl BaoMap for Database {
     type Entry = DbPair;
     type Outboard = PreOrderMemOutboard<Bytes>;
     type DataReader = Either<Bytes, File>;
     fn get(&self, hash: &Hash) -> Option<Self::Entry> {
         let entry = self.get(hash)?;
+        self.touch(hash);
         Some(DbPair {
             hash: blake3::Hash::from(*hash),
             entry,
@@ -159,13 +357,13 @@ impl BaoMap for Database {
 
 impl BaoReadonlyDb for Database {
     fn blobs(&self) -> Box<dyn Iterator<Item = Hash> + Send + Sync + 'static> {
-        let inner = self.0.read().unwrap();
+        let inner = self.entries.read().unwrap();
         let items = inner.iter().map(|(hash, _)| *hash).collect::<Vec<_>>();
         Box::new(items.into_iter())
     }
 
     fn roots(&self) -> Box<dyn Iterator<Item = Hash> + Send + Sync + 'static> {
-        let inner = self.0.read().unwrap();
+        let inner = self.entries.read().unwrap();
         let items = inner
             .iter()
             .filter(|(_, entry)| !entry.is_external())
@@ -181,7 +379,10 @@ impl BaoReadonlyDb for Database {
 
 impl From<HashMap<Hash, DbEntry>> for Database {
     fn from(map: HashMap<Hash, DbEntry>) -> Self {
-        Self(Arc::new(RwLock::new(map)))
+        Self {
+            entries: Arc::new(RwLock::new(map)),
+            ..Default::default()
+        }
     }
 }
 
@@ -192,10 +393,19 @@ impl From<HashMap<Hash, DbEntry>> for Database {
 pub(crate) struct Snapshot<E> {
     /// list of paths we have, hash is the hash of the blob or collection
     paths: Box<dyn Iterator<Item = (Hash, u64, Option<PathBuf>)>>,
+    /// map of hash to modification time, for external blobs that have one
+    mtimes: Box<dyn Iterator<Item = (Hash, SystemTime)>>,
+    /// list of pinned hashes
+    pins: Box<dyn Iterator<Item = Hash>>,
+    /// list of named tags
+    tags: Box<dyn Iterator<Item = (String, Tag)>>,
     /// map of hash to outboard, hash is the hash of the outboard and is unique
     outboards: Box<dyn Iterator<Item = result::Result<(Hash, Bytes), E>>>,
     /// map of hash to collection, hash is the hash of the collection and is unique
     collections: Box<dyn Iterator<Item = result::Result<(Hash, Bytes), E>>>,
+    /// list of small internal entries with their outboard and data, which are not
+    /// contained in `outboards` and `collections`
+    inline: Box<dyn Iterator<Item = (Hash, Bytes, Bytes)>>,
 }
 
 impl<E> fmt::Debug for Snapshot<E> {
@@ -220,6 +430,10 @@ struct DataPaths {
     outboards_dir: PathBuf,
     collections_dir: PathBuf,
     paths_file: PathBuf,
+    mtimes_file: PathBuf,
+    pins_file: PathBuf,
+    tags_file: PathBuf,
+    inline_file: PathBuf,
 }
 
 impl DataPaths {
@@ -228,6 +442,10 @@ impl DataPaths {
             outboards_dir: data_dir.join(FNAME_OUTBOARDS),
             collections_dir: data_dir.join(FNAME_COLLECTIONS),
             paths_file: data_dir.join(FNAME_PATHS),
+            mtimes_file: data_dir.join(FNAME_MTIMES),
+            pins_file: data_dir.join(FNAME_PINS),
+            tags_file: data_dir.join(FNAME_TAGS),
+            inline_file: data_dir.join(FNAME_INLINE),
             data_dir,
         }
     }
@@ -239,6 +457,23 @@ fn format_hash(hash: &Hash) -> String {
     hex::encode(hash.as_ref())
 }
 
+/// Write `data` to the file at `path`, throttled by `throttle`.
+fn write_throttled(path: PathBuf, data: &[u8], throttle: &Arc<IoThrottle>) -> io::Result<()> {
+    let mut writer = ThrottledWriter::new(std::fs::File::create(path)?, throttle.clone());
+    writer.write_all(data)?;
+    writer.flush()
+}
+
+/// Read a file that was added in a later version of the database, using the default
+/// value if it does not exist.
+fn read_optional<T: serde::de::DeserializeOwned + Default>(path: &Path) -> anyhow::Result<T> {
+    match std::fs::read(path) {
+        Ok(data) => Ok(postcard::from_bytes(&data)?),
+        Err(cause) if cause.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+        Err(cause) => Err(cause).with_context(|| format!("Failed reading {}", path.display())),
+    }
+}
+
 /// Parse a hash from a string, e.g. a file name.
 fn parse_hash(hash: &str) -> anyhow::Result<Hash> {
     let hash = hex::decode(hash)?;
@@ -254,14 +489,28 @@ impl Snapshot<io::Error> {
             outboards_dir,
             collections_dir,
             paths_file,
+            mtimes_file,
+            pins_file,
+            tags_file,
+            inline_file,
             ..
         } = DataPaths::new(data_dir.as_ref().to_path_buf());
+        let mtimes: Vec<(Hash, SystemTime)> = read_optional(&mtimes_file)?;
+        let pins: Vec<Hash> = read_optional(&pins_file)?;
+        let tags: Vec<(String, Tag)> = read_optional(&tags_file)?;
+        let inline: Vec<(Hash, Bytes, Bytes)> = read_optional(&inline_file)?;
         let paths = fs::read(&paths_file)
             .with_context(|| format!("Failed reading {}", paths_file.display()))?;
         let paths = postcard::from_bytes::<Vec<(Hash, u64, Option<PathBuf>)>>(&paths)?;
+        let inline_hashes = inline
+            .iter()
+            .map(|(hash, _, _)| *hash)
+            .collect::<BTreeSet<_>>();
+        // inline entries have no files in the outboards and collections directories
         let hashes = paths
             .iter()
             .map(|(hash, _, _)| *hash)
+            .filter(|hash| !inline_hashes.contains(hash))
             .collect::<BTreeSet<_>>();
         let outboards = hashes.clone().into_iter().map(move |hash| {
             let path = outboards_dir.join(format_hash(&hash));
@@ -316,8 +565,12 @@ impl Snapshot<io::Error> {
             .filter_map(|x| x.transpose());
         Ok(Self {
             paths: Box::new(paths.into_iter()),
+            mtimes: Box::new(mtimes.into_iter()),
+            pins: Box::new(pins.into_iter()),
+            tags: Box::new(tags.into_iter()),
             outboards: Box::new(outboards),
             collections: Box::new(collections),
+            inline: Box::new(inline.into_iter()),
         })
     }
 }
@@ -327,12 +580,18 @@ where
     io::Error: From<E>,
 {
     /// Persist the snapshot to disk.
-    pub fn persist(self, data_dir: impl AsRef<Path>) -> io::Result<()> {
+    ///
+    /// Writing outboards and collections is throttled by `throttle`.
+    pub fn persist(self, data_dir: impl AsRef<Path>, throttle: &Arc<IoThrottle>) -> io::Result<()> {
         use std::fs;
         let DataPaths {
             outboards_dir,
             collections_dir,
             paths_file,
+            mtimes_file,
+            pins_file,
+            tags_file,
+            inline_file,
             ..
         } = DataPaths::new(data_dir.as_ref().to_path_buf());
         fs::create_dir_all(&data_dir)?;
@@ -341,22 +600,343 @@ where
         for item in self.outboards {
             let (hash, outboard) = item.map_err(Into::into)?;
             let path = outboards_dir.join(format_hash(&hash));
-            fs::write(path, &outboard)?;
+            write_throttled(path, &outboard, throttle)?;
         }
         for item in self.collections {
             let (hash, collection) = item.map_err(Into::into)?;
             let path = collections_dir.join(format_hash(&hash));
-            fs::write(path, &collection)?;
+            write_throttled(path, &collection, throttle)?;
         }
         let mut paths = self.paths.collect::<Vec<_>>();
         paths.sort_by_key(|(path, _, _)| *path);
         let paths_content = postcard::to_stdvec(&paths).expect("failed to serialize paths file");
         fs::write(paths_file, paths_content)?;
+        let mut mtimes = self.mtimes.collect::<Vec<_>>();
+        mtimes.sort_by_key(|(hash, _)| *hash);
+        let mtimes_content = postcard::to_stdvec(&mtimes).expect("failed to serialize mtimes file");
+        fs::write(mtimes_file, mtimes_content)?;
+        let pins = self.pins.collect::<Vec<_>>();
+        let pins_content = postcard::to_stdvec(&pins).expect("failed to serialize pins file");
+        fs::write(pins_file, pins_content)?;
+        let tags = self.tags.collect::<Vec<_>>();
+        let tags_content = postcard::to_stdvec(&tags).expect("failed to serialize tags file");
+        fs::write(tags_file, tags_content)?;
+        let mut inline = self.inline.collect::<Vec<_>>();
+        inline.sort_by_key(|(hash, _, _)| *hash);
+        let inline_content = postcard::to_stdvec(&inline).expect("failed to serialize inline file");
+        write_throttled(inline_file, &inline_content, throttle)?;
         Ok(())
     }
 }
 
 impl Database {
+    /// Limit the disk io used by background work on this database.
+    ///
+    /// This throttles validating blobs and persisting the database, so that they do not
+    /// starve serving data. Serving is never throttled.
+    pub fn with_io_limits(self, limits: IoLimits) -> Self {
+        Self {
+            throttle: Arc::new(IoThrottle::new(limits)),
+            ..self
+        }
+    }
+
+    /// The limits for background disk io, see [`Database::with_io_limits`].
+    pub fn io_limits(&self) -> IoLimits {
+        self.throttle.limits()
+    }
+
+    /// Limit the total size of the data in this database.
+    ///
+    /// The quota is enforced when importing data, according to its [`QuotaPolicy`].
+    /// Entries already in the database are not affected until the next import.
+    pub fn with_quota(self, quota: Option<Quota>) -> Self {
+        Self { quota, ..self }
+    }
+
+    /// The quota of this database, see [`Database::with_quota`].
+    pub fn quota(&self) -> Option<Quota> {
+        self.quota
+    }
+
+    /// Store blobs smaller than `max_size` bytes inline.
+    ///
+    /// Files imported with [`Database::import_file`] or [`Database::import_reader`] that
+    /// are smaller than this are read into memory and kept as internal entries, instead of
+    /// referencing a file per blob. When persisting, internal entries smaller than this
+    /// are written to a single file together with their outboards, instead of two files
+    /// per entry. Readers see no difference between inline and other entries. `0`, the
+    /// default, disables inline storage.
+    pub fn with_inline_threshold(self, max_size: u64) -> Self {
+        Self {
+            inline_threshold: max_size,
+            ..self
+        }
+    }
+
+    /// The size below which blobs are stored inline, see
+    /// [`Database::with_inline_threshold`].
+    pub fn inline_threshold(&self) -> u64 {
+        self.inline_threshold
+    }
+
+    /// True if data of `size` bytes is stored inline.
+    fn is_inline(&self, size: u64) -> bool {
+        size < self.inline_threshold
+    }
+
+    /// Statistics about the content of this database.
+    pub fn stats(&self) -> DbStats {
+        let mut stats = DbStats::default();
+        for entry in self.entries.read().unwrap().values() {
+            stats.add(entry);
+        }
+        stats
+    }
+
+    /// Pin the entry for `hash`, so it is never evicted.
+    ///
+    /// Pinning a collection also pins all blobs it contains. Fails if there is no entry
+    /// for `hash`.
+    pub fn pin(&self, hash: Hash) -> anyhow::Result<()> {
+        anyhow::ensure!(self.get(&hash).is_some(), "blob {hash} not found");
+        self.pins.write().unwrap().insert(hash);
+        Ok(())
+    }
+
+    /// Unpin the entry for `hash`, returning false if it was not pinned.
+    ///
+    /// This only removes the pin for `hash` itself. A blob that is also contained in
+    /// another pinned collection stays pinned.
+    pub fn unpin(&self, hash: &Hash) -> bool {
+        self.pins.write().unwrap().remove(hash)
+    }
+
+    /// All hashes that were pinned with [`Database::pin`].
+    pub fn pins(&self) -> Vec<Hash> {
+        self.pins.read().unwrap().iter().copied().collect()
+    }
+
+    /// True if `hash` is pinned, either directly or through a pinned collection.
+    pub fn is_pinned(&self, hash: &Hash) -> bool {
+        let inner = self.entries.read().unwrap();
+        pinned_closure(&inner, &self.pins.read().unwrap()).contains(hash)
+    }
+
+    /// Tag the entry for `hash` with `name`, replacing the previous tag with this name.
+    ///
+    /// Like a pin, a tag keeps the entry and all blobs of a tagged collection from being
+    /// evicted. Once `expires` has passed the tag is removed, and the entry can be evicted
+    /// again unless it is retained otherwise. Fails if there is no entry for `hash`.
+    /// Returns the previous tag with this name.
+    pub fn tag(
+        &self,
+        name: impl Into<String>,
+        hash: Hash,
+        expires: Option<SystemTime>,
+    ) -> anyhow::Result<Option<Tag>> {
+        anyhow::ensure!(self.get(&hash).is_some(), "blob {hash} not found");
+        let tag = Tag { hash, expires };
+        Ok(self.tags.write().unwrap().insert(name.into(), tag))
+    }
+
+    /// Remove the tag with `name`, returning it.
+    pub fn untag(&self, name: &str) -> Option<Tag> {
+        self.tags.write().unwrap().remove(name)
+    }
+
+    /// All tags that have not expired, sorted by name.
+    pub fn tags(&self) -> Vec<(String, Tag)> {
+        self.expire_tags();
+        let tags = self.tags.read().unwrap();
+        tags.iter()
+            .map(|(name, tag)| (name.clone(), *tag))
+            .collect()
+    }
+
+    /// Remove all tags that have expired, returning them.
+    ///
+    /// This happens automatically before entries are evicted and when the database is
+    /// persisted.
+    pub fn expire_tags(&self) -> Vec<(String, Tag)> {
+        let now = SystemTime::now();
+        let mut tags = self.tags.write().unwrap();
+        let expired = tags
+            .iter()
+            .filter(|(_, tag)| tag.is_expired_at(now))
+            .map(|(name, tag)| (name.clone(), *tag))
+            .collect::<Vec<_>>();
+        for (name, tag) in &expired {
+            tracing::debug!(%name, hash = %tag.hash, "tag expired");
+            tags.remove(name);
+        }
+        expired
+    }
+
+    /// The hashes that must not be evicted: pins and tags, but not their children.
+    fn retained(&self) -> BTreeSet<Hash> {
+        let mut retained = self.pins.read().unwrap().clone();
+        retained.extend(self.tags.read().unwrap().values().map(|tag| tag.hash));
+        retained
+    }
+
+    /// Record that the entry for `hash` was used.
+    fn touch(&self, hash: &Hash) {
+        self.last_access
+            .lock()
+            .unwrap()
+            .insert(*hash, Instant::now());
+    }
+
+    /// Add entries to the database, enforcing the quota.
+    ///
+    /// Existing entries are kept, like in [`Database::union_with`]. Fails without adding
+    /// anything if the entries do not fit.
+    pub fn insert(&self, entries: HashMap<Hash, DbEntry>) -> anyhow::Result<()> {
+        let mut inner = self.entries.write().unwrap();
+        self.insert_locked(&mut inner, entries)
+    }
+
+    /// [`Database::insert`] for callers which already hold the write lock on the entries.
+    fn insert_locked(
+        &self,
+        inner: &mut HashMap<Hash, DbEntry>,
+        entries: HashMap<Hash, DbEntry>,
+    ) -> anyhow::Result<()> {
+        let mut last_access = self.last_access.lock().unwrap();
+        if let Some(quota) = self.quota {
+            let new_bytes: u64 = entries
+                .iter()
+                .filter(|(hash, _)| !inner.contains_key(hash))
+                .map(|(_, entry)| entry.data_size())
+                .sum();
+            let mut used: u64 = inner.values().map(DbEntry::data_size).sum();
+            if used + new_bytes > quota.max_bytes {
+                let err = move || {
+                    anyhow::anyhow!(
+                        "quota of {} bytes exceeded: {used} bytes used, {new_bytes} bytes to add",
+                        quota.max_bytes,
+                    )
+                };
+                if quota.policy != QuotaPolicy::EvictLeastRecentlyUsed {
+                    return Err(err());
+                }
+                self.expire_tags();
+                let pinned = pinned_closure(inner, &self.retained());
+                // entries that were never accessed since loading the database go first
+                let mut candidates = inner
+                    .iter()
+                    .filter(|(hash, _)| !entries.contains_key(hash) && !pinned.contains(hash))
+                    .map(|(hash, entry)| (last_access.get(hash).copied(), *hash, entry.data_size()))
+                    .collect::<Vec<_>>();
+                candidates.sort_unstable();
+                let mut evict = Vec::new();
+                for (_, hash, size) in candidates {
+                    if used + new_bytes <= quota.max_bytes {
+                        break;
+                    }
+                    evict.push((hash, size));
+                    used -= size;
+                }
+                if used + new_bytes > quota.max_bytes {
+                    return Err(err());
+                }
+                for (hash, size) in evict {
+                    tracing::debug!(%hash, size, "evicting entry to stay within quota");
+                    inner.remove(&hash);
+                    last_access.remove(&hash);
+                }
+            }
+        }
+        let now = Instant::now();
+        for (hash, entry) in entries {
+            inner.entry(hash).or_insert(entry);
+            last_access.insert(hash, now);
+        }
+        Ok(())
+    }
+
+    /// Remove files that are no longer referenced by the database.
+    ///
+    /// Outboards and collections are persisted to a file per entry in `data_dir`, and
+    /// these files are not removed when entries are evicted. This removes them, as well as
+    /// unreferenced files in the [`CompactOptions::owned_dirs`]. It can run while the
+    /// database is in use.
+    pub async fn compact(
+        &self,
+        data_dir: impl AsRef<Path>,
+        opts: CompactOptions,
+    ) -> io::Result<CompactStats> {
+        let data_dir = data_dir.as_ref().to_path_buf();
+        let db = self.clone();
+        tokio::task::spawn_blocking(move || db.compact_internal(data_dir, opts)).await?
+    }
+
+    fn compact_internal(
+        &self,
+        data_dir: PathBuf,
+        opts: CompactOptions,
+    ) -> io::Result<CompactStats> {
+        let _persist = self.persist_lock.lock().unwrap();
+        // hold the entries for the whole run, so nothing is added while we remove files
+        let inner = self.entries.read().unwrap();
+        let mut stats = CompactStats::default();
+        let DataPaths {
+            outboards_dir,
+            collections_dir,
+            ..
+        } = DataPaths::new(data_dir);
+        for dir in [outboards_dir, collections_dir] {
+            let entries = match std::fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(cause) if cause.kind() == io::ErrorKind::NotFound => continue,
+                Err(cause) => return Err(cause),
+            };
+            for entry in entries {
+                let path = entry?.path();
+                let hash = path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .and_then(|name| parse_hash(name).ok());
+                // files we did not write are left alone
+                if matches!(hash, Some(hash) if !inner.contains_key(&hash)) {
+                    stats.remove(&path)?;
+                }
+            }
+        }
+        let referenced = inner
+            .values()
+            .filter_map(DbEntry::blob_path)
+            .map(|path| std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()))
+            .collect::<HashSet<_>>();
+        let now = SystemTime::now();
+        for dir in &opts.owned_dirs {
+            let entries = match std::fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(cause) if cause.kind() == io::ErrorKind::NotFound => continue,
+                Err(cause) => return Err(cause),
+            };
+            for entry in entries {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if !metadata.is_file() {
+                    continue;
+                }
+                let age = now.duration_since(metadata.modified()?).unwrap_or_default();
+                let path = std::fs::canonicalize(entry.path())?;
+                if age >= opts.min_age && !referenced.contains(&path) {
+                    stats.remove(&path)?;
+                }
+            }
+        }
+        tracing::info!(
+            "Compaction removed {} files, {} bytes",
+            stats.files,
+            stats.bytes
+        );
+        Ok(stats)
+    }
+
     /// Load a database from disk for testing. Synchronous.
     pub fn load_test(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
         let dir = dir.as_ref().to_path_buf();
@@ -378,9 +958,11 @@ impl Database {
     }
 
     fn save_internal(&self, dir: PathBuf) -> io::Result<()> {
+        let _persist = self.persist_lock.lock().unwrap();
+        self.expire_tags();
         tracing::info!("Persisting database to {}...", dir.display());
         let snapshot = self.snapshot();
-        snapshot.persist(dir)?;
+        snapshot.persist(dir, &self.throttle)?;
         tracing::info!("Database stored");
         io::Result::Ok(())
     }
@@ -406,7 +988,12 @@ impl Database {
             outboards,
             collections,
             paths,
+            mtimes,
+            pins,
+            tags,
+            inline,
         } = snapshot;
+        let mtimes = mtimes.collect::<HashMap<_, _>>();
         let outboards = outboards
             .collect::<result::Result<HashMap<_, _>, E>>()
             .map_err(Into::into)
@@ -424,6 +1011,7 @@ impl Database {
                         outboard: outboard.clone(),
                         path,
                         size,
+                        mtime: mtimes.get(&hash).copied(),
                     },
                 );
             }
@@ -439,8 +1027,16 @@ impl Database {
                 );
             }
         }
+        for (hash, outboard, data) in inline {
+            db.insert(hash, DbEntry::Internal { outboard, data });
+        }
 
-        Ok(Self(Arc::new(RwLock::new(db))))
+        Ok(Self {
+            entries: Arc::new(RwLock::new(db)),
+            pins: Arc::new(RwLock::new(pins.collect())),
+            tags: Arc::new(RwLock::new(tags.collect())),
+            ..Default::default()
+        })
     }
 
     /// Validate the entire database, including collections.
@@ -449,7 +1045,7 @@ impl Database {
     async fn validate0(&self, tx: mpsc::Sender<ValidateProgress>) -> anyhow::Result<()> {
         // This makes a copy of the db, but since the outboards are Bytes, it's not expensive.
         let mut data = self
-            .0
+            .entries
             .read()
             .unwrap()
             .clone()
@@ -471,6 +1067,7 @@ impl Database {
                 };
                 let entry_tx = tx.clone();
                 let done_tx = tx.clone();
+                let throttle = self.throttle.clone();
                 async move {
                     let size = boc.size().await;
                     entry_tx
@@ -493,6 +1090,7 @@ impl Database {
                                 match std::fs::File::open(&path) {
                                     Ok(data) => {
                                         tracing::info!("validating {}", path.display());
+                                        let data = ThrottledReader::new(data, throttle);
                                         let res = validate_bao(hash, data, outboard, progress);
                                         tracing::info!("done validating {}", path.display());
                                         res
@@ -526,9 +1124,19 @@ impl Database {
 
     /// take a snapshot of the database
     pub(crate) fn snapshot(&self) -> Snapshot<NoError> {
-        let this = self.0.read().unwrap();
+        let this = self.entries.read().unwrap();
+        let is_inline = |v: &DbEntry| !v.is_external() && self.is_inline(v.data_size());
+        let inline = this
+            .iter()
+            .filter(|(_, v)| is_inline(v))
+            .filter_map(|(k, v)| match v {
+                DbEntry::External { .. } => None,
+                DbEntry::Internal { outboard, data } => Some((*k, outboard.clone(), data.clone())),
+            })
+            .collect::<Vec<_>>();
         let outboards = this
             .iter()
+            .filter(|(_, v)| !is_inline(v))
             .map(|(k, v)| match v {
                 DbEntry::External { outboard, .. } => (*k, outboard.clone()),
                 DbEntry::Internal { outboard, .. } => (*k, outboard.clone()),
@@ -537,6 +1145,7 @@ impl Database {
 
         let collections = this
             .iter()
+            .filter(|(_, v)| !is_inline(v))
             .filter_map(|(k, v)| match v {
                 DbEntry::External { .. } => None,
                 DbEntry::Internal { data, .. } => Some((*k, data.clone())),
@@ -551,21 +1160,33 @@ impl Database {
             })
             .collect::<Vec<_>>();
 
+        let mtimes = this
+            .iter()
+            .filter_map(|(k, v)| match v {
+                DbEntry::External { mtime, .. } => mtime.map(|mtime| (*k, mtime)),
+                DbEntry::Internal { .. } => None,
+            })
+            .collect::<Vec<_>>();
+
         Snapshot {
             outboards: Box::new(outboards.into_iter().map(Ok)),
             collections: Box::new(collections.into_iter().map(Ok)),
             paths: Box::new(paths.into_iter()),
+            mtimes: Box::new(mtimes.into_iter()),
+            pins: Box::new(self.pins.read().unwrap().clone().into_iter()),
+            tags: Box::new(self.tags.read().unwrap().clone().into_iter()),
+            inline: Box::new(inline.into_iter()),
         }
     }
 
     /// Get the entry for a given hash.
     pub fn get(&self, key: &Hash) -> Option<DbEntry> {
-        self.0.read().unwrap().get(key).cloned()
+        self.entries.read().unwrap().get(key).cloned()
     }
 
     /// Compute the union of this database with another.
     pub fn union_with(&self, db: HashMap<Hash, DbEntry>) {
-        let mut inner = self.0.write().unwrap();
+        let mut inner = self.entries.write().unwrap();
         for (k, v) in db {
             inner.entry(k).or_insert(v);
         }
@@ -574,7 +1195,7 @@ impl Database {
     /// Iterate over all blobs that are stored externally.
     pub fn external(&self) -> impl Iterator<Item = (Hash, PathBuf, u64)> + 'static {
         let items = self
-            .0
+            .entries
             .read()
             .unwrap()
             .iter()
@@ -591,7 +1212,7 @@ impl Database {
     /// Iterate over all collections in the database.
     pub fn internal(&self) -> impl Iterator<Item = (Hash, Bytes)> + 'static {
         let items = self
-            .0
+            .entries
             .read()
             .unwrap()
             .iter()
@@ -607,8 +1228,296 @@ impl Database {
 
     /// Unwrap into the inner HashMap
     pub fn to_inner(&self) -> HashMap<Hash, DbEntry> {
-        self.0.read().unwrap().clone()
+        self.entries.read().unwrap().clone()
+    }
+
+    /// Import a single file as an externally stored blob, returning its hash.
+    ///
+    /// The data is not copied, the database only references the file. If the file
+    /// changes while it is in the database, reading the blob fails. If a blob with the
+    /// same hash already exists, the existing entry is kept.
+    ///
+    /// Files below the [inline threshold](Database::with_inline_threshold) are copied
+    /// into the database instead.
+    pub async fn import_file(&self, path: PathBuf) -> anyhow::Result<Hash> {
+        let meta = tokio::fs::metadata(&path)
+            .await
+            .with_context(|| format!("Failed to read file size from {}", path.display()))?;
+        let size = meta.len();
+        if self.is_inline(size) {
+            let data = tokio::fs::read(&path)
+                .await
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            return self.import_bytes(data.into());
+        }
+        let mtime = meta.modified().ok();
+        let (hash, outboard) = {
+            let path = path.clone();
+            tokio::task::spawn_blocking(move || compute_outboard(&path, size, |_| {})).await??
+        };
+        self.insert(HashMap::from([(
+            hash,
+            DbEntry::External {
+                outboard: Bytes::from(outboard),
+                path,
+                size,
+                mtime,
+            },
+        )]))?;
+        Ok(hash)
+    }
+
+    /// Import the data read from `reader` as an externally stored blob, returning its hash.
+    ///
+    /// The data is streamed to a file in `dir`, which is created if needed, so data of
+    /// unknown length, e.g. the output of another process, is never held in memory. The
+    /// file is named after the hash of the data, and is removed again if the blob is
+    /// already in the database. If the size of the data is known, passing it as
+    /// `size_hint` fails imports that exceed the quota before reading any data.
+    ///
+    /// Data below the [inline threshold](Database::with_inline_threshold) is moved into
+    /// the database, and the file is removed.
+    pub async fn import_reader(
+        &self,
+        mut reader: impl AsyncRead + Unpin,
+        dir: impl AsRef<Path>,
+        size_hint: Option<u64>,
+    ) -> anyhow::Result<Hash> {
+        let dir = dir.as_ref();
+        if let (Some(quota), Some(size)) = (self.quota, size_hint) {
+            anyhow::ensure!(
+                size <= quota.max_bytes,
+                "blob of {size} bytes exceeds the quota of {} bytes",
+                quota.max_bytes
+            );
+        }
+        tokio::fs::create_dir_all(dir)
+            .await
+            .with_context(|| format!("failed to create {}", dir.display()))?;
+        let part_path = dir.join(format!("{:016x}.part", rand::random::<u64>()));
+        let res = async {
+            let file = tokio::fs::File::create(&part_path).await?;
+            let mut writer = tokio::io::BufWriter::with_capacity(1024 * 1024, file);
+            let size = tokio::io::copy(&mut reader, &mut writer).await?;
+            writer.flush().await?;
+            writer.into_inner().sync_all().await?;
+            let (hash, outboard) = {
+                let part_path = part_path.clone();
+                tokio::task::spawn_blocking(move || compute_outboard(&part_path, size, |_| {}))
+                    .await??
+            };
+            anyhow::Ok((hash, outboard, size))
+        }
+        .await;
+        let (hash, outboard, size) = match res {
+            Ok(res) => res,
+            Err(err) => {
+                tokio::fs::remove_file(&part_path).await.ok();
+                return Err(err);
+            }
+        };
+        if self.get(&hash).is_some() {
+            tokio::fs::remove_file(&part_path).await?;
+            return Ok(hash);
+        }
+        if self.is_inline(size) {
+            let data = tokio::fs::read(&part_path).await;
+            tokio::fs::remove_file(&part_path).await?;
+            self.insert(HashMap::from([(
+                hash,
+                DbEntry::Internal {
+                    outboard: Bytes::from(outboard),
+                    data: Bytes::from(data?),
+                },
+            )]))?;
+            return Ok(hash);
+        }
+        let path = dir.join(hash.to_hex());
+        let db = self.clone();
+        tokio::task::spawn_blocking(move || {
+            // check and rename under the write lock, so a concurrent import of the same
+            // content can not replace the file after its mtime was recorded
+            let mut inner = db.entries.write().unwrap();
+            if inner.contains_key(&hash) {
+                std::fs::remove_file(&part_path)?;
+                return Ok(());
+            }
+            std::fs::rename(&part_path, &path)?;
+            let mtime = std::fs::metadata(&path)?.modified().ok();
+            let entry = DbEntry::External {
+                outboard: Bytes::from(outboard),
+                path: path.clone(),
+                size,
+                mtime,
+            };
+            db.insert_locked(&mut inner, HashMap::from([(hash, entry)]))
+                .map_err(|err| {
+                    std::fs::remove_file(&path).ok();
+                    err
+                })
+        })
+        .await??;
+        Ok(hash)
     }
+
+    /// Import all files in the directory tree at `root`, returning the hash of a new
+    /// collection containing them.
+    ///
+    /// The blobs in the collection are named by their path relative to `root`, using `/`
+    /// as separator, so the directory structure can be recreated on the receiving side.
+    /// Outboards are computed for several files in parallel. Progress is reported on
+    /// `progress`, ending with [`ProvideProgress::AllDone`].
+    pub async fn import_dir(
+        &self,
+        root: PathBuf,
+        progress: Progress<ProvideProgress>,
+    ) -> anyhow::Result<Hash> {
+        anyhow::ensure!(root.is_dir(), "not a directory: {}", root.display());
+        // walking a large tree is blocking io as well
+        let data_sources = tokio::task::spawn_blocking(move || create_data_sources(root)).await??;
+        let (entries, hash) = create_collection_inner(data_sources, progress).await?;
+        self.insert(entries)?;
+        Ok(hash)
+    }
+
+    /// Export the blob with the given hash to the file at `target`.
+    ///
+    /// The data is verified against the outboard while it is written, so a blob whose
+    /// data changed on disk since it was added fails with an error instead of exporting
+    /// corrupt data. If `recursive` is true and the hash is a collection, the collection
+    /// is exported to the directory `target` instead, recreating the paths of its blobs.
+    /// Blobs which do not parse as a collection are exported to the file `target` as usual.
+    ///
+    /// `progress` is called with the total number of bytes exported so far.
+    pub async fn export(
+        &self,
+        hash: Hash,
+        target: PathBuf,
+        recursive: bool,
+        progress: impl Fn(u64) + Send + Sync + 'static,
+    ) -> anyhow::Result<()> {
+        let entry = self.get(&hash).context("blob not found")?;
+        let collection = match &entry {
+            DbEntry::Internal { data, .. } if recursive => Collection::from_bytes(data).ok(),
+            _ => None,
+        };
+        let Some(collection) = collection else {
+            return self
+                .export_blob(hash, &target, 0, &progress)
+                .await
+                .map(|_| ());
+        };
+        let mut offset = 0;
+        for blob in collection.blobs() {
+            let path = pathbuf_from_name(&blob.name);
+            anyhow::ensure!(
+                path.components()
+                    .all(|c| matches!(c, std::path::Component::Normal(_))),
+                "invalid blob name {:?}",
+                blob.name
+            );
+            let path = target.join(path);
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            offset = self
+                .export_blob(blob.hash, &path, offset, &progress)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Export a single blob, returning `offset` plus the size of the blob.
+    async fn export_blob(
+        &self,
+        hash: Hash,
+        target: &Path,
+        offset: u64,
+        progress: &impl Fn(u64),
+    ) -> anyhow::Result<u64> {
+        use bao_tree::io::fsm::{
+            encode_ranges_validated, BaoContentItem, ResponseDecoderReadingNext,
+            ResponseDecoderStart,
+        };
+        use iroh_io::AsyncSliceWriter;
+        use range_collections::RangeSet2;
+
+        let entry =
+            <Self as BaoMap>::get(self, &hash).with_context(|| format!("blob {hash} not found"))?;
+        let outboard = entry.outboard().await?;
+        let data = entry.data_reader().await?;
+        let target2 = target.to_path_buf();
+        let mut file = File::create(move || std::fs::File::create(&target2))
+            .await
+            .with_context(|| format!("failed to create {}", target.display()))?;
+        // encoding verifies the data against the outboard, decoding extracts the data again
+        let (send, recv) = tokio::io::duplex(64 * 1024);
+        let encode = async move {
+            encode_ranges_validated(data, outboard, &RangeSet2::all(), send)
+                .await
+                .with_context(|| format!("blob {hash} failed to validate"))
+        };
+        let decode = async {
+            let start =
+                ResponseDecoderStart::new(hash.into(), RangeSet2::all(), IROH_BLOCK_SIZE, recv);
+            let (mut reading, size) = start.next().await?;
+            let mut written = 0;
+            loop {
+                let item = match reading.next().await {
+                    ResponseDecoderReadingNext::Done(_) => break,
+                    ResponseDecoderReadingNext::More((next, item)) => {
+                        reading = next;
+                        item?
+                    }
+                };
+                if let BaoContentItem::Leaf(leaf) = item {
+                    written += leaf.data.len() as u64;
+                    file.write_bytes_at(leaf.offset.0, leaf.data).await?;
+                    progress(offset + written);
+                }
+            }
+            file.sync().await?;
+            anyhow::Ok(size)
+        };
+        match tokio::try_join!(encode, decode) {
+            Ok(((), size)) => Ok(offset + size),
+            Err(err) => {
+                tokio::fs::remove_file(target).await.ok();
+                Err(err)
+            }
+        }
+    }
+
+    /// Add data as an internally stored entry, returning its hash.
+    ///
+    /// This is meant for small data such as collections. If an entry with the same hash
+    /// already exists, the existing entry is kept.
+    pub fn import_bytes(&self, data: Bytes) -> anyhow::Result<Hash> {
+        let (outboard, hash) = bao_tree::io::outboard(&data, IROH_BLOCK_SIZE);
+        let hash = Hash::from(hash);
+        self.insert(HashMap::from([(
+            hash,
+            DbEntry::Internal {
+                outboard: Bytes::from(outboard),
+                data,
+            },
+        )]))?;
+        Ok(hash)
+    }
+}
+
+/// All hashes that are pinned, directly or as part of a pinned collection.
+fn pinned_closure(entries: &HashMap<Hash, DbEntry>, pins: &BTreeSet<Hash>) -> BTreeSet<Hash> {
+    let mut pinned = pins.clone();
+    for hash in pins {
+        if let Some(DbEntry::Internal { data, .. }) = entries.get(hash) {
+            // internal entries that are not collections have no children
+            if let Ok(collection) = Collection::from_bytes(data) {
+                pinned.extend(collection.blobs().iter().map(|blob| blob.hash));
+            }
+        }
+    }
+    pinned
 }
 
 /// Data for a blob
@@ -713,6 +1622,8 @@ struct BlobWithOutboard {
     name: String,
     /// The size of the original data.
     size: u64,
+    /// The modification time of the original data, if available.
+    mtime: Option<SystemTime>,
     /// The hash of the blob.
     hash: Hash,
     /// The bao outboard data.
@@ -765,6 +1676,7 @@ fn outboard_from_datasource(
         )
     })?;
     let size = file_meta.len();
+    let mtime = file_meta.modified().ok();
     // TODO: Found should really send the PathBuf, not the name?
     progress.blocking_send(ProvideProgress::Found {
         name: data_source.name().to_string(),
@@ -782,6 +1694,7 @@ fn outboard_from_datasource(
         path: data_source.path().to_path_buf(),
         name: data_source.name().to_string(),
         size,
+        mtime,
         hash,
         outboard: Bytes::from(outboard),
     })
@@ -859,6 +1772,7 @@ pub async fn create_collection_inner(
         path,
         name,
         size,
+        mtime,
         hash,
         outboard,
     } in outboards
@@ -870,6 +1784,7 @@ pub async fn create_collection_inner(
                 outboard,
                 path,
                 size,
+                mtime,
             },
         );
         total_blobs_size += size;
@@ -906,6 +1821,7 @@ mod tests {
     use proptest::prelude::*;
     use std::collections::HashMap;
     use std::str::FromStr;
+    use std::sync::atomic::{AtomicU64, Ordering};
     use testdir::testdir;
 
     use crate::database::flat::Snapshot;
@@ -943,6 +1859,7 @@ mod tests {
                         outboard,
                         size,
                         path,
+                        mtime: Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(size)),
                     },
                 );
             }
@@ -973,7 +1890,7 @@ mod tests {
         fn database_persistence_roundtrip(db in db(10, 1024 * 64)) {
             let dir = tempfile::tempdir().unwrap();
             let snapshot = db.snapshot();
-            snapshot.persist(&dir).unwrap();
+            snapshot.persist(&dir, &Default::default()).unwrap();
             let snapshot2 = Snapshot::load(&dir).unwrap();
             let db2 = Database::from_snapshot(snapshot2).unwrap();
             let db = db.to_inner();
@@ -1033,4 +1950,410 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_import_reader() -> anyhow::Result<()> {
+        let dir = testdir!();
+        let db = Database::default();
+        let data = (0..100_000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let expected = Hash::from(blake3::hash(&data));
+        for _ in 0..2 {
+            let (mut writer, reader) = tokio::io::duplex(1024);
+            let data = data.clone();
+            let write = tokio::spawn(async move { writer.write_all(&data).await });
+            let hash = db.import_reader(reader, &dir, None).await?;
+            write.await??;
+            assert_eq!(hash, expected);
+        }
+        let entry = db.get(&expected).unwrap();
+        assert_eq!(
+            entry.blob_path(),
+            Some(dir.join(expected.to_hex()).as_path())
+        );
+        assert_eq!(std::fs::read(dir.join(expected.to_hex()))?, data);
+        // importing the same content twice only keeps a single copy
+        assert_eq!(std::fs::read_dir(&dir)?.count(), 1);
+
+        let db = db.with_quota(Some(Quota {
+            max_bytes: 1000,
+            policy: QuotaPolicy::Reject,
+        }));
+        let res = db.import_reader(&[0u8; 2000][..], &dir, Some(2000)).await;
+        assert!(res.is_err());
+        assert_eq!(std::fs::read_dir(&dir)?.count(), 1);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_import_reader() -> anyhow::Result<()> {
+        let dir = testdir!();
+        let db = Database::default();
+        let data = (0..100_000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let expected = Hash::from(blake3::hash(&data));
+        let imports = (0..16).map(|_| {
+            let (db, dir, data) = (db.clone(), dir.clone(), data.clone());
+            tokio::spawn(async move { db.import_reader(&data[..], &dir, None).await })
+        });
+        for hash in futures::future::try_join_all(imports).await? {
+            assert_eq!(hash?, expected);
+        }
+        // no import replaced the file after another one recorded its mtime
+        let entry = db.get(&expected).unwrap();
+        assert!(entry.data_reader().await.is_ok());
+        assert_eq!(std::fs::read_dir(&dir)?.count(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_inline() -> anyhow::Result<()> {
+        let dir = testdir!();
+        let imports = dir.join("imports");
+        tokio::fs::create_dir_all(&imports).await?;
+        tokio::fs::write(imports.join("small"), b"small").await?;
+        tokio::fs::write(imports.join("large"), vec![1u8; 2000]).await?;
+        let db = Database::default().with_inline_threshold(1024);
+        let small = db.import_file(imports.join("small")).await?;
+        let large = db.import_file(imports.join("large")).await?;
+        let streamed = db.import_reader(&b"streamed"[..], &imports, None).await?;
+        let collection = db.import_bytes(vec![2u8; 4000].into())?;
+        for hash in [small, streamed] {
+            assert!(!db.get(&hash).unwrap().is_external());
+        }
+        assert!(db.get(&large).unwrap().is_external());
+        // the streamed data was moved into the database
+        assert_eq!(std::fs::read_dir(&imports)?.count(), 2);
+
+        db.save(dir.join("db")).await?;
+        let outboards = dir.join("db").join(FNAME_OUTBOARDS);
+        let collections = dir.join("db").join(FNAME_COLLECTIONS);
+        assert_eq!(std::fs::read_dir(&outboards)?.count(), 2);
+        assert_eq!(std::fs::read_dir(&collections)?.count(), 1);
+        assert!(collections.join(format_hash(&collection)).exists());
+
+        // reading inline entries does not depend on the threshold
+        let loaded = Database::load(dir.join("db")).await?;
+        assert_eq!(loaded.to_inner(), db.to_inner());
+        let entry = <Database as BaoMap>::get(&loaded, &small).unwrap();
+        let data = match entry.data_reader().await? {
+            Either::Left(data) => data,
+            Either::Right(_) => panic!("expected inline data"),
+        };
+        assert_eq!(&data[..], b"small");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_import_dir() -> anyhow::Result<()> {
+        let dir = testdir!();
+        let root = dir.join("root");
+        tokio::fs::create_dir_all(root.join("a/b")).await?;
+        tokio::fs::write(root.join("top.txt"), b"top").await?;
+        tokio::fs::write(root.join("a/middle.txt"), b"middle").await?;
+        tokio::fs::write(root.join("a/b/bottom.txt"), b"bottom").await?;
+
+        let db = Database::default();
+        let (tx, mut rx) = mpsc::channel(16);
+        let progress = tokio::spawn(async move {
+            let mut events = Vec::new();
+            while let Some(event) = rx.recv().await {
+                events.push(event);
+            }
+            events
+        });
+        let hash = db.import_dir(root.clone(), Progress::new(tx)).await?;
+        let events = progress.await?;
+        assert!(matches!(events.last(), Some(ProvideProgress::AllDone { hash: h }) if *h == hash));
+
+        let Some(DbEntry::Internal { data, .. }) = db.get(&hash) else {
+            panic!("expected a collection");
+        };
+        let collection = Collection::from_bytes(&data)?;
+        let names = collection
+            .blobs()
+            .iter()
+            .map(|blob| blob.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["a/b/bottom.txt", "a/middle.txt", "top.txt"]);
+        assert_eq!(collection.total_blobs_size(), 15);
+        for blob in collection.blobs() {
+            let entry = db.get(&blob.hash).expect("blob missing");
+            assert_eq!(entry.blob_path(), Some(root.join(&blob.name).as_path()));
+        }
+
+        assert!(db
+            .import_dir(root.join("top.txt"), Progress::none())
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_export() -> anyhow::Result<()> {
+        let dir = testdir!();
+        let root = dir.join("root");
+        tokio::fs::create_dir_all(root.join("a")).await?;
+        let big = (0..100_000u32).map(|i| i as u8).collect::<Vec<_>>();
+        tokio::fs::write(root.join("top.txt"), b"top").await?;
+        tokio::fs::write(root.join("a/big.bin"), &big).await?;
+
+        let db = Database::default();
+        let hash = db.import_dir(root.clone(), Progress::none()).await?;
+
+        let out = dir.join("out");
+        let exported = Arc::new(AtomicU64::new(0));
+        let exported2 = exported.clone();
+        db.export(hash, out.clone(), true, move |n| {
+            exported2.store(n, Ordering::SeqCst)
+        })
+        .await?;
+        assert_eq!(tokio::fs::read(out.join("top.txt")).await?, b"top");
+        assert_eq!(tokio::fs::read(out.join("a/big.bin")).await?, big);
+        assert_eq!(exported.load(Ordering::SeqCst), big.len() as u64 + 3);
+
+        // a single blob is exported to a file
+        let blob = Hash::from(blake3::hash(b"top"));
+        db.export(blob, dir.join("single.txt"), false, |_| {})
+            .await?;
+        assert_eq!(tokio::fs::read(dir.join("single.txt")).await?, b"top");
+
+        // a raw blob stored inline is exported to a file, even when recursive
+        let raw = db.import_bytes(Bytes::from_static(b"raw"))?;
+        db.export(raw, dir.join("raw.txt"), true, |_| {}).await?;
+        assert_eq!(tokio::fs::read(dir.join("raw.txt")).await?, b"raw");
+
+        // data that changed on disk fails to validate
+        tokio::fs::write(root.join("top.txt"), b"pot").await?;
+        assert!(db
+            .export(blob, dir.join("changed.txt"), false, |_| {})
+            .await
+            .is_err());
+        assert!(!dir.join("changed.txt").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_changed_file_is_not_read() -> anyhow::Result<()> {
+        let dir = testdir!();
+        let path = dir.join("data.txt");
+        tokio::fs::write(&path, b"hello").await?;
+        let db = Database::default();
+        let hash = db.import_file(path.clone()).await?;
+        let entry = db.get(&hash).context("blob missing")?;
+        assert!(entry.data_reader().await.is_ok());
+
+        // the reference survives persisting the database
+        db.save(dir.join("db")).await?;
+        let loaded = Database::load(dir.join("db")).await?;
+        assert_eq!(loaded.get(&hash), Some(entry.clone()));
+
+        tokio::fs::write(&path, b"hello world").await?;
+        let err = entry.data_reader().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[test]
+    fn test_quota() -> anyhow::Result<()> {
+        let db = Database::default().with_quota(Some(Quota {
+            max_bytes: 10,
+            policy: QuotaPolicy::Reject,
+        }));
+        let a = db.import_bytes(Bytes::from_static(b"aaaa"))?;
+        let b = db.import_bytes(Bytes::from_static(b"bbbb"))?;
+        assert!(db.import_bytes(Bytes::from_static(b"cccc")).is_err());
+        // importing existing content does not count twice
+        assert_eq!(db.import_bytes(Bytes::from_static(b"aaaa"))?, a);
+        assert_eq!(db.stats().collections, 2);
+        assert_eq!(db.stats().total_bytes(), 8);
+
+        let db = db.with_quota(Some(Quota {
+            max_bytes: 10,
+            policy: QuotaPolicy::EvictLeastRecentlyUsed,
+        }));
+        // serving a makes b the least recently used entry
+        assert!(BaoMap::get(&db, &a).is_some());
+        let c = db.import_bytes(Bytes::from_static(b"cccc"))?;
+        assert!(db.get(&a).is_some());
+        assert!(db.get(&b).is_none());
+        assert!(db.get(&c).is_some());
+        assert_eq!(db.stats().total_bytes(), 8);
+        // content larger than the quota is rejected even when evicting
+        assert!(db.import_bytes(Bytes::from(vec![0u8; 11])).is_err());
+        assert_eq!(db.stats().total_bytes(), 8);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pin() -> anyhow::Result<()> {
+        let dir = testdir!();
+        let root = dir.join("root");
+        tokio::fs::create_dir_all(&root).await?;
+        tokio::fs::write(root.join("a.txt"), b"aaaa").await?;
+        tokio::fs::write(root.join("b.txt"), b"bbbb").await?;
+        let db = Database::default();
+        let collection = db.import_dir(root, Progress::none()).await?;
+        let collection_size = db
+            .get(&collection)
+            .context("collection missing")?
+            .data_size();
+        let a = Hash::from(blake3::hash(b"aaaa"));
+        let other = db.import_bytes(Bytes::from_static(b"other"))?;
+
+        assert!(db.pin(Hash::from(blake3::hash(b"missing"))).is_err());
+        db.pin(collection)?;
+        assert!(db.is_pinned(&collection));
+        assert!(db.is_pinned(&a));
+        assert!(!db.is_pinned(&other));
+
+        // pins survive persisting the database
+        db.save(dir.join("db")).await?;
+        let db = Database::load(dir.join("db")).await?;
+        assert_eq!(db.pins(), vec![collection]);
+
+        // only unpinned entries are evicted
+        let db = db.with_quota(Some(Quota {
+            max_bytes: collection_size + 8 + 5,
+            policy: QuotaPolicy::EvictLeastRecentlyUsed,
+        }));
+        db.import_bytes(Bytes::from_static(b"12345"))?;
+        assert!(db.get(&other).is_none());
+        assert!(db.get(&a).is_some());
+        assert!(db.import_bytes(Bytes::from(vec![0u8; 6])).is_err());
+        // a failed import does not evict anything
+        assert_eq!(db.stats().total_bytes(), collection_size + 8 + 5);
+
+        assert!(db.unpin(&collection));
+        assert!(!db.unpin(&collection));
+        assert!(!db.is_pinned(&a));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tag() -> anyhow::Result<()> {
+        let dir = testdir!();
+        let db = Database::default();
+        let a = db.import_bytes(Bytes::from_static(b"aaaa"))?;
+        let b = db.import_bytes(Bytes::from_static(b"bbbb"))?;
+        let c = db.import_bytes(Bytes::from_static(b"cccc"))?;
+
+        assert!(db
+            .tag("missing", Hash::from(blake3::hash(b"missing")), None)
+            .is_err());
+        let soon = SystemTime::now() + Duration::from_millis(200);
+        assert_eq!(db.tag("release", a, None)?, None);
+        assert_eq!(db.tag("nightly", b, Some(soon))?, None);
+        assert_eq!(db.tags().len(), 2);
+
+        // tags survive persisting the database
+        db.save(dir.join("db")).await?;
+        let db = Database::load(dir.join("db")).await?;
+        assert_eq!(
+            db.tags(),
+            vec![
+                (
+                    "nightly".to_string(),
+                    Tag {
+                        hash: b,
+                        expires: Some(soon)
+                    }
+                ),
+                (
+                    "release".to_string(),
+                    Tag {
+                        hash: a,
+                        expires: None
+                    }
+                ),
+            ]
+        );
+
+        // tagged entries are not evicted until the tag expires
+        let db = db.with_quota(Some(Quota {
+            max_bytes: 12,
+            policy: QuotaPolicy::EvictLeastRecentlyUsed,
+        }));
+        let d = db.import_bytes(Bytes::from_static(b"dddd"))?;
+        assert!(db.get(&b).is_some());
+        assert!(db.get(&c).is_none());
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        db.import_bytes(Bytes::from_static(b"eeee"))?;
+        assert!(db.get(&a).is_some());
+        assert!(db.get(&b).is_none());
+        assert!(db.get(&d).is_some());
+        assert_eq!(
+            db.tags(),
+            vec![(
+                "release".to_string(),
+                Tag {
+                    hash: a,
+                    expires: None
+                }
+            )]
+        );
+
+        // replacing and removing tags
+        assert_eq!(db.tag("release", d, None)?.map(|tag| tag.hash), Some(a));
+        assert_eq!(db.untag("release").map(|tag| tag.hash), Some(d));
+        assert_eq!(db.untag("release"), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compact() -> anyhow::Result<()> {
+        let dir = testdir!();
+        let imports = dir.join("imports");
+        tokio::fs::create_dir_all(&imports).await?;
+        tokio::fs::write(imports.join("kept"), b"kept").await?;
+        tokio::fs::write(imports.join("evicted"), b"evicted").await?;
+        tokio::fs::write(imports.join("unknown"), b"unknown").await?;
+        let db = Database::default();
+        let kept = db.import_file(imports.join("kept")).await?;
+        let evicted = db.import_file(imports.join("evicted")).await?;
+        let small = db.import_bytes(Bytes::from_static(b"small"))?;
+        db.save(dir.join("db")).await?;
+        let outboards = dir.join("db").join(FNAME_OUTBOARDS);
+        let collections = dir.join("db").join(FNAME_COLLECTIONS);
+        assert_eq!(std::fs::read_dir(&outboards)?.count(), 3);
+
+        // evict all but the first entry
+        let db = db.with_quota(Some(Quota {
+            max_bytes: 4 + 3,
+            policy: QuotaPolicy::EvictLeastRecentlyUsed,
+        }));
+        db.touch(&kept);
+        let new = db.import_bytes(Bytes::from_static(b"new"))?;
+        assert!(db.get(&evicted).is_none() && db.get(&small).is_none());
+
+        // recently modified files are kept
+        let opts = CompactOptions {
+            owned_dirs: vec![imports.clone()],
+            ..Default::default()
+        };
+        let stats = db.compact(dir.join("db"), opts.clone()).await?;
+        assert_eq!(stats.files, 3);
+        assert!(!outboards.join(format_hash(&evicted)).exists());
+        assert!(!collections.join(format_hash(&small)).exists());
+        assert!(imports.join("evicted").exists());
+
+        let stats = db
+            .compact(
+                dir.join("db"),
+                CompactOptions {
+                    min_age: Duration::ZERO,
+                    ..opts
+                },
+            )
+            .await?;
+        assert_eq!(stats.files, 2);
+        assert_eq!(stats.bytes, 7 + 7);
+        assert!(imports.join("kept").exists());
+        assert!(!imports.join("evicted").exists());
+        assert!(!imports.join("unknown").exists());
+
+        // the compacted database can still be saved and loaded
+        db.save(dir.join("db")).await?;
+        let db = Database::load(dir.join("db")).await?;
+        assert!(db.get(&kept).is_some() && db.get(&new).is_some());
+        Ok(())
+    }
 }
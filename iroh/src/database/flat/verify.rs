@@ -0,0 +1,250 @@
+//! Verification of the stored data against the outboards, and repair of damaged data.
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use bao_tree::{ByteNum, ChunkNum};
+use futures::StreamExt;
+use iroh_bytes::get::fsm::{self, ConnectedNext, EndBlobNext};
+use iroh_bytes::protocol::{GetRequest, RangeSpecSeq};
+use iroh_bytes::Hash;
+use iroh_io::{AsyncSliceWriter, File};
+use range_collections::RangeSet2;
+use tracing::debug;
+
+use super::{Database, DbEntry};
+use crate::dial;
+use crate::util::io::valid_ranges;
+use crate::util::throttle::{IoThrottle, ThrottledReader};
+
+/// A blob whose data does not match its outboard, found by [`Database::verify_all`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DamagedBlob {
+    /// The hash of the blob.
+    pub hash: Hash,
+    /// The path of the data, `None` for internally stored data.
+    pub path: Option<PathBuf>,
+    /// The chunks whose data does not match the outboard.
+    pub damaged: RangeSet2<ChunkNum>,
+    /// True if the data is shorter than the blob, or missing.
+    pub truncated: bool,
+}
+
+impl Database {
+    /// Verifies the data of all entries against their outboards, returning the damaged ones.
+    ///
+    /// The data is re-hashed on background threads, throttled by the
+    /// [`IoLimits`](crate::util::throttle::IoLimits) of the database. Unlike validating,
+    /// this finds all damaged chunks of a blob, so they can be refetched with
+    /// [`Database::repair`]. Entries added after this is called are not verified.
+    pub async fn verify_all(&self) -> Result<Vec<DamagedBlob>> {
+        let throttle = self.throttle.clone();
+        let mut results = futures::stream::iter(self.to_inner())
+            .map(|(hash, entry)| {
+                let throttle = throttle.clone();
+                tokio::task::spawn_blocking(move || verify_entry(hash, entry, throttle))
+            })
+            .buffer_unordered(num_cpus::get());
+        let mut damaged = Vec::new();
+        while let Some(res) = results.next().await {
+            if let Some(blob) = res?? {
+                debug!(hash = %blob.hash, "found damaged blob");
+                damaged.push(blob);
+            }
+        }
+        damaged.sort_by_key(|blob| blob.hash);
+        Ok(damaged)
+    }
+
+    /// Verifies the data of the entry for `hash`, returning `None` if it is intact.
+    pub async fn verify(&self, hash: Hash) -> Result<Option<DamagedBlob>> {
+        let entry = self
+            .get(&hash)
+            .with_context(|| format!("{hash} not found"))?;
+        let throttle = self.throttle.clone();
+        tokio::task::spawn_blocking(move || verify_entry(hash, entry, throttle)).await?
+    }
+
+    /// Repairs the data of the entry for `hash` by refetching its damaged chunks.
+    ///
+    /// The peers are tried in order until one of them provides the data, which is verified
+    /// while it is received. For external entries only the damaged chunks are written to
+    /// the file of the entry, internal entries are replaced. Does nothing if the data is
+    /// intact.
+    ///
+    /// The get state machine is not `Send`, so this must run on a local task, e.g. on the
+    /// local pool of the [`runtime`](iroh_bytes::util::runtime).
+    pub async fn repair(
+        &self,
+        hash: Hash,
+        peers: impl IntoIterator<Item = dial::Options>,
+    ) -> Result<()> {
+        let Some(damaged) = self.verify(hash).await? else {
+            return Ok(());
+        };
+        let mut last_err = None;
+        for opts in peers {
+            let peer_id = opts.peer_id;
+            match self.repair_from(&damaged, opts).await {
+                Ok(()) => return Ok(()),
+                Err(err) => {
+                    debug!(%hash, %peer_id, "failed to repair: {err:#}");
+                    last_err = Some(err.context(format!("failed to repair from {peer_id}")));
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| anyhow!("no peers to repair {hash} from")))
+    }
+
+    async fn repair_from(&self, damaged: &DamagedBlob, opts: dial::Options) -> Result<()> {
+        let hash = damaged.hash;
+        let entry = self
+            .get(&hash)
+            .with_context(|| format!("{hash} not found"))?;
+        let connection = dial::dial(opts).await?;
+        let ranges = match entry {
+            DbEntry::External { .. } => damaged.damaged.clone(),
+            DbEntry::Internal { .. } => RangeSet2::all(),
+        };
+        let request = GetRequest::new(hash, RangeSpecSeq::new([ranges]));
+        let connected = fsm::start(connection, request.into()).next().await?;
+        let ConnectedNext::StartRoot(start) = connected.next().await? else {
+            bail!("expected root to be present");
+        };
+        let header = start.next();
+        let (end, entry) = match entry {
+            DbEntry::External {
+                outboard,
+                path,
+                size,
+                ..
+            } => {
+                let path2 = path.clone();
+                let mut file = File::create(move || {
+                    let file = std::fs::OpenOptions::new()
+                        .write(true)
+                        .create(true)
+                        .open(&path2)?;
+                    // cut off any data past the end of the blob, and extend truncated files
+                    file.set_len(size)?;
+                    Ok(file)
+                })
+                .await
+                .with_context(|| format!("failed to open {}", path.display()))?;
+                let (content, _size) = header.next().await?;
+                let end = content.write_all(&mut file).await?;
+                file.sync().await?;
+                drop(file);
+                let mtime = tokio::fs::metadata(&path).await?.modified().ok();
+                let entry = DbEntry::External {
+                    outboard,
+                    path,
+                    size,
+                    mtime,
+                };
+                (end, entry)
+            }
+            DbEntry::Internal { outboard, .. } => {
+                let (end, data) = header.concatenate_into_vec().await?;
+                let entry = DbEntry::Internal {
+                    outboard,
+                    data: data.into(),
+                };
+                (end, entry)
+            }
+        };
+        let EndBlobNext::Closing(closing) = end.next() else {
+            bail!("expected end of stream");
+        };
+        closing.next().await?;
+        self.entries.write().unwrap().insert(hash, entry);
+        if let Some(damaged) = self.verify(hash).await? {
+            bail!("still damaged after repair: {:?}", damaged.damaged);
+        }
+        debug!(%hash, "repaired blob");
+        Ok(())
+    }
+}
+
+/// Verifies the data of an entry, blocking while reading it.
+fn verify_entry(
+    hash: Hash,
+    entry: DbEntry,
+    throttle: Arc<IoThrottle>,
+) -> Result<Option<DamagedBlob>> {
+    let size = entry.data_size();
+    let (valid, truncated, path) = match entry {
+        DbEntry::External { outboard, path, .. } => match std::fs::File::open(&path) {
+            Ok(file) => {
+                let truncated = file.metadata()?.len() < size;
+                let data = ThrottledReader::new(file, throttle);
+                (valid_ranges(hash, data, outboard)?, truncated, Some(path))
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                (RangeSet2::empty(), true, Some(path))
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to open {}", path.display()))
+            }
+        },
+        DbEntry::Internal { outboard, data } => {
+            (valid_ranges(hash, data.as_ref(), outboard)?, false, None)
+        }
+    };
+    let damaged = RangeSet2::from(ChunkNum(0)..ByteNum(size).chunks()).difference(&valid);
+    if damaged.is_empty() && !truncated {
+        return Ok(None);
+    }
+    Ok(Some(DamagedBlob {
+        hash,
+        path,
+        damaged,
+        truncated,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_verify() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("blob");
+        let data = (0..100_000u32).map(|i| i as u8).collect::<Vec<_>>();
+        std::fs::write(&path, &data)?;
+        let db = Database::default();
+        let hash = db.import_file(path.clone()).await?;
+        let internal = db.import_bytes(vec![7u8; 1000].into())?;
+        assert!(db.verify_all().await?.is_empty());
+
+        // damage the second chunk group
+        let mut damaged = data.clone();
+        damaged[20_000] ^= 1;
+        std::fs::write(&path, &damaged)?;
+        let blob = db.verify(hash).await?.expect("damaged");
+        assert_eq!(blob.path.as_deref(), Some(path.as_path()));
+        assert_eq!(blob.damaged, RangeSet2::from(ChunkNum(16)..ChunkNum(32)));
+        assert!(!blob.truncated);
+
+        // truncate it
+        std::fs::write(&path, &data[..50_000])?;
+        let blob = db.verify(hash).await?.expect("damaged");
+        assert!(blob.truncated);
+        assert_eq!(
+            blob.damaged,
+            RangeSet2::from(ChunkNum(48)..ByteNum(100_000).chunks())
+        );
+        assert_eq!(db.verify_all().await?, vec![blob]);
+
+        std::fs::remove_file(&path)?;
+        let blob = db.verify(hash).await?.expect("damaged");
+        assert!(blob.truncated);
+        assert_eq!(
+            blob.damaged,
+            RangeSet2::from(ChunkNum(0)..ByteNum(100_000).chunks())
+        );
+        assert_eq!(db.verify(internal).await?, None);
+        Ok(())
+    }
+}
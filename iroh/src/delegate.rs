@@ -0,0 +1,330 @@
+//! Delegated fetch: ask a node to download content from another node and serve it to you.
+//!
+//! Node A sends a [`DelegatedFetch`] to node B as a custom get request. Node B, running a
+//! [`DelegateHandler`], downloads the content described by the contained [`Ticket`] from
+//! node C into its own database and then answers the request with that content as if it
+//! had been a normal get request. This lets a well connected node act as a seedbox for
+//! devices behind hostile NATs or on slow links.
+//!
+//! The custom request passes through the node's
+//! [`RequestAuthorizationHandler`](iroh_bytes::provider::RequestAuthorizationHandler)
+//! like any other request, so a node exposing a [`DelegateHandler`] should be configured
+//! with an authorization handler to avoid fetching on behalf of arbitrary peers.
+//!
+//! Concurrent requests for the same content from the same provider are coalesced into a
+//! single fetch.
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use bao_tree::ChunkNum;
+use bytes::Bytes;
+use futures::future::{BoxFuture, Shared};
+use futures::{FutureExt, TryFutureExt};
+use iroh_bytes::get::fsm::{self, ConnectedNext, EndBlobNext};
+use iroh_bytes::protocol::{CustomGetRequest, GetRequest, RangeSpecSeq, Request, RequestToken};
+use iroh_bytes::provider::{CustomGetHandler, NoopTransferHandler, TransferHandler};
+use iroh_bytes::util::runtime;
+use iroh_bytes::Hash;
+use iroh_io::{AsyncSliceWriter, File};
+use iroh_net::derp::DerpMap;
+use iroh_net::tls::{Keypair, PeerId};
+use range_collections::RangeSet2;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::collection::Collection;
+use crate::database::flat::{Database, DbEntry};
+use crate::dial::{self, Ticket};
+
+/// A request asking a node to fetch the content of a [`Ticket`] and serve it back.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DelegatedFetch {
+    /// The content to fetch, and where to fetch it from.
+    pub ticket: Ticket,
+}
+
+impl DelegatedFetch {
+    /// Creates a new delegated fetch for the content of `ticket`.
+    pub fn new(ticket: Ticket) -> Self {
+        Self { ticket }
+    }
+
+    /// Creates the request to send to the delegate.
+    ///
+    /// `token` is the token for the delegate. The token for the node the content is
+    /// fetched from is part of the ticket.
+    pub fn into_request(self, token: Option<RequestToken>) -> Result<Request> {
+        let data = postcard::to_stdvec(&self)?;
+        Ok(Request::CustomGet(CustomGetRequest {
+            token,
+            deadline: None,
+            data: data.into(),
+        }))
+    }
+}
+
+/// A fetch shared by all requests for the same content.
+type SharedFetch = Shared<BoxFuture<'static, Result<GetRequest, Arc<anyhow::Error>>>>;
+
+/// Identifies a fetch: the content, whether it is recursive, and the provider and token it
+/// is fetched with.
+///
+/// Requests only share a fetch if they fetch from the same provider with the same token,
+/// so a request can not use the result of a fetch it would not have been allowed to make.
+type FetchKey = (Hash, bool, PeerId, Option<RequestToken>);
+
+/// A [`CustomGetHandler`] that fetches the content of a [`DelegatedFetch`] into a
+/// [`Database`] before serving it.
+///
+/// Content that is already in the database is not fetched again, and requests for content
+/// that is currently being fetched wait for that fetch instead of starting another one.
+/// Fetched content is reported to the [`TransferHandler`] set with
+/// [`DelegateHandler::transfer_handler`].
+#[derive(Debug, Clone)]
+pub struct DelegateHandler {
+    db: Database,
+    dir: PathBuf,
+    derp_map: Option<DerpMap>,
+    rt: runtime::Handle,
+    transfer_handler: Arc<dyn TransferHandler>,
+    /// Fetches in progress.
+    in_flight: Arc<Mutex<HashMap<FetchKey, SharedFetch>>>,
+}
+
+impl DelegateHandler {
+    /// Creates a new handler adding fetched content to `db`.
+    ///
+    /// Fetched blobs are stored in files inside `dir`, which is created if needed.
+    pub fn new(db: Database, dir: PathBuf, derp_map: Option<DerpMap>, rt: runtime::Handle) -> Self {
+        Self {
+            db,
+            dir,
+            derp_map,
+            rt,
+            transfer_handler: Arc::new(NoopTransferHandler),
+            in_flight: Default::default(),
+        }
+    }
+
+    /// Sets the handler notified about the content fetched into the database.
+    pub fn transfer_handler(mut self, transfer_handler: Arc<dyn TransferHandler>) -> Self {
+        self.transfer_handler = transfer_handler;
+        self
+    }
+
+    /// Returns the fetch for the content of `ticket`, starting it if needed.
+    fn shared_fetch(&self, ticket: Ticket) -> SharedFetch {
+        let key = (
+            ticket.hash(),
+            ticket.recursive(),
+            ticket.peer(),
+            ticket.token().cloned(),
+        );
+        let mut in_flight = self.in_flight.lock().unwrap();
+        in_flight
+            .entry(key.clone())
+            .or_insert_with(|| {
+                let this = self.clone();
+                async move {
+                    let res = this.clone().dial_and_fetch(ticket).await;
+                    // later requests start a new fetch, e.g. to retry after a failure
+                    this.in_flight.lock().unwrap().remove(&key);
+                    res
+                }
+                .map_err(Arc::new)
+                .boxed()
+                .shared()
+            })
+            .clone()
+    }
+
+    async fn dial_and_fetch(self, ticket: Ticket) -> Result<GetRequest> {
+        anyhow::ensure!(!ticket.is_expired(), "ticket has expired");
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .with_context(|| format!("failed to create {}", self.dir.display()))?;
+        let opts = ticket.as_get_options(Keypair::generate(), self.derp_map.clone());
+        let connection = dial::dial(opts).await?;
+        // the get state machine is not Send, so drive it on the local pool
+        let local = self.rt.local_pool().clone();
+        local
+            .spawn_pinned(move || self.fetch(ticket, connection))
+            .await?
+    }
+
+    async fn fetch(self, ticket: Ticket, connection: quinn::Connection) -> Result<GetRequest> {
+        let hash = ticket.hash();
+        let peer = ticket.peer();
+        let recursive = ticket.recursive();
+        let token = ticket.token().cloned();
+        if recursive {
+            let collection = match self.db.get(&hash) {
+                Some(DbEntry::Internal { data, .. }) => Collection::from_bytes(&data)?,
+                _ => {
+                    let data = self
+                        .fetch_collection(&connection, hash, token.clone())
+                        .await?;
+                    let collection = Collection::from_bytes(&data)?;
+                    let size = data.len() as u64;
+                    self.db.import_bytes(data)?;
+                    self.transfer_handler.fetched(peer, hash, size).await;
+                    collection
+                }
+            };
+            self.fetch_children(&connection, peer, hash, token, collection)
+                .await?;
+            Ok(GetRequest::all(hash))
+        } else {
+            if self.db.get(&hash).is_none() {
+                self.fetch_blob(&connection, peer, hash, token).await?;
+            }
+            Ok(GetRequest::single(hash))
+        }
+    }
+
+    async fn fetch_collection(
+        &self,
+        connection: &quinn::Connection,
+        hash: Hash,
+        token: Option<RequestToken>,
+    ) -> Result<Bytes> {
+        let request = GetRequest::single(hash).with_token(token);
+        let connected = fsm::start(connection.clone(), request.into())
+            .next()
+            .await?;
+        let ConnectedNext::StartRoot(start) = connected.next().await? else {
+            anyhow::bail!("expected root to be present");
+        };
+        let (end, data) = start.next().concatenate_into_vec().await?;
+        let EndBlobNext::Closing(closing) = end.next() else {
+            anyhow::bail!("expected end of stream");
+        };
+        closing.next().await?;
+        Ok(data.into())
+    }
+
+    async fn fetch_blob(
+        &self,
+        connection: &quinn::Connection,
+        peer: PeerId,
+        hash: Hash,
+        token: Option<RequestToken>,
+    ) -> Result<()> {
+        let request = GetRequest::single(hash).with_token(token);
+        let connected = fsm::start(connection.clone(), request.into())
+            .next()
+            .await?;
+        let ConnectedNext::StartRoot(start) = connected.next().await? else {
+            anyhow::bail!("expected root to be present");
+        };
+        let end = self.write_blob(peer, hash, start.next()).await?;
+        let EndBlobNext::Closing(closing) = end.next() else {
+            anyhow::bail!("expected end of stream");
+        };
+        closing.next().await?;
+        Ok(())
+    }
+
+    /// Fetches all children of the collection that are not yet in the database.
+    async fn fetch_children(
+        &self,
+        connection: &quinn::Connection,
+        peer: PeerId,
+        hash: Hash,
+        token: Option<RequestToken>,
+        collection: Collection,
+    ) -> Result<()> {
+        let blobs = collection.into_inner();
+        if blobs.iter().all(|blob| self.db.get(&blob.hash).is_some()) {
+            return Ok(());
+        }
+        // skip the root and all children we already have
+        let mut ranges: Vec<RangeSet2<ChunkNum>> = vec![RangeSet2::empty()];
+        for blob in &blobs {
+            if self.db.get(&blob.hash).is_none() {
+                ranges.push(RangeSet2::all());
+            } else {
+                ranges.push(RangeSet2::empty());
+            }
+        }
+        let request = GetRequest::new(hash, RangeSpecSeq::new(ranges)).with_token(token);
+        let connected = fsm::start(connection.clone(), request.into())
+            .next()
+            .await?;
+        let mut next = match connected.next().await? {
+            ConnectedNext::StartRoot(_) => anyhow::bail!("unexpected root"),
+            ConnectedNext::StartChild(start) => EndBlobNext::MoreChildren(start),
+            ConnectedNext::Closing(closing) => EndBlobNext::Closing(closing),
+        };
+        let closing = loop {
+            let start = match next {
+                EndBlobNext::MoreChildren(start) => start,
+                EndBlobNext::Closing(closing) => break closing,
+            };
+            let Some(blob) = blobs.get(start.child_offset() as usize) else {
+                break start.finish();
+            };
+            let end = self
+                .write_blob(peer, blob.hash, start.next(blob.hash))
+                .await?;
+            next = end.next();
+        };
+        closing.next().await?;
+        Ok(())
+    }
+
+    /// Writes a blob fetched from `peer` to a file in `dir` and adds it to the database.
+    async fn write_blob(
+        &self,
+        peer: PeerId,
+        hash: Hash,
+        header: fsm::AtBlobHeader,
+    ) -> Result<fsm::AtEndBlob> {
+        // concurrent fetches of the same blob from different providers each get their own file
+        let name = format!("{}.{:016x}.part", hash.to_hex(), rand::random::<u64>());
+        let part_path = self.dir.join(name);
+        let part_path2 = part_path.clone();
+        let mut file = File::create(move || {
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&part_path2)
+        })
+        .await?;
+        let (content, size) = header.next().await?;
+        let end = content.write_all(&mut file).await?;
+        file.sync().await?;
+        drop(file);
+        if self.db.get(&hash).is_some() {
+            tokio::fs::remove_file(&part_path).await?;
+        } else {
+            let path = self.dir.join(hash.to_hex());
+            tokio::fs::rename(&part_path, &path).await?;
+            let imported = self.db.import_file(path).await?;
+            anyhow::ensure!(imported == hash, "hash mismatch for fetched blob {hash}");
+            debug!(%hash, "fetched blob for delegated fetch");
+        }
+        self.transfer_handler.fetched(peer, hash, size).await;
+        Ok(end)
+    }
+}
+
+impl CustomGetHandler for DelegateHandler {
+    fn handle(
+        &self,
+        _token: Option<RequestToken>,
+        request: Bytes,
+    ) -> BoxFuture<'static, Result<GetRequest>> {
+        let this = self.clone();
+        async move {
+            let request: DelegatedFetch =
+                postcard::from_bytes(&request).context("invalid delegated fetch request")?;
+            this.shared_fetch(request.ticket)
+                .await
+                .map_err(|err| anyhow::anyhow!("delegated fetch failed: {err:#}"))
+        }
+        .boxed()
+    }
+}
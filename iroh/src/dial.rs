@@ -3,11 +3,15 @@
 //! This is in it's own module to enforce the invariant that you can not construct a ticket
 //! with an empty address list.
 
+use std::collections::HashMap;
 use std::fmt::{self, Display};
 use std::net::SocketAddr;
+use std::path::Path;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
-use anyhow::{ensure, Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use iroh_bytes::protocol::RequestToken;
 use iroh_bytes::Hash;
 use iroh_net::derp::DerpMap;
@@ -54,10 +58,176 @@ pub async fn dial(opts: Options) -> anyhow::Result<quinn::Connection> {
         .context("failed to connect to provider")
 }
 
+/// Dial a peer like [`dial`], skipping addresses that failed recently.
+///
+/// The outcome of the dial is recorded in `backoff`. Fails without dialing if all
+/// addresses of the peer are backing off and there is no DERP region to fall back to.
+pub async fn dial_with_backoff(
+    opts: Options,
+    backoff: &DialBackoff,
+) -> anyhow::Result<quinn::Connection> {
+    let peer_id = opts.peer_id;
+    let addrs = backoff.usable_addrs(peer_id, &opts.addrs);
+    if addrs.is_empty() && opts.derp_region.is_none() {
+        bail!("all addresses of {peer_id} failed recently, not dialing");
+    }
+    let tried = addrs.clone();
+    match dial(Options { addrs, ..opts }).await {
+        Ok(connection) => {
+            backoff.record_success(peer_id);
+            Ok(connection)
+        }
+        Err(err) => {
+            backoff.record_failure(peer_id, &tried);
+            Err(err)
+        }
+    }
+}
+
+/// Wait after the first failed dial of an address.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(5);
+/// Upper limit for the wait after repeated failures.
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+/// Failures older than this are forgotten.
+const FORGET_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Failed dials, kept per peer and address.
+///
+/// After a dial fails, the addresses are not dialed again for a while. The wait doubles
+/// with every further failure up to an hour, and failures are forgotten after a day.
+/// The state can be saved and loaded again, so that a restarted node does not
+/// immediately redial peers that are known to be unreachable.
+#[derive(Debug, Clone, Default)]
+pub struct DialBackoff {
+    failures: Arc<Mutex<HashMap<(PeerId, SocketAddr), Failure>>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct Failure {
+    /// Number of consecutive failures.
+    count: u32,
+    /// Time of the last failure.
+    last: SystemTime,
+}
+
+impl Failure {
+    /// The time until which the address should not be dialed.
+    fn retry_at(&self) -> SystemTime {
+        let backoff = INITIAL_BACKOFF
+            .saturating_mul(1 << self.count.saturating_sub(1).min(16))
+            .min(MAX_BACKOFF);
+        self.last + backoff
+    }
+}
+
+impl DialBackoff {
+    /// Loads the state saved with [`DialBackoff::save`].
+    ///
+    /// Returns an empty state if the file does not exist.
+    pub async fn load(path: &Path) -> Result<Self> {
+        let data = match tokio::fs::read(path).await {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", path.display()))
+            }
+        };
+        let entries: Vec<((PeerId, SocketAddr), Failure)> = postcard::from_bytes(&data)
+            .with_context(|| format!("invalid dial backoff state in {}", path.display()))?;
+        let this = Self {
+            failures: Arc::new(Mutex::new(entries.into_iter().collect())),
+        };
+        this.forget_old(SystemTime::now());
+        Ok(this)
+    }
+
+    /// Saves the state to a file, creating the parent directory if needed.
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        self.forget_old(SystemTime::now());
+        let entries: Vec<_> = self
+            .failures
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, failure)| (*key, *failure))
+            .collect();
+        let data = postcard::to_stdvec(&entries)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(path, data)
+            .await
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    /// Records a failed dial of `peer` at `addrs`.
+    pub fn record_failure(&self, peer: PeerId, addrs: &[SocketAddr]) {
+        self.record_failure_at(peer, addrs, SystemTime::now())
+    }
+
+    fn record_failure_at(&self, peer: PeerId, addrs: &[SocketAddr], now: SystemTime) {
+        let mut failures = self.failures.lock().unwrap();
+        for addr in addrs {
+            let failure = failures.entry((peer, *addr)).or_insert(Failure {
+                count: 0,
+                last: now,
+            });
+            // a failure that was about to be forgotten starts over
+            if now.duration_since(failure.last).unwrap_or_default() > FORGET_AFTER {
+                failure.count = 0;
+            }
+            failure.count = failure.count.saturating_add(1);
+            failure.last = now;
+        }
+    }
+
+    /// Records a successful dial of `peer`, clearing the failures of all its addresses.
+    pub fn record_success(&self, peer: PeerId) {
+        self.failures
+            .lock()
+            .unwrap()
+            .retain(|(failed_peer, _), _| *failed_peer != peer);
+    }
+
+    /// Returns the time until which `addr` of `peer` should not be dialed, if any.
+    pub fn retry_at(&self, peer: PeerId, addr: SocketAddr) -> Option<SystemTime> {
+        self.retry_at_time(peer, addr, SystemTime::now())
+    }
+
+    fn retry_at_time(&self, peer: PeerId, addr: SocketAddr, now: SystemTime) -> Option<SystemTime> {
+        let failures = self.failures.lock().unwrap();
+        let retry_at = failures.get(&(peer, addr))?.retry_at();
+        (retry_at > now).then_some(retry_at)
+    }
+
+    /// Returns the addresses of `peer` that are not backing off.
+    pub fn usable_addrs(&self, peer: PeerId, addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+        let now = SystemTime::now();
+        addrs
+            .iter()
+            .copied()
+            .filter(|addr| self.retry_at_time(peer, *addr, now).is_none())
+            .collect()
+    }
+
+    fn forget_old(&self, now: SystemTime) {
+        self.failures.lock().unwrap().retain(|_, failure| {
+            now.duration_since(failure.last).unwrap_or_default() <= FORGET_AFTER
+        });
+    }
+}
+
+/// Version byte at the start of the binary encoding of a version 2 [`Ticket`].
+///
+/// Version 1 tickets have no version byte. They start with the length of the hash, 32,
+/// so the two can not be confused.
+const TICKET_V2: u8 = 2;
+
 /// A token containing everything to get a file from the provider.
 ///
 /// It is a single item which can be easily serialized and deserialized.  The [`Display`]
-/// and [`FromStr`] implementations serialize to base32.
+/// and [`FromStr`] implementations serialize to base32. Tickets of the first version,
+/// which have no expiry and capability, are still parsed.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Ticket {
     /// The hash to retrieve.
@@ -74,6 +244,54 @@ pub struct Ticket {
     recursive: bool,
     /// DERP region of the provider
     derp_region: Option<u16>,
+    /// Expiry as seconds since the unix epoch.
+    expires: Option<u64>,
+    /// What the holder of the ticket may do.
+    capability: Capability,
+}
+
+/// What the holder of a [`Ticket`] is allowed to do.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Capability {
+    /// The content can be fetched.
+    #[default]
+    Read,
+    /// The content can be fetched, and the provider accepts data from the holder.
+    ReadWrite,
+}
+
+/// The first version of the ticket format.
+#[derive(Debug, Deserialize)]
+struct TicketV1 {
+    hash: Hash,
+    peer: PeerId,
+    token: Option<RequestToken>,
+    addrs: Vec<SocketAddr>,
+    recursive: bool,
+    derp_region: Option<u16>,
+}
+
+impl From<TicketV1> for Ticket {
+    fn from(value: TicketV1) -> Self {
+        let TicketV1 {
+            hash,
+            peer,
+            token,
+            addrs,
+            recursive,
+            derp_region,
+        } = value;
+        Self {
+            hash,
+            peer,
+            token,
+            addrs,
+            recursive,
+            derp_region,
+            expires: None,
+            capability: Capability::Read,
+        }
+    }
 }
 
 impl Ticket {
@@ -94,19 +312,28 @@ impl Ticket {
             token,
             recursive,
             derp_region,
+            expires: None,
+            capability: Capability::Read,
         })
     }
 
-    /// Deserializes from bytes.
+    /// Deserializes from bytes, in either the current or the first version.
     pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
-        let slf: Ticket = postcard::from_bytes(bytes)?;
+        let slf: Ticket = match bytes.split_first() {
+            Some((&TICKET_V2, rest)) => postcard::from_bytes(rest)?,
+            _ => postcard::from_bytes::<TicketV1>(bytes)
+                .context("unsupported ticket version")?
+                .into(),
+        };
         ensure!(!slf.addrs.is_empty(), "Invalid address list in ticket");
         Ok(slf)
     }
 
     /// Serializes to bytes.
     pub fn to_bytes(&self) -> Vec<u8> {
-        postcard::to_stdvec(self).expect("postcard::to_stdvec is infallible")
+        let mut bytes = vec![TICKET_V2];
+        bytes.extend(postcard::to_stdvec(self).expect("postcard::to_stdvec is infallible"));
+        bytes
     }
 
     /// The hash of the item this ticket can retrieve.
@@ -151,6 +378,38 @@ impl Ticket {
         self.derp_region
     }
 
+    /// The time after which the ticket should no longer be used, if any.
+    pub fn expires(&self) -> Option<SystemTime> {
+        self.expires
+            .map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    /// Set the expiry of this ticket, rounded down to whole seconds.
+    pub fn with_expires(self, expires: Option<SystemTime>) -> Self {
+        let expires = expires.map(|time| {
+            time.duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        });
+        Self { expires, ..self }
+    }
+
+    /// True if the ticket has an expiry and it has passed.
+    pub fn is_expired(&self) -> bool {
+        self.expires()
+            .map_or(false, |expires| expires <= SystemTime::now())
+    }
+
+    /// What the holder of this ticket is allowed to do.
+    pub fn capability(&self) -> Capability {
+        self.capability
+    }
+
+    /// Set the capability of this ticket.
+    pub fn with_capability(self, capability: Capability) -> Self {
+        Self { capability, ..self }
+    }
+
     /// Get the contents of the ticket, consuming it.
     pub fn into_parts(
         self,
@@ -169,6 +428,7 @@ impl Ticket {
             addrs,
             recursive,
             derp_region,
+            ..
         } = self;
         (hash, peer, addrs, token, recursive, derp_region)
     }
@@ -213,6 +473,67 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_dial_backoff() {
+        let backoff = DialBackoff::default();
+        let peer = PeerId::from(Keypair::generate().public());
+        let other = PeerId::from(Keypair::generate().public());
+        let a = SocketAddr::from_str("127.0.0.1:1234").unwrap();
+        let b = SocketAddr::from_str("127.0.0.1:1235").unwrap();
+        let t0 = SystemTime::now();
+
+        backoff.record_failure_at(peer, &[a], t0);
+        assert_eq!(
+            backoff.retry_at_time(peer, a, t0),
+            Some(t0 + INITIAL_BACKOFF)
+        );
+        assert_eq!(backoff.retry_at_time(peer, b, t0), None);
+        assert_eq!(backoff.retry_at_time(other, a, t0), None);
+        assert_eq!(backoff.usable_addrs(peer, &[a, b]), vec![b]);
+
+        // the wait doubles with every failure, up to the maximum
+        let t1 = t0 + INITIAL_BACKOFF;
+        backoff.record_failure_at(peer, &[a], t1);
+        assert_eq!(
+            backoff.retry_at_time(peer, a, t1),
+            Some(t1 + INITIAL_BACKOFF * 2)
+        );
+        for _ in 0..20 {
+            backoff.record_failure_at(peer, &[a], t1);
+        }
+        assert_eq!(backoff.retry_at_time(peer, a, t1), Some(t1 + MAX_BACKOFF));
+        assert_eq!(backoff.retry_at_time(peer, a, t1 + MAX_BACKOFF), None);
+
+        // old failures are forgotten
+        let t2 = t1 + FORGET_AFTER * 2;
+        backoff.record_failure_at(peer, &[a], t2);
+        assert_eq!(
+            backoff.retry_at_time(peer, a, t2),
+            Some(t2 + INITIAL_BACKOFF)
+        );
+
+        backoff.record_success(peer);
+        assert_eq!(backoff.retry_at_time(peer, a, t2), None);
+    }
+
+    #[tokio::test]
+    async fn test_dial_backoff_persistence() -> Result<()> {
+        let dir = testdir::testdir!();
+        let path = dir.join("dial-backoff");
+        let backoff = DialBackoff::load(&path).await?;
+        let peer = PeerId::from(Keypair::generate().public());
+        let addr = SocketAddr::from_str("127.0.0.1:1234").unwrap();
+        backoff.record_failure(peer, &[addr]);
+        let retry_at = backoff.retry_at(peer, addr);
+        assert!(retry_at.is_some());
+        backoff.save(&path).await?;
+
+        let loaded = DialBackoff::load(&path).await?;
+        assert_eq!(loaded.retry_at(peer, addr), retry_at);
+        assert!(loaded.usable_addrs(peer, &[addr]).is_empty());
+        Ok(())
+    }
+
     #[test]
     fn test_ticket_base32_roundtrip() {
         let hash = blake3::hash(b"hi there");
@@ -228,12 +549,54 @@ mod tests {
             token: Some(token),
             recursive: true,
             derp_region,
+            expires: None,
+            capability: Capability::Read,
         };
+        let ticket = ticket
+            .with_expires(Some(SystemTime::now() + Duration::from_secs(60)))
+            .with_capability(Capability::ReadWrite);
         let base32 = ticket.to_string();
         println!("Ticket: {base32}");
         println!("{} bytes", base32.len());
 
         let ticket2: Ticket = base32.parse().unwrap();
         assert_eq!(ticket2, ticket);
+        assert!(!ticket2.is_expired());
+        assert_eq!(ticket2.capability(), Capability::ReadWrite);
+    }
+
+    #[test]
+    fn test_ticket_v1() {
+        #[derive(Serialize)]
+        struct TicketV1 {
+            hash: Hash,
+            peer: PeerId,
+            token: Option<RequestToken>,
+            addrs: Vec<SocketAddr>,
+            recursive: bool,
+            derp_region: Option<u16>,
+        }
+        let hash = Hash::from(blake3::hash(b"hi there"));
+        let peer = PeerId::from(Keypair::generate().public());
+        let addr = SocketAddr::from_str("127.0.0.1:1234").unwrap();
+        let v1 = TicketV1 {
+            hash,
+            peer,
+            token: None,
+            addrs: vec![addr],
+            recursive: false,
+            derp_region: Some(1),
+        };
+        let ticket = Ticket::from_bytes(&postcard::to_stdvec(&v1).unwrap()).unwrap();
+        assert_eq!(
+            ticket,
+            Ticket::new(hash, peer, vec![addr], None, false, Some(1)).unwrap()
+        );
+        assert_eq!(ticket.expires(), None);
+        assert_eq!(ticket.capability(), Capability::Read);
+
+        let expired = ticket.with_expires(Some(SystemTime::now() - Duration::from_secs(1)));
+        assert!(expired.is_expired());
+        assert!(Ticket::from_bytes(&[3, 0, 0]).is_err());
     }
 }
@@ -0,0 +1,353 @@
+//! A read-only FUSE filesystem for collections.
+//!
+//! [`CollectionFs`] presents the blobs of a [`Collection`] as files, named by their names in
+//! the collection, with `/` separated parts of the names as directories. Mounting it allows
+//! browsing a collection with normal tools:
+//!
+//! ```no_run
+//! # async fn example(db: iroh::database::flat::Database, hash: iroh_bytes::Hash) -> anyhow::Result<()> {
+//! use iroh::fuse::CollectionFs;
+//!
+//! let fs = CollectionFs::new(db, hash).await?;
+//! let session = fs.mount("/mnt/collection")?;
+//! // the collection is unmounted when the session is dropped
+//! # drop(session);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! File data is read from the database on demand, and verified against the outboard of
+//! the blob before it is returned. Reads of data which does not verify fail with `EIO`.
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fmt;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, ensure, Context, Result};
+use fuser::{
+    BackgroundSession, FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData,
+    ReplyDirectory, ReplyEntry, ReplyOpen, Request, FUSE_ROOT_ID,
+};
+use iroh_bytes::provider::BaoMap;
+use iroh_bytes::Hash;
+use libc::c_int;
+use tracing::warn;
+
+use crate::collection::Collection;
+use crate::util::io::{blob_size, read_range_verified};
+
+/// How long the kernel may cache attributes and names, content addressed data never changes.
+const TTL: Duration = Duration::from_secs(60 * 60);
+
+/// The block size reported for files.
+const BLOCK_SIZE: u32 = 512;
+
+/// A file or directory of the filesystem.
+#[derive(Debug)]
+enum Inode {
+    Dir {
+        parent: u64,
+        children: BTreeMap<String, u64>,
+    },
+    File {
+        hash: Hash,
+        size: u64,
+    },
+}
+
+/// A read-only filesystem with the content of a [`Collection`].
+pub struct CollectionFs<D> {
+    db: D,
+    rt: tokio::runtime::Handle,
+    /// The inodes, inode number `n` is at index `n - 1`.
+    inodes: Vec<Inode>,
+    mounted: SystemTime,
+}
+
+impl<D> fmt::Debug for CollectionFs<D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CollectionFs")
+            .field("inodes", &self.inodes.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<D: BaoMap> CollectionFs<D> {
+    /// Creates a filesystem for the collection `hash` in `db`.
+    ///
+    /// All blobs of the collection must be in `db`. Must be called from within a tokio
+    /// runtime, which is used to read data from `db` once the filesystem is mounted.
+    pub async fn new(db: D, hash: Hash) -> Result<Self> {
+        let size = blob_size(&db, &hash)
+            .await?
+            .with_context(|| format!("collection {hash} not found"))?;
+        let mut data = Vec::new();
+        read_range_verified(&db, hash, 0..size, &mut data).await?;
+        let collection =
+            Collection::from_bytes(&data).with_context(|| format!("{hash} is not a collection"))?;
+
+        let mut inodes = vec![Inode::Dir {
+            parent: FUSE_ROOT_ID,
+            children: BTreeMap::new(),
+        }];
+        for blob in collection.blobs() {
+            let size = blob_size(&db, &blob.hash)
+                .await?
+                .with_context(|| format!("blob {} of {hash} not found", blob.name))?;
+            let mut parts = blob.name.split('/').peekable();
+            let mut dir = FUSE_ROOT_ID;
+            while let Some(part) = parts.next() {
+                ensure!(
+                    !part.is_empty() && part != "." && part != "..",
+                    "invalid name in collection: {}",
+                    blob.name
+                );
+                let next = inodes.len() as u64 + 1;
+                let Inode::Dir { children, .. } = &mut inodes[dir as usize - 1] else {
+                    bail!("conflicting names in collection: {}", blob.name);
+                };
+                if parts.peek().is_none() {
+                    ensure!(
+                        children.insert(part.to_string(), next).is_none(),
+                        "conflicting names in collection: {}",
+                        blob.name
+                    );
+                    inodes.push(Inode::File {
+                        hash: blob.hash,
+                        size,
+                    });
+                } else if let Some(&child) = children.get(part) {
+                    dir = child;
+                } else {
+                    children.insert(part.to_string(), next);
+                    inodes.push(Inode::Dir {
+                        parent: dir,
+                        children: BTreeMap::new(),
+                    });
+                    dir = next;
+                }
+            }
+        }
+        Ok(Self {
+            db,
+            rt: tokio::runtime::Handle::current(),
+            inodes,
+            mounted: SystemTime::now(),
+        })
+    }
+
+    /// Mounts the filesystem read-only at `mountpoint`.
+    ///
+    /// The filesystem is served from a background thread, and unmounted when the returned
+    /// session is dropped.
+    pub fn mount(self, mountpoint: impl AsRef<Path>) -> Result<BackgroundSession> {
+        let options = [MountOption::RO, MountOption::FSName("iroh".to_string())];
+        let session = fuser::spawn_mount2(self, mountpoint.as_ref(), &options)
+            .with_context(|| format!("failed to mount {}", mountpoint.as_ref().display()))?;
+        Ok(session)
+    }
+
+    fn inode(&self, ino: u64) -> Result<&Inode, c_int> {
+        ino.checked_sub(1)
+            .and_then(|index| self.inodes.get(index as usize))
+            .ok_or(libc::ENOENT)
+    }
+
+    fn lookup_child(&self, parent: u64, name: &OsStr) -> Result<u64, c_int> {
+        let Inode::Dir { children, .. } = self.inode(parent)? else {
+            return Err(libc::ENOTDIR);
+        };
+        let name = name.to_str().ok_or(libc::ENOENT)?;
+        children.get(name).copied().ok_or(libc::ENOENT)
+    }
+
+    fn attr(&self, req: &Request<'_>, ino: u64) -> Result<FileAttr, c_int> {
+        let (kind, size, perm, nlink) = match self.inode(ino)? {
+            Inode::Dir { .. } => (FileType::Directory, 0, 0o555, 2),
+            Inode::File { size, .. } => (FileType::RegularFile, *size, 0o444, 1),
+        };
+        Ok(FileAttr {
+            ino,
+            size,
+            blocks: (size + BLOCK_SIZE as u64 - 1) / BLOCK_SIZE as u64,
+            atime: self.mounted,
+            mtime: self.mounted,
+            ctime: self.mounted,
+            crtime: self.mounted,
+            kind,
+            perm,
+            nlink,
+            uid: req.uid(),
+            gid: req.gid(),
+            rdev: 0,
+            blksize: BLOCK_SIZE,
+            flags: 0,
+        })
+    }
+
+    /// Reads up to `len` bytes of the file `ino` starting at `offset`.
+    async fn read_at(&self, ino: u64, offset: u64, len: u64) -> Result<Vec<u8>, c_int> {
+        let Inode::File { hash, size } = self.inode(ino)? else {
+            return Err(libc::EISDIR);
+        };
+        let range = offset.min(*size)..offset.saturating_add(len).min(*size);
+        let mut data = Vec::with_capacity((range.end - range.start) as usize);
+        if let Err(err) = read_range_verified(&self.db, *hash, range, &mut data).await {
+            warn!(%hash, "failed to read blob: {err:#}");
+            return Err(libc::EIO);
+        }
+        Ok(data)
+    }
+}
+
+impl<D: BaoMap> Filesystem for CollectionFs<D> {
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self
+            .lookup_child(parent, name)
+            .and_then(|ino| self.attr(req, ino))
+        {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn getattr(&mut self, req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.attr(req, ino) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: c_int, reply: ReplyOpen) {
+        match self.inode(ino) {
+            Ok(Inode::File { .. }) if flags & libc::O_ACCMODE == libc::O_RDONLY => {
+                reply.opened(0, fuser::consts::FOPEN_KEEP_CACHE)
+            }
+            Ok(Inode::File { .. }) => reply.error(libc::EACCES),
+            Ok(Inode::Dir { .. }) => reply.error(libc::EISDIR),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let Ok(offset) = u64::try_from(offset) else {
+            return reply.error(libc::EINVAL);
+        };
+        // requests are handled on the fuse session thread, outside of the runtime
+        match self.rt.block_on(self.read_at(ino, offset, size as u64)) {
+            Ok(data) => reply.data(&data),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let (parent, children) = match self.inode(ino) {
+            Ok(Inode::Dir { parent, children }) => (*parent, children),
+            Ok(Inode::File { .. }) => return reply.error(libc::ENOTDIR),
+            Err(errno) => return reply.error(errno),
+        };
+        let entries = [(ino, "."), (parent, "..")]
+            .into_iter()
+            .chain(children.iter().map(|(name, ino)| (*ino, name.as_str())));
+        for (i, (ino, name)) in entries.enumerate().skip(offset.max(0) as usize) {
+            let kind = match self.inodes[ino as usize - 1] {
+                Inode::Dir { .. } => FileType::Directory,
+                Inode::File { .. } => FileType::RegularFile,
+            };
+            // the offset of an entry is the offset of the next one
+            if reply.add(ino, i as i64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collection::Blob;
+    use crate::database::mem;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_collection_fs() -> Result<()> {
+        let mut db = mem::Database::default();
+        let hello = db.insert(b"hello");
+        let world = db.insert(vec![7u8; 100_000]);
+        let collection = Collection::new(
+            vec![
+                Blob {
+                    name: "hello.txt".into(),
+                    hash: hello,
+                },
+                Blob {
+                    name: "dir/sub/world.bin".into(),
+                    hash: world,
+                },
+            ],
+            100_005,
+        )?;
+        let hash = db.insert(collection.to_bytes()?);
+        let fs = CollectionFs::new(db.clone(), hash).await?;
+
+        let hello_ino = fs
+            .lookup_child(FUSE_ROOT_ID, OsStr::new("hello.txt"))
+            .unwrap();
+        assert_eq!(fs.read_at(hello_ino, 0, 1024).await.unwrap(), b"hello");
+        assert_eq!(fs.read_at(hello_ino, 1, 3).await.unwrap(), b"ell");
+        assert_eq!(fs.read_at(hello_ino, 10, 3).await.unwrap(), b"");
+
+        let dir = fs.lookup_child(FUSE_ROOT_ID, OsStr::new("dir")).unwrap();
+        let sub = fs.lookup_child(dir, OsStr::new("sub")).unwrap();
+        let world_ino = fs.lookup_child(sub, OsStr::new("world.bin")).unwrap();
+        assert_eq!(
+            fs.read_at(world_ino, 50_000, 4096).await.unwrap(),
+            vec![7u8; 4096]
+        );
+        assert_eq!(fs.read_at(dir, 0, 1).await, Err(libc::EISDIR));
+        assert_eq!(
+            fs.lookup_child(FUSE_ROOT_ID, OsStr::new("missing")),
+            Err(libc::ENOENT)
+        );
+        assert_eq!(
+            fs.lookup_child(hello_ino, OsStr::new("x")),
+            Err(libc::ENOTDIR)
+        );
+
+        let collection = Collection::new(
+            vec![
+                Blob {
+                    name: "a".into(),
+                    hash: hello,
+                },
+                Blob {
+                    name: "a/b".into(),
+                    hash: hello,
+                },
+            ],
+            10,
+        )?;
+        let hash = db.insert(collection.to_bytes()?);
+        assert!(CollectionFs::new(db, hash).await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,451 @@
+//! An HTTP gateway serving blobs and collections from a [`Database`].
+//!
+//! This allows browsers and other HTTP clients to fetch content from a node:
+//!
+//! ```text
+//! GET /blob/<hash>
+//! GET /collection/<hash>/<path>
+//! ```
+//!
+//! Data is verified against its outboard while it is streamed, so a blob whose file was
+//! changed or corrupted ends in an aborted response instead of wrong data. A single byte
+//! range can be requested with the `Range` header. The content type is guessed from the
+//! name of a collection entry, or else from the first bytes of the blob.
+use std::future::Future;
+use std::net::TcpListener;
+use std::ops::Range;
+
+use anyhow::{Context, Result};
+use bao_tree::io::fsm::Outboard;
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use hyper::header::{
+    ACCEPT_RANGES, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, RANGE,
+};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, HeaderMap, Method, Request, Response, Server, StatusCode};
+use iroh_bytes::provider::{BaoMap, BaoMapEntry};
+use iroh_bytes::Hash;
+use tracing::warn;
+
+use crate::collection::Collection;
+use crate::database::flat::Database;
+use crate::util::io::{read_range_verified, DataSink};
+
+/// The path prefix for single blobs.
+const BLOB_PREFIX: &str = "/blob/";
+/// The path prefix for entries of collections.
+const COLLECTION_PREFIX: &str = "/collection/";
+/// The number of bytes looked at when guessing the content type from the data.
+const SNIFF_LEN: u64 = 512;
+
+/// An HTTP gateway serving the content of a [`Database`].
+#[derive(Debug, Clone)]
+pub struct Gateway {
+    db: Database,
+}
+
+impl Gateway {
+    /// Creates a new gateway serving the content of `db`.
+    pub fn new(db: Database) -> Self {
+        Self { db }
+    }
+
+    /// Serves the gateway on the given listener until `shutdown` completes.
+    ///
+    /// There is no authentication, everything in the database can be read by anyone who
+    /// can connect to the listener.
+    pub async fn serve(
+        self,
+        listener: TcpListener,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<()> {
+        listener.set_nonblocking(true)?;
+        let make_svc = make_service_fn(move |_conn| {
+            let this = self.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |req| {
+                    let this = this.clone();
+                    async move { Ok::<_, hyper::Error>(this.handle(req).await) }
+                }))
+            }
+        });
+        Server::from_tcp(listener)?
+            .serve(make_svc)
+            .with_graceful_shutdown(shutdown)
+            .await?;
+        Ok(())
+    }
+
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        let head = match *req.method() {
+            Method::GET => false,
+            Method::HEAD => true,
+            _ => return response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+        };
+        match self.get(req.uri().path(), req.headers(), head).await {
+            Ok(res) => res,
+            Err(err) => {
+                warn!("gateway request failed: {err:#}");
+                response(StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}"))
+            }
+        }
+    }
+
+    async fn get(&self, path: &str, headers: &HeaderMap, head: bool) -> Result<Response<Body>> {
+        let (hash, name) = if let Some(hash) = path.strip_prefix(BLOB_PREFIX) {
+            let Ok(hash) = hash.parse::<Hash>() else {
+                return Ok(response(StatusCode::BAD_REQUEST, "invalid hash"));
+            };
+            (hash, None)
+        } else if let Some(rest) = path.strip_prefix(COLLECTION_PREFIX) {
+            let Some((hash, name)) = rest.split_once('/') else {
+                return Ok(response(StatusCode::NOT_FOUND, "not found"));
+            };
+            let Ok(hash) = hash.parse::<Hash>() else {
+                return Ok(response(StatusCode::BAD_REQUEST, "invalid hash"));
+            };
+            let Ok(name) = percent_encoding::percent_decode_str(name).decode_utf8() else {
+                return Ok(response(StatusCode::BAD_REQUEST, "invalid path"));
+            };
+            if self.db.get(&hash).is_none() {
+                return Ok(response(StatusCode::NOT_FOUND, "collection not found"));
+            }
+            let data = self.read_to_end(hash).await?;
+            let collection = Collection::from_bytes(&data)
+                .with_context(|| format!("{hash} is not a collection"))?;
+            let Some(blob) = collection.blobs().iter().find(|blob| blob.name == name) else {
+                return Ok(response(StatusCode::NOT_FOUND, "not found in collection"));
+            };
+            (blob.hash, Some(blob.name.clone()))
+        } else {
+            return Ok(response(StatusCode::NOT_FOUND, "not found"));
+        };
+
+        let Some(entry) = BaoMap::get(&self.db, &hash) else {
+            return Ok(response(StatusCode::NOT_FOUND, "blob not found"));
+        };
+        let size = entry.outboard().await?.tree().size().0;
+        let head_bytes = hyper::body::to_bytes(self.read_range(hash, 0..size.min(SNIFF_LEN)))
+            .await
+            .with_context(|| format!("failed to read {hash}"))?;
+        let mut builder = Response::builder()
+            .header(ACCEPT_RANGES, "bytes")
+            .header(CONTENT_TYPE, content_type(name.as_deref(), &head_bytes))
+            .header(ETAG, format!("\"{hash}\""))
+            // content addressed data never changes
+            .header(CACHE_CONTROL, "public, max-age=31536000, immutable");
+        let range = match parse_range(headers, size) {
+            ByteRange::Full => 0..size,
+            ByteRange::Partial(range) => {
+                builder = builder.status(StatusCode::PARTIAL_CONTENT).header(
+                    CONTENT_RANGE,
+                    format!("bytes {}-{}/{size}", range.start, range.end - 1),
+                );
+                range
+            }
+            ByteRange::Unsatisfiable => {
+                return Ok(Response::builder()
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(CONTENT_RANGE, format!("bytes */{size}"))
+                    .body(Body::empty())
+                    .expect("valid response"));
+            }
+        };
+        let body = if head {
+            Body::empty()
+        } else {
+            self.read_range(hash, range.clone())
+        };
+        Ok(builder
+            .header(CONTENT_LENGTH, range.end - range.start)
+            .body(body)
+            .expect("valid response"))
+    }
+
+    /// Reads a whole blob into memory, meant for small blobs such as collections.
+    async fn read_to_end(&self, hash: Hash) -> Result<Bytes> {
+        let Some(entry) = BaoMap::get(&self.db, &hash) else {
+            anyhow::bail!("blob {hash} not found");
+        };
+        let size = entry.outboard().await?.tree().size().0;
+        hyper::body::to_bytes(self.read_range(hash, 0..size))
+            .await
+            .with_context(|| format!("failed to read {hash}"))
+    }
+
+    /// Returns a body streaming the verified bytes of `range` of a blob.
+    ///
+    /// If the data fails to verify the body is aborted.
+    fn read_range(&self, hash: Hash, range: Range<u64>) -> Body {
+        if range.is_empty() {
+            return Body::empty();
+        }
+        let (mut sender, body) = Body::channel();
+        let db = self.db.clone();
+        tokio::spawn(async move {
+            if let Err(err) = read_range_verified(&db, hash, range, &mut sender).await {
+                warn!(%hash, "gateway failed to send blob: {err:#}");
+                sender.abort();
+            }
+        });
+        body
+    }
+}
+
+impl DataSink for hyper::body::Sender {
+    fn send(&mut self, data: Bytes) -> BoxFuture<'_, Result<()>> {
+        async move {
+            self.send_data(data).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+/// The byte range requested by a `Range` header.
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    /// No range, or a range we don't support, the whole blob is sent.
+    Full,
+    /// A range within the blob.
+    Partial(Range<u64>),
+    /// A range starting past the end of the blob.
+    Unsatisfiable,
+}
+
+/// Parses the `Range` header of a request for a blob of `size` bytes.
+///
+/// Only a single byte range is supported, other headers are ignored as allowed by
+/// RFC 9110.
+fn parse_range(headers: &HeaderMap, size: u64) -> ByteRange {
+    let Some(spec) = headers
+        .get(RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().strip_prefix("bytes="))
+    else {
+        return ByteRange::Full;
+    };
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return ByteRange::Full;
+    };
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // bytes=<start>-<end>, the end is inclusive
+        (Ok(start), Ok(end)) if start <= end => start..end.saturating_add(1).min(size),
+        // bytes=<start>-
+        (Ok(start), Err(_)) if end.is_empty() => start..size,
+        // bytes=-<suffix length>
+        (Err(_), Ok(len)) if start.is_empty() => {
+            if len == 0 {
+                return ByteRange::Unsatisfiable;
+            }
+            size.saturating_sub(len)..size
+        }
+        _ => return ByteRange::Full,
+    };
+    if range.start >= size {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Partial(range)
+    }
+}
+
+/// Guesses the content type from a file name, falling back to the first bytes of the data.
+fn content_type(name: Option<&str>, head: &[u8]) -> &'static str {
+    let extension = name
+        .and_then(|name| name.rsplit_once('.'))
+        .map(|(_, ext)| ext.to_ascii_lowercase());
+    let by_extension = match extension.as_deref() {
+        Some("html" | "htm") => Some("text/html; charset=utf-8"),
+        Some("css") => Some("text/css; charset=utf-8"),
+        Some("js" | "mjs") => Some("text/javascript; charset=utf-8"),
+        Some("json") => Some("application/json"),
+        Some("txt" | "md") => Some("text/plain; charset=utf-8"),
+        Some("svg") => Some("image/svg+xml"),
+        Some("png") => Some("image/png"),
+        Some("jpg" | "jpeg") => Some("image/jpeg"),
+        Some("gif") => Some("image/gif"),
+        Some("webp") => Some("image/webp"),
+        Some("mp4" | "m4v") => Some("video/mp4"),
+        Some("webm") => Some("video/webm"),
+        Some("mp3") => Some("audio/mpeg"),
+        Some("ogg") => Some("audio/ogg"),
+        Some("wav") => Some("audio/wav"),
+        Some("pdf") => Some("application/pdf"),
+        Some("wasm") => Some("application/wasm"),
+        Some("zip") => Some("application/zip"),
+        _ => None,
+    };
+    if let Some(content_type) = by_extension {
+        return content_type;
+    }
+    const MAGIC: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"\x1a\x45\xdf\xa3", "video/webm"),
+        (b"\0asm", "application/wasm"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"ID3", "audio/mpeg"),
+        (b"OggS", "audio/ogg"),
+    ];
+    if let Some((_, content_type)) = MAGIC.iter().find(|(magic, _)| head.starts_with(magic)) {
+        return content_type;
+    }
+    if head.len() >= 12 && &head[..4] == b"RIFF" && &head[8..12] == b"WEBP" {
+        return "image/webp";
+    }
+    if head.len() >= 8 && &head[4..8] == b"ftyp" {
+        return "video/mp4";
+    }
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        // the sniffed prefix may end in the middle of a character
+        Err(err) if err.error_len().is_none() => {
+            std::str::from_utf8(&head[..err.valid_up_to()]).expect("valid prefix")
+        }
+        Err(_) => return "application/octet-stream",
+    };
+    let start = text.trim_start().to_ascii_lowercase();
+    if start.starts_with("<!doctype html") || start.starts_with("<html") {
+        "text/html; charset=utf-8"
+    } else {
+        "text/plain; charset=utf-8"
+    }
+}
+
+fn response(status: StatusCode, body: impl Into<Body>) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(body.into())
+        .expect("valid response")
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{Client, HeaderMap};
+
+    use super::*;
+    use crate::collection::CollectionBuilder;
+
+    async fn get(
+        addr: std::net::SocketAddr,
+        path: &str,
+        range: Option<&str>,
+    ) -> Result<(StatusCode, HeaderMap, Bytes)> {
+        let mut req = Request::get(format!("http://{addr}{path}"));
+        if let Some(range) = range {
+            req = req.header(RANGE, range);
+        }
+        let res = Client::new().request(req.body(Body::empty())?).await?;
+        let status = res.status();
+        let headers = res.headers().clone();
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        Ok((status, headers, body))
+    }
+
+    #[test]
+    fn test_parse_range() {
+        let parse = |value: &str, size| {
+            let mut headers = HeaderMap::new();
+            headers.insert(RANGE, value.parse().unwrap());
+            parse_range(&headers, size)
+        };
+        assert_eq!(parse_range(&HeaderMap::new(), 10), ByteRange::Full);
+        assert_eq!(parse("bytes=2-4", 10), ByteRange::Partial(2..5));
+        assert_eq!(parse("bytes=2-100", 10), ByteRange::Partial(2..10));
+        assert_eq!(parse("bytes=2-", 10), ByteRange::Partial(2..10));
+        assert_eq!(parse("bytes=-3", 10), ByteRange::Partial(7..10));
+        assert_eq!(parse("bytes=-30", 10), ByteRange::Partial(0..10));
+        assert_eq!(parse("bytes=10-", 10), ByteRange::Unsatisfiable);
+        assert_eq!(parse("bytes=-0", 10), ByteRange::Unsatisfiable);
+        assert_eq!(parse("bytes=4-2", 10), ByteRange::Full);
+        assert_eq!(parse("bytes=0-1,4-5", 10), ByteRange::Full);
+        assert_eq!(parse("items=0-1", 10), ByteRange::Full);
+    }
+
+    #[test]
+    fn test_content_type() {
+        assert_eq!(
+            content_type(Some("a/b.HTML"), b""),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(content_type(None, b"\x89PNG\r\n\x1a\n...."), "image/png");
+        assert_eq!(content_type(None, b"\0\0\0\x20ftypisom"), "video/mp4");
+        assert_eq!(
+            content_type(None, b"  <!DOCTYPE html><p>"),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(content_type(None, b"hello"), "text/plain; charset=utf-8");
+        // a multi byte character cut off at the end of the sniffed prefix
+        assert_eq!(content_type(None, b"hi \xc3"), "text/plain; charset=utf-8");
+        assert_eq!(
+            content_type(None, b"\xff\xfe\0"),
+            "application/octet-stream"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_gateway() -> Result<()> {
+        let db = Database::default();
+        let data: Bytes = (0..100_000u32).map(|i| i as u8).collect::<Vec<_>>().into();
+        let blob = db.import_bytes(data.clone())?;
+        let collection = CollectionBuilder::new()
+            .add_bytes("site/index.html", "<p>hello</p>")
+            .add_bytes("data.bin", data.clone())
+            .build(&db)
+            .await?;
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(Gateway::new(db).serve(listener, async move {
+            shutdown_rx.await.ok();
+        }));
+
+        let (status, headers, body) = get(addr, &format!("/blob/{blob}"), None).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[CONTENT_LENGTH], "100000");
+        assert_eq!(headers[ACCEPT_RANGES], "bytes");
+        assert_eq!(body, data);
+
+        let (status, headers, body) =
+            get(addr, &format!("/blob/{blob}"), Some("bytes=5000-70000")).await?;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(headers[CONTENT_RANGE], "bytes 5000-70000/100000");
+        assert_eq!(body, data.slice(5000..70001));
+
+        let (status, headers, _) =
+            get(addr, &format!("/blob/{blob}"), Some("bytes=100000-")).await?;
+        assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(headers[CONTENT_RANGE], "bytes */100000");
+
+        let path = format!("/collection/{collection}/site%2Findex.html");
+        let (status, headers, body) = get(addr, &path, None).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[CONTENT_TYPE], "text/html; charset=utf-8");
+        assert_eq!(body, "<p>hello</p>");
+
+        let path = format!("/collection/{collection}/data.bin");
+        let (status, _, body) = get(addr, &path, Some("bytes=-10")).await?;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body, data.slice(99_990..));
+
+        let path = format!("/collection/{collection}/missing");
+        let (status, _, _) = get(addr, &path, None).await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let missing = Hash::from(blake3::hash(b"missing"));
+        let (status, _, _) = get(addr, &format!("/blob/{missing}"), None).await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _, _) = get(addr, "/blob/nohash", None).await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        shutdown_tx.send(()).ok();
+        server.await??;
+        Ok(())
+    }
+}
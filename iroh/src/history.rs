@@ -0,0 +1,270 @@
+//! A history of the transfers of a node.
+//!
+//! The node records the data added to it, and clients record the gets they run on behalf
+//! of the node's user. The history survives restarts of the node when it is loaded from a
+//! file, so interrupted transfers can be found and restarted later.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use iroh_bytes::protocol::RequestToken;
+use iroh_bytes::Hash;
+use iroh_net::tls::PeerId;
+use serde::{Deserialize, Serialize};
+
+use crate::util::io::write_private;
+
+/// Number of records kept, older records are dropped.
+const MAX_RECORDS: usize = 256;
+
+/// A transfer recorded in the [`History`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    /// Identifier of the record, unique within the history.
+    pub id: u64,
+    /// When the transfer was started.
+    pub started: SystemTime,
+    /// What was transferred.
+    pub op: Operation,
+    /// How the transfer ended.
+    pub outcome: Outcome,
+    /// Number of bytes transferred.
+    pub bytes: u64,
+}
+
+/// A transfer that can be recorded in the [`History`].
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Operation {
+    /// Data at a path was added to the node.
+    Add {
+        /// The path of the added file or directory.
+        path: PathBuf,
+    },
+    /// Data was fetched from a provider.
+    Get {
+        /// The hash that was fetched.
+        hash: Hash,
+        /// The provider.
+        peer: PeerId,
+        /// The addresses of the provider.
+        addrs: Vec<SocketAddr>,
+        /// The DERP region of the provider.
+        derp_region: Option<u16>,
+        /// The request token, if any.
+        token: Option<RequestToken>,
+        /// True if only a single blob was fetched, false for a collection.
+        single: bool,
+        /// The directory the data was saved in, `None` for stdout.
+        out: Option<PathBuf>,
+    },
+}
+
+/// The outcome of a recorded transfer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Outcome {
+    /// The transfer is still running.
+    Running,
+    /// The transfer completed.
+    Done {
+        /// The hash of the transferred data.
+        hash: Hash,
+    },
+    /// The transfer failed.
+    Failed {
+        /// Why it failed.
+        reason: String,
+    },
+    /// The transfer was stopped before it completed.
+    Interrupted,
+}
+
+impl Outcome {
+    /// Whether the transfer ended without completing.
+    pub fn is_incomplete(&self) -> bool {
+        !matches!(self, Outcome::Done { .. })
+    }
+}
+
+/// The recent transfers of a node.
+///
+/// Keeps the last 256 records. A history loaded with [`History::load`] is written back to
+/// its file after every change.
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    inner: Arc<Mutex<Inner>>,
+    /// Serializes writes of the file, so an older state never overwrites a newer one.
+    save_lock: Arc<tokio::sync::Mutex<()>>,
+    path: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Inner {
+    next_id: u64,
+    records: VecDeque<Record>,
+}
+
+impl History {
+    /// Loads the history from `path`, and keeps it up to date from now on.
+    ///
+    /// Returns an empty history if the file does not exist. Transfers that were running
+    /// when the history was last written are marked as interrupted.
+    pub async fn load(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let mut inner = match tokio::fs::read(&path).await {
+            Ok(data) => postcard::from_bytes::<Inner>(&data)
+                .with_context(|| format!("invalid history in {}", path.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Inner::default(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", path.display()))
+            }
+        };
+        for record in inner.records.iter_mut() {
+            if record.outcome == Outcome::Running {
+                record.outcome = Outcome::Interrupted;
+            }
+        }
+        Ok(Self {
+            inner: Arc::new(Mutex::new(inner)),
+            save_lock: Default::default(),
+            path: Some(path),
+        })
+    }
+
+    /// Records the start of a transfer, returning the id of its record.
+    pub async fn start(&self, op: Operation) -> Result<u64> {
+        let id = {
+            let mut inner = self.inner.lock().unwrap();
+            let id = inner.next_id;
+            inner.next_id += 1;
+            inner.records.push_back(Record {
+                id,
+                started: SystemTime::now(),
+                op,
+                outcome: Outcome::Running,
+                bytes: 0,
+            });
+            while inner.records.len() > MAX_RECORDS {
+                inner.records.pop_front();
+            }
+            id
+        };
+        self.save().await?;
+        Ok(id)
+    }
+
+    /// Records how the transfer with the given id ended.
+    ///
+    /// Returns the updated record, or `None` if there is no record with this id.
+    pub async fn finish(&self, id: u64, outcome: Outcome, bytes: u64) -> Result<Option<Record>> {
+        let record = {
+            let mut inner = self.inner.lock().unwrap();
+            let Some(record) = inner.records.iter_mut().find(|r| r.id == id) else {
+                return Ok(None);
+            };
+            record.outcome = outcome;
+            record.bytes = bytes;
+            record.clone()
+        };
+        self.save().await?;
+        Ok(Some(record))
+    }
+
+    /// Returns the record with the given id.
+    pub fn get(&self, id: u64) -> Option<Record> {
+        let inner = self.inner.lock().unwrap();
+        inner.records.iter().find(|r| r.id == id).cloned()
+    }
+
+    /// Returns all records, oldest first.
+    pub fn records(&self) -> Vec<Record> {
+        self.inner.lock().unwrap().records.iter().cloned().collect()
+    }
+
+    async fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let _guard = self.save_lock.lock().await;
+        let data = postcard::to_stdvec(&*self.inner.lock().unwrap())?;
+        // the records contain the request tokens of gets
+        write_private(path, &data)
+            .await
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_history() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("history");
+        let history = History::load(&path).await?;
+        assert!(history.records().is_empty());
+
+        let add = Operation::Add {
+            path: "/some/file".into(),
+        };
+        let done = history.start(add.clone()).await?;
+        let running = history.start(add.clone()).await?;
+        assert_ne!(done, running);
+        let hash = Hash::from(blake3::hash(b"hello"));
+        let record = history.finish(done, Outcome::Done { hash }, 5).await?;
+        assert_eq!(record.map(|r| r.bytes), Some(5));
+        assert!(history
+            .finish(1000, Outcome::Interrupted, 0)
+            .await?
+            .is_none());
+
+        // running transfers did not survive a restart
+        let history = History::load(&path).await?;
+        let records = history.records();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].outcome, Outcome::Done { hash });
+        assert_eq!(records[1].outcome, Outcome::Interrupted);
+        assert_eq!(history.get(running).map(|r| r.op), Some(add.clone()));
+
+        // ids are not reused, and old records are dropped
+        for _ in 0..MAX_RECORDS {
+            history.start(add.clone()).await?;
+        }
+        let records = history.records();
+        assert_eq!(records.len(), MAX_RECORDS);
+        assert!(history.get(done).is_none());
+        assert_eq!(records.last().unwrap().id, MAX_RECORDS as u64 + 1);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_history_private() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("history");
+        let history = History::load(&path).await?;
+        let get = Operation::Get {
+            hash: Hash::from(blake3::hash(b"hello")),
+            peer: iroh_net::tls::Keypair::generate().public().into(),
+            addrs: Vec::new(),
+            derp_region: None,
+            token: Some(RequestToken::generate()),
+            single: true,
+            out: None,
+        };
+        history.start(get.clone()).await?;
+        // the file contains the token, so only the owner may read it
+        let mode = std::fs::metadata(&path)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let history = History::load(&path).await?;
+        assert_eq!(history.records()[0].op, get);
+        Ok(())
+    }
+}
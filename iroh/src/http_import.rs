@@ -0,0 +1,192 @@
+//! A local HTTP endpoint to import blobs into a running node.
+//!
+//! This allows processes that can not speak the iroh RPC protocol, e.g. shell scripts
+//! using `curl`, to publish content through a running node:
+//!
+//! ```text
+//! curl -H "Authorization: Bearer <token>" --data-binary @file http://127.0.0.1:4919/blobs
+//! ```
+//!
+//! The response body is the hash of the imported blob. Every request must carry the
+//! token the endpoint was created with, see [`ImportEndpoint::new`].
+use std::future::Future;
+use std::io;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::pin::Pin;
+
+use anyhow::{Context, Result};
+use futures::TryStreamExt;
+use hyper::body::HttpBody;
+use hyper::header::AUTHORIZATION;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use iroh_bytes::protocol::RequestToken;
+use iroh_bytes::Hash;
+use subtle::ConstantTimeEq;
+use tokio_util::io::StreamReader;
+use tracing::{debug, warn};
+
+use crate::database::flat::Database;
+
+/// The path blobs are posted to.
+const BLOBS_PATH: &str = "/blobs";
+
+/// An HTTP endpoint that imports posted bytes into a [`Database`].
+#[derive(Debug, Clone)]
+pub struct ImportEndpoint {
+    db: Database,
+    token: RequestToken,
+    dir: PathBuf,
+}
+
+impl ImportEndpoint {
+    /// Creates a new endpoint importing into `db`.
+    ///
+    /// Posted data is stored in files inside `dir`, which is created if needed. Requests
+    /// must send `token` in an `Authorization: Bearer <token>` header.
+    pub fn new(db: Database, token: RequestToken, dir: PathBuf) -> Self {
+        Self { db, token, dir }
+    }
+
+    /// Serves the endpoint on the given listener until `shutdown` completes.
+    ///
+    /// The listener should be bound to a loopback address, the endpoint is meant for
+    /// other processes on the same machine.
+    pub async fn serve(
+        self,
+        listener: TcpListener,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<()> {
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .with_context(|| format!("failed to create {}", self.dir.display()))?;
+        listener.set_nonblocking(true)?;
+        let make_svc = make_service_fn(move |_conn| {
+            let this = self.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |req| {
+                    let this = this.clone();
+                    async move { Ok::<_, hyper::Error>(this.handle(req).await) }
+                }))
+            }
+        });
+        Server::from_tcp(listener)?
+            .serve(make_svc)
+            .with_graceful_shutdown(shutdown)
+            .await?;
+        Ok(())
+    }
+
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        if req.uri().path() != BLOBS_PATH {
+            return response(StatusCode::NOT_FOUND, "not found");
+        }
+        if req.method() != Method::POST {
+            return response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed");
+        }
+        if !self.is_authorized(&req) {
+            return response(StatusCode::UNAUTHORIZED, "invalid or missing token");
+        }
+        match self.import(req.into_body()).await {
+            Ok(hash) => {
+                debug!(%hash, "imported blob over http");
+                response(StatusCode::OK, hash.to_string())
+            }
+            Err(err) => {
+                warn!("http import failed: {err:#}");
+                response(StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}"))
+            }
+        }
+    }
+
+    fn is_authorized(&self, req: &Request<Body>) -> bool {
+        req.headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .and_then(|token| token.trim().parse::<RequestToken>().ok())
+            // compare in constant time, to not leak the token through timing
+            .map_or(false, |token| {
+                token
+                    .as_bytes()
+                    .as_ref()
+                    .ct_eq(self.token.as_bytes().as_ref())
+                    .into()
+            })
+    }
+
+    /// Stores the body in a file inside `dir` and adds it to the database.
+    async fn import(&self, mut body: Body) -> Result<Hash> {
+        let size_hint = body.size_hint().exact();
+        let data = futures::stream::poll_fn(|cx| Pin::new(&mut body).poll_data(cx))
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err));
+        let reader = StreamReader::new(data);
+        self.db.import_reader(reader, &self.dir, size_hint).await
+    }
+}
+
+fn response(status: StatusCode, body: impl Into<Body>) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(body.into())
+        .expect("valid response")
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::Client;
+    use testdir::testdir;
+
+    use super::*;
+
+    async fn post(
+        addr: std::net::SocketAddr,
+        token: Option<&RequestToken>,
+        data: &'static [u8],
+    ) -> Result<(StatusCode, String)> {
+        let mut req = Request::post(format!("http://{addr}{BLOBS_PATH}"));
+        if let Some(token) = token {
+            req = req.header(AUTHORIZATION, format!("Bearer {token}"));
+        }
+        let res = Client::new().request(req.body(Body::from(data))?).await?;
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        Ok((status, String::from_utf8(body.to_vec())?))
+    }
+
+    #[tokio::test]
+    async fn test_http_import() -> Result<()> {
+        let dir = testdir!();
+        let db = Database::default();
+        let token = RequestToken::generate();
+        let endpoint = ImportEndpoint::new(db.clone(), token.clone(), dir.clone());
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(endpoint.serve(listener, async move {
+            shutdown_rx.await.ok();
+        }));
+
+        let (status, _) = post(addr, None, b"hello").await?;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = post(addr, Some(&RequestToken::generate()), b"hello").await?;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let expected = Hash::from(blake3::hash(b"hello"));
+        for _ in 0..2 {
+            let (status, body) = post(addr, Some(&token), b"hello").await?;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body.parse::<Hash>()?, expected);
+        }
+        let entry = db.get(&expected).context("blob not imported")?;
+        let path = entry.blob_path().context("blob not external")?;
+        assert_eq!(std::fs::read(path)?, b"hello");
+        // importing the same content twice only keeps a single copy
+        assert_eq!(std::fs::read_dir(&dir)?.count(), 1);
+
+        shutdown_tx.send(()).ok();
+        server.await??;
+        Ok(())
+    }
+}
@@ -0,0 +1,74 @@
+//! Storage for the keypair of a node.
+//!
+//! A [`KeyStore`] keeps the [`Keypair`] which determines the [`PeerId`] of a node. The
+//! [`crate::node::Builder`] loads the keypair from its keystore when no keypair is given,
+//! generating and storing one on first use.
+//!
+//! [`PeerId`]: iroh_net::tls::PeerId
+use std::fmt::Debug;
+
+use anyhow::Result;
+use iroh_net::tls::Keypair;
+
+#[cfg(feature = "file-keystore")]
+pub mod file;
+#[cfg(feature = "keychain-keystore")]
+pub mod keychain;
+
+/// Stores the keypair of a node.
+///
+/// Implementations may block, e.g. to derive an encryption key or to wait for the user to
+/// unlock a keychain, so they should not be called from async code directly.
+pub trait KeyStore: Debug + Send + Sync + 'static {
+    /// Loads the stored keypair, `None` if no keypair is stored.
+    fn load(&self) -> Result<Option<Keypair>>;
+
+    /// Stores `keypair`, replacing the stored keypair if there is one.
+    fn store(&self, keypair: &Keypair) -> Result<()>;
+
+    /// Loads the stored keypair, generating and storing a new one if there is none.
+    fn load_or_generate(&self) -> Result<Keypair> {
+        match self.load()? {
+            Some(keypair) => Ok(keypair),
+            None => {
+                let keypair = Keypair::generate();
+                self.store(&keypair)?;
+                Ok(keypair)
+            }
+        }
+    }
+
+    /// Replaces the stored keypair with a newly generated one.
+    ///
+    /// The previous keypair is returned as part of the [`Rotation`], so peers can be told
+    /// about the new [`PeerId`] by a node still reachable under the previous one.
+    ///
+    /// [`PeerId`]: iroh_net::tls::PeerId
+    fn rotate(&self) -> Result<Rotation> {
+        let previous = self.load()?;
+        let current = Keypair::generate();
+        self.store(&current)?;
+        Ok(Rotation { previous, current })
+    }
+}
+
+/// The outcome of [`KeyStore::rotate`].
+#[derive(Debug, Clone)]
+pub struct Rotation {
+    /// The keypair stored before the rotation, if there was one.
+    pub previous: Option<Keypair>,
+    /// The newly generated keypair, which is stored now.
+    pub current: Keypair,
+}
+
+/// Moves the keypair stored in `from` to `to`, e.g. from a file to the OS keychain.
+///
+/// The keypair is left in `from`, remove it once the node uses `to`. Returns the moved
+/// keypair, `None` if `from` stores none, in which case `to` is not changed.
+pub fn migrate(from: &dyn KeyStore, to: &dyn KeyStore) -> Result<Option<Keypair>> {
+    let keypair = from.load()?;
+    if let Some(ref keypair) = keypair {
+        to.store(keypair)?;
+    }
+    Ok(keypair)
+}
@@ -0,0 +1,175 @@
+//! A [`KeyStore`] keeping the keypair in a passphrase encrypted file.
+//!
+//! The keypair is stored in the OpenSSH format, encrypted with ChaCha20-Poly1305 using a key
+//! derived from the passphrase with scrypt.
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use chacha20poly1305::aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use iroh_net::tls::Keypair;
+
+use super::KeyStore;
+
+/// The magic bytes at the start of an encrypted keypair file.
+const MAGIC: &[u8; 8] = b"irohkey1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = MAGIC.len() + 1 + SALT_LEN + NONCE_LEN;
+
+/// The default scrypt work factor, as the base 2 logarithm of the scrypt cost parameter.
+pub const DEFAULT_WORK_FACTOR: u8 = scrypt::Params::RECOMMENDED_LOG_N;
+
+/// Stores the keypair in a file encrypted with a passphrase.
+///
+/// The file is only readable by the current user on unix. The passphrase is not stored,
+/// and is needed for both loading and storing the keypair.
+pub struct EncryptedFileKeyStore {
+    path: PathBuf,
+    passphrase: String,
+    work_factor: u8,
+}
+
+impl fmt::Debug for EncryptedFileKeyStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptedFileKeyStore")
+            .field("path", &self.path)
+            .field("work_factor", &self.work_factor)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EncryptedFileKeyStore {
+    /// Creates a keystore for the file at `path`, encrypted with `passphrase`.
+    pub fn new(path: impl Into<PathBuf>, passphrase: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            passphrase: passphrase.into(),
+            work_factor: DEFAULT_WORK_FACTOR,
+        }
+    }
+
+    /// Sets the scrypt work factor used when storing a keypair.
+    ///
+    /// Each increment doubles the time and memory needed to derive the encryption key,
+    /// defaults to [`DEFAULT_WORK_FACTOR`]. Stored files record their work factor, so it
+    /// can be changed without breaking existing files.
+    pub fn work_factor(mut self, work_factor: u8) -> Self {
+        self.work_factor = work_factor;
+        self
+    }
+
+    /// The path of the encrypted keypair file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn cipher(&self, work_factor: u8, salt: &[u8]) -> Result<ChaCha20Poly1305> {
+        let params = scrypt::Params::new(work_factor, 8, 1, 32)
+            .map_err(|err| anyhow!("invalid scrypt work factor {work_factor}: {err}"))?;
+        let mut key = Key::default();
+        scrypt::scrypt(self.passphrase.as_bytes(), salt, &params, &mut key)
+            .map_err(|err| anyhow!("failed to derive key: {err}"))?;
+        Ok(ChaCha20Poly1305::new(&key))
+    }
+}
+
+impl KeyStore for EncryptedFileKeyStore {
+    fn load(&self) -> Result<Option<Keypair>> {
+        let data = match std::fs::read(&self.path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    format!("failed to read keypair file {}", self.path.display())
+                })
+            }
+        };
+        ensure!(
+            data.len() > HEADER_LEN && data.starts_with(MAGIC),
+            "{} is not an encrypted keypair file",
+            self.path.display()
+        );
+        let (header, ciphertext) = data.split_at(HEADER_LEN);
+        let work_factor = header[MAGIC.len()];
+        let salt = &header[MAGIC.len() + 1..MAGIC.len() + 1 + SALT_LEN];
+        let nonce = Nonce::from_slice(&header[HEADER_LEN - NONCE_LEN..]);
+        let Ok(plaintext) = self.cipher(work_factor, salt)?.decrypt(nonce, ciphertext) else {
+            bail!("wrong passphrase for {}", self.path.display());
+        };
+        let keypair = Keypair::try_from_openssh(plaintext).context("invalid keyfile")?;
+        Ok(Some(keypair))
+    }
+
+    fn store(&self, keypair: &Keypair) -> Result<()> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ser_key = keypair.to_openssh()?;
+        let ciphertext = self
+            .cipher(self.work_factor, &salt)?
+            .encrypt(&nonce, ser_key.as_bytes())
+            .map_err(|err| anyhow!("failed to encrypt keypair: {err}"))?;
+
+        let parent = self
+            .path
+            .parent()
+            .ok_or_else(|| anyhow!("no parent directory found for '{}'", self.path.display()))?;
+        std::fs::create_dir_all(parent)?;
+
+        // write to a temporary file next to the keypair file, and move it in place
+        let temp_path = self.path.with_extension("tmp");
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options
+            .open(&temp_path)
+            .with_context(|| format!("unable to create {}", temp_path.display()))?;
+        file.write_all(MAGIC)?;
+        file.write_all(&[self.work_factor])?;
+        file.write_all(&salt)?;
+        file.write_all(&nonce)?;
+        file.write_all(&ciphertext)?;
+        file.sync_all().context("unable to write keyfile")?;
+        drop(file);
+        std::fs::rename(&temp_path, &self.path).context("failed to rename keyfile")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypted_file_keystore() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("keys").join("keypair");
+        let store = EncryptedFileKeyStore::new(&path, "secret").work_factor(4);
+        assert!(store.load()?.is_none());
+
+        let keypair = store.load_or_generate()?;
+        let loaded = store.load()?.expect("keypair stored");
+        assert_eq!(loaded.public(), keypair.public());
+        let raw = std::fs::read(&path)?;
+        let ser_key = keypair.to_openssh()?;
+        assert!(!raw
+            .windows(ser_key.len())
+            .any(|window| window == ser_key.as_bytes()));
+
+        let wrong = EncryptedFileKeyStore::new(&path, "wrong").work_factor(4);
+        assert!(wrong.load().is_err());
+
+        let rotation = store.rotate()?;
+        assert_eq!(rotation.previous.unwrap().public(), keypair.public());
+        assert_ne!(rotation.current.public(), keypair.public());
+        assert_eq!(
+            store.load()?.expect("keypair stored").public(),
+            rotation.current.public()
+        );
+        Ok(())
+    }
+}
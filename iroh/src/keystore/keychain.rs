@@ -0,0 +1,98 @@
+//! A [`KeyStore`] keeping the keypair in the keychain of the operating system.
+//!
+//! This uses the keychain on macOS, the credential manager on Windows and the kernel
+//! keyutils on Linux.
+use anyhow::{Context, Result};
+use iroh_net::tls::Keypair;
+use keyring::Entry;
+
+use super::KeyStore;
+
+/// The default keychain service name for iroh keypairs.
+pub const DEFAULT_SERVICE: &str = "iroh";
+
+/// Stores the keypair in the keychain of the operating system.
+#[derive(Debug)]
+pub struct KeychainKeyStore {
+    entry: Entry,
+}
+
+impl KeychainKeyStore {
+    /// Creates a keystore for the keychain entry of `user` in [`DEFAULT_SERVICE`].
+    pub fn new(user: &str) -> Result<Self> {
+        Self::with_service(DEFAULT_SERVICE, user)
+    }
+
+    /// Creates a keystore for the keychain entry of `user` in `service`.
+    pub fn with_service(service: &str, user: &str) -> Result<Self> {
+        let entry = Entry::new(service, user).context("failed to open keychain entry")?;
+        Ok(Self::from_entry(entry))
+    }
+
+    /// Creates a keystore for an existing keychain entry.
+    pub fn from_entry(entry: Entry) -> Self {
+        Self { entry }
+    }
+
+    /// Removes the keypair from the keychain.
+    pub fn delete(&self) -> Result<()> {
+        match self.entry.delete_password() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(err) => Err(err).context("failed to delete keypair from keychain"),
+        }
+    }
+}
+
+impl KeyStore for KeychainKeyStore {
+    fn load(&self) -> Result<Option<Keypair>> {
+        match self.entry.get_password() {
+            Ok(ser_key) => {
+                let keypair = Keypair::try_from_openssh(ser_key).context("invalid keypair")?;
+                Ok(Some(keypair))
+            }
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(err) => Err(err).context("failed to read keypair from keychain"),
+        }
+    }
+
+    fn store(&self, keypair: &Keypair) -> Result<()> {
+        let ser_key = keypair.to_openssh()?;
+        self.entry
+            .set_password(&ser_key)
+            .context("failed to write keypair to keychain")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::keystore::migrate;
+
+    use super::*;
+
+    #[test]
+    fn test_keychain_keystore() -> Result<()> {
+        let credential = keyring::mock::default_credential_builder().build(None, "iroh", "test")?;
+        let store = KeychainKeyStore::from_entry(Entry::new_with_credential(credential));
+        assert!(store.load()?.is_none());
+
+        let keypair = store.load_or_generate()?;
+        assert_eq!(
+            store.load()?.expect("keypair stored").public(),
+            keypair.public()
+        );
+
+        let credential =
+            keyring::mock::default_credential_builder().build(None, "iroh", "other")?;
+        let other = KeychainKeyStore::from_entry(Entry::new_with_credential(credential));
+        let moved = migrate(&store, &other)?.expect("keypair moved");
+        assert_eq!(moved.public(), keypair.public());
+        assert_eq!(
+            other.load()?.expect("keypair stored").public(),
+            keypair.public()
+        );
+
+        store.delete()?;
+        assert!(store.load()?.is_none());
+        Ok(())
+    }
+}
@@ -6,9 +6,23 @@ pub use iroh_net as net;
 
 #[cfg(feature = "iroh-collection")]
 pub mod collection;
+#[cfg(feature = "cli")]
+pub mod config;
 pub mod database;
+#[cfg(all(feature = "flat-db", feature = "iroh-collection"))]
+pub mod delegate;
 pub mod dial;
+#[cfg(feature = "fuse")]
+pub mod fuse;
+#[cfg(feature = "gateway")]
+pub mod gateway;
+pub mod history;
+#[cfg(feature = "http-import")]
+pub mod http_import;
+pub mod keystore;
 pub mod node;
+#[cfg(feature = "flat-db")]
+pub mod push;
 pub mod rpc_protocol;
 pub mod util;
 
@@ -4,13 +4,11 @@ use anyhow::{Context, Result};
 use clap::Parser;
 use tracing_subscriber::{prelude::*, EnvFilter};
 
+use iroh::config::{iroh_config_path, Config, CONFIG_FILE_NAME, ENV_PREFIX};
+
 mod commands;
-mod config;
 
-use crate::{
-    commands::{init_metrics_collection, Cli},
-    config::{iroh_config_path, Config, CONFIG_FILE_NAME, ENV_PREFIX},
-};
+use crate::commands::{init_metrics_collection, Cli};
 
 fn main() -> Result<()> {
     let rt = tokio::runtime::Builder::new_multi_thread()
@@ -5,6 +5,7 @@
 //! You can monitor what is happening in the node using [`Node::subscribe`].
 //!
 //! To shut down the node, call [`Node::shutdown`].
+use std::collections::BTreeMap;
 use std::fmt::Debug;
 use std::future::Future;
 use std::net::{Ipv4Addr, SocketAddr};
@@ -13,24 +14,25 @@ use std::sync::Arc;
 use std::task::Poll;
 use std::time::Duration;
 
-use anyhow::{Context, Result};
-use bytes::Bytes;
+use anyhow::{ensure, Context, Result};
 use futures::future::{BoxFuture, Shared};
 use futures::{FutureExt, Stream, StreamExt, TryFutureExt};
 use iroh_bytes::collection::{CollectionParser, NoCollectionParser};
-use iroh_bytes::protocol::GetRequest;
 use iroh_bytes::{
     protocol::{Closed, Request, RequestToken},
     provider::{
-        BaoMap, BaoMapEntry, BaoReadonlyDb, CustomGetHandler, ProvideProgress,
-        RequestAuthorizationHandler, ValidateProgress,
+        BaoMap, BaoMapEntry, BaoReadonlyDb, CustomGetHandler, Handlers, ProvideProgress,
+        PushHandler, RequestAuthorizationHandler, TransferHandler, ValidateProgress,
     },
     util::runtime,
-    util::Hash,
+    util::{Hash, RpcResult},
 };
+use iroh_io::AsyncSliceReader;
 use iroh_net::{
     config::Endpoint,
     derp::DerpMap,
+    dialer::Dialer,
+    magic_endpoint::ConnectionPath,
     tls::{self, Keypair, PeerId},
     MagicEndpoint,
 };
@@ -44,11 +46,17 @@ use tokio_util::sync::CancellationToken;
 use tracing::{debug, trace};
 
 use crate::dial::Ticket;
+use crate::history::{History, Operation, Outcome};
+use crate::keystore::KeyStore;
 use crate::rpc_protocol::{
-    AddrsRequest, AddrsResponse, IdRequest, IdResponse, ListBlobsRequest, ListBlobsResponse,
-    ListCollectionsRequest, ListCollectionsResponse, ProvideRequest, ProviderRequest,
-    ProviderResponse, ProviderService, ShutdownRequest, ValidateRequest, VersionRequest,
-    VersionResponse, WatchRequest, WatchResponse,
+    AddrsRequest, AddrsResponse, BlobReadRequest, BlobReadResponse, HistoryFinishRequest,
+    HistoryGetRequest, HistoryGetResponse, HistoryListRequest, HistoryListResponse,
+    HistoryStartRequest, HistoryStartResponse, IdRequest, IdResponse, ListBlobsRequest,
+    ListBlobsResponse, ListCollectionsRequest, ListCollectionsResponse, PeersRequest,
+    PeersResponse, ProvideRequest, ProviderRequest, ProviderResponse, ProviderService,
+    ShutdownRequest, TagListRequest, TagListResponse, TagRemoveRequest, TagRemoveResponse,
+    TagSetRequest, TagSetResponse, ValidateRequest, VersionRequest, VersionResponse, WatchRequest,
+    WatchResponse,
 };
 
 const MAX_CONNECTIONS: u32 = 1024;
@@ -59,6 +67,9 @@ const HEALTH_POLL_WAIT: Duration = Duration::from_secs(1);
 /// 11204 is "iroh" in leetspeak <https://simple.wikipedia.org/wiki/Leet>
 pub const DEFAULT_BIND_ADDR: (Ipv4Addr, u16) = (Ipv4Addr::LOCALHOST, 11204);
 
+/// Size of the chunks blobs are sent in over RPC.
+const BLOB_READ_CHUNK_SIZE: usize = 64 * 1024;
+
 /// How long we wait at most for some endpoints to be discovered.
 const ENDPOINT_WAIT: Duration = Duration::from_secs(5);
 
@@ -79,71 +90,38 @@ where
     C: CollectionParser,
 {
     bind_addr: SocketAddr,
-    keypair: Keypair,
+    keypair: Option<Keypair>,
+    keystore: Option<Arc<dyn KeyStore>>,
     rpc_endpoint: E,
     db: D,
     keylog: bool,
-    custom_get_handler: Arc<dyn CustomGetHandler>,
-    auth_handler: Arc<dyn RequestAuthorizationHandler>,
+    handlers: Handlers,
     derp_map: Option<DerpMap>,
     collection_parser: C,
+    history: History,
+    shutdown_grace_period: Duration,
+    protocols: ProtocolRegistry,
     rt: Option<runtime::Handle>,
 }
 
 const PROTOCOLS: [&[u8]; 1] = [&iroh_bytes::protocol::ALPN];
 
-/// A noop authorization handler that does not do any authorization.
-///
-/// This is the default. It does not have to be pub, since it is going to be
-/// boxed.
-#[derive(Debug)]
-struct NoopRequestAuthorizationHandler;
-
-impl RequestAuthorizationHandler for NoopRequestAuthorizationHandler {
-    fn authorize(
-        &self,
-        token: Option<RequestToken>,
-        _request: &Request,
-    ) -> BoxFuture<'static, anyhow::Result<()>> {
-        async move {
-            if let Some(token) = token {
-                anyhow::bail!(
-                    "no authorization handler defined, but token was provided: {:?}",
-                    token
-                );
-            }
-            Ok(())
-        }
-        .boxed()
-    }
-}
-
-#[derive(Debug)]
-struct NoopCustomGetHandler;
-
-impl CustomGetHandler for NoopCustomGetHandler {
-    fn handle(
-        &self,
-        _token: Option<RequestToken>,
-        _request: Bytes,
-    ) -> BoxFuture<'static, anyhow::Result<GetRequest>> {
-        async move { Err(anyhow::anyhow!("no custom get handler defined")) }.boxed()
-    }
-}
-
 impl<D: BaoMap> Builder<D> {
     /// Creates a new builder for [`Node`] using the given database.
     fn with_db(db: D) -> Self {
         Self {
             bind_addr: DEFAULT_BIND_ADDR.into(),
-            keypair: Keypair::generate(),
+            keypair: None,
+            keystore: None,
             db,
             keylog: false,
             derp_map: None,
             rpc_endpoint: Default::default(),
-            custom_get_handler: Arc::new(NoopCustomGetHandler),
-            auth_handler: Arc::new(NoopRequestAuthorizationHandler),
+            handlers: Handlers::default(),
             collection_parser: NoCollectionParser,
+            history: History::default(),
+            shutdown_grace_period: Duration::ZERO,
+            protocols: ProtocolRegistry::default(),
             rt: None,
         }
     }
@@ -164,13 +142,16 @@ where
         Builder {
             bind_addr: self.bind_addr,
             keypair: self.keypair,
+            keystore: self.keystore,
             db: self.db,
             keylog: self.keylog,
-            custom_get_handler: self.custom_get_handler,
-            auth_handler: self.auth_handler,
+            handlers: self.handlers,
             rpc_endpoint: value,
             derp_map: self.derp_map,
             collection_parser: self.collection_parser,
+            history: self.history,
+            shutdown_grace_period: self.shutdown_grace_period,
+            protocols: self.protocols,
             rt: self.rt,
         }
     }
@@ -185,16 +166,29 @@ where
             collection_parser,
             bind_addr: self.bind_addr,
             keypair: self.keypair,
+            keystore: self.keystore,
             db: self.db,
             keylog: self.keylog,
-            custom_get_handler: self.custom_get_handler,
-            auth_handler: self.auth_handler,
+            handlers: self.handlers,
             rpc_endpoint: self.rpc_endpoint,
             derp_map: self.derp_map,
+            history: self.history,
+            shutdown_grace_period: self.shutdown_grace_period,
+            protocols: self.protocols,
             rt: self.rt,
         }
     }
 
+    /// Applies the bind address and the [`DerpMap`] of a [`Config`].
+    ///
+    /// [`Config`]: crate::config::Config
+    #[cfg(feature = "cli")]
+    pub fn config(mut self, config: &crate::config::Config) -> Self {
+        self.bind_addr = config.bind_addr();
+        self.derp_map = config.derp_map();
+        self
+    }
+
     /// Sets the `[DerpMap]`
     pub fn derp_map(mut self, dm: DerpMap) -> Self {
         self.derp_map = Some(dm);
@@ -202,19 +196,32 @@ where
     }
 
     /// Configure the custom get handler.
-    pub fn custom_get_handler(self, custom_get_handler: Arc<dyn CustomGetHandler>) -> Self {
-        Self {
-            custom_get_handler,
-            ..self
-        }
+    pub fn custom_get_handler(mut self, custom_get_handler: Arc<dyn CustomGetHandler>) -> Self {
+        self.handlers.custom_get = custom_get_handler;
+        self
     }
 
     /// Configures a custom authorization handler.
-    pub fn custom_auth_handler(self, auth_handler: Arc<dyn RequestAuthorizationHandler>) -> Self {
-        Self {
-            auth_handler,
-            ..self
-        }
+    pub fn custom_auth_handler(
+        mut self,
+        auth_handler: Arc<dyn RequestAuthorizationHandler>,
+    ) -> Self {
+        self.handlers.authorization = auth_handler;
+        self
+    }
+
+    /// Configures a handler notified about the content served by the node.
+    pub fn transfer_handler(mut self, transfer_handler: Arc<dyn TransferHandler>) -> Self {
+        self.handlers.transfer = transfer_handler;
+        self
+    }
+
+    /// Configures the handler accepting blobs pushed by peers.
+    ///
+    /// By default all pushes are rejected.
+    pub fn push_handler(mut self, push_handler: Arc<dyn PushHandler>) -> Self {
+        self.handlers.push = push_handler;
+        self
     }
 
     /// Binds the node service to a different socket.
@@ -227,7 +234,15 @@ where
 
     /// Uses the given [`Keypair`] for the [`PeerId`] instead of a newly generated one.
     pub fn keypair(mut self, keypair: Keypair) -> Self {
-        self.keypair = keypair;
+        self.keypair = Some(keypair);
+        self
+    }
+
+    /// Loads the [`Keypair`] from a [`KeyStore`] if none is given with [`Builder::keypair`].
+    ///
+    /// If the keystore holds no keypair yet a new one is generated and stored.
+    pub fn keystore(mut self, keystore: Arc<dyn KeyStore>) -> Self {
+        self.keystore = Some(keystore);
         self
     }
 
@@ -241,6 +256,38 @@ where
         self
     }
 
+    /// Records transfers in the given [`History`].
+    ///
+    /// By default the history is kept in memory only.
+    pub fn history(mut self, history: History) -> Self {
+        self.history = history;
+        self
+    }
+
+    /// Lets running transfers finish when the node is shut down.
+    ///
+    /// On [`Node::shutdown`] no new connections are accepted, and open connections get up
+    /// to `grace_period` to finish before they are closed.  By default they are closed
+    /// immediately.
+    pub fn shutdown_grace_period(mut self, grace_period: Duration) -> Self {
+        self.shutdown_grace_period = grace_period;
+        self
+    }
+
+    /// Serves the protocols of the given [`ProtocolRegistry`], in addition to iroh-bytes.
+    ///
+    /// Replaces the protocols registered earlier.
+    pub fn protocols(mut self, protocols: ProtocolRegistry) -> Self {
+        self.protocols = protocols;
+        self
+    }
+
+    /// Serves the protocol `alpn` with `handler`, in addition to iroh-bytes.
+    pub fn protocol(mut self, alpn: impl Into<Vec<u8>>, handler: Arc<dyn ProtocolHandler>) -> Self {
+        self.protocols.register(alpn, handler);
+        self
+    }
+
     /// Sets the tokio runtime to use.
     ///
     /// If not set, the current runtime will be picked up.
@@ -257,6 +304,24 @@ where
     pub async fn spawn(self) -> Result<Node<D>> {
         trace!("spawning node");
         let rt = self.rt.context("runtime not set")?;
+        ensure!(
+            self.protocols.get(&iroh_bytes::protocol::ALPN).is_none(),
+            "the iroh-bytes ALPN is served by the node itself"
+        );
+        let alpns = PROTOCOLS
+            .iter()
+            .map(|p| p.to_vec())
+            .chain(self.protocols.alpns().map(|p| p.to_vec()))
+            .collect();
+        let keypair = match (self.keypair, self.keystore) {
+            (Some(keypair), _) => keypair,
+            (None, Some(keystore)) => {
+                tokio::task::spawn_blocking(move || keystore.load_or_generate())
+                    .await?
+                    .context("failed to load keypair from keystore")?
+            }
+            (None, None) => Keypair::generate(),
+        };
 
         let (endpoints_update_s, endpoints_update_r) = flume::bounded(1);
         let mut transport_config = quinn::TransportConfig::default();
@@ -265,12 +330,13 @@ where
             .max_concurrent_uni_streams(0u32.into());
 
         let endpoint = MagicEndpoint::builder()
-            .keypair(self.keypair.clone())
-            .alpns(PROTOCOLS.iter().map(|p| p.to_vec()).collect())
+            .keypair(keypair.clone())
+            .alpns(alpns)
             .keylog(self.keylog)
             .derp_map(self.derp_map)
             .transport_config(transport_config)
             .concurrent_connections(MAX_CONNECTIONS)
+            .close_grace_period(self.shutdown_grace_period)
             .on_endpoints(Box::new(move |eps| {
                 if !endpoints_update_s.is_disconnected() && !eps.is_empty() {
                     endpoints_update_s.send(()).ok();
@@ -292,12 +358,14 @@ where
         let callbacks = Callbacks::default();
         let inner = Arc::new(NodeInner {
             db: self.db,
+            dialer: Dialer::new(endpoint.clone()),
             endpoint: endpoint.clone(),
-            keypair: self.keypair,
+            keypair,
             controller,
             cancel_token,
             callbacks: callbacks.clone(),
             cb_sender,
+            history: self.history,
             rt,
         });
         let task = {
@@ -313,9 +381,9 @@ where
                     handler,
                     self.rpc_endpoint,
                     internal_rpc,
-                    self.custom_get_handler,
-                    self.auth_handler,
+                    self.handlers,
                     self.collection_parser,
+                    self.protocols,
                     rt3,
                 )
                 .await
@@ -345,9 +413,9 @@ where
         handler: RpcHandler<D, C>,
         rpc: E,
         internal_rpc: impl ServiceEndpoint<ProviderService>,
-        custom_get_handler: Arc<dyn CustomGetHandler>,
-        auth_handler: Arc<dyn RequestAuthorizationHandler>,
+        handlers: Handlers,
         collection_parser: C,
+        protocols: ProtocolRegistry,
         rt: runtime::Handle,
     ) {
         let rpc = RpcServer::new(rpc);
@@ -360,6 +428,7 @@ where
             );
         }
         let cancel_token = handler.inner.cancel_token.clone();
+        let protocols = Arc::new(protocols);
 
         loop {
             tokio::select! {
@@ -390,27 +459,34 @@ where
                     }
                 },
                 // handle incoming p2p connections
-                Some(mut connecting) = server.accept() => {
-
-                    let alpn = match get_alpn(&mut connecting).await {
-                        Ok(alpn) => alpn,
-                        Err(err) => {
-                            tracing::error!("invalid handshake: {:?}", err);
-                            continue;
+                Some(connecting) = server.accept() => {
+                    // Accepting the connection through the endpoint tracks it, so the
+                    // shutdown grace period waits for it to be closed.
+                    let server = server.clone();
+                    let db = handler.inner.db.clone();
+                    let handlers = handlers.clone();
+                    let collection_parser = collection_parser.clone();
+                    let protocols = protocols.clone();
+                    let rt2 = rt.clone();
+                    let callbacks = callbacks.clone();
+                    rt.main().spawn(async move {
+                        let (_, alpn, conn) = match server.accept_conn(connecting).await {
+                            Ok(res) => res,
+                            Err(err) => {
+                                tracing::error!("invalid handshake: {:?}", err);
+                                return;
+                            }
+                        };
+                        if alpn.as_bytes() == iroh_bytes::protocol::ALPN.as_ref() {
+                            iroh_bytes::provider::handle_connection(conn, db, callbacks, collection_parser, handlers, rt2).await
+                        } else if let Some(handler) = protocols.get(alpn.as_bytes()) {
+                            if let Err(err) = handler.accept(conn).await {
+                                tracing::warn!("{alpn} connection failed: {err:#}");
+                            }
+                        } else {
+                            tracing::error!("unknown protocol: {}", alpn);
                         }
-                    };
-                    if alpn.as_bytes() == iroh_bytes::protocol::ALPN.as_ref() {
-                        let db = handler.inner.db.clone();
-                        let custom_get_handler = custom_get_handler.clone();
-                        let auth_handler = auth_handler.clone();
-                        let collection_parser = collection_parser.clone();
-                        let rt2 = rt.clone();
-                        let callbacks = callbacks.clone();
-                        rt.main().spawn(iroh_bytes::provider::handle_connection(connecting, db, callbacks, collection_parser, custom_get_handler, auth_handler, rt2));
-                    } else {
-                        tracing::error!("unknown protocol: {}", alpn);
-                        continue;
-                    }
+                    });
                 }
                 // Handle new callbacks
                 Some(cb) = cb_receiver.recv() => {
@@ -421,9 +497,8 @@ where
         }
 
         // Closing the Endpoint is the equivalent of calling Connection::close on all
-        // connections: Operations will immediately fail with
-        // ConnectionError::LocallyClosed.  All streams are interrupted, this is not
-        // graceful.
+        // connections, once the shutdown grace period is over: Operations will fail with
+        // ConnectionError::LocallyClosed.  All streams still open are interrupted.
         let error_code = Closed::ProviderTerminating;
         server
             .close(error_code.into(), error_code.reason())
@@ -432,14 +507,45 @@ where
     }
 }
 
-async fn get_alpn(connecting: &mut quinn::Connecting) -> Result<String> {
-    let data = connecting.handshake_data().await?;
-    match data.downcast::<quinn::crypto::rustls::HandshakeData>() {
-        Ok(data) => match data.protocol {
-            Some(protocol) => std::string::String::from_utf8(protocol).map_err(Into::into),
-            None => anyhow::bail!("no ALPN protocol available"),
-        },
-        Err(_) => anyhow::bail!("unknown handshake type"),
+/// Handles incoming connections of a protocol served by the [`Node`].
+///
+/// Register handlers for their ALPN in a [`ProtocolRegistry`].
+pub trait ProtocolHandler: Debug + Send + Sync + 'static {
+    /// Handles an incoming connection, once the handshake completed.
+    ///
+    /// The returned future is spawned as a new task, an error is logged.
+    fn accept(&self, conn: quinn::Connection) -> BoxFuture<'static, Result<()>>;
+}
+
+/// The protocols served by a [`Node`] besides iroh-bytes, by their ALPN.
+///
+/// The node's accept loop routes each incoming connection to the [`ProtocolHandler`]
+/// registered for the ALPN it negotiated.
+#[derive(Debug, Clone, Default)]
+pub struct ProtocolRegistry {
+    handlers: BTreeMap<Vec<u8>, Arc<dyn ProtocolHandler>>,
+}
+
+impl ProtocolRegistry {
+    /// Registers `handler` for the protocol `alpn`.
+    ///
+    /// Returns the handler previously registered for `alpn`, if any.
+    pub fn register(
+        &mut self,
+        alpn: impl Into<Vec<u8>>,
+        handler: Arc<dyn ProtocolHandler>,
+    ) -> Option<Arc<dyn ProtocolHandler>> {
+        self.handlers.insert(alpn.into(), handler)
+    }
+
+    /// Returns the handler registered for the protocol `alpn`.
+    pub fn get(&self, alpn: &[u8]) -> Option<&Arc<dyn ProtocolHandler>> {
+        self.handlers.get(alpn)
+    }
+
+    /// Returns the ALPNs of the registered protocols.
+    pub fn alpns(&self) -> impl Iterator<Item = &[u8]> {
+        self.handlers.keys().map(|alpn| alpn.as_slice())
     }
 }
 
@@ -494,6 +600,7 @@ pub struct Node<D: BaoMap> {
 struct NodeInner<D> {
     db: D,
     endpoint: MagicEndpoint,
+    dialer: Dialer,
     keypair: Keypair,
     cancel_token: CancellationToken,
     controller: FlumeConnection<ProviderResponse, ProviderRequest>,
@@ -501,6 +608,7 @@ struct NodeInner<D> {
     cb_sender: mpsc::Sender<Box<dyn Fn(Event) -> BoxFuture<'static, ()> + Send + Sync + 'static>>,
     #[allow(dead_code)]
     callbacks: Callbacks,
+    history: History,
     rt: runtime::Handle,
 }
 
@@ -577,17 +685,38 @@ impl<D: BaoReadonlyDb> Node<D> {
         self.inner.endpoint.my_derp().await
     }
 
-    /// Aborts the node.
+    /// Shuts down the node.
     ///
-    /// This does not gracefully terminate currently: all connections are closed and
-    /// anything in-transit is lost.  The task will stop running and awaiting this
-    /// [`Node`] will complete.
-    ///
-    /// The shutdown behaviour will become more graceful in the future.
+    /// No new connections are accepted.  Open connections are closed once the
+    /// [`Builder::shutdown_grace_period`] is over, by default immediately, and anything
+    /// in-transit is lost.  The task will stop running and awaiting this [`Node`] will
+    /// complete.
     pub fn shutdown(&self) {
         self.inner.cancel_token.cancel();
     }
 
+    /// Returns the [`MagicEndpoint`] of the node.
+    ///
+    /// Connections to protocols other than the ones served by the node are rejected.
+    pub fn endpoint(&self) -> &MagicEndpoint {
+        &self.inner.endpoint
+    }
+
+    /// Returns the [`Dialer`] of the node, to share connections to other peers.
+    pub fn dialer(&self) -> &Dialer {
+        &self.inner.dialer
+    }
+
+    /// Returns the database of the node.
+    pub fn db(&self) -> &D {
+        &self.inner.db
+    }
+
+    /// Returns the [`History`] of the transfers of this node.
+    pub fn history(&self) -> &History {
+        &self.inner.history
+    }
+
     /// Returns a token that can be used to cancel the node.
     pub fn cancel_token(&self) -> CancellationToken {
         self.inner.cancel_token.clone()
@@ -703,19 +832,39 @@ impl<D: BaoMap + BaoReadonlyDb, C: CollectionParser> RpcHandler<D, C> {
         let (tx, rx) = mpsc::channel(1);
         let tx2 = tx.clone();
         self.rt().local_pool().spawn_pinned(|| async move {
-            if let Err(e) = self.provide0(msg, tx).await {
-                tx2.send(ProvideProgress::Abort(e.into())).await.unwrap();
+            let history = self.inner.history.clone();
+            let op = Operation::Add {
+                path: msg.path.clone(),
+            };
+            let id = history
+                .start(op)
+                .await
+                .map_err(|err| tracing::warn!("failed to record add in history: {err:#}"))
+                .ok();
+            let (outcome, bytes) = match self.provide0(msg, tx).await {
+                Ok((hash, bytes)) => (Outcome::Done { hash }, bytes),
+                Err(e) => {
+                    let reason = format!("{e:#}");
+                    tx2.send(ProvideProgress::Abort(e.into())).await.unwrap();
+                    (Outcome::Failed { reason }, 0)
+                }
+            };
+            if let Some(id) = id {
+                if let Err(err) = history.finish(id, outcome, bytes).await {
+                    tracing::warn!("failed to record add in history: {err:#}");
+                }
             }
         });
         tokio_stream::wrappers::ReceiverStream::new(rx)
     }
 
+    /// Adds the data at the requested path, returning its hash and size.
     #[cfg(feature = "flat-db")]
     async fn provide0(
         self,
         msg: ProvideRequest,
         progress: tokio::sync::mpsc::Sender<ProvideProgress>,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<(Hash, u64)> {
         use crate::database::flat::{create_collection_inner, create_data_sources, Database};
         use crate::util::progress::Progress;
         use std::any::Any;
@@ -728,12 +877,16 @@ impl<D: BaoMap + BaoReadonlyDb, C: CollectionParser> RpcHandler<D, C> {
         // create the collection
         // todo: provide feedback for progress
         let (db, hash) = create_collection_inner(data_sources, Progress::new(progress)).await?;
+        let mut bytes = 0;
+        for entry in db.values().filter(|entry| entry.is_external()) {
+            bytes += entry.size().await;
+        }
 
         // todo: generify this
         // for now provide will only work if D is a Database
         let boxed_db: Box<dyn Any> = Box::new(self.inner.db.clone());
         if let Some(current) = boxed_db.downcast_ref::<Database>().cloned() {
-            current.union_with(db);
+            current.insert(db)?;
         } else {
             anyhow::bail!("provide not supported yet for this database type");
         }
@@ -745,7 +898,7 @@ impl<D: BaoMap + BaoReadonlyDb, C: CollectionParser> RpcHandler<D, C> {
             ))
             .await;
 
-        Ok(())
+        Ok((hash, bytes))
     }
 
     #[cfg(not(feature = "flat-db"))]
@@ -753,10 +906,62 @@ impl<D: BaoMap + BaoReadonlyDb, C: CollectionParser> RpcHandler<D, C> {
         self,
         _msg: ProvideRequest,
         _progress: tokio::sync::mpsc::Sender<ProvideProgress>,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<(Hash, u64)> {
         anyhow::bail!("provide not supported yet for this database type");
     }
 
+    /// The database of the node, if it supports tags.
+    #[cfg(feature = "flat-db")]
+    fn tag_db(&self) -> anyhow::Result<crate::database::flat::Database> {
+        use std::any::Any;
+        let boxed_db: Box<dyn Any> = Box::new(self.inner.db.clone());
+        boxed_db
+            .downcast_ref::<crate::database::flat::Database>()
+            .cloned()
+            .context("tags not supported for this database type")
+    }
+
+    #[cfg(feature = "flat-db")]
+    async fn tag_set(self, msg: TagSetRequest) -> RpcResult<TagSetResponse> {
+        let previous = self.tag_db()?.tag(msg.name, msg.hash, msg.expires)?;
+        Ok(TagSetResponse {
+            previous: previous.map(|tag| tag.hash),
+        })
+    }
+
+    #[cfg(feature = "flat-db")]
+    async fn tag_remove(self, msg: TagRemoveRequest) -> RpcResult<TagRemoveResponse> {
+        let removed = self.tag_db()?.untag(&msg.name);
+        Ok(TagRemoveResponse {
+            removed: removed.map(|tag| tag.hash),
+        })
+    }
+
+    #[cfg(feature = "flat-db")]
+    fn tag_list(self, _msg: TagListRequest) -> impl Stream<Item = TagListResponse> {
+        let tags = self.tag_db().map(|db| db.tags()).unwrap_or_default();
+        futures::stream::iter(tags).map(|(name, tag)| TagListResponse {
+            name,
+            hash: tag.hash,
+            expires: tag.expires,
+        })
+    }
+
+    #[cfg(not(feature = "flat-db"))]
+    async fn tag_set(self, _msg: TagSetRequest) -> RpcResult<TagSetResponse> {
+        Err(anyhow::anyhow!("tags not supported for this database type").into())
+    }
+
+    #[cfg(not(feature = "flat-db"))]
+    async fn tag_remove(self, _msg: TagRemoveRequest) -> RpcResult<TagRemoveResponse> {
+        Err(anyhow::anyhow!("tags not supported for this database type").into())
+    }
+
+    #[cfg(not(feature = "flat-db"))]
+    fn tag_list(self, _msg: TagListRequest) -> impl Stream<Item = TagListResponse> {
+        futures::stream::empty()
+    }
+
     async fn version(self, _: VersionRequest) -> VersionResponse {
         VersionResponse {
             version: env!("CARGO_PKG_VERSION").to_string(),
@@ -770,6 +975,7 @@ impl<D: BaoMap + BaoReadonlyDb, C: CollectionParser> RpcHandler<D, C> {
                 .local_endpoint_addresses()
                 .await
                 .unwrap_or_default(),
+            derp_region: self.inner.endpoint.my_derp().await,
             version: env!("CARGO_PKG_VERSION").to_string(),
         }
     }
@@ -792,6 +998,65 @@ impl<D: BaoMap + BaoReadonlyDb, C: CollectionParser> RpcHandler<D, C> {
             self.inner.cancel_token.cancel();
         }
     }
+    fn peers(self, _: PeersRequest) -> impl Stream<Item = PeersResponse> + Send + 'static {
+        async move {
+            let infos = self
+                .inner
+                .endpoint
+                .connection_infos()
+                .await
+                .unwrap_or_default();
+            futures::stream::iter(infos).map(|info| {
+                let (direct_addr, derp_region) = match info.path {
+                    Some(ConnectionPath::Direct(addr)) => (Some(addr), None),
+                    Some(ConnectionPath::Derp(region)) => (None, Some(region)),
+                    None => (None, None),
+                };
+                PeersResponse {
+                    peer_id: Box::new(info.peer_id),
+                    direct_addr,
+                    derp_region,
+                    rtt: info.rtt,
+                    last_active: info.last_active.elapsed(),
+                }
+            })
+        }
+        .flatten_stream()
+    }
+    fn blob_read(
+        self,
+        msg: BlobReadRequest,
+    ) -> impl Stream<Item = RpcResult<BlobReadResponse>> + Send + 'static {
+        let (tx, rx) = mpsc::channel(2);
+        let db = self.inner.db.clone();
+        self.rt().local_pool().spawn_pinned(move || async move {
+            if let Err(e) = read_blob(db, msg.hash, tx.clone()).await {
+                tx.send(Err(e.into())).await.ok();
+            }
+        });
+        tokio_stream::wrappers::ReceiverStream::new(rx)
+    }
+    fn history_list(self, _: HistoryListRequest) -> impl Stream<Item = HistoryListResponse> {
+        let records = self.inner.history.records();
+        futures::stream::iter(records).map(|record| HistoryListResponse { record })
+    }
+    async fn history_get(self, msg: HistoryGetRequest) -> HistoryGetResponse {
+        HistoryGetResponse {
+            record: self.inner.history.get(msg.id),
+        }
+    }
+    async fn history_start(self, msg: HistoryStartRequest) -> RpcResult<HistoryStartResponse> {
+        let id = self.inner.history.start(msg.op).await?;
+        Ok(HistoryStartResponse { id })
+    }
+    async fn history_finish(self, msg: HistoryFinishRequest) -> RpcResult<HistoryGetResponse> {
+        let record = self
+            .inner
+            .history
+            .finish(msg.id, msg.outcome, msg.bytes)
+            .await?;
+        Ok(HistoryGetResponse { record })
+    }
     fn watch(self, _: WatchRequest) -> impl Stream<Item = WatchResponse> {
         futures::stream::unfold((), |()| async move {
             tokio::time::sleep(HEALTH_POLL_WAIT).await;
@@ -840,10 +1105,51 @@ fn handle_rpc_request<
                 chan.server_streaming(msg, handler, RpcHandler::validate)
                     .await
             }
+            HistoryList(msg) => {
+                chan.server_streaming(msg, handler, RpcHandler::history_list)
+                    .await
+            }
+            HistoryGet(msg) => chan.rpc(msg, handler, RpcHandler::history_get).await,
+            HistoryStart(msg) => chan.rpc(msg, handler, RpcHandler::history_start).await,
+            HistoryFinish(msg) => chan.rpc(msg, handler, RpcHandler::history_finish).await,
+            TagSet(msg) => chan.rpc(msg, handler, RpcHandler::tag_set).await,
+            TagRemove(msg) => chan.rpc(msg, handler, RpcHandler::tag_remove).await,
+            TagList(msg) => {
+                chan.server_streaming(msg, handler, RpcHandler::tag_list)
+                    .await
+            }
+            BlobRead(msg) => {
+                chan.server_streaming(msg, handler, RpcHandler::blob_read)
+                    .await
+            }
+            Peers(msg) => chan.server_streaming(msg, handler, RpcHandler::peers).await,
         }
     });
 }
 
+/// Sends the content of the blob `hash` in chunks of [`BLOB_READ_CHUNK_SIZE`].
+///
+/// Stops early without an error if the receiver is dropped.
+async fn read_blob<D: BaoMap>(
+    db: D,
+    hash: Hash,
+    tx: mpsc::Sender<RpcResult<BlobReadResponse>>,
+) -> Result<()> {
+    let entry = db.get(&hash).context("blob not found")?;
+    let mut reader = entry.data_reader().await?;
+    let size = reader.len().await?;
+    let mut offset = 0;
+    while offset < size {
+        let data = reader.read_at(offset, BLOB_READ_CHUNK_SIZE).await?;
+        ensure!(!data.is_empty(), "blob is shorter than its size");
+        offset += data.len() as u64;
+        if tx.send(Ok(BlobReadResponse { data })).await.is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
 /// Create a [`quinn::ServerConfig`] with the given keypair and limits.
 pub fn make_server_config(
     keypair: &Keypair,
@@ -920,7 +1226,7 @@ impl RequestAuthorizationHandler for StaticTokenAuthHandler {
 #[cfg(all(test, feature = "flat-db"))]
 mod tests {
     use anyhow::bail;
-    use futures::StreamExt;
+    use futures::{StreamExt, TryStreamExt};
     use std::collections::HashMap;
     use std::net::Ipv4Addr;
     use std::path::Path;
@@ -950,6 +1256,162 @@ mod tests {
         assert!(!ticket.addrs().is_empty());
     }
 
+    #[tokio::test]
+    async fn test_node_graceful_shutdown() -> Result<()> {
+        let rt = test_runtime();
+        let (db, _) = crate::database::mem::Database::new([("test", b"hello")]);
+        let node = Node::builder(db)
+            .bind_addr((Ipv4Addr::UNSPECIFIED, 0).into())
+            .shutdown_grace_period(Duration::from_secs(10))
+            .runtime(&rt)
+            .spawn()
+            .await?;
+        assert_eq!(node.endpoint().peer_id(), node.peer_id());
+        assert_eq!(node.dialer().endpoint().peer_id(), node.peer_id());
+
+        // Without open connections the node does not wait for the grace period.
+        node.shutdown();
+        tokio::time::timeout(Duration::from_secs(5), node)
+            .await
+            .context("shutdown timed out")??;
+        Ok(())
+    }
+
+    #[cfg(feature = "file-keystore")]
+    #[tokio::test]
+    async fn test_node_keystore() -> Result<()> {
+        let rt = test_runtime();
+        let dir = tempfile::tempdir()?;
+        let keystore: Arc<dyn KeyStore> = Arc::new(
+            crate::keystore::file::EncryptedFileKeyStore::new(dir.path().join("keypair"), "pw")
+                .work_factor(4),
+        );
+        let mut peer_ids = Vec::new();
+        for _ in 0..2 {
+            let (db, _) = crate::database::mem::Database::new([("test", b"hello")]);
+            let node = Node::builder(db)
+                .bind_addr((Ipv4Addr::UNSPECIFIED, 0).into())
+                .keystore(keystore.clone())
+                .runtime(&rt)
+                .spawn()
+                .await?;
+            peer_ids.push(node.peer_id());
+            node.shutdown();
+            node.await?;
+        }
+        assert_eq!(peer_ids[0], peer_ids[1]);
+        let stored = keystore.load()?.context("keypair stored")?;
+        assert_eq!(PeerId::from(stored.public()), peer_ids[0]);
+        Ok(())
+    }
+
+    const ECHO_ALPN: &[u8] = b"n0/test-echo/0";
+
+    #[derive(Debug)]
+    struct Echo;
+
+    impl ProtocolHandler for Echo {
+        fn accept(&self, conn: quinn::Connection) -> BoxFuture<'static, Result<()>> {
+            async move {
+                let (mut send, mut recv) = conn.accept_bi().await?;
+                let msg = recv.read_to_end(1024).await?;
+                send.write_all(&msg).await?;
+                send.finish().await?;
+                Ok(())
+            }
+            .boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_node_protocol_registry() -> Result<()> {
+        let rt = test_runtime();
+        let (db, _) = crate::database::mem::Database::new([("test", b"hello")]);
+        let node = Node::builder(db)
+            .bind_addr((Ipv4Addr::UNSPECIFIED, 0).into())
+            .protocol(ECHO_ALPN, Arc::new(Echo))
+            .runtime(&rt)
+            .spawn()
+            .await?;
+        let _drop_guard = node.cancel_token().drop_guard();
+
+        let client = MagicEndpoint::builder().bind(0).await?;
+        let addrs = node.local_endpoint_addresses().await?;
+        let conn = client
+            .connect(node.peer_id(), ECHO_ALPN, None, &addrs)
+            .await?;
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(b"hello").await?;
+        send.finish().await?;
+        assert_eq!(recv.read_to_end(1024).await?, b"hello");
+
+        // The node serves iroh-bytes itself.
+        let (db, _) = crate::database::mem::Database::new([("test", b"hello")]);
+        let res = Node::builder(db)
+            .protocol(iroh_bytes::protocol::ALPN, Arc::new(Echo))
+            .runtime(&rt)
+            .spawn()
+            .await;
+        assert!(res.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_node_rpc_blob_read_and_peers() -> Result<()> {
+        let rt = test_runtime();
+        let content = vec![7u8; 3 * BLOB_READ_CHUNK_SIZE / 2];
+        let (db, hashes) = crate::database::mem::Database::new([("test", content.clone())]);
+        let node = Node::builder(db)
+            .bind_addr((Ipv4Addr::UNSPECIFIED, 0).into())
+            .protocol(ECHO_ALPN, Arc::new(Echo))
+            .runtime(&rt)
+            .spawn()
+            .await?;
+        let _drop_guard = node.cancel_token().drop_guard();
+        let controller = node.controller();
+
+        let hash = hashes["test"].into();
+        let mut stream = controller
+            .server_streaming(BlobReadRequest { hash })
+            .await?;
+        let mut data = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            data.extend_from_slice(&chunk??.data);
+        }
+        assert_eq!(data, content);
+
+        let missing = Hash::new(b"missing");
+        let mut stream = controller
+            .server_streaming(BlobReadRequest { hash: missing })
+            .await?;
+        assert!(stream.next().await.context("no response")??.is_err());
+
+        let peer = MagicEndpoint::builder()
+            .alpns(vec![ECHO_ALPN.to_vec()])
+            .bind(0)
+            .await?;
+        let accept = tokio::spawn({
+            let peer = peer.clone();
+            async move {
+                let connecting = peer.accept().await.context("no conn")?;
+                anyhow::Ok(connecting.await?)
+            }
+        });
+        let addr = (Ipv4Addr::LOCALHOST, peer.local_addr()?.0.port()).into();
+        let _conn = node
+            .endpoint()
+            .connect(peer.peer_id(), ECHO_ALPN, None, &[addr])
+            .await?;
+        let _peer_conn = accept.await??;
+        let peers: Vec<_> = controller
+            .server_streaming(PeersRequest)
+            .await?
+            .try_collect()
+            .await?;
+        assert!(peers.iter().any(|p| *p.peer_id == peer.peer_id()));
+        Ok(())
+    }
+
     #[cfg(feature = "flat-db")]
     #[tokio::test]
     async fn test_node_add_collection_event() -> Result<()> {
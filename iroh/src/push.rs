@@ -0,0 +1,226 @@
+//! Push blobs to peers, and receive blobs pushed by peers into a [`Database`].
+//!
+//! A node that has a blob can send it to a peer with [`push`], without the peer requesting
+//! it, e.g. to replicate content to backup nodes or mirrors. The peer only accepts pushes
+//! if it was configured with a [`PushHandler`], such as a [`PushReceiver`] adding the
+//! pushed blobs to its database. Push requests pass through the
+//! [`RequestAuthorizationHandler`](iroh_bytes::provider::RequestAuthorizationHandler) of
+//! the receiving node like any other request, so a node accepting pushes should be
+//! configured with an authorization handler to avoid storing content of arbitrary peers.
+//!
+//! A [`Seeder`] pushes content to several peers until it is stored on a given number of
+//! them.
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use iroh_bytes::protocol::{PushRequest, RequestToken};
+use iroh_bytes::provider::PushHandler;
+use iroh_bytes::push::PushOutcome;
+use iroh_bytes::Hash;
+use iroh_net::tls::PeerId;
+use tracing::debug;
+
+use crate::database::flat::Database;
+use crate::dial;
+
+/// Push the blob `hash` from `db` to the peer described by `opts`.
+///
+/// `token` is the request token for the peer.
+pub async fn push(
+    db: &Database,
+    hash: Hash,
+    opts: dial::Options,
+    token: Option<RequestToken>,
+) -> Result<PushOutcome> {
+    let connection = dial::dial(opts).await?;
+    let request = PushRequest::new(hash).with_token(token);
+    iroh_bytes::push::push(&connection, db, request).await
+}
+
+/// A [`PushHandler`] adding all pushed blobs to a [`Database`].
+///
+/// Pushed blobs are stored in files inside `dir`, named after their hash, like the blobs
+/// imported with [`Database::import_reader`].
+#[derive(Debug, Clone)]
+pub struct PushReceiver {
+    db: Database,
+    dir: PathBuf,
+}
+
+impl PushReceiver {
+    /// Creates a new handler adding pushed blobs to `db`.
+    ///
+    /// The blobs are stored in files inside `dir`, which is created if needed.
+    pub fn new(db: Database, dir: PathBuf) -> Self {
+        Self { db, dir }
+    }
+}
+
+impl PushHandler for PushReceiver {
+    fn accept(&self, _peer: PeerId, hash: Hash) -> BoxFuture<'static, Result<PathBuf>> {
+        let dir = self.dir.clone();
+        async move {
+            tokio::fs::create_dir_all(&dir)
+                .await
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+            // concurrent pushes of the same blob each get their own file
+            let name = format!("{}.{:016x}.part", hash.to_hex(), rand::random::<u64>());
+            Ok(dir.join(name))
+        }
+        .boxed()
+    }
+
+    fn stored(
+        &self,
+        peer: PeerId,
+        hash: Hash,
+        part_path: PathBuf,
+    ) -> BoxFuture<'static, Result<()>> {
+        let this = self.clone();
+        async move {
+            if this.db.get(&hash).is_some() {
+                tokio::fs::remove_file(&part_path).await?;
+                return Ok(());
+            }
+            let path = this.dir.join(hash.to_hex());
+            tokio::fs::rename(&part_path, &path).await?;
+            let imported = this.db.import_file(path).await?;
+            anyhow::ensure!(imported == hash, "hash mismatch for pushed blob {hash}");
+            debug!(%hash, %peer, "stored pushed blob");
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+/// The result of [`Seeder::seed`].
+#[derive(Debug, Clone, Default)]
+pub struct SeedReport {
+    /// The peers storing the content, including peers that already stored it earlier.
+    pub replicas: Vec<PeerId>,
+    /// The peers the content could not be pushed to, with the error of the last attempt.
+    pub failed: Vec<(PeerId, String)>,
+}
+
+/// Pushes content to several peers, until it is stored on enough of them.
+///
+/// The seeder remembers which peers stored which content, so seeding the same content
+/// again only pushes to further peers if the content has fewer replicas than requested.
+#[derive(Debug, Clone)]
+pub struct Seeder {
+    db: Database,
+    token: Option<RequestToken>,
+    retries: usize,
+    retry_delay: Duration,
+    /// The peers known to store a hash.
+    replicas: Arc<Mutex<HashMap<Hash, Vec<PeerId>>>>,
+}
+
+impl Seeder {
+    /// Creates a seeder pushing content from `db`.
+    pub fn new(db: Database) -> Self {
+        Self {
+            db,
+            token: None,
+            retries: 2,
+            retry_delay: Duration::from_secs(1),
+            replicas: Default::default(),
+        }
+    }
+
+    /// Sets the request token sent to the peers.
+    pub fn token(mut self, token: Option<RequestToken>) -> Self {
+        self.token = token;
+        self
+    }
+
+    /// Sets how often a failed push to a peer is retried, 2 by default.
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Sets how long to wait before retrying failed pushes, 1 second by default.
+    pub fn retry_delay(mut self, retry_delay: Duration) -> Self {
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// The peers known to store `hash`.
+    pub fn replicas(&self, hash: &Hash) -> Vec<PeerId> {
+        let replicas = self.replicas.lock().unwrap();
+        replicas.get(hash).cloned().unwrap_or_default()
+    }
+
+    /// Pushes `hash` to `targets` until it is stored on `replicas` peers.
+    ///
+    /// The targets are tried in order, pushing to as many of them at once as replicas are
+    /// missing. Peers that fail are retried after the others, so the content ends up on
+    /// the first targets that accept it. Peers already known to store the content are
+    /// skipped. Fails if the content is not in the database, but not if fewer replicas
+    /// than requested were reached, check the returned report for that.
+    pub async fn seed(
+        &self,
+        hash: Hash,
+        targets: impl IntoIterator<Item = dial::Options>,
+        replicas: usize,
+    ) -> Result<SeedReport> {
+        anyhow::ensure!(self.db.get(&hash).is_some(), "blob {hash} not found");
+        let mut report = SeedReport {
+            replicas: self.replicas(&hash),
+            failed: Vec::new(),
+        };
+        let mut pending = targets
+            .into_iter()
+            .filter(|opts| !report.replicas.contains(&opts.peer_id))
+            .map(|opts| (opts, 0))
+            .collect::<VecDeque<_>>();
+        while report.replicas.len() < replicas && !pending.is_empty() {
+            let missing = replicas - report.replicas.len();
+            let batch = pending
+                .drain(..missing.min(pending.len()))
+                .collect::<Vec<_>>();
+            let results = futures::future::join_all(batch.into_iter().map(|(opts, attempts)| {
+                let peer_id = opts.peer_id;
+                let token = self.token.clone();
+                async move {
+                    let res = push(&self.db, hash, opts.clone(), token).await;
+                    (opts, attempts, peer_id, res)
+                }
+            }))
+            .await;
+            let mut retry = false;
+            for (opts, attempts, peer_id, res) in results {
+                match res {
+                    Ok(outcome) => {
+                        debug!(%hash, %peer_id, ?outcome, "seeded blob");
+                        let mut known = self.replicas.lock().unwrap();
+                        let peers = known.entry(hash).or_default();
+                        if !peers.contains(&peer_id) {
+                            peers.push(peer_id);
+                        }
+                        report.replicas.push(peer_id);
+                    }
+                    Err(err) if attempts < self.retries => {
+                        debug!(%hash, %peer_id, "failed to seed blob, retrying: {err:#}");
+                        pending.push_back((opts, attempts + 1));
+                        retry = true;
+                    }
+                    Err(err) => {
+                        debug!(%hash, %peer_id, "failed to seed blob: {err:#}");
+                        report.failed.push((peer_id, format!("{err:#}")));
+                    }
+                }
+            }
+            if retry && report.replicas.len() < replicas {
+                tokio::time::sleep(self.retry_delay).await;
+            }
+        }
+        Ok(report)
+    }
+}
@@ -7,12 +7,19 @@
 //! response, while others like provide have a stream of responses.
 //!
 //! Note that this is subject to change. The RPC protocol is not yet stable.
-use std::{net::SocketAddr, path::PathBuf};
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
 
+use bytes::Bytes;
 use derive_more::{From, TryInto};
-use iroh_bytes::Hash;
+use iroh_bytes::{util::RpcResult, Hash};
 use iroh_net::tls::PeerId;
 
+use crate::history::{Operation, Outcome, Record};
+
 use quic_rpc::{
     message::{Msg, RpcMsg, ServerStreaming, ServerStreamingMsg},
     Service,
@@ -54,6 +61,30 @@ impl ServerStreamingMsg<ProviderService> for ValidateRequest {
     type Response = ValidateProgress;
 }
 
+/// A request to read the content of a blob
+///
+/// Will produce a stream of [`BlobReadResponse`] chunks, in order.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlobReadRequest {
+    /// The hash of the blob to read
+    pub hash: Hash,
+}
+
+/// A chunk of the content of a blob
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlobReadResponse {
+    /// The data of the chunk
+    pub data: Bytes,
+}
+
+impl Msg<ProviderService> for BlobReadRequest {
+    type Pattern = ServerStreaming;
+}
+
+impl ServerStreamingMsg<ProviderService> for BlobReadRequest {
+    type Response = RpcResult<BlobReadResponse>;
+}
+
 /// List all blobs, including collections
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ListBlobsRequest;
@@ -106,6 +137,141 @@ impl ServerStreamingMsg<ProviderService> for ListCollectionsRequest {
     type Response = ListCollectionsResponse;
 }
 
+/// Tag a hash, keeping it from being evicted until the tag expires
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TagSetRequest {
+    /// The name of the tag, replacing an existing tag with the same name
+    pub name: String,
+    /// The hash to tag
+    pub hash: Hash,
+    /// When the tag expires, `None` to keep it until it is removed
+    pub expires: Option<SystemTime>,
+}
+
+/// A response to a tag set request
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TagSetResponse {
+    /// The hash that was tagged with this name before, if any
+    pub previous: Option<Hash>,
+}
+
+impl RpcMsg<ProviderService> for TagSetRequest {
+    type Response = RpcResult<TagSetResponse>;
+}
+
+/// Remove a tag
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TagRemoveRequest {
+    /// The name of the tag
+    pub name: String,
+}
+
+/// A response to a tag remove request
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TagRemoveResponse {
+    /// The hash that was tagged, or `None` if there was no tag with this name
+    pub removed: Option<Hash>,
+}
+
+impl RpcMsg<ProviderService> for TagRemoveRequest {
+    type Response = RpcResult<TagRemoveResponse>;
+}
+
+/// List all tags that have not expired
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TagListRequest;
+
+/// A response to a tag list request
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TagListResponse {
+    /// The name of the tag
+    pub name: String,
+    /// The tagged hash
+    pub hash: Hash,
+    /// When the tag expires
+    pub expires: Option<SystemTime>,
+}
+
+impl Msg<ProviderService> for TagListRequest {
+    type Pattern = ServerStreaming;
+}
+
+impl ServerStreamingMsg<ProviderService> for TagListRequest {
+    type Response = TagListResponse;
+}
+
+/// List the transfers in the history of the node
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryListRequest;
+
+/// A response to a history list request
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryListResponse {
+    /// A transfer in the history
+    pub record: Record,
+}
+
+impl Msg<ProviderService> for HistoryListRequest {
+    type Pattern = ServerStreaming;
+}
+
+impl ServerStreamingMsg<ProviderService> for HistoryListRequest {
+    type Response = HistoryListResponse;
+}
+
+/// A request to get a single transfer from the history of the node
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryGetRequest {
+    /// The id of the record
+    pub id: u64,
+}
+
+/// A response to a history get or finish request
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryGetResponse {
+    /// The record, or `None` if there is no record with the requested id
+    pub record: Option<Record>,
+}
+
+impl RpcMsg<ProviderService> for HistoryGetRequest {
+    type Response = HistoryGetResponse;
+}
+
+/// A request to record the start of a transfer in the history of the node
+///
+/// This is used by clients that run transfers outside of the node, such as gets.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryStartRequest {
+    /// The transfer that was started
+    pub op: Operation,
+}
+
+/// A response to a history start request
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryStartResponse {
+    /// The id of the new record
+    pub id: u64,
+}
+
+impl RpcMsg<ProviderService> for HistoryStartRequest {
+    type Response = RpcResult<HistoryStartResponse>;
+}
+
+/// A request to record how a transfer started with [`HistoryStartRequest`] ended
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryFinishRequest {
+    /// The id of the record
+    pub id: u64,
+    /// How the transfer ended
+    pub outcome: Outcome,
+    /// Number of bytes transferred
+    pub bytes: u64,
+}
+
+impl RpcMsg<ProviderService> for HistoryFinishRequest {
+    type Response = RpcResult<HistoryGetResponse>;
+}
+
 /// A request to watch for the node status
 #[derive(Serialize, Deserialize, Debug)]
 pub struct WatchRequest;
@@ -147,6 +313,35 @@ impl RpcMsg<ProviderService> for AddrsRequest {
     type Response = AddrsResponse;
 }
 
+/// A request to list the peers the node is connected to
+///
+/// Will produce a stream of [`PeersResponse`] messages, one per peer.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PeersRequest;
+
+impl Msg<ProviderService> for PeersRequest {
+    type Pattern = ServerStreaming;
+}
+
+impl ServerStreamingMsg<ProviderService> for PeersRequest {
+    type Response = PeersResponse;
+}
+
+/// The response to a peers request
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PeersResponse {
+    /// The peer id of the peer
+    pub peer_id: Box<PeerId>,
+    /// The address the peer is reached at directly, if any
+    pub direct_addr: Option<SocketAddr>,
+    /// The DERP region the peer is reached through, if it is not reached directly
+    pub derp_region: Option<u16>,
+    /// The average round trip time to the peer
+    pub rtt: Option<Duration>,
+    /// How long ago data was last sent to the peer
+    pub last_active: Duration,
+}
+
 /// The response to a watch request
 #[derive(Serialize, Deserialize, Debug)]
 pub struct WatchResponse {
@@ -161,6 +356,8 @@ pub struct IdResponse {
     pub peer_id: Box<PeerId>,
     /// The addresses of the node
     pub listen_addrs: Vec<SocketAddr>,
+    /// The DERP region the node is connected to
+    pub derp_region: Option<u16>,
     /// The version of the node
     pub version: String,
 }
@@ -204,6 +401,15 @@ pub enum ProviderRequest {
     Addrs(AddrsRequest),
     Shutdown(ShutdownRequest),
     Validate(ValidateRequest),
+    HistoryList(HistoryListRequest),
+    HistoryGet(HistoryGetRequest),
+    HistoryStart(HistoryStartRequest),
+    HistoryFinish(HistoryFinishRequest),
+    TagSet(TagSetRequest),
+    TagRemove(TagRemoveRequest),
+    TagList(TagListRequest),
+    BlobRead(BlobReadRequest),
+    Peers(PeersRequest),
 }
 
 /// The response enum, listing all possible responses.
@@ -219,6 +425,15 @@ pub enum ProviderResponse {
     Addrs(AddrsResponse),
     Validate(ValidateProgress),
     Shutdown(()),
+    HistoryList(HistoryListResponse),
+    HistoryGet(HistoryGetResponse),
+    HistoryStart(RpcResult<HistoryStartResponse>),
+    HistoryFinish(RpcResult<HistoryGetResponse>),
+    TagSet(RpcResult<TagSetResponse>),
+    TagRemove(RpcResult<TagRemoveResponse>),
+    TagList(TagListResponse),
+    BlobRead(RpcResult<BlobReadResponse>),
+    Peers(PeersResponse),
 }
 
 impl Service for ProviderService {
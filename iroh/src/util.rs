@@ -1,3 +1,4 @@
 //! utilites for io and for reporting progress
 pub mod io;
 pub mod progress;
+pub mod throttle;
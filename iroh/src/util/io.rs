@@ -1,20 +1,55 @@
 //! Utilities for working with tokio io
 use anyhow::Context;
 use derive_more::Display;
+use futures::future::BoxFuture;
+use futures::FutureExt;
 use range_collections::RangeSet2;
+use std::ops::Range;
 use std::path::{Component, Path};
 use std::{io::Write, path::PathBuf, result};
 use thiserror::Error;
 
-use bao_tree::io::sync::encode_ranges_validated;
+use bao_tree::io::fsm::{
+    self, BaoContentItem, Outboard, ResponseDecoderReadingNext, ResponseDecoderStart,
+};
+use bao_tree::io::sync::{encode_ranges_validated, valid_file_ranges};
 use bao_tree::io::{
     sync::{ReadAt, Size},
     EncodeError,
 };
+use bao_tree::{ByteNum, ChunkNum};
 use bytes::Bytes;
+use iroh_bytes::provider::{BaoMap, BaoMapEntry};
 use iroh_bytes::Hash;
 use iroh_bytes::IROH_BLOCK_SIZE;
 
+/// Writes `data` to a file only readable by the current user, replacing `path` atomically.
+///
+/// The data is written to a temporary file next to `path`, which is moved into place. Use
+/// this for secrets like tokens, which must not be readable by other users.
+pub async fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let tmp = path.with_extension("tmp");
+    // the mode only applies to new files, so never reuse a left over temporary file
+    match tokio::fs::remove_file(&tmp).await {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(&tmp).await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    drop(file);
+    tokio::fs::rename(&tmp, path).await
+}
+
 /// Create a pathbuf from a name.
 pub fn pathbuf_from_name(name: &str) -> PathBuf {
     let mut path = PathBuf::new();
@@ -56,6 +91,37 @@ pub fn validate_bao<F: Fn(u64)>(
     Ok(())
 }
 
+/// Returns the chunks of the data that match the outboard.
+///
+/// Unlike [`validate_bao`] this does not stop at the first mismatch. Data missing at the
+/// end of a truncated file does not match.
+pub fn valid_ranges(
+    hash: Hash,
+    data_reader: impl ReadAt,
+    outboard: Bytes,
+) -> result::Result<RangeSet2<ChunkNum>, BaoValidationError> {
+    let hash = blake3::Hash::from(hash);
+    let outboard =
+        bao_tree::io::outboard::PreOrderMemOutboard::new(hash, IROH_BLOCK_SIZE, &outboard)?;
+    let valid = valid_file_ranges(&outboard, ZeroPadded(data_reader))?;
+    Ok(valid)
+}
+
+/// A [`ReadAt`] reading zeros past the end of the inner reader.
+struct ZeroPadded<R>(R);
+
+impl<R: ReadAt> ReadAt for ZeroPadded<R> {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.0.read_at(pos, buf)? {
+            0 => {
+                buf.fill(0);
+                Ok(buf.len())
+            }
+            n => Ok(n),
+        }
+    }
+}
+
 /// little util that discards data but prints progress every 1MB
 struct DevNull<F>(u64, F);
 
@@ -102,6 +168,88 @@ pub fn canonicalize_path(path: impl AsRef<Path>) -> anyhow::Result<String> {
     Ok(parts.join("/"))
 }
 
+/// Receives the verified bytes of a blob read with [`read_range_verified`].
+pub trait DataSink: Send {
+    /// Appends the next verified bytes.
+    fn send(&mut self, data: Bytes) -> BoxFuture<'_, anyhow::Result<()>>;
+}
+
+impl DataSink for Vec<u8> {
+    fn send(&mut self, data: Bytes) -> BoxFuture<'_, anyhow::Result<()>> {
+        self.extend_from_slice(&data);
+        futures::future::ok(()).boxed()
+    }
+}
+
+/// Returns the size of a blob in `db`, `None` if the blob is not in `db`.
+pub async fn blob_size<D: BaoMap>(db: &D, hash: &Hash) -> anyhow::Result<Option<u64>> {
+    let Some(entry) = db.get(hash) else {
+        return Ok(None);
+    };
+    Ok(Some(entry.outboard().await?.tree().size().0))
+}
+
+/// Sends the bytes of `range` of a blob in `db` to `sink`, as soon as they are verified
+/// against the outboard of the blob.
+///
+/// Fails without sending the rest of the range if the data does not match the outboard,
+/// e.g. because the file of the blob was changed or corrupted.
+pub async fn read_range_verified<D: BaoMap>(
+    db: &D,
+    hash: Hash,
+    range: Range<u64>,
+    sink: &mut impl DataSink,
+) -> anyhow::Result<()> {
+    if range.is_empty() {
+        return Ok(());
+    }
+    let entry = db
+        .get(&hash)
+        .with_context(|| format!("blob {hash} not found"))?;
+    let outboard = entry.outboard().await?;
+    let data = entry.data_reader().await?;
+    let chunks = RangeSet2::from(ByteNum(range.start).full_chunks()..ByteNum(range.end).chunks());
+    // encoding verifies the data against the outboard, decoding extracts the data again
+    let (send, recv) = tokio::io::duplex(64 * 1024);
+    let encode = {
+        let chunks = chunks.clone();
+        async move {
+            fsm::encode_ranges_validated(data, outboard, &chunks, send)
+                .await
+                .with_context(|| format!("blob {hash} failed to validate"))
+        }
+    };
+    let decode = async {
+        let start = ResponseDecoderStart::new(hash.into(), chunks, IROH_BLOCK_SIZE, recv);
+        let (mut reading, _size) = start.next().await?;
+        loop {
+            let item = match reading.next().await {
+                ResponseDecoderReadingNext::Done(_) => break,
+                ResponseDecoderReadingNext::More((next, item)) => {
+                    reading = next;
+                    item?
+                }
+            };
+            if let BaoContentItem::Leaf(leaf) = item {
+                // leaves cover whole chunks, cut them down to the requested range
+                let leaf_start = leaf.offset.0;
+                let leaf_end = leaf_start + leaf.data.len() as u64;
+                let from = range.start.max(leaf_start);
+                let to = range.end.min(leaf_end);
+                if from < to {
+                    let data = leaf
+                        .data
+                        .slice((from - leaf_start) as usize..(to - leaf_start) as usize);
+                    sink.send(data).await?;
+                }
+            }
+        }
+        anyhow::Ok(())
+    };
+    tokio::try_join!(encode, decode)?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -109,4 +257,19 @@ mod tests {
     fn test_canonicalize_path() {
         assert_eq!(super::canonicalize_path("foo/bar").unwrap(), "foo/bar");
     }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_write_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = testdir::testdir!();
+        let path = dir.join("secret");
+        std::fs::write(&path, "old").unwrap();
+        super::write_private(&path, b"new").await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(!path.with_extension("tmp").exists());
+    }
 }
@@ -0,0 +1,219 @@
+//! Token bucket throttling for disk io.
+//!
+//! Background work on the store, like validating all blobs or persisting the database,
+//! can saturate a slow disk and starve the requests the node is serving. An
+//! [`IoThrottle`] limits the bandwidth and number of operations such work may use.
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bao_tree::io::sync::{ReadAt, Size};
+use serde::{Deserialize, Serialize};
+
+/// Largest single read or write passed through a throttled reader or writer.
+///
+/// Splitting large operations keeps the io spread out evenly instead of in bursts.
+const MAX_OP_SIZE: usize = 64 * 1024;
+
+/// Limits for throttled disk io.
+///
+/// `None` means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IoLimits {
+    /// Maximum number of bytes read per second.
+    pub read_bytes_per_sec: Option<u64>,
+    /// Maximum number of read operations per second.
+    pub read_ops_per_sec: Option<u64>,
+    /// Maximum number of bytes written per second.
+    pub write_bytes_per_sec: Option<u64>,
+    /// Maximum number of write operations per second.
+    pub write_ops_per_sec: Option<u64>,
+}
+
+/// A token bucket, refilled at a fixed rate up to a burst capacity of one second.
+///
+/// Taking more tokens than available puts the bucket into debt, which is paid back by
+/// waiting. This allows operations larger than the capacity.
+struct TokenBucket {
+    rate: u64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        let rate = rate.max(1);
+        Self {
+            rate,
+            state: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    /// Takes `n` tokens, returning how long to wait before using them.
+    fn take(&self, n: u64) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = &mut *state;
+        let now = Instant::now();
+        let capacity = self.rate as f64;
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * capacity).min(capacity);
+        *last = now;
+        *tokens -= n as f64;
+        if *tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-*tokens / capacity)
+        }
+    }
+}
+
+/// Throttles disk io according to [`IoLimits`].
+///
+/// The default throttle is unlimited.
+#[derive(Default)]
+pub struct IoThrottle {
+    limits: IoLimits,
+    read_bytes: Option<TokenBucket>,
+    read_ops: Option<TokenBucket>,
+    write_bytes: Option<TokenBucket>,
+    write_ops: Option<TokenBucket>,
+}
+
+impl fmt::Debug for IoThrottle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IoThrottle")
+            .field("limits", &self.limits)
+            .finish()
+    }
+}
+
+impl IoThrottle {
+    /// Creates a new throttle with the given limits.
+    pub fn new(limits: IoLimits) -> Self {
+        Self {
+            limits,
+            read_bytes: limits.read_bytes_per_sec.map(TokenBucket::new),
+            read_ops: limits.read_ops_per_sec.map(TokenBucket::new),
+            write_bytes: limits.write_bytes_per_sec.map(TokenBucket::new),
+            write_ops: limits.write_ops_per_sec.map(TokenBucket::new),
+        }
+    }
+
+    /// The limits of this throttle.
+    pub fn limits(&self) -> IoLimits {
+        self.limits
+    }
+
+    /// Blocks the current thread until a read of `len` bytes is allowed.
+    pub fn read_blocking(&self, len: usize) {
+        std::thread::sleep(Self::wait(&self.read_ops, &self.read_bytes, len));
+    }
+
+    /// Blocks the current thread until a write of `len` bytes is allowed.
+    pub fn write_blocking(&self, len: usize) {
+        std::thread::sleep(Self::wait(&self.write_ops, &self.write_bytes, len));
+    }
+
+    fn wait(ops: &Option<TokenBucket>, bytes: &Option<TokenBucket>, len: usize) -> Duration {
+        let ops = ops.as_ref().map_or(Duration::ZERO, |b| b.take(1));
+        let bytes = bytes
+            .as_ref()
+            .map_or(Duration::ZERO, |b| b.take(len as u64));
+        ops.max(bytes)
+    }
+}
+
+/// A [`ReadAt`] wrapper that throttles reads.
+#[derive(Debug)]
+pub struct ThrottledReader<R> {
+    inner: R,
+    throttle: Arc<IoThrottle>,
+}
+
+impl<R> ThrottledReader<R> {
+    /// Wraps `inner`, throttling reads with `throttle`.
+    pub fn new(inner: R, throttle: Arc<IoThrottle>) -> Self {
+        Self { inner, throttle }
+    }
+}
+
+impl<R: ReadAt> ReadAt for ThrottledReader<R> {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(MAX_OP_SIZE);
+        self.throttle.read_blocking(len);
+        self.inner.read_at(pos, &mut buf[..len])
+    }
+}
+
+impl<R: Size> Size for ThrottledReader<R> {
+    fn size(&self) -> io::Result<Option<u64>> {
+        self.inner.size()
+    }
+}
+
+/// A [`Write`] wrapper that throttles writes.
+#[derive(Debug)]
+pub struct ThrottledWriter<W> {
+    inner: W,
+    throttle: Arc<IoThrottle>,
+}
+
+impl<W> ThrottledWriter<W> {
+    /// Wraps `inner`, throttling writes with `throttle`.
+    pub fn new(inner: W, throttle: Arc<IoThrottle>) -> Self {
+        Self { inner, throttle }
+    }
+
+    /// Returns the inner writer.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for ThrottledWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(MAX_OP_SIZE);
+        self.throttle.write_blocking(len);
+        self.inner.write(&buf[..len])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket_debt() {
+        let bucket = TokenBucket::new(1000);
+        // the initial burst is free
+        assert_eq!(bucket.take(1000), Duration::ZERO);
+        // going into debt requires waiting until it is paid back
+        let wait = bucket.take(500);
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn throttled_writer() -> io::Result<()> {
+        let throttle = Arc::new(IoThrottle::new(IoLimits {
+            write_bytes_per_sec: Some(100 * 1024),
+            ..Default::default()
+        }));
+        let mut writer = ThrottledWriter::new(Vec::new(), throttle);
+        let start = Instant::now();
+        // one second of burst, then half a second of waiting
+        writer.write_all(&[0u8; 150 * 1024])?;
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        assert_eq!(writer.into_inner().len(), 150 * 1024);
+
+        // an unlimited throttle does not wait
+        let mut writer = ThrottledWriter::new(Vec::new(), Arc::new(IoThrottle::default()));
+        let start = Instant::now();
+        writer.write_all(&[0u8; 1024 * 1024])?;
+        assert!(start.elapsed() < Duration::from_millis(400));
+        Ok(())
+    }
+}
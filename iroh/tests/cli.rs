@@ -268,6 +268,73 @@ fn cli_provide_addresses() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn cli_provide_history() -> Result<()> {
+    let dir = testdir!();
+    let path = dir.join("foo");
+    make_rand_file(1000, &path)?;
+    let rpc_port = "4998";
+
+    let mut provider = make_provider(&path, &Input::Path, None, Some(rpc_port))?;
+    // wait for the provider to start
+    let _all_in_one = match_provide_output(&mut provider, 1)?;
+
+    // the add is recorded as done right after the ticket is printed
+    let mut stdout = String::new();
+    for _ in 0..50 {
+        let output = cmd(iroh_bin(), ["history", "--rpc-port", rpc_port])
+            .stdout_capture()
+            .run()?;
+        assert!(output.status.success());
+        stdout = String::from_utf8(output.stdout).unwrap();
+        if stdout.contains("(done ") {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    let path = path.canonicalize()?;
+    assert!(
+        stdout.starts_with("0 ") && stdout.contains(&format!("add {}", path.display())),
+        "unexpected history: {stdout}"
+    );
+    assert!(stdout.contains("(done "), "unexpected history: {stdout}");
+    assert!(stdout.contains("1000B"), "unexpected history: {stdout}");
+    Ok(())
+}
+
+#[test]
+fn cli_blob_get_and_share() -> Result<()> {
+    let dir = testdir!();
+    let path = dir.join("foo");
+    let hash = make_rand_file(1000, &path)?;
+    let rpc_port = "4997";
+
+    let mut provider = make_provider(&path, &Input::Path, None, Some(rpc_port))?;
+    // wait for the provider to start
+    let _all_in_one = match_provide_output(&mut provider, 1)?;
+
+    let output = cmd(
+        iroh_bin(),
+        ["blob", "get", &hash.to_string(), "--rpc-port", rpc_port],
+    )
+    .stdout_capture()
+    .run()?;
+    assert!(output.status.success());
+    assert_eq!(output.stdout, std::fs::read(&path)?);
+
+    let output = cmd(
+        iroh_bin(),
+        ["blob", "share", &hash.to_string(), "--rpc-port", rpc_port],
+    )
+    .stdout_capture()
+    .run()?;
+    assert!(output.status.success());
+    let ticket = Ticket::from_str(String::from_utf8(output.stdout)?.trim())?;
+    assert_eq!(ticket.hash(), hash);
+    assert!(!ticket.recursive());
+    Ok(())
+}
+
 /// Parameter for `test_provide_get_loop`, that determines how we handle the fetched data from the
 /// `iroh get` command
 #[derive(Debug, PartialEq)]
@@ -31,9 +31,11 @@ use tracing_subscriber::{prelude::*, EnvFilter};
 
 use iroh_bytes::{
     collection::{CollectionParser, CollectionStats, LinkStream},
-    get::{fsm, fsm::ConnectedNext, Stats},
-    protocol::{AnyGetRequest, CustomGetRequest, GetRequest, RequestToken},
-    provider::{self, BaoReadonlyDb, CustomGetHandler, RequestAuthorizationHandler},
+    get::{self, fsm, fsm::ConnectedNext, Stats},
+    protocol::{AnyGetRequest, Closed, CustomGetRequest, FanOutRequest, GetRequest, RequestToken},
+    provider::{
+        self, BaoReadonlyDb, CustomGetHandler, RequestAuthorizationHandler, TransferHandler,
+    },
     util::runtime,
     Hash,
 };
@@ -300,6 +302,8 @@ where
                     events.push(event);
                     break;
                 }
+                // Only sent if the transfer takes long enough.
+                Event::ByteProvide(provider::Event::TransferProgress { .. }) => {}
                 _ => events.push(event),
             }
         }
@@ -508,6 +512,146 @@ async fn test_ipv6() {
     .expect("get failed");
 }
 
+#[tokio::test]
+async fn test_fan_out() {
+    let rt = test_runtime();
+    // a chunk group is 16 KiB, so these cover an empty blob, a blob smaller than a
+    // single chunk group, an exact multiple of the group size and a large blob that
+    // is not a multiple of the group size
+    let sizes = [0, 100, 4 * 16 * 1024, 1024 * 1024 + 1234];
+    let blobs = sizes
+        .iter()
+        .map(|size| {
+            let mut data = vec![0u8; *size];
+            rand::thread_rng().fill_bytes(&mut data);
+            (size.to_string(), data)
+        })
+        .collect::<Vec<_>>();
+    let (db, hashes) = mem::Database::new(blobs.clone());
+    let addr = (Ipv4Addr::UNSPECIFIED, 0).into();
+    let node = test_node(db, addr).runtime(&rt).spawn().await.unwrap();
+    let addrs = node.local_endpoint_addresses().await.unwrap();
+    let peer_id = node.peer_id();
+    tokio::time::timeout(Duration::from_secs(30), async move {
+        let connection = iroh::dial::dial(get_options(peer_id, addrs)).await?;
+        for (name, expected) in &blobs {
+            let hash = hashes[name].into();
+            for streams in [0, 1, 3, 8, u16::MAX] {
+                let mut data = bytes::BytesMut::new();
+                let request = FanOutRequest::new(hash, streams);
+                let stats = get::fan_out(&connection, request, &mut data).await?;
+                assert_eq!(&data[..], &expected[..], "size {name}, streams {streams}");
+                assert!(stats.bytes_read >= expected.len() as u64);
+            }
+        }
+        // streams left over from an aborted request don't break the next one
+        let (name, expected) = blobs.last().unwrap();
+        let hash = hashes[name].into();
+        let aborted = get::fan_out(
+            &connection,
+            FanOutRequest::new(hash, 8),
+            bytes::BytesMut::new(),
+        );
+        tokio::time::timeout(Duration::from_millis(1), aborted)
+            .await
+            .ok();
+        let mut data = bytes::BytesMut::new();
+        get::fan_out(&connection, FanOutRequest::new(hash, 8), &mut data).await?;
+        assert_eq!(&data[..], &expected[..]);
+        // requesting an unknown blob fails
+        let request = FanOutRequest::new(Hash::from([0u8; 32]), 8);
+        let res = get::fan_out(&connection, request, bytes::BytesMut::new()).await;
+        assert!(res.is_err());
+        anyhow::Ok(())
+    })
+    .await
+    .expect("timeout")
+    .expect("fan-out get failed");
+}
+
+#[tokio::test]
+async fn test_byte_range() {
+    let rt = test_runtime();
+    let mut data = vec![0u8; 100_000];
+    rand::thread_rng().fill_bytes(&mut data);
+    let (db, hashes) = mem::Database::new([("blob", data.clone())]);
+    let hash: Hash = hashes["blob"].into();
+    let addr = (Ipv4Addr::UNSPECIFIED, 0).into();
+    let node = test_node(db, addr).runtime(&rt).spawn().await.unwrap();
+    let addrs = node.local_endpoint_addresses().await.unwrap();
+    let peer_id = node.peer_id();
+    tokio::time::timeout(Duration::from_secs(10), async move {
+        let connection = iroh::dial::dial(get_options(peer_id, addrs)).await?;
+        // not aligned to chunks, a range within a single chunk, and a range past the end
+        for range in [5000..6500, 2048..2049, 99_000..200_000] {
+            let request = GetRequest::byte_range(hash, range.clone());
+            let connected = fsm::start(connection.clone(), request.into())
+                .next()
+                .await?;
+            let ConnectedNext::StartRoot(start) = connected.next().await? else {
+                bail!("expected root");
+            };
+            let (end, actual) = start.next().read_byte_range(range.clone()).await?;
+            let expected = &data[range.start as usize..(range.end as usize).min(data.len())];
+            assert_eq!(actual, expected, "range {range:?}");
+            let fsm::EndBlobNext::Closing(closing) = end.next() else {
+                bail!("expected end");
+            };
+            let stats = closing.next().await?;
+            // only the chunk groups overlapping the range are sent, at most two of them here
+            assert!(stats.bytes_read < 40 * 1024, "range {range:?}");
+        }
+        anyhow::Ok(())
+    })
+    .await
+    .expect("timeout")
+    .expect("range get failed");
+}
+
+#[tokio::test]
+async fn test_send_verified() {
+    let rt = test_runtime();
+    let mut data = vec![0u8; 100_000];
+    rand::thread_rng().fill_bytes(&mut data);
+    let (db, hashes) = mem::Database::new([("blob", data.clone())]);
+    let hash: Hash = hashes["blob"].into();
+    let addr = (Ipv4Addr::UNSPECIFIED, 0).into();
+    let node = test_node(db, addr).runtime(&rt).spawn().await.unwrap();
+    let addrs = node.local_endpoint_addresses().await.unwrap();
+    let peer_id = node.peer_id();
+    tokio::time::timeout(Duration::from_secs(10), async move {
+        let connection = iroh::dial::dial(get_options(peer_id, addrs)).await?;
+        let connected = fsm::start(connection, GetRequest::single(hash).into())
+            .next()
+            .await?;
+        let ConnectedNext::StartRoot(start) = connected.next().await? else {
+            bail!("expected root");
+        };
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<(u64, Bytes)>(1);
+        let receive = async move {
+            let mut received = Vec::new();
+            let mut items = 0;
+            while let Some((offset, bytes)) = rx.recv().await {
+                // data arrives in order, one leaf at a time
+                assert_eq!(offset, received.len() as u64);
+                received.extend_from_slice(&bytes);
+                items += 1;
+            }
+            (received, items)
+        };
+        // the sender is dropped when the blob is done, which ends the receiver
+        let send = async move { start.next().send_verified(&tx).await };
+        let (end, (received, items)) = tokio::join!(send, receive);
+        end?;
+        assert_eq!(received, data);
+        assert!(items > 1);
+        anyhow::Ok(())
+    })
+    .await
+    .expect("timeout")
+    .expect("get failed");
+}
+
 #[tokio::test]
 async fn test_run_ticket() {
     let rt = test_runtime();
@@ -581,8 +725,8 @@ async fn run_custom_get_request<C: CollectionParser>(
     // we assume that the request includes the entire collection
     let (mut next, root, mut c) = {
         let ConnectedNext::StartRoot(sc) = connected.next().await? else {
-                panic!("request did not include collection");
-            };
+            panic!("request did not include collection");
+        };
         println!("getting collection");
         let (done, data) = sc.next().concatenate_into_vec().await?;
         let mut data = Bytes::from(data);
@@ -751,12 +895,15 @@ async fn test_custom_request_blob() {
     tokio::time::timeout(Duration::from_secs(10), async move {
         let request: AnyGetRequest = iroh_bytes::protocol::Request::CustomGet(CustomGetRequest {
             token: None,
+            deadline: None,
             data: Bytes::from(&b"hello"[..]),
         });
         let connection = iroh::dial::dial(get_options(peer_id, addrs)).await?;
         let response = fsm::start(connection, request);
         let connected = response.next().await?;
-        let ConnectedNext::StartRoot(start) = connected.next().await? else { panic!() };
+        let ConnectedNext::StartRoot(start) = connected.next().await? else {
+            panic!()
+        };
         let header = start.next();
         let (_, actual) = header.concatenate_into_vec().await?;
         assert_eq!(actual, expected);
@@ -785,6 +932,7 @@ async fn test_custom_request_collection() {
     tokio::time::timeout(Duration::from_secs(10), async move {
         let request: AnyGetRequest = CustomGetRequest {
             token: None,
+            deadline: None,
             data: Bytes::from(&b"hello"[..]),
         }
         .into();
@@ -800,6 +948,163 @@ async fn test_custom_request_collection() {
     .expect("get failed");
 }
 
+#[derive(Clone, Debug)]
+struct SlowCustomHandler;
+
+impl CustomGetHandler for SlowCustomHandler {
+    fn handle(
+        &self,
+        _token: Option<RequestToken>,
+        _data: Bytes,
+    ) -> BoxFuture<'static, anyhow::Result<GetRequest>> {
+        async move {
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            bail!("too slow")
+        }
+        .boxed()
+    }
+}
+
+#[tokio::test]
+async fn test_request_deadline() {
+    let rt = test_runtime();
+    let (db, _hashes) = iroh::database::mem::Database::new([("test", b"hello".to_vec())]);
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let node = test_node(db, addr)
+        .runtime(&rt)
+        .custom_get_handler(Arc::new(SlowCustomHandler))
+        .spawn()
+        .await
+        .unwrap();
+    let addrs = node.local_endpoint_addresses().await.unwrap();
+    let peer_id = node.peer_id();
+    tokio::time::timeout(Duration::from_secs(10), async move {
+        let request: AnyGetRequest = CustomGetRequest {
+            token: None,
+            deadline: Some(Duration::from_millis(100)),
+            data: Bytes::from(&b"hello"[..]),
+        }
+        .into();
+        let connection = iroh::dial::dial(get_options(peer_id, addrs)).await?;
+        let connected = fsm::start(connection, request).next().await?;
+        let err = connected.next().await.expect_err("deadline not enforced");
+        let get::GetResponseError::Generic(err) = err else {
+            panic!("unexpected error {err:?}");
+        };
+        let err = err
+            .downcast_ref::<std::io::Error>()
+            .and_then(|err| err.get_ref())
+            .and_then(|err| err.downcast_ref::<quinn::ReadError>());
+        assert!(
+            matches!(err, Some(quinn::ReadError::Reset(code)) if *code == Closed::DeadlineExceeded.into()),
+            "unexpected error {err:?}"
+        );
+        anyhow::Ok(())
+    })
+    .await
+    .expect("timeout")
+    .expect("get failed");
+}
+
+#[tokio::test]
+async fn test_request_cancel() -> Result<()> {
+    let rt = test_runtime();
+    let mut data = vec![0u8; 10 * 1024 * 1024];
+    rand::thread_rng().fill_bytes(&mut data);
+    let (db, hashes) = iroh::database::mem::Database::new([("test", data)]);
+    let hash = Hash::from(*hashes.values().next().unwrap());
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let node = test_node(db, addr).runtime(&rt).spawn().await?;
+    let (events_sender, mut events_recv) = mpsc::unbounded_channel();
+    node.subscribe(move |event| {
+        let events_sender = events_sender.clone();
+        async move {
+            events_sender.send(event).ok();
+        }
+        .boxed()
+    })
+    .await?;
+
+    let addrs = node.local_endpoint_addresses().await?;
+    let peer_id = node.peer_id();
+    tokio::time::timeout(Duration::from_secs(10), async move {
+        let connection = iroh::dial::dial(get_options(peer_id, addrs)).await?;
+        let request = GetRequest::single(hash).into();
+        let connected = fsm::start(connection.clone(), request).next().await?;
+        let ConnectedNext::StartRoot(start) = connected.next().await? else {
+            panic!("expected root");
+        };
+        let (content, _size) = start.next().next().await?;
+        let stats = content.cancel();
+        assert!(stats.bytes_read < 10 * 1024 * 1024);
+        loop {
+            match events_recv.recv().await.context("events ended")? {
+                Event::ByteProvide(provider::Event::TransferAborted { .. }) => break,
+                Event::ByteProvide(provider::Event::TransferCollectionCompleted { .. }) => {
+                    bail!("transfer completed")
+                }
+                _ => {}
+            }
+        }
+        // the connection is still usable
+        assert!(connection.close_reason().is_none());
+        anyhow::Ok(())
+    })
+    .await
+    .context("timeout")?
+}
+
+#[tokio::test]
+async fn test_shutdown_grace_period() -> Result<()> {
+    let rt = test_runtime();
+    let mut data = vec![0u8; 10 * 1024 * 1024];
+    rand::thread_rng().fill_bytes(&mut data);
+    let (db, hashes) = iroh::database::mem::Database::new([("test", data.clone())]);
+    let hash = Hash::from(*hashes.values().next().unwrap());
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let node = test_node(db, addr)
+        .shutdown_grace_period(Duration::from_secs(10))
+        .runtime(&rt)
+        .spawn()
+        .await?;
+    let addrs = node.local_endpoint_addresses().await?;
+    let peer_id = node.peer_id();
+
+    tokio::time::timeout(Duration::from_secs(10), async move {
+        let connection = iroh::dial::dial(get_options(peer_id, addrs)).await?;
+        let request = GetRequest::single(hash).into();
+        let connected = fsm::start(connection.clone(), request).next().await?;
+        let ConnectedNext::StartRoot(start) = connected.next().await? else {
+            panic!("expected root");
+        };
+        let (mut content, _size) = start.next().next().await?;
+        // the transfer started, shutting down waits for it to complete
+        node.shutdown();
+        let mut received = Vec::new();
+        let end = loop {
+            match content.next().await {
+                fsm::BlobContentNext::More((next, item)) => {
+                    if let bao_tree::io::fsm::BaoContentItem::Leaf(leaf) = item? {
+                        received.extend_from_slice(&leaf.data);
+                    }
+                    content = next;
+                }
+                fsm::BlobContentNext::Done(end) => break end,
+            }
+        };
+        assert_eq!(received, data);
+        let fsm::EndBlobNext::Closing(closing) = end.next() else {
+            panic!("expected the end of the request");
+        };
+        closing.next().await?;
+        connection.close(0u32.into(), b"done");
+        node.await?;
+        anyhow::Ok(())
+    })
+    .await
+    .context("timeout")?
+}
+
 #[derive(Clone, Debug)]
 struct CustomAuthHandler;
 
@@ -885,3 +1190,350 @@ async fn test_token_passthrough() -> Result<()> {
 
     Ok(())
 }
+
+/// A [`TransferHandler`] recording all transfers.
+#[derive(Debug, Default)]
+struct RecordingTransferHandler {
+    served: std::sync::Mutex<Vec<(PeerId, Hash, u64)>>,
+    fetched: std::sync::Mutex<Vec<(PeerId, Hash, u64)>>,
+}
+
+impl TransferHandler for RecordingTransferHandler {
+    fn served(&self, peer: PeerId, hash: Hash, bytes: u64) -> BoxFuture<'static, ()> {
+        self.served.lock().unwrap().push((peer, hash, bytes));
+        future::ready(()).boxed()
+    }
+
+    fn fetched(&self, peer: PeerId, hash: Hash, bytes: u64) -> BoxFuture<'static, ()> {
+        self.fetched.lock().unwrap().push((peer, hash, bytes));
+        future::ready(()).boxed()
+    }
+}
+
+#[cfg(feature = "flat-db")]
+#[tokio::test]
+async fn test_delegated_fetch() -> Result<()> {
+    use iroh::database::flat;
+    use iroh::delegate::{DelegateHandler, DelegatedFetch};
+
+    let rt = test_runtime();
+    let child1 = b"hello".to_vec();
+    let child2 = vec![1u8; 123456];
+    let (source_db, hash) = create_test_db([("a", &child1), ("b", &child2)]);
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let served = Arc::new(RecordingTransferHandler::default());
+    let source = test_node(source_db, addr)
+        .runtime(&rt)
+        .transfer_handler(served.clone())
+        .spawn()
+        .await?;
+    let source_peer_id = source.peer_id();
+    let ticket = source.ticket(hash).await?;
+    let source_requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let source_requests2 = source_requests.clone();
+    source
+        .subscribe(move |event| {
+            if let Event::ByteProvide(provider::Event::GetRequestReceived { .. }) = event {
+                source_requests2.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+            async {}.boxed()
+        })
+        .await?;
+
+    let dir = testdir!();
+    let delegate_db = flat::Database::default();
+    let token = RequestToken::generate();
+    let fetched = Arc::new(RecordingTransferHandler::default());
+    let delegate = test_node(delegate_db.clone(), addr)
+        .runtime(&rt)
+        .custom_get_handler(Arc::new(
+            DelegateHandler::new(delegate_db.clone(), dir, None, rt.clone())
+                .transfer_handler(fetched.clone()),
+        ))
+        .custom_auth_handler(Arc::new(StaticTokenAuthHandler::new(Some(token.clone()))))
+        .spawn()
+        .await?;
+    let addrs = delegate.local_endpoint_addresses().await?;
+    let peer_id = delegate.peer_id();
+
+    // every dial creates a new endpoint, so this needs a bit more time than other tests
+    tokio::time::timeout(Duration::from_secs(30), async move {
+        // the delegate refuses to fetch without the right token
+        let request = DelegatedFetch::new(ticket.clone()).into_request(None)?;
+        assert!(
+            run_get_request(get_options(peer_id, addrs.clone()), request)
+                .await
+                .is_err()
+        );
+        assert!(delegate_db.get(&hash).is_none());
+
+        // concurrent requests share a single fetch, which gets the collection and then
+        // its children from the source
+        let fetch = || async {
+            let request = DelegatedFetch::new(ticket.clone()).into_request(Some(token.clone()))?;
+            let (_collection, items, _stats) =
+                run_get_request(get_options(peer_id, addrs.clone()), request).await?;
+            assert_eq!(items.len(), 2);
+            assert_eq!(items[&0], child1);
+            assert_eq!(items[&1], child2);
+            anyhow::Ok(())
+        };
+        tokio::try_join!(fetch(), fetch())?;
+        assert!(delegate_db.get(&hash).is_some());
+        assert_eq!(source_requests.load(std::sync::atomic::Ordering::SeqCst), 2);
+
+        // the source reports serving the collection and its children, and the delegate
+        // reports fetching them from the source
+        let child1_hash = Hash::from(blake3::hash(&child1));
+        let child2_hash = Hash::from(blake3::hash(&child2));
+        let mut served_hashes: Vec<_> = served
+            .served
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, hash, bytes)| {
+                assert!(*bytes > 0);
+                *hash
+            })
+            .collect();
+        served_hashes.sort();
+        let mut expected = vec![hash, child1_hash, child2_hash];
+        expected.sort();
+        assert_eq!(served_hashes, expected);
+        let fetched = fetched.fetched.lock().unwrap().clone();
+        assert_eq!(fetched.len(), 3);
+        assert!(fetched.iter().all(|(peer, _, _)| *peer == source_peer_id));
+        assert!(fetched.contains(&(source_peer_id, child1_hash, child1.len() as u64)));
+        assert!(fetched.contains(&(source_peer_id, child2_hash, child2.len() as u64)));
+
+        // fetching again serves the content from the delegate without asking the source
+        fetch().await?;
+        assert_eq!(source_requests.load(std::sync::atomic::Ordering::SeqCst), 2);
+
+        // a single blob of the collection
+        let blob_hash = Hash::from(blake3::hash(&child2));
+        let ticket = iroh::dial::Ticket::new(
+            blob_hash,
+            ticket.peer(),
+            ticket.addrs().to_vec(),
+            None,
+            false,
+            ticket.derp_region(),
+        )?;
+        let request = DelegatedFetch::new(ticket).into_request(Some(token.clone()))?;
+        let connection = iroh::dial::dial(get_options(peer_id, addrs)).await?;
+        let connected = fsm::start(connection, request).next().await?;
+        let ConnectedNext::StartRoot(start) = connected.next().await? else {
+            bail!("expected root");
+        };
+        let (_, actual) = start.next().concatenate_into_vec().await?;
+        assert_eq!(actual, child2);
+        anyhow::Ok(())
+    })
+    .await
+    .context("timeout")?
+    .context("delegated fetch failed")?;
+    Ok(())
+}
+
+#[cfg(feature = "flat-db")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_repair() -> Result<()> {
+    use iroh::database::flat;
+
+    let rt = test_runtime();
+    let data = (0..200_000u32).map(|i| i as u8).collect::<Vec<_>>();
+    let (source_db, _) = create_test_db([("blob", &data)]);
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let source = test_node(source_db, addr).runtime(&rt).spawn().await?;
+    let addrs = source.local_endpoint_addresses().await?;
+    let opts = || get_options(source.peer_id(), addrs.clone());
+
+    let dir = testdir!();
+    let path = dir.join("blob");
+    fs::write(&path, &data).await?;
+    let db = flat::Database::default();
+    let hash = db.import_file(path.clone()).await?;
+    let internal = db.import_bytes(Bytes::from_static(b"internal"))?;
+
+    // corrupt a chunk in the middle, and truncate the end
+    let mut damaged = data.clone();
+    damaged[50_000] ^= 0xff;
+    damaged.truncate(150_000);
+    fs::write(&path, &damaged).await?;
+    let report = db.verify_all().await?;
+    assert_eq!(report.len(), 1);
+    assert_eq!(report[0].hash, hash);
+    assert!(report[0].truncated);
+
+    // every dial creates a new endpoint, so this needs a bit more time than other tests
+    tokio::time::timeout(Duration::from_secs(30), async {
+        db.repair(hash, [opts()]).await?;
+        assert!(db.verify_all().await?.is_empty());
+        assert_eq!(fs::read(&path).await?, data);
+
+        // a missing file is fetched entirely, and intact data is not fetched at all
+        fs::remove_file(&path).await?;
+        assert!(db.repair(hash, []).await.is_err());
+        db.repair(hash, [opts()]).await?;
+        assert_eq!(fs::read(&path).await?, data);
+        db.repair(internal, []).await
+    })
+    .await
+    .context("timeout")?
+    .context("repair failed")?;
+    Ok(())
+}
+
+#[cfg(feature = "flat-db")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_push() -> Result<()> {
+    use iroh::database::flat;
+    use iroh::push::{push, PushReceiver};
+    use iroh_bytes::push::PushOutcome;
+
+    let rt = test_runtime();
+    let data = (0..100_000u32).map(|i| i as u8).collect::<Vec<_>>();
+    let db = flat::Database::default();
+    let hash = db.import_bytes(data.clone().into())?;
+    let addr = "127.0.0.1:0".parse().unwrap();
+
+    let dir = testdir!();
+    let receiver_db = flat::Database::default();
+    let token = RequestToken::generate();
+    let fetched = Arc::new(RecordingTransferHandler::default());
+    let receiver = test_node(receiver_db.clone(), addr)
+        .runtime(&rt)
+        .push_handler(Arc::new(PushReceiver::new(
+            receiver_db.clone(),
+            dir.clone(),
+        )))
+        .custom_auth_handler(Arc::new(StaticTokenAuthHandler::new(Some(token.clone()))))
+        .transfer_handler(fetched.clone())
+        .spawn()
+        .await?;
+    let addrs = receiver.local_endpoint_addresses().await?;
+    let opts = || get_options(receiver.peer_id(), addrs.clone());
+    // nodes reject pushes by default
+    let rejecting = test_node(flat::Database::default(), addr)
+        .runtime(&rt)
+        .spawn()
+        .await?;
+    let rejecting_addrs = rejecting.local_endpoint_addresses().await?;
+
+    // every dial creates a new endpoint, so this needs a bit more time than other tests
+    tokio::time::timeout(Duration::from_secs(30), async {
+        // the receiver refuses pushes without the right token
+        assert!(push(&db, hash, opts(), None).await.is_err());
+        assert!(receiver_db.get(&hash).is_none());
+
+        let opts = opts();
+        let pusher = opts.keypair.public().into();
+        let outcome = push(&db, hash, opts, Some(token.clone())).await?;
+        assert_eq!(
+            outcome,
+            PushOutcome::Sent {
+                size: data.len() as u64
+            }
+        );
+        let entry = receiver_db.get(&hash).context("pushed blob not stored")?;
+        assert_eq!(entry.blob_path(), Some(dir.join(hash.to_hex()).as_path()));
+        assert_eq!(fs::read(dir.join(hash.to_hex())).await?, data);
+        assert_eq!(
+            *fetched.fetched.lock().unwrap(),
+            vec![(pusher, hash, data.len() as u64)]
+        );
+
+        // content the receiver already has is not sent again
+        let outcome = push(
+            &db,
+            hash,
+            get_options(receiver.peer_id(), addrs.clone()),
+            Some(token.clone()),
+        )
+        .await?;
+        assert_eq!(outcome, PushOutcome::Present);
+
+        let opts = get_options(rejecting.peer_id(), rejecting_addrs);
+        assert!(push(&db, hash, opts, None).await.is_err());
+        anyhow::Ok(())
+    })
+    .await
+    .context("timeout")?
+    .context("push failed")?;
+    Ok(())
+}
+
+#[cfg(feature = "flat-db")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_seed() -> Result<()> {
+    use iroh::database::flat;
+    use iroh::push::{PushReceiver, Seeder};
+
+    let rt = test_runtime();
+    let db = flat::Database::default();
+    let hash = db.import_bytes(vec![7u8; 50_000].into())?;
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let mut nodes = Vec::new();
+    let mut dbs = Vec::new();
+    for i in 0..3 {
+        let receiver_db = flat::Database::default();
+        let dir = testdir!().join(i.to_string());
+        let node = test_node(receiver_db.clone(), addr)
+            .runtime(&rt)
+            .push_handler(Arc::new(PushReceiver::new(receiver_db.clone(), dir)))
+            .spawn()
+            .await?;
+        nodes.push(node);
+        dbs.push(receiver_db);
+    }
+    // nodes reject pushes by default
+    let rejecting = test_node(flat::Database::default(), addr)
+        .runtime(&rt)
+        .spawn()
+        .await?;
+    let mut targets = Vec::new();
+    for node in std::iter::once(&rejecting).chain(&nodes) {
+        targets.push((node.peer_id(), node.local_endpoint_addresses().await?));
+    }
+    let targets = move || {
+        targets
+            .clone()
+            .into_iter()
+            .map(|(peer_id, addrs)| get_options(peer_id, addrs))
+    };
+
+    let seeder = Seeder::new(db)
+        .retries(1)
+        .retry_delay(Duration::from_millis(10));
+    // every dial creates a new endpoint, and seeding dials sequentially in several rounds
+    tokio::time::timeout(Duration::from_secs(60), async {
+        // the rejecting peer is retried after the first peer that is not tried yet
+        let report = seeder.seed(hash, targets(), 2).await?;
+        assert_eq!(
+            report.replicas,
+            vec![nodes[0].peer_id(), nodes[1].peer_id()]
+        );
+        assert!(report.failed.is_empty());
+        assert!(dbs[0].get(&hash).is_some() && dbs[1].get(&hash).is_some());
+        assert!(dbs[2].get(&hash).is_none());
+
+        // known replicas are not pushed to again
+        let report = seeder.seed(hash, targets(), 3).await?;
+        assert_eq!(report.replicas.len(), 3);
+        assert!(report.failed.is_empty());
+        assert!(dbs[2].get(&hash).is_some());
+        assert_eq!(seeder.replicas(&hash), report.replicas);
+
+        // peers are reported as failed once they are out of retries
+        let report = seeder.seed(hash, targets(), 4).await?;
+        assert_eq!(report.replicas.len(), 3);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, rejecting.peer_id());
+        anyhow::Ok(())
+    })
+    .await
+    .context("timeout")?
+    .context("seeding failed")?;
+    Ok(())
+}